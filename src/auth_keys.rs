@@ -0,0 +1,346 @@
+use msg_framing::AuthKeyRotation;
+
+use secp256k1;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Secp256k1, Signature};
+
+use utils;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now_ms() -> u64 {
+	let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Parses a job provider/pool argument of the form host:port[@pubkey], where pubkey is a
+/// hex-encoded compressed public key to pin as the server's auth key.
+// We never parse server arguments in sample-pool
+#[allow(dead_code)]
+pub fn parse_host_port_key(arg: &str) -> Option<(String, Option<PublicKey>)> {
+	let mut parts = arg.rsplitn(2, '@');
+	let last = parts.next().unwrap();
+	match parts.next() {
+		Some(host_port) => {
+			let key = match utils::hex_to_vec(last) {
+				Some(bytes) => match PublicKey::from_slice(&Secp256k1::new(), &bytes) {
+					Ok(key) if bytes.len() == 33 => key,
+					_ => return None,
+				},
+				None => return None,
+			};
+			Some((host_port.to_string(), Some(key)))
+		},
+		None => Some((last.to_string(), None)),
+	}
+}
+
+/// A known_hosts-style file of "host:port hex_pubkey" lines, used to remember the auth keys of
+/// servers which weren't pinned on the command line (ie trust-on-first-use). Also updated when a
+/// server rotates to a new key.
+// We never construct this in sample-pool
+#[allow(dead_code)]
+pub struct KnownHosts {
+	path: String,
+	keys: Mutex<HashMap<String, PublicKey>>,
+}
+
+// We never construct this in sample-pool
+#[allow(dead_code)]
+impl KnownHosts {
+	/// Loads the file at path, which is created on first write if it doesn't exist yet.
+	pub fn load(path: String) -> Result<Arc<Self>, io::Error> {
+		let mut keys = HashMap::new();
+		match fs::File::open(&path) {
+			Ok(file) => {
+				let secp_ctx = Secp256k1::new();
+				for line in io::BufReader::new(file).lines() {
+					let line = line?;
+					let mut fields = line.split_whitespace();
+					match (fields.next(), fields.next()) {
+						(Some(host_port), Some(key_hex)) if !host_port.starts_with('#') => {
+							let key = match utils::hex_to_vec(key_hex) {
+								Some(bytes) => PublicKey::from_slice(&secp_ctx, &bytes).ok(),
+								None => None,
+							};
+							match key {
+								Some(key) => { keys.insert(host_port.to_string(), key); },
+								None => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)),
+							}
+						},
+						_ => {},
+					}
+				}
+			},
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+			Err(e) => return Err(e),
+		}
+		Ok(Arc::new(Self { path, keys: Mutex::new(keys) }))
+	}
+
+	pub fn get(&self, host_port: &str) -> Option<PublicKey> {
+		self.keys.lock().unwrap().get(host_port).cloned()
+	}
+
+	fn learn(&self, host_port: &str, key: &PublicKey) {
+		let mut keys = self.keys.lock().unwrap();
+		if keys.get(host_port) == Some(key) { return; }
+		keys.insert(host_port.to_string(), key.clone());
+
+		let mut contents = String::new();
+		for (host_port, key) in keys.iter() {
+			contents += host_port;
+			contents.push(' ');
+			utils::push_bytes_hex(&key.serialize(), &mut contents);
+			contents.push('\n');
+		}
+		let tmp_path = self.path.clone() + ".tmp";
+		if let Err(e) = fs::File::create(&tmp_path).and_then(|mut file| file.write_all(contents.as_bytes())).and_then(|_| fs::rename(&tmp_path, &self.path)) {
+			println!("Failed to write known_hosts file {}: {}", self.path, e);
+		} else {
+			println!("Remembered auth key {} for {} in {}", utils::bytes_to_hex(&key.serialize()), host_port, self.path);
+		}
+	}
+}
+
+/// The auth key(s) we accept signatures from for a given work/pool server: the one we were
+/// configured with (or saw first) and, once the server announces one via AuthKeyRotation, its
+/// successor. Both are accepted until the announced rotation time, after which only the successor
+/// is.
+// We never construct this in sample-pool
+#[allow(dead_code)]
+pub struct PinnedAuthKeys {
+	current: Option<PublicKey>,
+	successor: Option<(PublicKey, u64)>,
+	known_hosts: Option<(Arc<KnownHosts>, String)>,
+}
+
+// We never construct this in sample-pool
+#[allow(dead_code)]
+impl PinnedAuthKeys {
+	/// Pins expected, or whatever key known_hosts has for host_port if expected is None. If neither
+	/// has a key, we trust the first one the server presents (and remember it in known_hosts).
+	pub fn new(host_port: &str, expected: Option<PublicKey>, known_hosts: &Option<Arc<KnownHosts>>) -> Self {
+		let current = match (expected, known_hosts) {
+			(Some(key), _) => Some(key),
+			(None, &Some(ref known_hosts)) => known_hosts.get(host_port),
+			(None, &None) => None,
+		};
+		Self {
+			current,
+			successor: None,
+			known_hosts: known_hosts.as_ref().map(|known_hosts| (known_hosts.clone(), host_port.to_string())),
+		}
+	}
+
+	fn learn_current(&self) {
+		if let (&Some(ref key), &Some((ref known_hosts, ref host_port))) = (&self.current, &self.known_hosts) {
+			known_hosts.learn(host_port, key);
+		}
+	}
+
+	fn expire_current(&mut self) {
+		if let Some((successor, rotation_timestamp)) = self.successor {
+			if now_ms() >= rotation_timestamp {
+				println!("Auth key rotation time reached, now only accepting the successor key");
+				self.current = Some(successor);
+				self.successor = None;
+				self.learn_current();
+			}
+		}
+	}
+
+	/// Checks the auth_key a server sent in its ProtocolVersion, pinning it if we have no key yet.
+	pub fn check_server_key(&mut self, key: &PublicKey) -> bool {
+		self.expire_current();
+		match self.current {
+			None => {
+				println!("Trusting auth key {} on first use", utils::bytes_to_hex(&key.serialize()));
+				self.current = Some(key.clone());
+				self.learn_current();
+				true
+			},
+			Some(current) => current == *key || self.successor.map(|(successor, _)| successor == *key).unwrap_or(false),
+		}
+	}
+
+	/// Checks a message signature against any key we currently accept.
+	pub fn verify(&mut self, secp_ctx: &Secp256k1, hash: &secp256k1::Message, sig: &Signature) -> bool {
+		self.expire_current();
+		self.current.iter().chain(self.successor.iter().map(|&(ref key, _)| key)).any(|key| {
+			secp_ctx.verify(hash, sig, key).is_ok()
+		})
+	}
+
+	/// Checks a message signature against the current key only (ie not an announced successor), as
+	/// is required for AuthKeyRotation messages.
+	pub fn verify_current(&mut self, secp_ctx: &Secp256k1, hash: &secp256k1::Message, sig: &Signature) -> bool {
+		self.expire_current();
+		match self.current {
+			Some(ref key) => secp_ctx.verify(hash, sig, key).is_ok(),
+			None => false,
+		}
+	}
+
+	/// Pins the successor from an AuthKeyRotation, which must have been checked with
+	/// verify_current first. A later announcement replaces an earlier one.
+	pub fn pin_successor(&mut self, rotation: &AuthKeyRotation) {
+		if self.current == Some(rotation.successor_key) { return; }
+		if self.successor != Some((rotation.successor_key, rotation.rotation_timestamp)) {
+			println!("Pinned successor auth key {}, accepting both keys until {}", utils::bytes_to_hex(&rotation.successor_key.serialize()), rotation.rotation_timestamp);
+		}
+		self.successor = Some((rotation.successor_key, rotation.rotation_timestamp));
+		self.expire_current();
+	}
+}
+
+/// Our own auth key and, if we're rotating to a new one, its successor and the time (in ms since
+/// the epoch) at which we start signing with it. Until then clients are sent an AuthKeyRotation
+/// (signed with the current key) right after ProtocolVersion so that they pin the successor.
+#[derive(Clone)]
+pub struct ServerAuthKeys {
+	current: SecretKey,
+	successor: Option<(SecretKey, u64)>,
+}
+
+impl ServerAuthKeys {
+	pub fn new(current: SecretKey, successor: Option<(SecretKey, u64)>) -> Self {
+		Self { current, successor }
+	}
+
+	/// The key to authenticate and sign messages with right now
+	pub fn signing_key(&self) -> &SecretKey {
+		match self.successor {
+			Some((ref successor, rotation_timestamp)) if now_ms() >= rotation_timestamp => successor,
+			_ => &self.current,
+		}
+	}
+
+	/// The AuthKeyRotation to announce to clients, and the (current) key to sign it with, if we
+	/// have yet to switch to our successor.
+	pub fn pending_rotation(&self, secp_ctx: &Secp256k1) -> Option<(AuthKeyRotation, &SecretKey)> {
+		match self.successor {
+			Some((ref successor, rotation_timestamp)) if now_ms() < rotation_timestamp => Some((AuthKeyRotation {
+				successor_key: PublicKey::from_secret_key(secp_ctx, successor).unwrap(),
+				rotation_timestamp,
+			}, &self.current)),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use auth_keys::*;
+
+	use std::env;
+
+	fn key(byte: u8) -> (SecretKey, PublicKey) {
+		let secp_ctx = Secp256k1::new();
+		let secret = SecretKey::from_slice(&secp_ctx, &[byte; 32]).unwrap();
+		let public = PublicKey::from_secret_key(&secp_ctx, &secret).unwrap();
+		(secret, public)
+	}
+
+	fn sign(secret: &SecretKey) -> (secp256k1::Message, Signature) {
+		let secp_ctx = Secp256k1::new();
+		let hash = secp256k1::Message::from_slice(&[42; 32]).unwrap();
+		(hash, secp_ctx.sign(&hash, secret).unwrap())
+	}
+
+	#[test]
+	fn test_parse_host_port_key() {
+		let (_, public) = key(1);
+		let hex = utils::bytes_to_hex(&public.serialize());
+		assert_eq!(parse_host_port_key("pool.example.com:8080"), Some(("pool.example.com:8080".to_string(), None)));
+		assert_eq!(parse_host_port_key(&format!("pool.example.com:8080@{}", hex)), Some(("pool.example.com:8080".to_string(), Some(public))));
+		assert_eq!(parse_host_port_key(&format!("[::1]:8080@{}", hex)), Some(("[::1]:8080".to_string(), Some(public))));
+		// Bad hex, uncompressed keys and non-keys are all rejected
+		assert_eq!(parse_host_port_key("pool.example.com:8080@zz"), None);
+		assert_eq!(parse_host_port_key(&format!("pool.example.com:8080@{}", utils::bytes_to_hex(&public.serialize_uncompressed()))), None);
+		assert_eq!(parse_host_port_key(&format!("pool.example.com:8080@{}", utils::bytes_to_hex(&[5; 33]))), None);
+	}
+
+	#[test]
+	fn test_rotation() {
+		let secp_ctx = Secp256k1::new();
+		let (current_secret, current) = key(1);
+		let (successor_secret, successor) = key(2);
+		let (other_secret, other) = key(3);
+		let (current_hash, current_sig) = sign(&current_secret);
+		let (successor_hash, successor_sig) = sign(&successor_secret);
+		let (other_hash, other_sig) = sign(&other_secret);
+
+		// Before the rotation time both keys are accepted, though only the current one may announce
+		// further rotations
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", Some(current), &None);
+		pinned.pin_successor(&AuthKeyRotation { successor_key: successor, rotation_timestamp: now_ms() + 60 * 60 * 1000 });
+		assert!(pinned.check_server_key(&current));
+		assert!(pinned.check_server_key(&successor));
+		assert!(!pinned.check_server_key(&other));
+		assert!(pinned.verify(&secp_ctx, &current_hash, &current_sig));
+		assert!(pinned.verify(&secp_ctx, &successor_hash, &successor_sig));
+		assert!(!pinned.verify(&secp_ctx, &other_hash, &other_sig));
+		assert!(pinned.verify_current(&secp_ctx, &current_hash, &current_sig));
+		assert!(!pinned.verify_current(&secp_ctx, &successor_hash, &successor_sig));
+
+		// Once it has passed only the successor is
+		pinned.pin_successor(&AuthKeyRotation { successor_key: successor, rotation_timestamp: now_ms() - 1 });
+		assert!(!pinned.check_server_key(&current));
+		assert!(pinned.check_server_key(&successor));
+		assert!(!pinned.verify(&secp_ctx, &current_hash, &current_sig));
+		assert!(pinned.verify(&secp_ctx, &successor_hash, &successor_sig));
+		assert!(!pinned.verify_current(&secp_ctx, &current_hash, &current_sig));
+		assert!(pinned.verify_current(&secp_ctx, &successor_hash, &successor_sig));
+	}
+
+	#[test]
+	fn test_known_hosts() {
+		let dir = env::temp_dir().join(format!("known-hosts-test-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("known_hosts").to_str().unwrap().to_string();
+		let (_, first) = key(1);
+		let (_, second) = key(2);
+		let (_, successor) = key(3);
+
+		// With no file and nothing pinned we trust (and remember) the first key we see...
+		let known_hosts = Some(KnownHosts::load(path.clone()).unwrap());
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", None, &known_hosts);
+		assert!(pinned.check_server_key(&first));
+		assert!(!pinned.check_server_key(&second));
+		assert!(pinned.check_server_key(&first));
+
+		// ...and refuse any other key after a restart
+		let known_hosts = Some(KnownHosts::load(path.clone()).unwrap());
+		assert_eq!(known_hosts.as_ref().unwrap().get("pool.example.com:8080"), Some(first));
+		assert_eq!(known_hosts.as_ref().unwrap().get("other.example.com:8080"), None);
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", None, &known_hosts);
+		assert!(!pinned.check_server_key(&second));
+		assert!(pinned.check_server_key(&first));
+
+		// A key given on the command line takes precedence
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", Some(second), &known_hosts);
+		assert!(pinned.check_server_key(&second));
+		assert!(!pinned.check_server_key(&first));
+
+		// Completed rotations are written back
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", None, &known_hosts);
+		pinned.pin_successor(&AuthKeyRotation { successor_key: successor, rotation_timestamp: now_ms() - 1 });
+		let known_hosts = Some(KnownHosts::load(path.clone()).unwrap());
+		assert_eq!(known_hosts.as_ref().unwrap().get("pool.example.com:8080"), Some(successor));
+		let mut pinned = PinnedAuthKeys::new("pool.example.com:8080", None, &known_hosts);
+		assert!(!pinned.check_server_key(&first));
+		assert!(pinned.check_server_key(&successor));
+
+		// Garbage in the file is an error rather than silently trusting whatever we see next
+		fs::write(&path, "pool.example.com:8080 zz\n").unwrap();
+		assert!(KnownHosts::load(path).is_err());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
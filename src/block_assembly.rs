@@ -0,0 +1,203 @@
+use msg_framing::{BlockTemplate,WinningNonce};
+use rpc_client::RPCClient;
+use work_client::{EventualTxData,JobProviderHandler};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction,TxIn,TxOut};
+use bitcoin::network::serialize;
+use bitcoin::network::encodable::VarInt;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use futures::{future,Future};
+
+use serde_json;
+
+use tokio;
+
+use utils;
+
+use std::sync::Arc;
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+	let mut res = [0; 32];
+	let mut sha = Sha256::new();
+	sha.input(data);
+	sha.result(&mut res);
+	sha.reset();
+	sha.input(&res);
+	sha.result(&mut res);
+	res
+}
+
+/// Hashes one level of a merkle tree into the next, duplicating the last entry if needed
+pub fn merkle_level_up(level: &Vec<[u8; 32]>) -> Vec<[u8; 32]> {
+	level.chunks(2).map(|pair| {
+		let mut concat = [0; 64];
+		concat[..32].copy_from_slice(&pair[0]);
+		concat[32..].copy_from_slice(&pair[pair.len() - 1]);
+		sha256d(&concat)
+	}).collect()
+}
+
+/// The merkle_rhss for a block's coinbase given all its txids (the coinbase's own, at index 0,
+/// doesn't matter).
+pub fn coinbase_merkle_branch(mut txids: Vec<[u8; 32]>) -> Vec<[u8; 32]> {
+	let mut merkle_rhss = Vec::new();
+	while txids.len() > 1 {
+		merkle_rhss.push(txids[1]);
+		txids = merkle_level_up(&txids);
+	}
+	merkle_rhss
+}
+
+/// The BIP 141 witness commitment output in a template's coinbase, if it has one (the last
+/// matching output wins, as in consensus).
+pub fn witness_commitment_output(template: &BlockTemplate) -> Option<&TxOut> {
+	template.appended_coinbase_outputs.iter().rev().find(|output| {
+		output.script_pubkey.len() >= 38 && output.script_pubkey[..6] == [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed]
+	})
+}
+
+/// Builds the serialized block (and its hash) for a full-block solution to template, given the
+/// transactions the template commits to (not including the coinbase).
+pub fn assemble_block(template: &BlockTemplate, nonces: &WinningNonce, txn: &Vec<Vec<u8>>) -> (Sha256dHash, Vec<u8>) {
+	let mut coinbase_tx = nonces.coinbase_tx.clone();
+	if witness_commitment_output(template).is_some() && coinbase_tx.input[0].witness.is_empty() {
+		// bitcoind always commits with an all-0 witness reserved value
+		coinbase_tx.input[0].witness = vec![vec![0; 32]];
+	}
+
+	let mut merkle_lhs = [0; 32];
+	merkle_lhs.copy_from_slice(&coinbase_tx.txid()[..]);
+	let mut sha = Sha256::new();
+	for rhs in template.merkle_rhss.iter() {
+		sha.reset();
+		sha.input(&merkle_lhs);
+		sha.input(&rhs[..]);
+		sha.result(&mut merkle_lhs);
+		sha.reset();
+		sha.input(&merkle_lhs);
+		sha.result(&mut merkle_lhs);
+	}
+
+	let header = BlockHeader {
+		version: nonces.header_version,
+		prev_blockhash: Sha256dHash::from(&template.header_prevblock[..]),
+		merkle_root: Sha256dHash::from(&merkle_lhs[..]),
+		time: nonces.header_time,
+		bits: template.header_nbits,
+		nonce: nonces.header_nonce,
+	};
+
+	let mut block = serialize::serialize(&header).unwrap();
+	block.extend_from_slice(&serialize::serialize(&VarInt(1 + txn.len() as u64)).unwrap());
+	block.extend_from_slice(&serialize::serialize(&coinbase_tx).unwrap());
+	for tx in txn.iter() {
+		block.extend_from_slice(tx);
+	}
+	(header.bitcoin_hash(), block)
+}
+
+/// Builds a block from template and its transactions with a stand-in coinbase (paying
+/// coinbase_value_remaining to OP_TRUE) and no proof of work, for bitcoind to check as a BIP 23
+/// block proposal.
+pub fn proposal_block(template: &BlockTemplate, txn: &Vec<Vec<u8>>) -> Vec<u8> {
+	let mut script_sig = template.coinbase_prefix.clone();
+	script_sig.extend_from_slice(&[0; 8]);
+	script_sig.extend_from_slice(&template.coinbase_postfix);
+	let mut outputs = vec![TxOut {
+		value: template.coinbase_value_remaining,
+		script_pubkey: Script::from(vec![0x51]),
+	}];
+	outputs.extend_from_slice(&template.appended_coinbase_outputs);
+
+	let nonces = WinningNonce {
+		template_timestamp: template.template_timestamp,
+		header_version: template.header_version,
+		header_time: template.header_time,
+		header_nonce: 0,
+		user_tag: Vec::new(),
+		coinbase_tx: Transaction {
+			version: template.coinbase_version,
+			input: vec![TxIn {
+				prev_hash: Default::default(),
+				prev_index: 0xffffffff,
+				script_sig: Script::from(script_sig),
+				sequence: template.coinbase_input_sequence,
+				witness: vec![],
+			}],
+			output: outputs,
+			lock_time: template.coinbase_locktime,
+		},
+	};
+	assemble_block(template, &nonces, txn).1
+}
+
+/// Gets full-block solutions out by every route we have: the job provider which issued the
+/// template (which builds the block itself, and gets the nonces again once it reconnects if it
+/// happens to be disconnected) and any bitcoind RPC endpoints we were given (which get the block
+/// assembled from the template and the transaction data we cached for it). Other job providers
+/// never see the block (the work protocol has no way to hand them one), so if the issuing provider
+/// disconnects at the wrong moment and never comes back, only a bitcoind RPC endpoint saves it.
+pub struct BlockSubmitter {
+	rpc_clients: Vec<RPCClient>,
+}
+
+impl BlockSubmitter {
+	pub fn new(rpc_clients: Vec<RPCClient>) -> Arc<Self> {
+		Arc::new(Self {
+			rpc_clients,
+		})
+	}
+
+	/// If we have a bitcoind RPC endpoint, has the first one fully validate template (as a block
+	/// proposal), which, unlike anything we can do without the outputs its transactions spend,
+	/// checks that its coinbase doesn't claim more than the subsidy plus fees. Resolves to
+	/// bitcoind's reason if it rejected the template. Proposals bitcoind can't judge (any
+	/// "*inconclusive*" result, eg because it's not on the same tip), duplicates and RPC failures
+	/// don't count against the template.
+	pub fn check_template(&self, template: &BlockTemplate, txn: &Vec<Vec<u8>>) -> Option<Box<dyn Future<Item=Option<String>, Error=()> + Send>> {
+		let client = self.rpc_clients.first()?;
+		let proposal = "{\"mode\":\"proposal\",\"data\":\"".to_string() + &utils::bytes_to_hex(&proposal_block(template, txn)) + "\"}";
+		Some(Box::new(client.make_rpc_call("getblocktemplate", &vec![&proposal]).then(|res| {
+			future::result(Ok(match res {
+				Ok(serde_json::Value::String(ref reason)) if !reason.contains("inconclusive") && reason != "duplicate" => Some(reason.clone()),
+				_ => None,
+			}))
+		})))
+	}
+
+	pub fn submit(self: &Arc<Self>, template: &Arc<BlockTemplate>, nonces: &WinningNonce, tx_data: &Arc<EventualTxData>, source: &Arc<JobProviderHandler>) {
+		source.send_nonce(nonces.clone());
+		if self.rpc_clients.is_empty() { return; }
+
+		if !tx_data.has_result() {
+			// We'll get it if the provider does (re)send it, but it may well never arrive
+			println!("Full block found before its job provider sent us the transactions for it, can't submitblock it until it does");
+		}
+		let us = self.clone();
+		let template = template.clone();
+		let nonces = nonces.clone();
+		tx_data.get_and(move |txn, _, _| {
+			let (block_hash, block) = assemble_block(&template, &nonces, txn);
+			println!("Assembled full block {}, submitting it via submitblock", block_hash.be_hex_string());
+
+			let block_hex = "\"".to_string() + &utils::bytes_to_hex(&block) + "\"";
+			for client in us.rpc_clients.iter() {
+				tokio::spawn(client.make_rpc_call("submitblock", &vec![&block_hex]).then(|res| {
+					match res {
+						Ok(ref result) if result.is_null() => println!("bitcoind accepted our block via submitblock"),
+						Ok(result) => println!("bitcoind rejected our block via submitblock: {}", result),
+						Err(_) => println!("Failed to submit our block via submitblock"),
+					}
+					Ok(())
+				}));
+			}
+		});
+	}
+}
@@ -1,5 +1,7 @@
 use futures::future;
 use futures::{Future,Stream,Sink};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
 
 use tokio;
 use tokio::{net, timer};
@@ -7,10 +9,122 @@ use tokio::{net, timer};
 use tokio_io::codec;
 use tokio_codec;
 
-use std::{io,marker};
+use socks5;
+use socks5::Socks5Proxy;
+
+use std::{cmp,io,marker};
 use std::net::{SocketAddr,ToSocketAddrs};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Well below the ~5 minutes after which many NAT boxes drop idle mappings
+const KEEPALIVE_INTERVAL_SECS: u64 = 60;
+/// Likewise, but also well below the read timeouts servers use to drop idle clients
+const DEFAULT_HEARTBEAT_SECS: u64 = 60;
+/// How long we keep using resolved addresses before resolving the host again on reconnect
+const RESOLVE_INTERVAL_SECS: u64 = 60*5;
+/// How long to give one address before also trying the next one (RFC 8305 recommends 250ms)
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+/// How long to wait between failed attempts to (re)connect to a host, doubling from min up to
+/// max on each failure (less up to half for jitter, so that a restarted pool doesn't get every
+/// proxy back at the same instant).
+#[derive(Clone, Copy)]
+pub struct ReconnectBackoff {
+	pub min: Duration,
+	pub max: Duration,
+}
+
+impl Default for ReconnectBackoff {
+	fn default() -> Self {
+		Self {
+			min: Duration::from_secs(1),
+			max: Duration::from_secs(60),
+		}
+	}
+}
+
+/// How we treat connections to upstream job providers and pools
+#[derive(Clone)]
+pub struct UpstreamConfig {
+	pub reconnect: ReconnectBackoff,
+	/// How often to send the host a message it will ignore (if ever), so that a quiet connection
+	/// doesn't look idle to the host or to any middleboxes along the way.
+	pub heartbeat: Option<Duration>,
+	/// Whether to drop connections to hosts which don't agree to encrypt them. Encryption is
+	/// negotiated in the clear, so otherwise anyone on the path can quietly turn it off.
+	pub require_encryption: bool,
+	/// Resolving blocks, so every connection resolves its host on this thread instead of stalling
+	/// the reactor
+	pub resolver: CpuPool,
+}
+
+impl Default for UpstreamConfig {
+	fn default() -> Self {
+		Self {
+			reconnect: ReconnectBackoff::default(),
+			heartbeat: Some(Duration::from_secs(DEFAULT_HEARTBEAT_SECS)),
+			require_encryption: false,
+			resolver: CpuPool::new(1),
+		}
+	}
+}
+
+impl ReconnectBackoff {
+	fn delay(&self, failures: u32) -> Duration {
+		let base = cmp::min(self.max, self.min * (1 << cmp::min(failures, 16)));
+		// We don't need anything better than the clock for jitter
+		let jitter = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos() % 1000;
+		base / 2 + base / 2 * jitter / 1000
+	}
+}
+
+/// Orders addresses alternating between address families (starting with whichever the resolver
+/// put first), as suggested by RFC 8305, so that a broken IPv6 (or IPv4) path only costs us
+/// one connection attempt delay.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+	let first_v6 = match addrs.first() { Some(addr) => addr.is_ipv6(), None => return addrs };
+	let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+	let mut res = Vec::with_capacity(first.len() + second.len());
+	first.reverse();
+	second.reverse();
+	loop {
+		match (first.pop(), second.pop()) {
+			(None, None) => return res,
+			(a, b) => {
+				if let Some(a) = a { res.push(a); }
+				if let Some(b) = b { res.push(b); }
+			}
+		}
+	}
+}
+
+type ConnectFuture = Box<dyn Future<Item = net::TcpStream, Error = ()> + Send>;
+
+/// Connects to the first address, starting a connection to the next one (and so on) each time
+/// the previous one fails or CONNECTION_ATTEMPT_DELAY_MS passes without it connecting, and
+/// returns whichever connects first.
+fn race_connect(mut addrs: Vec<SocketAddr>) -> ConnectFuture {
+	if addrs.is_empty() {
+		return Box::new(future::err(()));
+	}
+	let addr = addrs.remove(0);
+	println!("Trying connection to {}", addr);
+	let attempt = net::TcpStream::connect(&addr).map_err(|_| ());
+	if addrs.is_empty() {
+		return Box::new(attempt);
+	}
+	Box::new(attempt.select2(timer::Delay::new(Instant::now() + Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS))).then(move |res| -> ConnectFuture {
+		match res {
+			Ok(future::Either::A((stream, _))) => Box::new(future::ok(stream)),
+			Err(future::Either::A((_, _))) => race_connect(addrs),
+			Ok(future::Either::B((_, attempt))) | Err(future::Either::B((_, attempt))) => {
+				let attempts: Vec<ConnectFuture> = vec![Box::new(attempt), race_connect(addrs)];
+				Box::new(future::select_ok(attempts).map(|(stream, _)| stream))
+			},
+		}
+	}))
+}
 
 pub trait ConnectionHandler<MessageType> {
 	type Stream : Stream<Item = MessageType> + Send;
@@ -18,98 +132,219 @@ pub trait ConnectionHandler<MessageType> {
 	fn new_connection(&self) -> (Self::Framer, Self::Stream);
 	fn handle_message(&self, msg: MessageType) -> Result<(), io::Error>;
 	fn connection_closed(&self);
+	/// Called once the send half of a connection has finished, ie the Stream returned by
+	/// new_connection ended and everything it yielded was flushed (or the socket failed).
+	fn send_side_closed(&self);
+	/// Called every UpstreamConfig::heartbeat while connected, should send the host something
+	/// it will ignore.
+	fn send_heartbeat(&self);
+	/// If this returns false we stop (re)connecting, eg because we're shutting down.
+	fn should_reconnect(&self) -> bool;
+	/// Called once a connection has closed. If the host (in a message signed with its auth key)
+	/// asked us to move to another host:port, returns it, and we connect there from then on.
+	fn take_redirect(&self) -> Option<String>;
 }
 
 pub struct ConnectionMaintainer<MessageType: 'static + Send, HandlerProvider : ConnectionHandler<MessageType>> {
 	host: String,
-	cur_addrs: Option<Vec<SocketAddr>>,
+	proxy: Option<Socks5Proxy>,
+	/// The addresses of the proxy, if we have one, otherwise of the host
+	cur_addrs: Vec<SocketAddr>,
+	/// When we got cur_addrs, or None if we should resolve again before the next attempt
+	resolved_at: Option<Instant>,
+	resolver: CpuPool,
+	backoff: ReconnectBackoff,
+	heartbeat: Option<Duration>,
+	failures: u32,
 	handler: HandlerProvider,
 	ph : marker::PhantomData<&'static MessageType>,
 }
 
-impl<MessageType : Send + Sync, HandlerProvider : 'static + ConnectionHandler<MessageType> + Send + Sync> ConnectionMaintainer<MessageType, HandlerProvider> {
-	pub fn new(host: String, handler: HandlerProvider) -> ConnectionMaintainer<MessageType, HandlerProvider> {
+impl<MessageType : Send + Sync, HandlerProvider : 'static + ConnectionHandler<MessageType> + Clone + Send + Sync> ConnectionMaintainer<MessageType, HandlerProvider> {
+	pub fn new(host: String, proxy: Option<Socks5Proxy>, config: UpstreamConfig, handler: HandlerProvider) -> ConnectionMaintainer<MessageType, HandlerProvider> {
 		ConnectionMaintainer {
 			host: host,
-			cur_addrs: None,
+			proxy,
+			cur_addrs: Vec::new(),
+			resolved_at: None,
+			resolver: config.resolver,
+			backoff: config.reconnect,
+			heartbeat: config.heartbeat,
+			failures: 0,
 			handler: handler,
 			ph: marker::PhantomData,
 		}
 	}
 
+	fn retry_later(mut self) {
+		let delay = self.backoff.delay(self.failures);
+		self.failures = self.failures.saturating_add(1);
+		println!("Retrying connection to {} in {} ms", self.host, delay.as_secs() * 1000 + delay.subsec_millis() as u64);
+		tokio::spawn(timer::Delay::new(Instant::now() + delay).then(move |_| -> future::FutureResult<(), ()> {
+			self.make_connection();
+			future::result(Ok(()))
+		}));
+	}
+
 	pub fn make_connection(mut self) {
-		if {
-			if self.cur_addrs.is_none() {
-				//TODO: Resolve async
-				match self.host.to_socket_addrs() {
-					Err(_) => {
-						true
-					},
+		if !self.handler.should_reconnect() {
+			println!("Not reconnecting to {} as we're shutting down", self.host);
+			return;
+		}
+
+		let fresh = match self.resolved_at {
+			Some(resolved_at) => resolved_at.elapsed() < Duration::from_secs(RESOLVE_INTERVAL_SECS),
+			None => false,
+		};
+		if fresh {
+			let addrs = self.cur_addrs.clone();
+			self.connect(addrs);
+		} else {
+			// Through a proxy we leave resolving the host to the proxy
+			let host = match self.proxy { Some(ref proxy) => proxy.host_port.clone(), None => self.host.clone() };
+			let resolve_host = host.clone();
+			tokio::spawn(self.resolver.spawn_fn(move || {
+				resolve_host.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>())
+			}).then(move |res| -> future::FutureResult<(), ()> {
+				match res {
 					Ok(addrs) => {
-						self.cur_addrs = Some(addrs.collect());
-						false
-					}
+						if addrs.is_empty() {
+							println!("{} resolved to no addresses", host);
+						} else {
+							self.cur_addrs = interleave_families(addrs);
+							self.resolved_at = Some(Instant::now());
+						}
+					},
+					Err(e) => println!("Failed to resolve {}: {}", host, e),
+				}
+				// If the resolver is having trouble keep going with what we last got from it
+				if self.cur_addrs.is_empty() {
+					self.retry_later();
+				} else {
+					let addrs = self.cur_addrs.clone();
+					self.connect(addrs);
 				}
-			} else { false }
-		} {
-			tokio::spawn(timer::Delay::new(Instant::now() + Duration::from_secs(10)).then(move |_| -> future::FutureResult<(), ()> {
-				self.make_connection();
 				future::result(Ok(()))
 			}));
-			return;
 		}
+	}
 
-		let addr_option = {
-			let addr = self.cur_addrs.as_mut().unwrap().pop();
-			if addr.is_none() {
-				self.cur_addrs = None;
-			}
-			addr
+	fn connect(mut self, addrs: Vec<SocketAddr>) {
+		let connection = match self.proxy {
+			Some(ref proxy) => {
+				let (proxy, host) = (proxy.clone(), self.host.clone());
+				Box::new(race_connect(addrs).and_then(move |stream| {
+					socks5::handshake(stream, &proxy, &host).map_err(|_| ())
+				}))
+			},
+			None => race_connect(addrs),
 		};
+		tokio::spawn(connection.then(move |res| -> future::FutureResult<(), ()> {
+			match res {
+				Ok(stream) => {
+					match self.proxy {
+						Some(ref proxy) => println!("Connected to {} through SOCKS5 proxy {}!", self.host, proxy.host_port),
+						None => println!("Connected to {}!", stream.peer_addr().unwrap()),
+					}
+					stream.set_nodelay(true).unwrap();
+					// Upstreams can go quiet for a while (eg pools between share responses), so have
+					// the kernel probe the link both to keep NAT mappings alive and to notice if the
+					// other end silently disappeared.
+					if let Err(e) = stream.set_keepalive(Some(Duration::from_secs(KEEPALIVE_INTERVAL_SECS))) {
+						println!("Failed to enable TCP keepalive on connection to {}: {}", self.host, e);
+					}
+
+					let (framer, tx_stream) = self.handler.new_connection();
+					let (tx, rx) = tokio_codec::Framed::new(stream, framer).split();
+					let stream = tx_stream.map_err(|_| -> io::Error {
+						panic!("mpsc streams cant generate errors!");
+					});
+					let send_handler = self.handler.clone();
+					tokio::spawn(tx.send_all(stream).then(move |_| {
+						println!("Disconnected on send side, will reconnect...");
+						send_handler.send_side_closed();
+						future::result(Ok(()))
+					}));
+					// Dropped (stopping the heartbeat) once the connection closes
+					let (heartbeat_stop, heartbeat_stopped) = oneshot::channel::<()>();
+					if let Some(interval) = self.heartbeat {
+						let heartbeat_handler = self.handler.clone();
+						tokio::spawn(timer::Interval::new(Instant::now() + interval, interval).for_each(move |_| {
+							heartbeat_handler.send_heartbeat();
+							future::result(Ok(()))
+						}).map_err(|_| ()).select2(heartbeat_stopped).then(|_| {
+							future::result(Ok(()))
+						}));
+					}
 
-		match addr_option {
-			Some(addr) => {
-				println!("Trying connection to {}", addr);
-
-				tokio::spawn(net::TcpStream::connect(&addr).then(move |res| -> future::FutureResult<(), ()> {
-					match res {
-						Ok(stream) => {
-							println!("Connected to {}!", stream.peer_addr().unwrap());
-							stream.set_nodelay(true).unwrap();
-
-							let (framer, tx_stream) = self.handler.new_connection();
-							let (tx, rx) = tokio_codec::Framed::new(stream, framer).split();
-							let stream = tx_stream.map_err(|_| -> io::Error {
-								panic!("mpsc streams cant generate errors!");
-							});
-							tokio::spawn(tx.send_all(stream).then(|_| {
-								println!("Disconnected on send side, will reconnect...");
-								future::result(Ok(()))
-							}));
-							let us = Arc::new(self);
-							let us_close = us.clone();
-							tokio::spawn(rx.for_each(move |msg| {
-								future::result(us.handler.handle_message(msg))
-							}).then(move |_| {
-								println!("Disconnected on recv side, will reconnect...");
-								us_close.handler.connection_closed();
-								Arc::try_unwrap(us_close).ok().unwrap().make_connection();
-								future::result(Ok(()))
-							}));
-						},
-						Err(_) => {
-							self.make_connection();
+					let connected_at = Instant::now();
+					let us = Arc::new(self);
+					let us_close = us.clone();
+					tokio::spawn(rx.for_each(move |msg| {
+						future::result(us.handler.handle_message(msg))
+					}).then(move |_| {
+						println!("Disconnected on recv side, will reconnect...");
+						drop(heartbeat_stop);
+						us_close.handler.connection_closed();
+						let mut us = Arc::try_unwrap(us_close).ok().unwrap();
+						if let Some(new_host) = us.handler.take_redirect() {
+							println!("Moving from {} to {} as it asked us to", us.host, new_host);
+							us.host = new_host;
+							us.cur_addrs.clear();
+							us.resolved_at = None;
+							us.failures = 0;
+							us.make_connection();
+							return future::result(Ok(()));
 						}
-					};
-					future::result(Ok(()))
-				}));
-			},
-			None => {
-				tokio::spawn(timer::Delay::new(Instant::now() + Duration::from_secs(10)).then(move |_| {
-					self.make_connection();
-					future::result(Ok(()))
-				}));
-			},
+						// Only reconnect right away if the connection was good for a while, otherwise a
+						// host which accepts and then immediately drops us would have us spinning.
+						if connected_at.elapsed() >= us.backoff.max {
+							us.failures = 0;
+							us.make_connection();
+						} else {
+							us.retry_later();
+						}
+						future::result(Ok(()))
+					}));
+				},
+				Err(_) => {
+					// Resolve again next time in case the host moved
+					self.resolved_at = None;
+					self.retry_later();
+				}
+			};
+			future::result(Ok(()))
+		}));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use connection_maintainer::*;
+
+	fn v4(port: u16) -> SocketAddr { SocketAddr::from(([127, 0, 0, 1], port)) }
+	fn v6(port: u16) -> SocketAddr { SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port)) }
+
+	#[test]
+	fn test_interleave_families() {
+		assert!(interleave_families(Vec::new()).is_empty());
+		assert_eq!(interleave_families(vec![v4(1), v4(2), v4(3)]), vec![v4(1), v4(2), v4(3)]);
+		assert_eq!(interleave_families(vec![v6(1), v6(2), v4(3), v4(4)]), vec![v6(1), v4(3), v6(2), v4(4)]);
+		assert_eq!(interleave_families(vec![v4(1), v6(2), v6(3), v4(4)]), vec![v4(1), v6(2), v4(4), v6(3)]);
+		// Leftovers of the bigger family go at the end, in the order the resolver gave them
+		assert_eq!(interleave_families(vec![v6(1), v4(2), v6(3), v6(4), v6(5)]), vec![v6(1), v4(2), v6(3), v6(4), v6(5)]);
+	}
+
+	#[test]
+	fn test_reconnect_delay() {
+		let backoff = ReconnectBackoff { min: Duration::from_secs(1), max: Duration::from_secs(60) };
+		for (failures, base) in [(0, 1), (1, 2), (2, 4), (5, 32), (6, 60), (16, 60), (u32::max_value(), 60)].iter() {
+			let delay = backoff.delay(*failures);
+			let base = Duration::from_secs(*base);
+			assert!(delay >= base / 2 && delay <= base);
 		}
+
+		let backoff = ReconnectBackoff { min: Duration::from_secs(0), max: Duration::from_secs(0) };
+		assert_eq!(backoff.delay(3), Duration::from_secs(0));
 	}
 }
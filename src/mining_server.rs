@@ -1,5 +1,8 @@
-use msg_framing::{BlockTemplate,BlockTemplateHeader,CoinbasePrefixPostfix,WinningNonce,WorkMessage,WorkMsgFramer};
-use work_info::WorkInfo;
+use auth_keys::ServerAuthKeys;
+use msg_framing::{AUTH_KEY_ROTATION_SIG_TYPE,BlockTemplate,BlockTemplateHeader,CoinbasePrefixPostfix,NewServerHostPort,PoolUserAuth,TransactionData,WinningNonce,WorkMessage,WorkMsgFramer};
+use pool_client::{PoolAuthAction, PoolProviderUserJob, UserUpdate};
+use transport_crypto::ENCRYPTION_FLAG;
+use work_info::{JobSet, WorkInfo};
 use utils;
 
 use bitcoin::blockdata::block::BlockHeader;
@@ -18,36 +21,110 @@ use futures::{future,Stream,Sink};
 use futures::future::Future;
 use futures::sync::mpsc;
 
+use futures_cpupool::CpuPool;
+
 use tokio;
 use tokio::{net, timer};
 
 use tokio_codec;
 
-use timeout_stream::TimeoutStream;
+use timeout_stream::{IdleTimeouts, ProgressIo, TimeoutSink, TimeoutStream};
 
 use secp256k1::key::{SecretKey,PublicKey};
 use secp256k1::Secp256k1;
+use secp256k1::Signature;
 use secp256k1;
 
-use std::collections::BTreeMap;
-use std::io;
+use std::collections::{HashMap, hash_map};
+use std::{cmp, io, mem};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, RwLock};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 
 struct MiningClient {
 	stream: mpsc::Sender<WorkMessage>,
 	client_id: u64,
+	/// The client's real address (ie from the PROXY protocol header if we're behind a balancer)
+	addr: SocketAddr,
 	use_header_variants: AtomicBool,
+	/// Client adds its own payout info (ie is another proxy using us as its job provider), so
+	/// gets the job provider's template instead of ours.
+	nonfinal_work: AtomicBool,
+	/// Bytes of coinbase scriptSig a nonfinal_work client wants to have left for itself
+	additional_coinbase_length: AtomicUsize,
 	handshake_complete: AtomicBool,
+	last_send: Mutex<Instant>,
+	/// Leading 0s in the share target we're giving this client, adjusted based on how many
+	/// shares it submits
+	share_target_0s: AtomicUsize,
+	/// The share target before the last adjustment, as the client may still be working on
+	/// templates it got before then
+	prev_share_target_0s: AtomicUsize,
+	/// Shares (including ones which met the real target) since the last adjustment
+	shares: AtomicUsize,
+	/// The template_timestamp of the last job we sent (or started signing a header for)
+	latest_template_timestamp: Mutex<u64>,
+	/// BlockTemplateHeaders waiting on (or being built on) the signing pool
+	header_signing: Mutex<HeaderSigning>,
+	/// The pool user this client mines for (if we're doing upstream auth), once we know it
+	user_id: Mutex<Option<Vec<u8>>>,
+	/// The pool's coinbase postfix and target for user_id, until which we can't send work
+	user_job: Mutex<Option<PoolProviderUserJob>>,
+	/// The pool rejected our user, we hang up the next time we hear from the client
+	needs_close: AtomicBool,
+}
+
+/// A client has at most one header being signed at once, with only the latest job it should get
+/// waiting behind it. This bounds the signing pool's queue by our client count, even if jobs (or
+/// retargets) come faster than we can sign them, and keeps each client's headers in order.
+#[derive(Default)]
+struct HeaderSigning {
+	in_flight: bool,
+	next: Option<(Arc<BlockTemplate>, [u8; 32], SecretKey)>,
+}
+
+struct MiningUser {
+	clients: Vec<Arc<MiningClient>>,
+	cur_job: Option<PoolProviderUserJob>,
+	/// What the first client gave us to auth the user with the pool, which the rest must match
+	user_auth: Vec<u8>,
+}
+
+/// Downstream proxies which don't tell us otherwise expect the coinbase prefix to leave them the
+/// usual 100 - 42 bytes of scriptSig.
+const DEFAULT_ADDITIONAL_COINBASE_LENGTH: usize = 100 - 42;
+
+/// Splits what follows "UserAuth" in a UserAuth vendor message, ie the user id, optionally
+/// followed by a 0 byte and the user's auth (eg password) for the pool, into (user_id, user_auth).
+/// Like stratum usernames, user ids may be user.worker_name, of which we only auth the user with
+/// the pool.
+fn parse_user_auth(msg: &[u8]) -> (Vec<u8>, Vec<u8>) {
+	let mut parts = msg.splitn(2, |c| *c == 0);
+	let user = parts.next().unwrap();
+	let user_auth = parts.next().unwrap_or(&[]).to_vec();
+	(user.split(|c| *c == b'.').next().unwrap().to_vec(), user_auth)
 }
 
+const INITIAL_SHARE_TARGET_LEADING_0S: u8 = 47; // Diff ~32768
+const MIN_SHARE_TARGET_LEADING_0S: u8 = 32; // Diff 1
+const MAX_SHARE_TARGET_LEADING_0S: u8 = 80;
+const MAX_CLIENT_SHARES_PER_30_SEC: usize = 30;
+const MIN_CLIENT_SHARES_PER_30_SEC: usize = 2;
+
 pub struct MiningServer {
 	secp_ctx: Secp256k1,
-	auth_key: SecretKey,
+	auth_keys: ServerAuthKeys,
 
 	clients: Mutex<(Vec<Arc<MiningClient>>, u64)>,
-	jobs: RwLock<BTreeMap<u64, WorkInfo>>,
+	jobs: RwLock<JobSet<u64, WorkInfo>>,
+	/// Set once we've started shutting down, after which we stop sending new work
+	shutting_down: AtomicBool,
+	/// Where we build BlockTemplateHeaders for header-variant clients
+	signing_pool: CpuPool,
+	users: Mutex<HashMap<Vec<u8>, MiningUser>>,
+	/// Locked after users
+	user_auth_requests: Option<Mutex<mpsc::Sender<PoolAuthAction>>>,
 }
 
 fn work_to_coinbase_tx(template: &BlockTemplate, client_id: u64) -> Transaction {
@@ -105,78 +182,234 @@ macro_rules! sign_message_ctx {
 }
 macro_rules! sign_message {
 	($msg: expr, $msg_type: expr, $server_ref: expr) => {
-		sign_message_ctx!($msg, $msg_type, $server_ref.secp_ctx, $server_ref.auth_key)
+		sign_message_ctx!($msg, $msg_type, $server_ref.secp_ctx, *$server_ref.auth_keys.signing_key())
 	}
 }
 
+/// Signatures over the templates for one job, so that clients mining on the same target (and, if
+/// we're doing upstream auth, for the same user) can share them instead of us signing a template
+/// for each.
+#[derive(Default)]
+struct JobSigs {
+	by_target_postfix: HashMap<([u8; 32], Vec<u8>), Signature>,
+}
+
+/// The easier of the job's target and the client's share target
+fn client_share_target(job_target: &[u8; 32], share_target_0s: usize) -> [u8; 32] {
+	utils::max_le(*job_target, utils::leading_0s_to_target(share_target_0s as u8))
+}
+
+thread_local! {
+	/// Each signing pool worker keeps its own context
+	static WORKER_SECP_CTX: Secp256k1 = Secp256k1::new();
+}
+
+/// Builds and signs a BlockTemplateHeader for one client. This is a coinbase txid, a merkle root
+/// and a signature per client per job, so it happens on the signing pool, not the reactor.
+fn client_header_msg(template: &BlockTemplate, client_id: u64, target: [u8; 32], auth_key: &SecretKey) -> WorkMessage {
+	let template_header = BlockTemplateHeader {
+		template_timestamp: template.template_timestamp,
+		template_variant: client_id,
+		target,
+
+		header_version: template.header_version,
+		header_prevblock: template.header_prevblock,
+		header_merkle_root: work_to_merkle_root(template, work_to_coinbase_tx(template, client_id).txid()),
+		header_time: template.header_time,
+		header_nbits: template.header_nbits,
+	};
+	WORKER_SECP_CTX.with(|secp_ctx| {
+		WorkMessage::BlockTemplateHeader {
+			signature: sign_message_ctx!(template_header, 9, secp_ctx, *auth_key),
+			template: template_header,
+		}
+	})
+}
+
+/// Signs client's next queued BlockTemplateHeader on signing_pool, sending it (unless a newer one
+/// was queued in the mean time) and moving on to the next when it's done.
+fn sign_client_headers(signing_pool: CpuPool, client: Arc<MiningClient>) {
+	let (template, target, auth_key) = {
+		let mut signing = client.header_signing.lock().unwrap();
+		match signing.next.take() {
+			Some(next) => next,
+			None => {
+				signing.in_flight = false;
+				return;
+			}
+		}
+	};
+	let client_id = client.client_id;
+	let template_timestamp = template.template_timestamp;
+	let pool_ref = signing_pool.clone();
+	tokio::spawn(signing_pool.spawn_fn(move || -> Result<WorkMessage, ()> {
+		Ok(client_header_msg(&template, client_id, target, &auth_key))
+	}).and_then(move |msg| {
+		// Don't bother if we've since given the client a newer job
+		let superseded = client.header_signing.lock().unwrap().next.is_some();
+		if !superseded && *client.latest_template_timestamp.lock().unwrap() == template_timestamp {
+			let _ = client.stream.clone().start_send(msg);
+		}
+		sign_client_headers(pool_ref, client);
+		future::result(Ok(()))
+	}));
+}
+
+/// Nonfinal_work clients build their own coinbase, so each gets the client id in its template's
+/// coinbase_prefix to keep clients from mining on the same work.
+fn nonfinal_client_template(template: &BlockTemplate, client_id: u64) -> BlockTemplate {
+	let mut template = template.clone();
+	template.coinbase_prefix.extend_from_slice(&utils::le64_to_array(client_id));
+	template
+}
+
+/// Checks that a full coinbase transaction from a nonfinal_work client starts with the prefix we
+/// gave it, keeps the job provider's outputs and doesn't claim more than is available.
+fn check_nonfinal_coinbase(template: &BlockTemplate, client_id: u64, coinbase_tx: &Transaction) -> bool {
+	if coinbase_tx.input.len() != 1 { return false; }
+	let prefix = nonfinal_client_template(template, client_id).coinbase_prefix;
+	if !coinbase_tx.input[0].script_sig[..].starts_with(&prefix[..]) { return false; }
+
+	if !coinbase_tx.output.ends_with(&template.appended_coinbase_outputs[..]) { return false; }
+	let mut value = 0u64;
+	for output in coinbase_tx.output.iter() {
+		value = match value.checked_add(output.value) {
+			Some(value) => value,
+			None => return false,
+		};
+	}
+	value <= template.coinbase_value_remaining
+}
+
 impl MiningServer {
-	pub fn new(job_providers: mpsc::UnboundedReceiver<WorkInfo>, auth_key: SecretKey) -> Arc<Self> {
+	/// If user_providers is set, clients mine for the pool user they tell us (or that their
+	/// listener was configured with), getting that user's coinbase postfix and target.
+	pub fn new(job_providers: mpsc::UnboundedReceiver<WorkInfo>, auth_keys: ServerAuthKeys, user_providers: Option<(mpsc::UnboundedReceiver<UserUpdate>, mpsc::Sender<PoolAuthAction>)>) -> Arc<Self> {
+		let (user_job_stream, user_auth_requests) = if let Some((user_job_stream, auth_sink)) = user_providers {
+			(Some(user_job_stream), Some(Mutex::new(auth_sink))) } else { (None, None) };
+
 		let us = Arc::new(Self {
 			secp_ctx: Secp256k1::new(),
-			auth_key: auth_key,
+			auth_keys,
 
 			clients: Mutex::new((Vec::new(), 0)),
-			jobs: RwLock::new(BTreeMap::new()),
+			jobs: RwLock::new(JobSet::new()),
+			shutting_down: AtomicBool::new(false),
+			signing_pool: CpuPool::new_num_cpus(),
+			users: Mutex::new(HashMap::new()),
+			user_auth_requests,
 		});
 
 		let us_cp = us.clone();
-		//This is dumb, but passing the borrow checker otherwise seems hard:
-		let second_secp_ctx = Secp256k1::new();
-		let auth_key_copy = auth_key;
 		tokio::spawn(job_providers.for_each(move |job| {
-			let our_template_sig = sign_message!(job.template, 4, us_cp);
-
 			{
 				let mut jobs = us_cp.jobs.write().unwrap();
-				jobs.insert(job.template.template_timestamp, job.clone());
+				jobs.insert(job.template.template_timestamp, job.template.header_prevblock, job.clone());
+			}
+			if us_cp.shutting_down.load(Ordering::Acquire) {
+				return future::result(Ok(()));
 			}
 
+			let mut sigs = JobSigs::default();
 			let clients = us_cp.clients.lock().unwrap().0.clone();
 			for client in clients {
 				if !client.handshake_complete.load(Ordering::Acquire) { continue; }
-				let mut client_stream = client.stream.clone();
-				if client.use_header_variants.load(Ordering::Acquire) {
-					let template_header = BlockTemplateHeader {
-						template_timestamp: job.template.template_timestamp,
-						template_variant: client.client_id,
-						target: job.template.target,
-
-						header_version: job.template.header_version,
-						header_prevblock: job.template.header_prevblock,
-						header_merkle_root: work_to_merkle_root(&*job.template, work_to_coinbase_tx(&*job.template, client.client_id).txid()),
-						header_time: job.template.header_time,
-						header_nbits: job.template.header_nbits,
-					};
-					let _ = client_stream.start_send(WorkMessage::BlockTemplateHeader {
-						signature: sign_message_ctx!(template_header, 9, second_secp_ctx, auth_key_copy),
-						template: template_header,
-					});
-				} else {
-					let _ = client_stream.start_send(WorkMessage::BlockTemplate {
-						signature: our_template_sig.clone(),
-						template: (*job.template).clone(),
-					});
-				}
+				us_cp.send_job(&job, &mut sigs, &client);
 			}
 
 			future::result(Ok(()))
 		}));
 
+		if let Some(users) = user_job_stream {
+			let us_cp = us.clone();
+			tokio::spawn(users.for_each(move |user_update| {
+				match user_update {
+					UserUpdate::WorkUpdate { user_id, user_info } => {
+						let clients = {
+							let mut users = us_cp.users.lock().unwrap();
+							if let Some(user) = users.get_mut(&user_id) {
+								user.cur_job = Some(user_info.clone());
+								user.clients.clone()
+							} else { Vec::new() }
+						};
+						let last_job = us_cp.jobs.read().unwrap().latest().cloned();
+						let mut sigs = JobSigs::default();
+						for client in clients {
+							*client.user_job.lock().unwrap() = Some(user_info.clone());
+							if !client.handshake_complete.load(Ordering::Acquire) || us_cp.shutting_down.load(Ordering::Acquire) { continue; }
+							if let Some(ref job) = last_job {
+								us_cp.send_job(job, &mut sigs, &client);
+							}
+						}
+					},
+					UserUpdate::DropUser { user_id } => {
+						let user_option = us_cp.users.lock().unwrap().remove(&user_id);
+						if let Some(user) = user_option {
+							for client in user.clients {
+								client.needs_close.store(true, Ordering::Release);
+							}
+						}
+					},
+				}
+				Ok(())
+			}));
+		}
+
 		let us_timer = us.clone(); // Wait, you wanted a deconstructor? LOL
 		tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(10), Duration::from_secs(1)).for_each(move |_| {
-			let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-			let timestamp = (time.as_secs() - 30) * 1000 + time.subsec_nanos() as u64 / 1_000_000;
-
 			let mut jobs = us_timer.jobs.write().unwrap();
-			while jobs.len() > 1 {
-				// There should be a much easier way to implement this...
-				let first_timestamp = match jobs.iter().next() {
-					Some((k, _)) => *k,
-					None => break,
+			jobs.expire(Duration::from_secs(30));
+
+			if us_timer.shutting_down.load(Ordering::Acquire) {
+				return future::result(Ok(()));
+			}
+			let last_job = jobs.latest().cloned();
+			mem::drop(jobs);
+
+			// Resend the latest job to anyone we haven't sent anything to in a while so that NAT
+			// boxes and the like don't decide the connection is dead.
+			if let Some(job) = last_job {
+				let send_target = Instant::now() - Duration::from_secs(29);
+				let mut sigs = JobSigs::default();
+				let clients = us_timer.clients.lock().unwrap().0.clone();
+				for client in clients {
+					if !client.handshake_complete.load(Ordering::Acquire) || *client.last_send.lock().unwrap() >= send_target { continue; }
+					us_timer.send_job(&job, &mut sigs, &client);
+				}
+			}
+
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
+		}));
+
+		let us_vardiff = us.clone();
+		tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(30), Duration::from_secs(30)).for_each(move |_| {
+			let last_job = us_vardiff.jobs.read().unwrap().latest().cloned();
+			let mut sigs = JobSigs::default();
+			let clients = us_vardiff.clients.lock().unwrap().0.clone();
+			for client in clients {
+				if !client.handshake_complete.load(Ordering::Acquire) || client.nonfinal_work.load(Ordering::Acquire) { continue; }
+
+				let shares = client.shares.swap(0, Ordering::AcqRel);
+				let cur_target = client.share_target_0s.load(Ordering::Acquire) as u8;
+
+				let new_target = if shares > MAX_CLIENT_SHARES_PER_30_SEC && cur_target < MAX_SHARE_TARGET_LEADING_0S {
+					cur_target + 1
+				} else if shares < MIN_CLIENT_SHARES_PER_30_SEC && cur_target > MIN_SHARE_TARGET_LEADING_0S {
+					cur_target - 1
+				} else {
+					cur_target
 				};
-				if first_timestamp < timestamp {
-					jobs.remove(&first_timestamp);
-				} else { break; }
+				client.prev_share_target_0s.store(cur_target as usize, Ordering::Release);
+				if new_target != cur_target {
+					println!("Client {} ({}) submitted {} shares with {} leading zeros in the last 30 seconds, moving it to {}", client.client_id, client.addr, shares, cur_target, new_target);
+					client.share_target_0s.store(new_target as usize, Ordering::Release);
+					if us_vardiff.shutting_down.load(Ordering::Acquire) { continue; }
+					if let Some(ref job) = last_job {
+						us_vardiff.send_job(job, &mut sigs, &client);
+					}
+				}
 			}
 
 			future::result(Ok(()))
@@ -187,14 +420,162 @@ impl MiningServer {
 		us
 	}
 
-	pub fn new_connection(us: Arc<Self>, stream: net::TcpStream) {
+	/// The (final) template for a client to mine job on. If we're doing upstream auth this has its
+	/// user's coinbase postfix and (at least) its user's target, so that its solutions go to the
+	/// pool as its user's shares, and is None until we've heard from the pool about its user.
+	fn client_template(&self, job: &WorkInfo, client: &MiningClient) -> Option<Arc<BlockTemplate>> {
+		if self.user_auth_requests.is_none() {
+			return Some(job.template.clone());
+		}
+		client.user_job.lock().unwrap().as_ref().map(|user_job| {
+			let mut template = (*job.template).clone();
+			template.coinbase_postfix.extend_from_slice(&user_job.coinbase_postfix);
+			template.target = utils::max_le(template.target, user_job.target);
+			Arc::new(template)
+		})
+	}
+
+	/// Sends a client the given job, ie either a template (signed once per target) or, via the
+	/// signing pool, a BlockTemplateHeader specific to the client.
+	fn send_job(&self, job: &WorkInfo, sigs: &mut JobSigs, client: &Arc<MiningClient>) {
+		let template_timestamp = job.template.template_timestamp;
+		let client_template = if client.nonfinal_work.load(Ordering::Acquire) { None } else {
+			match self.client_template(job, client) {
+				Some(template) => Some(template),
+				None => return,
+			}
+		};
+		*client.last_send.lock().unwrap() = Instant::now();
+		*client.latest_template_timestamp.lock().unwrap() = template_timestamp;
+
+		if client.nonfinal_work.load(Ordering::Acquire) {
+			let template = nonfinal_client_template(&job.nonfinal_template, client.client_id);
+			if template.coinbase_prefix.len() + client.additional_coinbase_length.load(Ordering::Acquire) > 100 {
+				println!("Not sending job to client {} as it doesn't leave enough room in the coinbase for it", client.client_id);
+				return;
+			}
+			let _ = client.stream.clone().start_send(WorkMessage::BlockTemplate {
+				signature: sign_message!(template, 4, self),
+				template,
+			});
+			return;
+		}
+
+		let client_template = client_template.unwrap();
+		let target = client_share_target(&client_template.target, client.share_target_0s.load(Ordering::Acquire));
+		if client.use_header_variants.load(Ordering::Acquire) {
+			let start_signing = {
+				let mut signing = client.header_signing.lock().unwrap();
+				signing.next = Some((client_template, target, self.auth_keys.signing_key().clone()));
+				!mem::replace(&mut signing.in_flight, true)
+			};
+			if start_signing {
+				sign_client_headers(self.signing_pool.clone(), client.clone());
+			}
+		} else {
+			let mut template = (*client_template).clone();
+			template.target = target;
+			let signature = sigs.by_target_postfix.entry((target, template.coinbase_postfix.clone())).or_insert_with(|| {
+				sign_message!(template, 4, self)
+			}).clone();
+			let _ = client.stream.clone().start_send(WorkMessage::BlockTemplate {
+				signature,
+				template,
+			});
+		}
+	}
+
+	/// Checks a solution from a (final-work) client against its share target, counting it as a
+	/// share and passing it on if it also meets the real target of the template (from
+	/// client_template) it was mining on.
+	fn handle_solution(client: &MiningClient, job: &WorkInfo, template: &BlockTemplate, nonces: WinningNonce, block_hash: Sha256dHash) {
+		let share_target_0s = cmp::min(client.share_target_0s.load(Ordering::Acquire), client.prev_share_target_0s.load(Ordering::Acquire));
+		if utils::does_hash_meet_target(&block_hash[..], &template.target[..]) {
+			client.shares.fetch_add(1, Ordering::AcqRel);
+			match job.solutions.unbounded_send(Arc::new((nonces, block_hash))) {
+				Ok(_) => {},
+				Err(_) => { panic!(); },
+			};
+		} else if utils::does_hash_meet_target(&block_hash[..], &client_share_target(&template.target, share_target_0s)[..]) {
+			client.shares.fetch_add(1, Ordering::AcqRel);
+		} else {
+			println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&template.target[..]));
+		}
+	}
+
+	/// Sets the pool user a client mines for, asking the pool to auth it (with user_auth) if it's
+	/// the first client for that user. Returns false if the client gave different user_auth from
+	/// the user's other clients or our connection to the pool is overloaded.
+	fn set_client_user(us: &Arc<Self>, client: &Arc<MiningClient>, user_id: Vec<u8>, user_auth: Vec<u8>) -> bool {
+		let mut users = us.users.lock().unwrap();
+		match users.entry(user_id.clone()) {
+			hash_map::Entry::Occupied(mut e) => {
+				if e.get().user_auth != user_auth {
+					println!("Client {} gave different auth for its user than its other clients", client.client_id);
+					return false;
+				}
+				*client.user_id.lock().unwrap() = Some(user_id);
+				e.get_mut().clients.push(client.clone());
+				*client.user_job.lock().unwrap() = e.get().cur_job.clone();
+			},
+			hash_map::Entry::Vacant(e) => {
+				let mut sink = us.user_auth_requests.as_ref().unwrap().lock().unwrap();
+				if !match sink.start_send(PoolAuthAction::AuthUser(PoolUserAuth {
+					suggested_target: [0xff; 32], //TODO: Let the client suggest one
+					minimum_target: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0],
+					user_id: user_id.clone(),
+					user_auth: user_auth.clone(),
+				})) {
+					Ok(sink) => sink.is_ready(),
+					Err(_) => false,
+				} {
+					return false;
+				}
+				*client.user_id.lock().unwrap() = Some(user_id);
+				e.insert(MiningUser {
+					clients: vec![client.clone()],
+					cur_job: None,
+					user_auth,
+				});
+			},
+		}
+		true
+	}
+
+	/// Stops sending new work and, if reconnect_to is set, points clients at it with a
+	/// NewWorkServer. Existing connections stay up so that in-flight solutions still make it to
+	/// us and upstream.
+	pub fn shutdown(&self, reconnect_to: &Option<(String, u16)>) {
+		self.shutting_down.store(true, Ordering::Release);
+
+		if let &Some((ref host, port)) = reconnect_to {
+			let new_host_port = host.clone() + ":" + &port.to_string();
+			let signature = sign_message!(NewServerHostPort(&new_host_port), 11, self);
+			let clients = self.clients.lock().unwrap().0.clone();
+			println!("Pointing {} clients at {}", clients.len(), new_host_port);
+			for client in clients {
+				if !client.handshake_complete.load(Ordering::Acquire) { continue; }
+				let _ = client.stream.clone().start_send(WorkMessage::NewWorkServer {
+					signature: signature.clone(),
+					new_host_port: new_host_port.clone(),
+				});
+			}
+		}
+	}
+
+	/// If we're doing upstream auth, user may be set to a (user_id, user_auth) to mine for without
+	/// waiting for the client to send a UserAuth vendor message.
+	pub fn new_connection(us: Arc<Self>, stream: net::TcpStream, addr: SocketAddr, timeouts: IdleTimeouts, user: Option<(Vec<u8>, Vec<u8>)>) {
 		stream.set_nodelay(true).unwrap();
 
-		let (tx, rx) = tokio_codec::Framed::new(stream, WorkMsgFramer::new()).split();
+		let framer = WorkMsgFramer::new();
+		let transport = framer.transport();
+		let (stream, write_progress) = ProgressIo::new(stream);
+		let (tx, rx) = tokio_codec::Framed::new(stream, framer).split();
 
 		let (client, mut send_sink) = {
 			let (send_sink, send_stream) = mpsc::channel(5);
-			tokio::spawn(tx.send_all(send_stream.map_err(|_| -> io::Error {
+			tokio::spawn(TimeoutSink::new(tx, timeouts.write, write_progress).send_all(send_stream.map_err(|_| -> io::Error {
 				panic!("mpsc streams cant generate errors!");
 			})).then(|_| {
 				future::result(Ok(()))
@@ -205,10 +586,22 @@ impl MiningServer {
 			let client = Arc::new(MiningClient {
 				stream: send_sink,
 				client_id: client_list.1,
+				addr,
 				use_header_variants: AtomicBool::new(false),
+				nonfinal_work: AtomicBool::new(false),
+				additional_coinbase_length: AtomicUsize::new(DEFAULT_ADDITIONAL_COINBASE_LENGTH),
 				handshake_complete: AtomicBool::new(false),
+				last_send: Mutex::new(Instant::now()),
+				share_target_0s: AtomicUsize::new(INITIAL_SHARE_TARGET_LEADING_0S as usize),
+				prev_share_target_0s: AtomicUsize::new(INITIAL_SHARE_TARGET_LEADING_0S as usize),
+				shares: AtomicUsize::new(0),
+				latest_template_timestamp: Mutex::new(0),
+				header_signing: Mutex::new(HeaderSigning::default()),
+				user_id: Mutex::new(None),
+				user_job: Mutex::new(None),
+				needs_close: AtomicBool::new(false),
 			});
-			println!("Got new client connection (id {})", client_list.1);
+			println!("Got new client connection (id {}) from {}", client_list.1, client.addr);
 			client_list.1 += 1;
 
 			let client_ref = client.clone();
@@ -216,13 +609,21 @@ impl MiningServer {
 			(client_ref, sink_dup)
 		};
 
+		if let Some((user_id, user_auth)) = user {
+			if us.user_auth_requests.is_some() && !MiningServer::set_client_user(&us, &client, user_id, user_auth) {
+				client.needs_close.store(true, Ordering::Release);
+			}
+		}
+
 		let client_close = client.clone();
 		let us_close = us.clone();
+		// The key we gave in ProtocolVersion and the client's flags, while we wait for EncryptionStart
+		let mut pending_encryption = None;
 
-		//TODO: Set a timer for the client to always push *something* to them every 30 seconds or
-		//so, as otherwise stratum clients time out.
-
-		tokio::spawn(TimeoutStream::new(rx, Duration::from_secs(60*10)).for_each(move |msg| -> future::FutureResult<(), io::Error> {
+		tokio::spawn(TimeoutStream::new(rx, timeouts.read).for_each(move |msg| -> future::FutureResult<(), io::Error> {
+			if client.needs_close.load(Ordering::Acquire) {
+				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+			}
 			macro_rules! send_response {
 				($msg: expr) => {
 					match send_sink.start_send($msg) {
@@ -231,21 +632,18 @@ impl MiningServer {
 					}
 				}
 			}
-			match msg {
-				WorkMessage::ProtocolSupport { max_version, min_version, flags } => {
-					if min_version > 1 || max_version < 1 {
-						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
-					}
-					if (flags & 0b11) == 0 {
-						// We don't support clients setting their own payout information
-						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+			// Sends everything which follows ProtocolVersion, once any encryption has started
+			macro_rules! finish_handshake {
+				($flags: expr) => {
+					if let Some((rotation, current_key)) = us.auth_keys.pending_rotation(&us.secp_ctx) {
+						send_response!(WorkMessage::VendorMessage {
+							signature: Some(sign_message_ctx!(rotation, AUTH_KEY_ROTATION_SIG_TYPE, us.secp_ctx, *current_key)),
+							vendor: b"mining-proxy".to_vec(),
+							message: rotation.to_vendor_message(),
+						});
 					}
-					send_response!(WorkMessage::ProtocolVersion {
-						selected_version: 1,
-						flags: if (flags & 0b11) == 0b11 { 0b11 } else { 0b01 },
-						auth_key: PublicKey::from_secret_key(&us.secp_ctx, &us.auth_key).unwrap(),
-					});
-					if !client.use_header_variants.load(Ordering::Acquire) {
+					// Nonfinal_work clients get their client id in their templates instead
+					if !client.use_header_variants.load(Ordering::Acquire) && !client.nonfinal_work.load(Ordering::Acquire) {
 						let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 						let prefix_postfix = CoinbasePrefixPostfix {
 							timestamp: time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000,
@@ -258,24 +656,66 @@ impl MiningServer {
 						});
 					}
 					let jobs = us.jobs.read().unwrap();
-					match jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
+					match jobs.latest() {
 						Some(job) => {
-							send_response!(WorkMessage::BlockTemplate {
-								signature: sign_message!(job.1.template, 4, us),
-								template: (*job.1.template).clone(),
-							});
+							us.send_job(job, &mut JobSigs::default(), &client);
 						}, None => {}
 					}
-					client.use_header_variants.store((flags & 0b11) == 0b11, Ordering::Release);
+					client.use_header_variants.store(($flags & 0b11) == 0b11, Ordering::Release);
 					client.handshake_complete.store(true, Ordering::Release);
+				}
+			}
+			match msg {
+				WorkMessage::ProtocolSupport { max_version, min_version, flags } => {
+					if min_version > 1 || max_version < 1 {
+						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+					}
+					// Clients which set their own payout information (ie flags 0) get non-final work
+					let nonfinal_work = (flags & 0b11) == 0;
+					if nonfinal_work && us.user_auth_requests.is_some() {
+						println!("Client {} wants to set its own payout information, which we can't allow while mining for pool users", client.client_id);
+						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+					}
+					client.nonfinal_work.store(nonfinal_work, Ordering::Release);
+					let encrypt = (flags & ENCRYPTION_FLAG) != 0;
+					let signing_key = *us.auth_keys.signing_key();
+					send_response!(WorkMessage::ProtocolVersion {
+						selected_version: 1,
+						flags: (if nonfinal_work { 0 } else if (flags & 0b11) == 0b11 { 0b11 } else { 0b01 }) | if encrypt { ENCRYPTION_FLAG } else { 0 },
+						auth_key: PublicKey::from_secret_key(&us.secp_ctx, &signing_key).unwrap(),
+					});
+					if encrypt {
+						// Everything else waits for the client's EncryptionStart so that it goes out encrypted
+						pending_encryption = Some((signing_key, flags));
+					} else {
+						finish_handshake!(flags);
+					}
+				},
+				WorkMessage::EncryptionStart { ephemeral_key, tag } => {
+					let (signing_key, flags) = match pending_encryption.take() {
+						Some(pending) => pending,
+						None => {
+							println!("Received EncryptionStart from client {} without negotiating encryption", client.client_id);
+							return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+						}
+					};
+					let (ephemeral_key, tag) = match transport.start_server(&us.secp_ctx, &signing_key, &ephemeral_key, &tag) {
+						Some(reply) => reply,
+						None => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))),
+					};
+					send_response!(WorkMessage::EncryptionStart { ephemeral_key, tag });
+					finish_handshake!(flags);
 				},
 				WorkMessage::ProtocolVersion { .. } => {
 					println!("Received ProtocolVersion?");
 					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 				},
-				WorkMessage::AdditionalCoinbaseLength { .. } => {
-					println!("Received AdditionalCoinbaseLength for final-work client?");
-					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+				WorkMessage::AdditionalCoinbaseLength { additional_length } => {
+					if !client.nonfinal_work.load(Ordering::Acquire) {
+						println!("Received AdditionalCoinbaseLength for final-work client?");
+						return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+					}
+					client.additional_coinbase_length.store(additional_length as usize, Ordering::Release);
 				},
 				WorkMessage::BlockTemplate { .. } => {
 					println!("Received BlockTemplate?");
@@ -284,34 +724,84 @@ impl MiningServer {
 				WorkMessage::WinningNonce { nonces } => {
 					let jobs = us.jobs.read().unwrap();
 					match jobs.get(&nonces.template_timestamp) {
-						Some(job) => {
+						Some(job) if client.nonfinal_work.load(Ordering::Acquire) => {
+							if !check_nonfinal_coinbase(&job.nonfinal_template, client.client_id, &nonces.coinbase_tx) {
+								println!("Got WinningNonce from client {} with a coinbase which doesn't match its job", client.client_id);
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+							}
 							let block_hash = BlockHeader {
 								version: nonces.header_version,
-								prev_blockhash: Sha256dHash::from(&job.template.header_prevblock[..]),
-								merkle_root: Sha256dHash::from(&work_to_merkle_root(&*job.template, nonces.coinbase_tx.txid())[..]),
+								prev_blockhash: Sha256dHash::from(&job.nonfinal_template.header_prevblock[..]),
+								merkle_root: Sha256dHash::from(&work_to_merkle_root(&*job.nonfinal_template, nonces.coinbase_tx.txid())[..]),
 								time: nonces.header_time,
-								bits: job.template.header_nbits,
+								bits: job.nonfinal_template.header_nbits,
 								nonce: nonces.header_nonce,
 							}.bitcoin_hash();
 
-							if utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
-								match job.solutions.unbounded_send(Arc::new((nonces, block_hash))) {
-									Ok(_) => {},
-									Err(_) => { panic!(); },
-								};
+							if utils::does_hash_meet_target(&block_hash[..], &job.nonfinal_template.target[..]) {
+								job.submitter.submit(&job.nonfinal_template, &nonces, &job.tx_data, &job.work_provider);
 							} else {
-								println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&job.template.target[..]));
+								println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&job.nonfinal_template.target[..]));
 							}
 						},
+						Some(job) => {
+							let template = match us.client_template(job, &client) {
+								Some(template) => template,
+								None => {
+									println!("Got WinningNonce from client {} which we haven't sent work", client.client_id);
+									return future::result(Ok(()));
+								},
+							};
+							let block_hash = BlockHeader {
+								version: nonces.header_version,
+								prev_blockhash: Sha256dHash::from(&template.header_prevblock[..]),
+								merkle_root: Sha256dHash::from(&work_to_merkle_root(&*template, nonces.coinbase_tx.txid())[..]),
+								time: nonces.header_time,
+								bits: template.header_nbits,
+								nonce: nonces.header_nonce,
+							}.bitcoin_hash();
+
+							MiningServer::handle_solution(&client, job, &template, nonces, block_hash);
+						},
 						None => {
 							println!("Got WinningNonceHeader for unknown job_id");
 						}
 					}
 				},
-				WorkMessage::TransactionDataRequest { .. } => {
-					//TODO
-					println!("Received TransactionDataRequest?");
-					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+				WorkMessage::TransactionDataRequest { template_timestamp } => {
+					let jobs = us.jobs.read().unwrap();
+					match jobs.get(&template_timestamp) {
+						Some(job) => {
+							// The job provider may not have sent us the transactions yet, in which case
+							// we reply once it does.
+							let us_ref = us.clone();
+							let sink_ref = send_sink.clone();
+							job.tx_data.get_and(move |txn, prev_header, extra_block_data| {
+								let data = TransactionData {
+									previous_header: prev_header.clone(),
+									template_timestamp,
+									extra_block_data: extra_block_data.clone(),
+									transactions: txn.clone(),
+								};
+								let _ = sink_ref.clone().start_send(WorkMessage::TransactionData {
+									signature: sign_message!(data, 7, us_ref),
+									data,
+								});
+							});
+						},
+						None => {
+							// There's no rejection message in the work protocol, so tell the client in a
+							// vendor message instead of hanging up on it.
+							println!("Client {} requested TransactionData for expired template {}", client.client_id, template_timestamp);
+							let mut message = b"TransactionDataUnavailable".to_vec();
+							message.extend_from_slice(&utils::le64_to_array(template_timestamp));
+							send_response!(WorkMessage::VendorMessage {
+								signature: None,
+								vendor: b"mining-proxy".to_vec(),
+								message,
+							});
+						}
+					}
 				},
 				WorkMessage::TransactionData { .. } => {
 					println!("Received TransactionData?");
@@ -329,30 +819,30 @@ impl MiningServer {
 					let jobs = us.jobs.read().unwrap();
 					match jobs.get(&template_timestamp) {
 						Some(job) => {
+							let template = match us.client_template(job, &client) {
+								Some(template) => template,
+								None => {
+									println!("Got WinningNonceHeader from client {} which we haven't sent work", client.client_id);
+									return future::result(Ok(()));
+								},
+							};
 							let block_hash = BlockHeader {
 								version: header_version,
-								prev_blockhash: Sha256dHash::from(&job.template.header_prevblock[..]),
-								merkle_root: Sha256dHash::from(&work_to_merkle_root(&*job.template, work_to_coinbase_tx(&*job.template, template_variant).txid())[..]),
+								prev_blockhash: Sha256dHash::from(&template.header_prevblock[..]),
+								merkle_root: Sha256dHash::from(&work_to_merkle_root(&*template, work_to_coinbase_tx(&*template, template_variant).txid())[..]),
 								time: header_time,
-								bits: job.template.header_nbits,
+								bits: template.header_nbits,
 								nonce: header_nonce,
 							}.bitcoin_hash();
 
-							if utils::does_hash_meet_target(&block_hash[..], &job.template.target[..]) {
-								match job.solutions.unbounded_send(Arc::new((WinningNonce {
-									template_timestamp,
-									header_version,
-									header_time,
-									header_nonce,
-									user_tag,
-									coinbase_tx: work_to_coinbase_tx(&*job.template, template_variant),
-								}, block_hash))) {
-									Ok(_) => {},
-									Err(_) => { panic!(); },
-								};
-							} else {
-								println!("Got work that missed target (hashed to {}, which is greater than {})", utils::bytes_to_hex(&block_hash[..]), utils::bytes_to_hex(&job.template.target[..]));
-							}
+							MiningServer::handle_solution(&client, job, &template, WinningNonce {
+								template_timestamp,
+								header_version,
+								header_time,
+								header_nonce,
+								user_tag,
+								coinbase_tx: work_to_coinbase_tx(&*template, template_variant),
+							}, block_hash);
 						},
 						None => {
 							println!("Got WinningNonceHeader for unknown job_id");
@@ -363,6 +853,31 @@ impl MiningServer {
 					println!("Got NewWorkServer?");
 					return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 				},
+				WorkMessage::VendorMessage { ref vendor, ref message, .. } if &vendor[..] == b"mining-proxy" && &message[..] == b"Heartbeat" => {},
+				WorkMessage::VendorMessage { ref vendor, ref message, .. } if us.user_auth_requests.is_some() && &vendor[..] == b"mining-proxy" && message.starts_with(b"UserAuth") => {
+					let (user_id, user_auth) = parse_user_auth(&message[b"UserAuth".len()..]);
+					let registered_id = client.user_id.lock().unwrap().clone();
+					match registered_id {
+						Some(ref registered_id) if *registered_id == user_id => {},
+						Some(_) => {
+							println!("Client {} tried to switch pool users", client.client_id);
+							return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+						},
+						None => {
+							if !MiningServer::set_client_user(&us, &client, user_id, user_auth) {
+								// Bad auth, or our connection to the upstream pool is overloaded
+								// (or someone is DoS'ing us with auth requests)
+								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+							}
+							if client.handshake_complete.load(Ordering::Acquire) {
+								let jobs = us.jobs.read().unwrap();
+								if let Some(job) = jobs.latest() {
+									us.send_job(job, &mut JobSigs::default(), &client);
+								}
+							}
+						},
+					}
+				},
 				WorkMessage::VendorMessage { .. } => {
 					println!("Got vendor message");
 					return future::result(Ok(()));
@@ -370,12 +885,91 @@ impl MiningServer {
 			}
 			future::result(Ok(()))
 		}).then(move |_| {
-			let mut clients = us_close.clients.lock().unwrap();
-			clients.0.retain(|client| {
-				!Arc::ptr_eq(&client_close, client)
-			});
-			println!("Client {} disconnected, now have {} clients!", client_close.client_id, clients.0.len());
+			{
+				let mut clients = us_close.clients.lock().unwrap();
+				clients.0.retain(|client| {
+					!Arc::ptr_eq(&client_close, client)
+				});
+				println!("Client {} ({}) disconnected, now have {} clients!", client_close.client_id, client_close.addr, clients.0.len());
+			}
+			if let Some(user_id) = client_close.user_id.lock().unwrap().take() {
+				let mut users = us_close.users.lock().unwrap();
+				if let hash_map::Entry::Occupied(mut e) = users.entry(user_id.clone()) {
+					e.get_mut().clients.retain(|client| {
+						!Arc::ptr_eq(&client_close, client)
+					});
+					if e.get().clients.is_empty() {
+						e.remove();
+						let mut sink = us_close.user_auth_requests.as_ref().unwrap().lock().unwrap().clone();
+						// As in StratumServer, a fresh clone() of the Sender always has room for
+						// one message
+						assert!(sink.start_send(PoolAuthAction::DropUser(user_id)).unwrap().is_ready());
+						tokio::spawn(sink.flush().then(|_| { Ok(()) }));
+					}
+				}
+			}
 			future::result(Ok(()))
 		}));
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use mining_server::*;
+
+	use bitcoin::blockdata::transaction::TxOut;
+
+	fn nonfinal_template() -> BlockTemplate {
+		BlockTemplate {
+			template_timestamp: 0,
+			target: [0xff; 32],
+			header_version: 0x20000000,
+			header_prevblock: [0; 32],
+			header_time: 0,
+			header_nbits: 0,
+			merkle_rhss: Vec::new(),
+			coinbase_value_remaining: 50_000,
+			coinbase_version: 1,
+			coinbase_prefix: vec![3, 0xa0, 0x68, 0x06],
+			coinbase_postfix: Vec::new(),
+			coinbase_input_sequence: 0xffffffff,
+			appended_coinbase_outputs: vec![TxOut { value: 0, script_pubkey: Script::from(vec![0x6a, 1, 42]) }],
+			coinbase_locktime: 0,
+		}
+	}
+
+	/// Builds the coinbase a downstream mining-proxy would for one of its (final work) clients
+	fn downstream_coinbase(template: &BlockTemplate, payout_value: u64) -> Transaction {
+		let mut downstream_template = template.clone();
+		downstream_template.coinbase_postfix = b"downstream".to_vec();
+		downstream_template.appended_coinbase_outputs.insert(0, TxOut { value: payout_value, script_pubkey: Script::from(vec![0x51]) });
+		work_to_coinbase_tx(&downstream_template, 3)
+	}
+
+	#[test]
+	fn test_nonfinal_coinbase() {
+		let template = nonfinal_template();
+		let client_template = nonfinal_client_template(&template, 7);
+		assert!(check_nonfinal_coinbase(&template, 7, &downstream_coinbase(&client_template, 50_000)));
+		assert!(check_nonfinal_coinbase(&template, 7, &downstream_coinbase(&client_template, 1)));
+
+		// Work from another client's template (or one without a client id) isn't this client's
+		assert!(!check_nonfinal_coinbase(&template, 8, &downstream_coinbase(&client_template, 50_000)));
+		assert!(!check_nonfinal_coinbase(&template, 7, &downstream_coinbase(&template, 50_000)));
+
+		assert!(!check_nonfinal_coinbase(&template, 7, &downstream_coinbase(&client_template, 50_001)));
+		let mut coinbase = downstream_coinbase(&client_template, 50_000);
+		coinbase.output.pop();
+		assert!(!check_nonfinal_coinbase(&template, 7, &coinbase));
+	}
+
+	#[test]
+	fn test_parse_user_auth() {
+		assert_eq!(parse_user_auth(b"alice"), (b"alice".to_vec(), Vec::new()));
+		assert_eq!(parse_user_auth(b"alice.rig1"), (b"alice".to_vec(), Vec::new()));
+		assert_eq!(parse_user_auth(b"alice.rig1\0hunter2"), (b"alice".to_vec(), b"hunter2".to_vec()));
+		// Passwords may have anything in them
+		assert_eq!(parse_user_auth(b"alice\0a.b\0c"), (b"alice".to_vec(), b"a.b\0c".to_vec()));
+		assert_eq!(parse_user_auth(b""), (Vec::new(), Vec::new()));
+	}
+}
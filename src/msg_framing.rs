@@ -15,6 +15,7 @@ use secp256k1::Signature;
 use std::error::Error;
 use std::{cmp, fmt, io};
 
+use transport_crypto::{TAG_LEN, TransportCrypto};
 use utils;
 
 fn le16_into_slice(u: u16, v: &mut [u8]) {
@@ -149,6 +150,7 @@ impl WinningNonce {
 	}
 }
 
+#[derive(Clone)]
 pub struct TransactionData {
 	pub previous_header: BlockHeader,
 	pub template_timestamp: u64,
@@ -157,7 +159,7 @@ pub struct TransactionData {
 }
 impl TransactionData {
 	pub fn encode_unsigned(&self, res: &mut bytes::BytesMut) {
-		res.reserve(8+80+4);
+		res.reserve(8+80+4+self.extra_block_data.len()+4);
 		res.put_u64_le(self.template_timestamp);
 		res.put_slice(&network::serialize::serialize(&self.previous_header).unwrap());
 		res.put_u32_le(self.extra_block_data.len() as u32);
@@ -198,6 +200,65 @@ impl BlockTemplateHeader {
 	}
 }
 
+/// NewWorkServer/NewPoolServer only carry a host:port, this wraps one so that it can be signed and
+/// checked the same way as every other signed message.
+// We never construct this in sample-pool
+#[allow(dead_code)]
+pub struct NewServerHostPort<'a>(pub &'a str);
+impl<'a> NewServerHostPort<'a> {
+	// We never construct this in sample-pool
+	#[allow(dead_code)]
+	pub fn encode_unsigned(&self, res: &mut bytes::BytesMut) {
+		res.reserve(1 + self.0.len());
+		res.put_u8(self.0.len() as u8);
+		res.put_slice(self.0.as_bytes());
+	}
+}
+
+/// Announces the key which will replace a server's auth_key at rotation_timestamp (in ms since
+/// the epoch). Sent signed by the current key so that clients which pinned the current key can
+/// pin the successor without being reconfigured. Clients should accept signatures from either key
+/// until rotation_timestamp, and only from the successor afterwards.
+/// This isn't part of the protocol, so it goes out as a "mining-proxy" VendorMessage (which other
+/// clients skip), signed as if it were a message of type AUTH_KEY_ROTATION_SIG_TYPE.
+#[derive(Clone)]
+pub struct AuthKeyRotation {
+	pub successor_key: PublicKey,
+	pub rotation_timestamp: u64,
+}
+pub const AUTH_KEY_ROTATION_SIG_TYPE: u8 = 24;
+impl AuthKeyRotation {
+	pub fn encode_unsigned(&self, res: &mut bytes::BytesMut) {
+		res.reserve(33 + 8);
+		res.put_slice(&self.successor_key.serialize());
+		res.put_u64_le(self.rotation_timestamp);
+	}
+
+	/// The message of the VendorMessage which announces us
+	pub fn to_vendor_message(&self) -> Vec<u8> {
+		let mut res = bytes::BytesMut::with_capacity(b"AuthKeyRotation".len() + 33 + 8);
+		res.put_slice(b"AuthKeyRotation");
+		self.encode_unsigned(&mut res);
+		res.to_vec()
+	}
+
+	/// Gets the AuthKeyRotation a VendorMessage announces, if it's one of ours
+	// We never receive these in sample-pool
+	#[allow(dead_code)]
+	pub fn from_vendor_message(secp_ctx: &Secp256k1, vendor: &[u8], message: &[u8]) -> Option<Self> {
+		if vendor != b"mining-proxy" || !message.starts_with(b"AuthKeyRotation") { return None; }
+		let rotation = &message[b"AuthKeyRotation".len()..];
+		if rotation.len() != 33 + 8 { return None; }
+		Some(Self {
+			successor_key: match PublicKey::from_slice(secp_ctx, &rotation[..33]) {
+				Ok(key) => key,
+				Err(_) => return None,
+			},
+			rotation_timestamp: utils::slice_to_le64(&rotation[33..]),
+		})
+	}
+}
+
 pub enum WorkMessage {
 	ProtocolSupport {
 		max_version: u16,
@@ -246,8 +307,12 @@ pub enum WorkMessage {
 		signature: Signature,
 		new_host_port: String,
 	},
-	// We never bother deserializing to VendorMessages
-	#[allow(dead_code)]
+	/// Sent by both sides to start encrypting, see transport_crypto::ENCRYPTION_FLAG
+	EncryptionStart {
+		ephemeral_key: PublicKey,
+		tag: [u8; TAG_LEN],
+	},
+	/// Only decoded if it's at most MAX_DECODED_VENDOR_MSG_LEN long, larger ones are skipped
 	VendorMessage {
 		signature: Option<Signature>,
 		vendor: Vec<u8>,
@@ -255,14 +320,18 @@ pub enum WorkMessage {
 	},
 }
 
+/// Vendor messages longer than this are skipped without being read into memory
+const MAX_DECODED_VENDOR_MSG_LEN: usize = 1024;
+
 // We never construct this in sample-pool
 #[allow(dead_code)]
-/// Decoder for work messages, not that we simply skip decoding Vendor messages to avoid creating a
-/// 16MB read buffer for them.
+/// Decoder for work messages, note that we simply skip decoding large Vendor messages to avoid
+/// creating a 16MB read buffer for them.
 pub struct WorkMsgFramer {
 	secp_ctx: Secp256k1,
 	/// Used to avoid reading large useless vendor messages into memory
 	skip_bytes: usize,
+	transport: TransportCrypto,
 }
 
 impl WorkMsgFramer {
@@ -272,10 +341,21 @@ impl WorkMsgFramer {
 		WorkMsgFramer {
 			secp_ctx: Secp256k1::new(),
 			skip_bytes: 0,
+			transport: TransportCrypto::new(),
 		}
 	}
+
+	/// Gets the handle with which the connection's message handler can turn on encryption
+	// We never construct this in sample-pool
+	#[allow(dead_code)]
+	pub fn transport(&self) -> TransportCrypto {
+		self.transport.clone()
+	}
 }
 
+/// Matches the initial capacity of tokio_codec::Framed's write buffer
+pub const ENCODE_SCRATCH_LEN: usize = 8 * 1024;
+
 #[derive(Debug)]
 struct CodecError;
 impl fmt::Display for CodecError {
@@ -289,11 +369,8 @@ impl Error for CodecError {
 	}
 }
 
-impl codec::Encoder for WorkMsgFramer {
-	type Item = WorkMessage;
-	type Error = io::Error;
-
-	fn encode(&mut self, msg: WorkMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
+impl WorkMsgFramer {
+	fn encode_plaintext(&mut self, msg: WorkMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
 		match msg {
 			WorkMessage::ProtocolSupport { max_version, min_version, flags } => {
 				res.reserve(1 + 3 + 2*3);
@@ -416,6 +493,14 @@ impl codec::Encoder for WorkMsgFramer {
 				res.put_u8(new_host_port.len() as u8);
 				res.put_slice(new_host_port.as_bytes());
 			},
+			WorkMessage::EncryptionStart { ref ephemeral_key, ref tag } => {
+				res.reserve(1 + 3 + 33 + TAG_LEN);
+				res.put_u8(25);
+				res.put_u8(33 + TAG_LEN as u8);
+				res.put_u16_le(0);
+				res.put_slice(&ephemeral_key.serialize());
+				res.put_slice(tag);
+			},
 			WorkMessage::VendorMessage { ref signature, ref vendor, ref message } => {
 				let len = 1 + if signature.is_some() { 64 } else { 0 } + 1 + vendor.len() + message.len();
 				if len > 0xffffff {
@@ -445,11 +530,50 @@ impl codec::Encoder for WorkMsgFramer {
 	}
 }
 
+impl codec::Encoder for WorkMsgFramer {
+	type Item = WorkMessage;
+	type Error = io::Error;
+
+	fn encode(&mut self, msg: WorkMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
+		if self.transport.is_sending_encrypted() {
+			// Some encoders lean on the slack Framed leaves in its write buffer, so start with as much
+			let mut encoded = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+			self.encode_plaintext(msg, &mut encoded)?;
+			self.transport.seal(&encoded, res);
+		} else {
+			let starts_encryption = match msg { WorkMessage::EncryptionStart { .. } => true, _ => false };
+			self.encode_plaintext(msg, res)?;
+			if starts_encryption {
+				self.transport.sent_encryption_start()?;
+			}
+		}
+		Ok(())
+	}
+}
+
 impl codec::Decoder for WorkMsgFramer {
 	type Item = WorkMessage;
 	type Error = io::Error;
 
 	fn decode(&mut self, bytes: &mut bytes::BytesMut) -> Result<Option<WorkMessage>, io::Error> {
+		if !self.transport.is_receiving_encrypted() {
+			return self.decode_plaintext(bytes);
+		}
+		while let Some(mut encoded) = self.transport.open(bytes)? {
+			let msg = self.decode_plaintext(&mut encoded)?;
+			if !encoded.is_empty() {
+				// Each encrypted frame must hold exactly one message, though a big vendor message
+				// we're skipping may carry on into the frames which follow it
+				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
+			}
+			if msg.is_some() { return Ok(msg); }
+		}
+		Ok(None)
+	}
+}
+
+impl WorkMsgFramer {
+	fn decode_plaintext(&mut self, bytes: &mut bytes::BytesMut) -> Result<Option<WorkMessage>, io::Error> {
 		if self.skip_bytes != 0 {
 			let read = cmp::min(self.skip_bytes, bytes.len());
 			bytes.advance(read);
@@ -473,13 +597,14 @@ impl codec::Decoder for WorkMsgFramer {
 			9 => len != 188,
 			10 => len > 284,
 			11 => len > 320,
+			25 => len != 33 + TAG_LEN,
 			12 => false,
 			_ => true,
 		} {
 			return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
 		}
 
-		if bytes[0] == 12 { // Vendor message
+		if bytes[0] == 12 && len > MAX_DECODED_VENDOR_MSG_LEN { // Vendor message we don't care about
 			if bytes.len() >= 4 + len {
 				bytes.advance(4 + len);
 				return Ok(None);
@@ -496,11 +621,13 @@ impl codec::Decoder for WorkMsgFramer {
 		macro_rules! get_slice {
 			( $size: expr ) => {
 				{
-					if read_pos as u64 + $size as u64 > len as u64 + 4 {
+					// $size may itself read (eg a length prefix), so only evaluate it once
+					let size = $size as usize;
+					if read_pos as u64 + size as u64 > len as u64 + 4 {
 						return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
 					}
-					read_pos += $size as usize;
-					&bytes[read_pos - ($size as usize)..read_pos]
+					read_pos += size;
+					&bytes[read_pos - size..read_pos]
 				}
 			}
 		}
@@ -782,7 +909,35 @@ impl codec::Decoder for WorkMsgFramer {
 				advance_bytes!();
 				Ok(Some(msg))
 			},
-			// 12 (VendorMessage) handled pre-match
+			25 => {
+				let ephemeral_key = match PublicKey::from_slice(&self.secp_ctx, get_slice!(33)) {
+					Ok(key) => key,
+					Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+				};
+				let mut tag = [0; TAG_LEN];
+				tag.copy_from_slice(get_slice!(TAG_LEN));
+				let msg = WorkMessage::EncryptionStart {
+					ephemeral_key,
+					tag,
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
+			12 => {
+				let signature = match get_slice!(1)[0] {
+					0 => None,
+					1 => match Signature::from_compact(&self.secp_ctx, get_slice!(64)) {
+						Ok(sig) => Some(sig),
+						Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+					},
+					_ => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError)),
+				};
+				let vendor = get_slice!(get_slice!(1)[0]).to_vec();
+				let message = get_slice!(len + 4 - read_pos).to_vec();
+				let msg = WorkMessage::VendorMessage { signature, vendor, message };
+				advance_bytes!();
+				Ok(Some(msg))
+			},
 			_ => {
 				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
 			}
@@ -1002,8 +1157,12 @@ pub enum PoolMessage {
 		signature: Signature,
 		new_host_port: String,
 	},
-	// We never bother deserializing to VendorMessages
-	#[allow(dead_code)]
+	/// Sent by both sides to start encrypting, see transport_crypto::ENCRYPTION_FLAG
+	EncryptionStart {
+		ephemeral_key: PublicKey,
+		tag: [u8; TAG_LEN],
+	},
+	/// Only decoded if it's at most MAX_DECODED_VENDOR_MSG_LEN long, larger ones are skipped
 	VendorMessage {
 		signature: Option<Signature>,
 		vendor: Vec<u8>,
@@ -1011,12 +1170,13 @@ pub enum PoolMessage {
 	},
 }
 
-/// Decoder for pool messages, not that we simply skip decoding Vendor messages to avoid creating a
-/// 16MB read buffer for them.
+/// Decoder for pool messages, note that we simply skip decoding large Vendor messages to avoid
+/// creating a 16MB read buffer for them.
 pub struct PoolMsgFramer {
 	secp_ctx: Secp256k1,
 	/// Used to avoid reading large useless vendor messages into memory
 	skip_bytes: usize,
+	transport: TransportCrypto,
 }
 
 impl PoolMsgFramer {
@@ -1024,15 +1184,18 @@ impl PoolMsgFramer {
 		PoolMsgFramer {
 			secp_ctx: Secp256k1::new(),
 			skip_bytes: 0,
+			transport: TransportCrypto::new(),
 		}
 	}
-}
 
-impl codec::Encoder for PoolMsgFramer {
-	type Item = PoolMessage;
-	type Error = io::Error;
+	/// Gets the handle with which the connection's message handler can turn on encryption
+	pub fn transport(&self) -> TransportCrypto {
+		self.transport.clone()
+	}
+}
 
-	fn encode(&mut self, msg: PoolMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
+impl PoolMsgFramer {
+	fn encode_plaintext(&mut self, msg: PoolMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
 		match msg {
 			PoolMessage::ProtocolSupport { max_version, min_version, flags } => {
 				res.reserve(1 + 3 + 2*3);
@@ -1201,6 +1364,14 @@ impl codec::Encoder for PoolMsgFramer {
 				res.put_u8(new_host_port.len() as u8);
 				res.put_slice(new_host_port.as_bytes());
 			},
+			PoolMessage::EncryptionStart { ref ephemeral_key, ref tag } => {
+				res.reserve(1 + 3 + 33 + TAG_LEN);
+				res.put_u8(25);
+				res.put_u8(33 + TAG_LEN as u8);
+				res.put_u16_le(0);
+				res.put_slice(&ephemeral_key.serialize());
+				res.put_slice(tag);
+			},
 			PoolMessage::VendorMessage { ref signature, ref vendor, ref message } => {
 				let len = 1 + if signature.is_some() { 64 } else { 0 } + 1 + vendor.len() + message.len();
 				if len > 0xffffff {
@@ -1230,11 +1401,50 @@ impl codec::Encoder for PoolMsgFramer {
 	}
 }
 
+impl codec::Encoder for PoolMsgFramer {
+	type Item = PoolMessage;
+	type Error = io::Error;
+
+	fn encode(&mut self, msg: PoolMessage, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
+		if self.transport.is_sending_encrypted() {
+			// Some encoders lean on the slack Framed leaves in its write buffer, so start with as much
+			let mut encoded = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+			self.encode_plaintext(msg, &mut encoded)?;
+			self.transport.seal(&encoded, res);
+		} else {
+			let starts_encryption = match msg { PoolMessage::EncryptionStart { .. } => true, _ => false };
+			self.encode_plaintext(msg, res)?;
+			if starts_encryption {
+				self.transport.sent_encryption_start()?;
+			}
+		}
+		Ok(())
+	}
+}
+
 impl codec::Decoder for PoolMsgFramer {
 	type Item = PoolMessage;
 	type Error = io::Error;
 
 	fn decode(&mut self, bytes: &mut bytes::BytesMut) -> Result<Option<PoolMessage>, io::Error> {
+		if !self.transport.is_receiving_encrypted() {
+			return self.decode_plaintext(bytes);
+		}
+		while let Some(mut encoded) = self.transport.open(bytes)? {
+			let msg = self.decode_plaintext(&mut encoded)?;
+			if !encoded.is_empty() {
+				// Each encrypted frame must hold exactly one message, though a big vendor message
+				// we're skipping may carry on into the frames which follow it
+				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
+			}
+			if msg.is_some() { return Ok(msg); }
+		}
+		Ok(None)
+	}
+}
+
+impl PoolMsgFramer {
+	fn decode_plaintext(&mut self, bytes: &mut bytes::BytesMut) -> Result<Option<PoolMessage>, io::Error> {
 		if self.skip_bytes != 0 {
 			let read = cmp::min(self.skip_bytes, bytes.len());
 			bytes.advance(read);
@@ -1261,13 +1471,14 @@ impl codec::Decoder for PoolMsgFramer {
 			22 => len > 512,
 			23 => len > 513,
 			11 => len > 320,
+			25 => len != 33 + TAG_LEN,
 			12 => false,
 			_ => true,
 		} {
 			return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
 		}
 
-		if bytes[0] == 12 { // Vendor message
+		if bytes[0] == 12 && len > MAX_DECODED_VENDOR_MSG_LEN { // Vendor message we don't care about
 			if bytes.len() >= 4 + len {
 				bytes.advance(4 + len);
 				return Ok(None);
@@ -1284,11 +1495,13 @@ impl codec::Decoder for PoolMsgFramer {
 		macro_rules! get_slice {
 			( $size: expr ) => {
 				{
-					if read_pos as u64 + $size as u64 > len as u64 + 4 {
+					// $size may itself read (eg a length prefix), so only evaluate it once
+					let size = $size as usize;
+					if read_pos as u64 + size as u64 > len as u64 + 4 {
 						return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError));
 					}
-					read_pos += $size as usize;
-					&bytes[read_pos - ($size as usize)..read_pos]
+					read_pos += size;
+					&bytes[read_pos - size..read_pos]
 				}
 			}
 		}
@@ -1618,10 +1831,159 @@ impl codec::Decoder for PoolMsgFramer {
 				advance_bytes!();
 				Ok(Some(msg))
 			},
-			// 12 (VendorMessage) handled pre-match
+			25 => {
+				let ephemeral_key = match PublicKey::from_slice(&self.secp_ctx, get_slice!(33)) {
+					Ok(key) => key,
+					Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+				};
+				let mut tag = [0; TAG_LEN];
+				tag.copy_from_slice(get_slice!(TAG_LEN));
+				let msg = PoolMessage::EncryptionStart {
+					ephemeral_key,
+					tag,
+				};
+				advance_bytes!();
+				Ok(Some(msg))
+			},
+			12 => {
+				let signature = match get_slice!(1)[0] {
+					0 => None,
+					1 => match Signature::from_compact(&self.secp_ctx, get_slice!(64)) {
+						Ok(sig) => Some(sig),
+						Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
+					},
+					_ => return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError)),
+				};
+				let vendor = get_slice!(get_slice!(1)[0]).to_vec();
+				let message = get_slice!(len + 4 - read_pos).to_vec();
+				let msg = PoolMessage::VendorMessage { signature, vendor, message };
+				advance_bytes!();
+				Ok(Some(msg))
+			},
 			_ => {
 				return Err(io::Error::new(io::ErrorKind::InvalidData, CodecError))
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use msg_framing::*;
+
+	use secp256k1::key::SecretKey;
+
+	use tokio_io::codec::{Decoder, Encoder};
+
+	fn vendor_msg(message: Vec<u8>) -> WorkMessage {
+		WorkMessage::VendorMessage {
+			signature: None,
+			vendor: b"mining-proxy".to_vec(),
+			message,
+		}
+	}
+
+	#[test]
+	fn test_work_vendor_messages() {
+		let mut framer = WorkMsgFramer::new();
+		let mut buf = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		framer.encode(vendor_msg(b"UserAuthalice\0pass".to_vec()), &mut buf).unwrap();
+		// Big vendor messages are skipped, even if they come in a bit at a time
+		framer.encode(vendor_msg(vec![0; MAX_DECODED_VENDOR_MSG_LEN * 2]), &mut buf).unwrap();
+		framer.encode(vendor_msg(b"Heartbeat".to_vec()), &mut buf).unwrap();
+
+		match framer.decode(&mut buf).unwrap() {
+			Some(WorkMessage::VendorMessage { signature: None, vendor, message }) => {
+				assert_eq!(vendor, b"mining-proxy");
+				assert_eq!(message, b"UserAuthalice\0pass");
+			},
+			_ => panic!(),
+		}
+		let mut rest = buf.split_off(100);
+		assert!(framer.decode(&mut buf).unwrap().is_none());
+		match framer.decode(&mut rest).unwrap() {
+			Some(WorkMessage::VendorMessage { message, .. }) => assert_eq!(message, b"Heartbeat"),
+			_ => panic!(),
+		}
+		assert!(rest.is_empty());
+
+		// Anything but 0 or 1 for the signature flag is bogus
+		let mut buf = bytes::BytesMut::from(vec![12, 2, 0, 0, 2, 0]);
+		assert!(framer.decode(&mut buf).is_err());
+	}
+
+	#[test]
+	fn test_encrypted_vendor_messages() {
+		let secp_ctx = Secp256k1::new();
+		let server_key = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		let (mut client, mut server) = (WorkMsgFramer::new(), WorkMsgFramer::new());
+		let (ephemeral_key, tag) = client.transport().start_client(&secp_ctx, &PublicKey::from_secret_key(&secp_ctx, &server_key).unwrap()).unwrap();
+		let mut buf = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		client.encode(WorkMessage::EncryptionStart { ephemeral_key, tag }, &mut buf).unwrap();
+		let (ephemeral_key, tag) = match server.decode(&mut buf).unwrap() {
+			Some(WorkMessage::EncryptionStart { ephemeral_key, tag }) => server.transport().start_server(&secp_ctx, &server_key, &ephemeral_key, &tag).unwrap(),
+			_ => panic!(),
+		};
+		server.encode(WorkMessage::EncryptionStart { ephemeral_key, tag }, &mut buf).unwrap();
+		match client.decode(&mut buf).unwrap() {
+			Some(WorkMessage::EncryptionStart { ephemeral_key, tag }) => assert!(client.transport().finish_client(&secp_ctx, &ephemeral_key, &tag)),
+			_ => panic!(),
+		}
+		assert!(client.transport().is_sending_encrypted() && server.transport().is_sending_encrypted());
+
+		// Big vendor messages are skipped whether they come in one frame or several
+		let mut big_msg = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		WorkMsgFramer::new().encode(vendor_msg(vec![0; MAX_DECODED_VENDOR_MSG_LEN * 2]), &mut big_msg).unwrap();
+		client.transport().seal(&big_msg, &mut buf);
+		client.transport().seal(&big_msg[..100], &mut buf);
+		client.transport().seal(&big_msg[100..], &mut buf);
+		client.encode(vendor_msg(b"Heartbeat".to_vec()), &mut buf).unwrap();
+		match server.decode(&mut buf).unwrap() {
+			Some(WorkMessage::VendorMessage { message, .. }) => assert_eq!(message, b"Heartbeat"),
+			_ => panic!(),
+		}
+		assert!(buf.is_empty());
+
+		// But nothing else may be split up
+		let mut heartbeat = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		WorkMsgFramer::new().encode(vendor_msg(b"Heartbeat".to_vec()), &mut heartbeat).unwrap();
+		client.transport().seal(&heartbeat.split_to(10), &mut buf);
+		client.transport().seal(&heartbeat, &mut buf);
+		assert!(server.decode(&mut buf).is_err());
+	}
+
+	#[test]
+	fn test_auth_key_rotation_vendor_message() {
+		let secp_ctx = Secp256k1::new();
+		let secret_key = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		let rotation = AuthKeyRotation {
+			successor_key: PublicKey::from_secret_key(&secp_ctx, &secret_key).unwrap(),
+			rotation_timestamp: 1_600_000_000_000,
+		};
+		let signature = secp_ctx.sign(&secp256k1::Message::from_slice(&[1; 32]).unwrap(), &secret_key).unwrap();
+
+		// Pool connections decode small vendor messages too now
+		let mut framer = PoolMsgFramer::new();
+		let mut buf = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		framer.encode(PoolMessage::VendorMessage {
+			signature: Some(signature),
+			vendor: b"mining-proxy".to_vec(),
+			message: rotation.to_vendor_message(),
+		}, &mut buf).unwrap();
+		match framer.decode(&mut buf).unwrap() {
+			Some(PoolMessage::VendorMessage { signature: Some(decoded_sig), vendor, message }) => {
+				assert!(decoded_sig == signature);
+				let decoded = AuthKeyRotation::from_vendor_message(&secp_ctx, &vendor, &message).unwrap();
+				assert!(decoded.successor_key == rotation.successor_key);
+				assert_eq!(decoded.rotation_timestamp, rotation.rotation_timestamp);
+			},
+			_ => panic!(),
+		}
+		assert!(buf.is_empty());
+
+		let message = rotation.to_vendor_message();
+		assert!(AuthKeyRotation::from_vendor_message(&secp_ctx, b"other-vendor", &message).is_none());
+		assert!(AuthKeyRotation::from_vendor_message(&secp_ctx, b"mining-proxy", &message[..message.len() - 1]).is_none());
+		assert!(AuthKeyRotation::from_vendor_message(&secp_ctx, b"mining-proxy", b"Heartbeat").is_none());
+	}
+}
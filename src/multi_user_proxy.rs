@@ -1,9 +1,18 @@
+extern crate base64;
 extern crate bitcoin;
 extern crate bytes;
 extern crate futures;
+extern crate futures_cpupool;
+extern crate hyper;
+#[cfg(feature = "stratum-tls")]
+extern crate native_tls;
+extern crate rand;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_codec;
+extern crate tokio_signal;
+#[cfg(feature = "stratum-tls")]
+extern crate tokio_tls;
 extern crate crypto;
 extern crate secp256k1;
 
@@ -11,76 +20,415 @@ extern crate secp256k1;
 extern crate serde_json;
 
 mod msg_framing;
+mod transport_crypto;
+use msg_framing::PoolUserAuth;
 
 mod stratum_server;
 use stratum_server::*;
 
+#[cfg(feature = "stratum-tls")]
+mod stratum_tls;
+#[cfg(feature = "stratum-tls")]
+use stratum_tls::StratumTls;
+
+mod mining_server;
+use mining_server::*;
+
 mod utils;
 
 mod work_info;
+
+mod block_assembly;
+use block_assembly::BlockSubmitter;
+
+mod rpc_client;
+use rpc_client::RPCClient;
+
+mod replay_provider;
 use work_info::*;
 
 mod connection_maintainer;
 use connection_maintainer::*;
 
+mod socks5;
+use socks5::Socks5Proxy;
+
+mod auth_keys;
+use auth_keys::{KnownHosts, PinnedAuthKeys, ServerAuthKeys};
+
 mod pool_client;
 use pool_client::*;
 
+mod share_queue;
+use share_queue::ShareQueue;
+
 mod work_client;
 use work_client::*;
 
 mod timeout_stream;
+use timeout_stream::IdleTimeouts;
+
+mod shutdown;
+use shutdown::*;
+
+mod proxy_protocol;
+use proxy_protocol::TrustedProxies;
+
+mod routing;
+
+use bitcoin::util::privkey;
 
 use futures::future;
-use futures::sync::mpsc;
+use futures::sync::{mpsc,oneshot};
 use futures::{Future,Stream,Sink};
 
-use tokio::net;
+use tokio::{net, timer};
 
-use std::env;
+use std::{cmp, env, fs, usize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+struct PoolHolder {
+	host_port: String,
+	/// Auth actions for the pool, which we forward to its PoolHandler in order
+	auth_tx: mpsc::UnboundedSender<PoolAuthAction>,
+	pool_work: Option<PoolProviderJob>,
+}
 
 struct CurrentWork {
 	cur_work: Option<WorkProviderJob>,
-	cur_pool_work: Option<PoolProviderJob>,
+	/// Picks the pool all our users are authed on and we send work for
+	failover: PoolFailover,
+	pools: Vec<PoolHolder>,
+	/// Every user our servers have asked us to auth, all of which we re-auth on whichever pool we
+	/// move to, and a bitmask of which servers (by index into job_senders/user_senders) want it
+	users: HashMap<Vec<u8>, (PoolUserAuth, u8)>,
+	job_senders: Vec<mpsc::UnboundedSender<WorkInfo>>,
+	user_senders: Vec<mpsc::UnboundedSender<UserUpdate>>,
+}
+
+impl CurrentWork {
+	/// clean_jobs is set when we've just moved to another pool, so that clients drop the old one's
+	/// jobs right away
+	fn send_cur_work(&mut self, clean_jobs: bool) {
+		let cur_pool = match self.failover.cur_pool() {
+			Some(cur_pool) => cur_pool,
+			None => return,
+		};
+		if let (&Some(ref work_info), &Some(ref pool_info)) = (&self.cur_work, &self.pools[cur_pool].pool_work) {
+			if let Some(mut work) = merge_job_pool(&None, work_info, Some(pool_info), None) {
+				work.clean_jobs = clean_jobs;
+				for job_sender in self.job_senders.iter_mut() {
+					job_sender.start_send(work.clone()).unwrap();
+				}
+			}
+		}
+	}
+
+	/// Handles an AuthUser/DropUser from one of our servers, only dropping a user from the pool
+	/// once no server wants it.
+	fn user_auth_action(&mut self, server: usize, action: PoolAuthAction) {
+		let forward = match action {
+			PoolAuthAction::AuthUser(ref auth) => {
+				let servers = self.users.get(&auth.user_id).map(|&(_, servers)| servers).unwrap_or(0);
+				self.users.insert(auth.user_id.clone(), (auth.clone(), servers | (1 << server)));
+				true
+			},
+			PoolAuthAction::DropUser(ref user_id) => {
+				let servers = match self.users.get_mut(user_id) {
+					Some(&mut (_, ref mut servers)) => {
+						*servers &= !(1 << server);
+						*servers
+					},
+					None => return,
+				};
+				if servers == 0 {
+					self.users.remove(user_id);
+				}
+				servers == 0
+			},
+		};
+		if forward {
+			if let Some(cur_pool) = self.failover.cur_pool() {
+				self.pools[cur_pool].auth_tx.unbounded_send(action).unwrap();
+			}
+		}
+	}
+
+	fn send_user_update(&mut self, update: UserUpdate) {
+		for user_sender in self.user_senders.iter_mut() {
+			user_sender.start_send(update.clone()).unwrap();
+		}
+	}
+
+	/// Moves all our users over to pool idx, which must have work. Users get the new pool's
+	/// coinbase postfix and difficulty once it responds to their AuthUser.
+	fn switch_to(&mut self, idx: usize) {
+		if let Some(cur_pool) = self.failover.cur_pool() {
+			println!("Switching from pool {} ({}) to pool {} ({}), moving {} users", cur_pool, self.pools[cur_pool].host_port, idx, self.pools[idx].host_port, self.users.len());
+			for user_id in self.users.keys() {
+				self.pools[cur_pool].auth_tx.unbounded_send(PoolAuthAction::DropUser(user_id.clone())).unwrap();
+			}
+		}
+		let provider = self.pools[idx].pool_work.as_ref().unwrap().provider.clone();
+		self.failover.switched_to(idx, provider.share_stats().shares_accepted);
+		for &(ref auth, _) in self.users.values() {
+			self.pools[idx].auth_tx.unbounded_send(PoolAuthAction::AuthUser(auth.clone())).unwrap();
+		}
+		provider.reset_weak_block_state();
+		self.send_cur_work(true);
+	}
+
+	fn timer_tick(&mut self) {
+		if let Some(cur_pool) = self.failover.cur_pool() {
+			let shares_accepted = self.pools[cur_pool].pool_work.as_ref().unwrap().provider.share_stats().shares_accepted;
+			if let Some(idx) = self.failover.check_probation(shares_accepted) {
+				self.switch_to(idx);
+			}
+		}
+		if let Some(idx) = self.failover.fail_back() {
+			self.switch_to(idx);
+		}
+	}
+
+	fn pool_updated(&mut self, idx: usize) {
+		let has_work = self.pools[idx].pool_work.is_some();
+		self.failover.pool_updated(idx, has_work);
+		if self.failover.cur_pool() == Some(idx) {
+			self.send_cur_work(false);
+		} else if let Some(idx) = self.failover.fail_back() {
+			self.switch_to(idx);
+		}
+	}
+
+	fn pool_failed(&mut self, idx: usize) {
+		let is_cur_pool = self.failover.cur_pool() == Some(idx);
+		if is_cur_pool {
+			println!("WARNING: POOL {} ({}) DISCONNECTED!", idx, self.pools[idx].host_port);
+			println!("This is going to cause a major bandwidth spike and probably some lost shares!");
+			println!("Please investigate sending the connection over a more reliable proxy, eg over a VPN.");
+			println!("This is probably also an indication that you should investigate loss rates and");
+			println!("latency on the link, which may be cause some share rejections/higher orphan rate");
+		}
+		if self.failover.pool_failed(idx) && is_cur_pool {
+			if let Some(idx) = self.failover.fail_over() {
+				self.switch_to(idx);
+			}
+		}
+	}
 }
 
 fn main() {
-	println!("USAGE: pool-proxy (--job_provider=host:port)* --pool_server=host:port --stratum_listen_bind=IP:port");
-	println!("A stratum proxy for a number of different user clients against one pool");
+	println!("USAGE: pool-proxy (--job_provider=host:port[@pubkey]|file://path)* (--pool_server=host:port[@pubkey])* [--socks5_proxy=[user:pass@]host:port|none] [--known_hosts=path] [--share_queue_dir=path] [--pool_failback_min_secs=secs] [--pool_failback_stable_secs=secs] [--pool_max_switches_per_hour=N] [--reconnect_min_secs=secs] [--reconnect_max_secs=secs] [--upstream_heartbeat_secs=secs] [--require_encryption] (--submitblock_rpc=user:pass@host:port)* [--stratum_listen_bind=IP:port] [--stratum_tls_listen_bind=IP:port --stratum_tls_cert=path --stratum_tls_key=path] [(--mining_listen_bind=[user_id[:password]@]IP:port)* --mining_auth_key=base58privkey] [--shutdown_reconnect_to=host:port] [--shutdown_timeout=secs] (--proxy_protocol_from=IP[/len])* [--stratum_read_timeout=secs] [--stratum_write_timeout=secs] [--mining_read_timeout=secs] [--mining_write_timeout=secs]");
+	println!("A stratum proxy for a number of different user clients against one pool at a time");
 	println!("--job_provider - bitcoind(s) running as mining server(s) to get work from");
+	println!("               or file://path to replay templates from a JSON scenario file (for testing)");
 	println!("--pool_server - pool server(s) to get payout address from/submit shares to");
+	println!("@pubkey - pin the hex-encoded auth key the job provider/pool must authenticate with");
+	println!("--socks5_proxy - connect to the job_providers/pool_servers given after this through a");
+	println!("                 SOCKS5 proxy (eg Tor, which also lets us reach .onion hosts), until");
+	println!("                 the next --socks5_proxy (none to connect directly again)");
+	println!("--known_hosts - file in which to remember the auth keys of job providers/pools which");
+	println!("                weren't pinned with @pubkey the first time they connect, refusing any");
+	println!("                other key after that (otherwise unpinned keys are trusted each run)");
+	println!("--share_queue_dir - directory in which to spill shares we couldn't send while the pool");
+	println!("                    was unreachable once too many are queued to keep in memory");
+	println!("--pool_failback_min_secs - time to stay on a backup pool before going back to a");
+	println!("                           higher-priority one which failed (default 600)");
+	println!("--pool_failback_stable_secs - time a higher-priority pool which failed must stay");
+	println!("                              connected before we go back to it, and within which it");
+	println!("                              must then accept a share (default 300)");
+	println!("--pool_max_switches_per_hour - stop going back to higher-priority pools after this");
+	println!("                               many pool switches in an hour (default 6)");
+	println!("--reconnect_min_secs - time to wait before retrying a job provider/pool we failed to");
+	println!("                       connect to, doubling on each failure (default 1)");
+	println!("--reconnect_max_secs - most time to wait between connection attempts (default 60)");
+	println!("--upstream_heartbeat_secs - how often to send job providers/pools a heartbeat so that");
+	println!("                            quiet connections stay up, or 0 to not (default 60)");
+	println!("--require_encryption - disconnect from job providers/pools which won't encrypt our");
+	println!("                       connection, instead of carrying on in the clear");
+	println!("--submitblock_rpc - bitcoind RPC endpoint(s) to also submitblock any full blocks we find");
+	println!("                    to, in addition to the job provider they were found on. The first is");
+	println!("                    also asked to validate job providers' templates, including their fees.");
+	println!("                    Full blocks are never sent to other job providers, so without this a");
+	println!("                    block found just as its job provider disconnects for good is lost");
 	println!("--stratum_listen_bind - the address to bind to to announce stratum jobs on");
+	println!("--stratum_tls_listen_bind - the address to bind to to announce the same stratum jobs on");
+	println!("                            over TLS (ie stratum+ssl), if built with the stratum-tls feature");
+	println!("--stratum_tls_cert - PEM certificate chain (leaf first) for stratum_tls_listen_bind");
+	println!("--stratum_tls_key - PEM (PKCS#8) private key for stratum_tls_cert. Both are re-read on");
+	println!("                    SIGHUP, so renewed certificates don't need a restart");
+	println!("--mining_listen_bind - the address(es) to bind to to announce jobs on natively. Clients");
+	println!("                       tell us their user with a \"mining-proxy\" vendor message of");
+	println!("                       \"UserAuth\" followed by the user id (and, if the pool wants");
+	println!("                       one, a 0 byte and their password), or all mine for user_id@");
+	println!("                       (or user_id:password@)");
+	println!("--mining_auth_key - the auth key to use to authenticate to native clients");
+	println!("--shutdown_reconnect_to - on SIGINT/SIGTERM, point clients here (eg a peer proxy)");
+	println!("                          instead of asking them to reconnect to us");
+	println!("--shutdown_timeout - seconds to drain shares upstream before exiting (default 10)");
+	println!("--proxy_protocol_from - connections from this IP or IP/prefix_len subnet (eg your TCP");
+	println!("                         load balancer) must start with a PROXY protocol v1/v2 header");
+	println!("                         giving the real client address (may be given multiple times)");
+	println!("--stratum_read_timeout - disconnect stratum clients which send nothing for this many seconds (default 600)");
+	println!("--stratum_write_timeout - disconnect stratum clients which stop reading for this many seconds (default 120)");
+	println!("--mining_read_timeout - disconnect native clients which send nothing for this many seconds (default 600)");
+	println!("--mining_write_timeout - disconnect native clients which stop reading for this many seconds (default 120)");
 	println!("We always try to keep exactly one connection open per argument, no matter how");
 	println!("many hosts a DNS name may resolve to. We try each hostname until one works.");
-	println!("Job providers are not prioritized (the latest job is always used)");
+	println!("Job providers are not prioritized (the latest job is always used), pools are");
+	println!("prioritized in the order they appear on the command line (subject to the");
+	println!("--pool_failback_* limits once a pool has failed). All users are moved to (and");
+	println!("re-authed on) whichever pool we're using.");
 
 	let mut job_provider_hosts = Vec::new();
-	let mut pool_server_host = None;
+	let mut known_hosts = None;
+	let mut socks5_proxy = None;
+	let mut share_queue_dir = None;
+	let mut submitblock_rpcs = Vec::new();
+	let mut pool_server_hosts = Vec::new();
+	let mut pool_failover = PoolFailoverConfig::default();
+	let mut upstream = UpstreamConfig::default();
 	let mut stratum_listen_bind = None;
+	let mut stratum_tls_listen_bind: Option<SocketAddr> = None;
+	let mut stratum_tls_cert = None;
+	let mut stratum_tls_key = None;
+	let mut trusted_proxies = TrustedProxies::new();
+	let mut stratum_timeouts = IdleTimeouts::default();
+	let mut mining_listen_binds = Vec::new();
+	let mut mining_auth_key = None;
+	let mut mining_timeouts = IdleTimeouts::default();
+	let mut shutdown_reconnect_to = None;
+	let mut shutdown_timeout = None;
 
 	for arg in env::args().skip(1) {
-		if arg.starts_with("--job_provider") {
-			match arg.split_at(15).1.to_socket_addrs() {
-				Err(_) => {
-					println!("Bad address resolution: {}", arg);
+		if arg.starts_with("--job_provider=file://") {
+			let host_port = arg.split_at(15).1.to_string();
+			let scenario = match replay_provider::Scenario::load(&host_port["file://".len()..]) {
+				Ok(scenario) => Arc::new(scenario),
+				Err(e) => {
+					println!("Failed to load job provider scenario: {}", e);
 					return;
+				}
+			};
+			job_provider_hosts.push(JobProviderInfo { host_port, auth_key: None, proxy: None, scenario: Some(scenario) });
+		} else if arg.starts_with("--job_provider") {
+			let (host_port, auth_key) = match auth_keys::parse_host_port_key(arg.split_at(15).1) {
+				Some(host_port_key) => host_port_key,
+				None => {
+					println!("Failed to parse job_provider auth key into a hex public key");
+					return;
+				}
+			};
+			if !socks5::check_upstream(&arg, &host_port, &socks5_proxy) {
+				return;
+			}
+			job_provider_hosts.push(JobProviderInfo { host_port, auth_key, proxy: socks5_proxy.clone(), scenario: None });
+		} else if arg.starts_with("--socks5_proxy") {
+			socks5_proxy = match arg.split_at(15).1 {
+				"none" => None,
+				proxy => match Socks5Proxy::parse(proxy) {
+					Some(proxy) => Some(proxy),
+					None => {
+						println!("Failed to parse socks5_proxy into [user:pass@]host:port");
+						return;
+					}
 				},
-				Ok(_) => job_provider_hosts.push(arg.split_at(15).1.to_string())
+			};
+		} else if arg.starts_with("--submitblock_rpc") {
+			let path_parts: Vec<&str> = arg.split_at(18).1.split('@').collect();
+			if path_parts.len() != 2 {
+				println!("Bad RPC URL provided");
+				return;
+			}
+			submitblock_rpcs.push(RPCClient::new(path_parts[0], path_parts[1]));
+		} else if arg.starts_with("--known_hosts") {
+			if known_hosts.is_some() {
+				println!("Cannot specify multiple known_hosts files");
+				return;
 			}
+			known_hosts = Some(match KnownHosts::load(arg.split_at(14).1.to_string()) {
+				Ok(known_hosts) => known_hosts,
+				Err(e) => {
+					println!("Failed to read known_hosts file: {}", e);
+					return;
+				}
+			});
+		} else if arg.starts_with("--share_queue_dir") {
+			if share_queue_dir.is_some() {
+				println!("Cannot specify multiple share_queue_dirs");
+				return;
+			}
+			let dir = arg.split_at(18).1.to_string();
+			if !fs::metadata(&dir).map(|metadata| metadata.is_dir()).unwrap_or(false) {
+				println!("share_queue_dir {} is not a directory", dir);
+				return;
+			}
+			share_queue_dir = Some(dir);
 		} else if arg.starts_with("--pool_server") {
-			if pool_server_host.is_some() {
-				println!("Cannot specify multiple pool servers");
+			let (host_port, auth_key) = match auth_keys::parse_host_port_key(arg.split_at(14).1) {
+				Some(host_port_key) => host_port_key,
+				None => {
+					println!("Failed to parse pool_server auth key into a hex public key");
+					return;
+				}
+			};
+			if !socks5::check_upstream(&arg, &host_port, &socks5_proxy) {
 				return;
 			}
-			match arg.split_at(14).1.to_socket_addrs() {
+			pool_server_hosts.push((host_port, auth_key, socks5_proxy.clone()));
+		} else if arg.starts_with("--pool_failback_min_secs") {
+			pool_failover.min_backup_secs = match arg.split_at(25).1.parse() {
+				Ok(secs) => secs,
 				Err(_) => {
-					println!("Bad address resolution: {}", arg);
+					println!("Failed to parse pool_failback_min_secs into a number of seconds");
 					return;
-				},
-				Ok(_) => pool_server_host = Some(arg.split_at(14).1.to_string()),
-			}
+				}
+			};
+		} else if arg.starts_with("--pool_failback_stable_secs") {
+			pool_failover.primary_stable_secs = match arg.split_at(28).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse pool_failback_stable_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--pool_max_switches_per_hour") {
+			pool_failover.max_switches_per_hour = match arg.split_at(29).1.parse() {
+				Ok(switches) => switches,
+				Err(_) => {
+					println!("Failed to parse pool_max_switches_per_hour into a number");
+					return;
+				}
+			};
+		} else if arg.starts_with("--reconnect_min_secs") {
+			upstream.reconnect.min = match arg.split_at(21).1.parse() {
+				Ok(secs) if secs > 0 => Duration::from_secs(secs),
+				_ => {
+					println!("Failed to parse reconnect_min_secs into a positive number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--reconnect_max_secs") {
+			upstream.reconnect.max = match arg.split_at(21).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse reconnect_max_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--upstream_heartbeat_secs") {
+			upstream.heartbeat = match arg.split_at(26).1.parse() {
+				Ok(0) => None,
+				Ok(secs) => Some(Duration::from_secs(secs)),
+				Err(_) => {
+					println!("Failed to parse upstream_heartbeat_secs into a number of seconds");
+					return;
+				}
+			};
 		} else if arg.starts_with("--stratum_listen_bind") {
 			if stratum_listen_bind.is_some() {
 				println!("Cannot specify multiple listen binds");
@@ -93,6 +441,126 @@ fn main() {
 					return;
 				}
 			});
+		} else if arg.starts_with("--stratum_tls_listen_bind") {
+			if stratum_tls_listen_bind.is_some() {
+				println!("Cannot specify multiple TLS listen binds");
+				return;
+			}
+			stratum_tls_listen_bind = Some(match arg.split_at(26).1.parse() {
+				Ok(sockaddr) => sockaddr,
+				Err(_) =>{
+					println!("Failed to parse stratum_tls_listen_bind into a socket address");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_tls_cert") {
+			if stratum_tls_cert.is_some() {
+				println!("Cannot specify multiple stratum_tls_certs");
+				return;
+			}
+			stratum_tls_cert = Some(arg.split_at(19).1.to_string());
+		} else if arg.starts_with("--stratum_tls_key") {
+			if stratum_tls_key.is_some() {
+				println!("Cannot specify multiple stratum_tls_keys");
+				return;
+			}
+			stratum_tls_key = Some(arg.split_at(18).1.to_string());
+		} else if arg.starts_with("--mining_listen_bind") {
+			let mut parts = arg.split_at(21).1.rsplitn(2, '@');
+			let listen_bind = match parts.next().unwrap().parse() {
+				Ok(sockaddr) => sockaddr,
+				Err(_) =>{
+					println!("Failed to parse mining_listen_bind into a socket address");
+					return;
+				}
+			};
+			let user = parts.next().map(|user| {
+				let mut user_parts = user.splitn(2, ':');
+				let user_id = user_parts.next().unwrap().as_bytes().to_vec();
+				(user_id, user_parts.next().unwrap_or("").as_bytes().to_vec())
+			});
+			mining_listen_binds.push((listen_bind, user));
+		} else if arg.starts_with("--mining_auth_key") {
+			if mining_auth_key.is_some() {
+				println!("Cannot specify multiple auth keys");
+				return;
+			}
+			mining_auth_key = Some(match privkey::Privkey::from_str(arg.split_at(18).1) {
+				Ok(privkey) => {
+					if !privkey.compressed {
+						println!("Private key must represent a compressed key!");
+						return;
+					}
+					privkey.key
+				},
+				Err(_) =>{
+					println!("Failed to parse mining_auth_key into a private key");
+					return;
+				}
+			});
+		} else if arg.starts_with("--mining_read_timeout") {
+			mining_timeouts.read = match arg.split_at(22).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse mining_read_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--mining_write_timeout") {
+			mining_timeouts.write = match arg.split_at(23).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse mining_write_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--shutdown_reconnect_to") {
+			if shutdown_reconnect_to.is_some() {
+				println!("Cannot specify multiple shutdown_reconnect_tos");
+				return;
+			}
+			shutdown_reconnect_to = Some(match socks5::split_host_port(arg.split_at(24).1) {
+				Some((host, port)) => (host.to_string(), port),
+				None => {
+					println!("Failed to parse shutdown_reconnect_to into a host:port");
+					return;
+				}
+			});
+		} else if arg.starts_with("--shutdown_timeout") {
+			if shutdown_timeout.is_some() {
+				println!("Cannot specify multiple shutdown_timeouts");
+				return;
+			}
+			shutdown_timeout = Some(match arg.split_at(19).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse shutdown_timeout into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--proxy_protocol_from") {
+			if !trusted_proxies.add(arg.split_at(22).1) {
+				println!("Failed to parse proxy_protocol_from into an IP or IP/prefix_len subnet");
+				return;
+			}
+		} else if arg == "--require_encryption" {
+			upstream.require_encryption = true;
+		} else if arg.starts_with("--stratum_read_timeout") {
+			stratum_timeouts.read = match arg.split_at(23).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse stratum_read_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--stratum_write_timeout") {
+			stratum_timeouts.write = match arg.split_at(24).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse stratum_write_timeout into a number of seconds");
+					return;
+				}
+			};
 		} else {
 			println!("Unkown arg: {}", arg);
 			return;
@@ -103,83 +571,230 @@ fn main() {
 		println!("Need at least some job providers");
 		return;
 	}
-	if pool_server_host.is_none() {
+	if pool_server_hosts.is_empty() {
 		println!("Need at least a pool server");
 		return;
 	}
-	if stratum_listen_bind.is_none() {
-		println!("Need some stratum listen bind");
+	if stratum_listen_bind.is_none() && stratum_tls_listen_bind.is_none() && mining_listen_binds.is_empty() {
+		println!("Need some listen bind");
 		return;
 	}
+	#[cfg(feature = "stratum-tls")]
+	let stratum_tls = match stratum_tls_listen_bind {
+		Some(listen_bind) => match (stratum_tls_cert, stratum_tls_key) {
+			(Some(cert), Some(key)) => match StratumTls::load(cert, key) {
+				Some(tls) => Some((listen_bind, tls)),
+				None => return,
+			},
+			_ => {
+				println!("Need some stratum_tls_cert and stratum_tls_key for stratum_tls_listen_bind");
+				return;
+			}
+		},
+		None => None,
+	};
+	#[cfg(not(feature = "stratum-tls"))]
+	let stratum_tls: Option<()> = if stratum_tls_listen_bind.is_none() { None } else {
+		println!("Can't serve stratum_tls_listen_bind without building with the stratum-tls feature");
+		return;
+	};
+	if !mining_listen_binds.is_empty() && mining_auth_key.is_none() {
+		println!("Need some mining_auth_key for mining_listen_bind");
+		return;
+	}
+	if upstream.reconnect.min > upstream.reconnect.max {
+		println!("reconnect_min_secs must not be greater than reconnect_max_secs");
+		return;
+	}
+
+	let trusted_proxies = Arc::new(trusted_proxies);
+
+	let shutdown_timeout = shutdown_timeout.unwrap_or(Duration::from_secs(10));
+
+	let shutdown = Shutdown::new();
+	let (exit_tx, exit_rx) = oneshot::channel();
 
 	let mut rt = tokio::runtime::Runtime::new().unwrap();
 	rt.spawn(future::lazy(move || -> Result<(), ()> {
+		let stop_listening = signal_received().shared();
+
+		// Our servers, in the order of CurrentWork's job_senders/user_senders
+		let mut servers_job_rx = Vec::new();
+		let mut job_senders = Vec::new();
+		let mut user_senders = Vec::new();
+		// Plaintext and TLS stratum clients share one StratumServer, and so one set of jobs
+		let serve_stratum = stratum_listen_bind.is_some() || stratum_tls.is_some();
+		for _ in 0..(if serve_stratum { 1 } else { 0 } + if mining_listen_binds.is_empty() { 0 } else { 1 }) {
+			let (job_sender, job_receiver) = mpsc::unbounded();
+			let (user_sender, user_receiver) = mpsc::unbounded();
+			job_senders.push(job_sender);
+			user_senders.push(user_sender);
+			servers_job_rx.push((job_receiver, user_receiver));
+		}
 		let cur_work = Arc::new(Mutex::new(CurrentWork {
 			cur_work: None,
-			cur_pool_work: None,
+			failover: PoolFailover::new(pool_failover, pool_server_hosts.len()),
+			pools: Vec::with_capacity(pool_server_hosts.len()),
+			users: HashMap::new(),
+			job_senders,
+			user_senders,
 		}));
-		let (mut job_sender, job_receiver) = mpsc::unbounded();
-		let (mut user_sender, user_receiver) = mpsc::unbounded();
 
 		let cur_work_job = cur_work.clone();
-		let mut job_sender_job = job_sender.clone();
-		tokio::spawn(MultiJobProvider::create(job_provider_hosts).for_each(move |work_update| {
+		tokio::spawn(MultiJobProvider::create(job_provider_hosts, known_hosts.clone(), upstream.clone(), BlockSubmitter::new(submitblock_rpcs), shutdown.clone()).for_each(move |work_update| {
 			let mut state = cur_work_job.lock().unwrap();
 			state.cur_work = Some(work_update);
-			if let &Some(ref pool_info) = &state.cur_pool_work {
-				if let Some(work) = merge_job_pool(&None, state.cur_work.as_ref().unwrap(), Some(pool_info), None) {
-					job_sender_job.start_send(work).unwrap();
-				}
-			}
+			state.send_cur_work(false);
 			Ok(())
 		}));
-		let (auth_write, auth_read) = mpsc::channel(25);
-		let (pool_handler, pool_rx) = PoolHandler::new(None, auth_read);
-		ConnectionMaintainer::new(pool_server_host.unwrap(), pool_handler).make_connection();
-
-		tokio::spawn(pool_rx.for_each(move |action| {
-			match action {
-				PoolProviderAction::ProviderDisconnected => {
-					println!("WARNING: POOL DISCONNECTED!");
-					println!("This is going to cause a major bandwidth spike and probably some lost shares!");
-					println!("Please investigate sending the connection over a more reliable proxy, eg over a VPN.");
-					println!("This is probably also an indication that you should investigate loss rates and");
-					println!("latency on the link, which may be cause some share rejections/higher orphan rate");
-				},
-				PoolProviderAction::PoolUpdate { info } => {
-					let mut state = cur_work.lock().unwrap();
-					state.cur_pool_work = Some(info);
-					if let &Some(ref work_info) = &state.cur_work {
-						if let Some(work) = merge_job_pool(&None, work_info, Some(state.cur_pool_work.as_ref().unwrap()), None) {
-							job_sender.start_send(work).unwrap();
+
+		for (idx, (pool_host_port, pool_auth_key, pool_proxy)) in pool_server_hosts.drain(..).enumerate() {
+			let (auth_tx, auth_rx) = mpsc::unbounded();
+			let (auth_write, auth_read) = mpsc::channel(25);
+			tokio::spawn(auth_write.sink_map_err(|_| ()).send_all(auth_rx).then(|_| {
+				Ok(())
+			}));
+			let (pool_handler, pool_rx) = PoolHandler::new(PinnedAuthKeys::new(&pool_host_port, pool_auth_key, &known_hosts), upstream.require_encryption, ShareQueue::new(&share_queue_dir, &pool_host_port), auth_read, &shutdown);
+			cur_work.lock().unwrap().pools.push(PoolHolder {
+				host_port: pool_host_port.clone(),
+				auth_tx,
+				pool_work: None,
+			});
+
+			let cur_work_pool = cur_work.clone();
+			let shutdown_pool = shutdown.clone();
+			tokio::spawn(pool_rx.for_each(move |action| {
+				let mut state = cur_work_pool.lock().unwrap();
+				match action {
+					PoolProviderAction::ProviderDisconnected => {
+						if shutdown_pool.is_started() { return Ok(()); }
+						state.pool_failed(idx);
+					},
+					PoolProviderAction::PoolUpdate { info } => {
+						state.pools[idx].pool_work = Some(info);
+						state.pool_updated(idx);
+					},
+					// Only the pool we're on has our users authed, anything else is left over from
+					// before we switched away from it
+					PoolProviderAction::UserUpdate { user_id, update } => {
+						if state.failover.cur_pool() == Some(idx) {
+							state.send_user_update(UserUpdate::WorkUpdate { user_id, user_info: update });
 						}
-					}
-				},
-				PoolProviderAction::UserUpdate { user_id, update } => {
-					user_sender.start_send(UserUpdate::WorkUpdate { user_id, user_info: update }).unwrap();
-				},
-				PoolProviderAction::UserReject { user_id } => {
-					user_sender.start_send(UserUpdate::DropUser { user_id }).unwrap();
-				},
-			}
-			Ok(())
+					},
+					PoolProviderAction::UserReject { user_id } => {
+						if state.failover.cur_pool() == Some(idx) {
+							state.users.remove(&user_id);
+							state.send_user_update(UserUpdate::DropUser { user_id });
+						}
+					},
+				}
+				Ok(())
+			}));
+			ConnectionMaintainer::new(pool_host_port, pool_proxy, upstream.clone(), pool_handler).make_connection();
+		}
+
+		let cur_work_timer = cur_work.clone();
+		tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(FAILBACK_CHECK_SECS), Duration::from_secs(FAILBACK_CHECK_SECS)).for_each(move |_| {
+			cur_work_timer.lock().unwrap().timer_tick();
+			future::result(Ok(()))
+		}).then(|_| {
+			future::result(Ok(()))
 		}));
 
-		let server = StratumServer::new(job_receiver, Some((user_receiver, auth_write)));
-		match net::TcpListener::bind(&stratum_listen_bind.unwrap()) {
-			Ok(listener) => {
-				tokio::spawn(listener.incoming().for_each(move |sock| {
-					StratumServer::new_connection(server.clone(), sock);
-					Ok(())
-				}).then(|_| {
-					Ok(())
-				}));
-			},
-			Err(_) => {
-				panic!("Failed to bind to listen bind addr");
+		// Our servers' user auths go to whichever pool we're on
+		let mut servers_rx = servers_job_rx.drain(..).enumerate().map(|(server_idx, (job_receiver, user_receiver))| {
+			let (auth_write, auth_read) = mpsc::channel(25);
+			let cur_work_auth = cur_work.clone();
+			tokio::spawn(auth_read.for_each(move |action| {
+				cur_work_auth.lock().unwrap().user_auth_action(server_idx, action);
+				Ok(())
+			}));
+			(job_receiver, (user_receiver, auth_write))
+		});
+
+		macro_rules! bind_and_handle {
+			($listen_bind: expr, $new_connection: expr) => {
+				let trusted_proxies = trusted_proxies.clone();
+				match net::TcpListener::bind(&$listen_bind) {
+					Ok(listener) => {
+						tokio::spawn(listener.incoming().for_each(move |sock| {
+							let new_connection = $new_connection;
+							tokio::spawn(proxy_protocol::accept(sock, &trusted_proxies).then(move |res| {
+								match res {
+									Ok((sock, addr)) => new_connection(sock, addr),
+									Err(e) => println!("Dropping connection which failed to send a valid PROXY protocol header: {}", e),
+								}
+								future::result(Ok(()))
+							}));
+							Ok(())
+						}).select2(stop_listening.clone()).then(|_| {
+							Ok(())
+						}));
+					},
+					Err(_) => {
+						panic!("Failed to bind to listen bind addr");
+					}
+				}
 			}
 		}
+
+		let stratum_server = if !serve_stratum { None } else {
+			let (job_receiver, user_providers) = servers_rx.next().unwrap();
+			let server = StratumServer::new(vec![job_receiver], Some(user_providers), None);
+			if let Some(listen_bind) = stratum_listen_bind {
+				let server_listen = server.clone();
+				bind_and_handle!(listen_bind, {
+					let server = server_listen.clone();
+					move |sock, addr| StratumServer::new_connection(server, sock, addr, stratum_timeouts)
+				});
+			}
+			#[cfg(feature = "stratum-tls")]
+			if let Some((listen_bind, tls)) = stratum_tls {
+				let server_listen = server.clone();
+				let tls_listen = tls.clone();
+				bind_and_handle!(listen_bind, {
+					let server = server_listen.clone();
+					let tls = tls_listen.clone();
+					move |sock, addr| StratumServer::new_tls_connection(server, sock, addr, stratum_timeouts, &tls)
+				});
+				tokio::spawn(StratumTls::reload_on_sighup(tls));
+			}
+			Some(server)
+		};
+		let mining_server = if mining_listen_binds.is_empty() { None } else {
+			let (job_receiver, user_providers) = servers_rx.next().unwrap();
+			let server = MiningServer::new(job_receiver, ServerAuthKeys::new(mining_auth_key.unwrap(), None), Some(user_providers));
+			for (listen_bind, user) in mining_listen_binds.drain(..) {
+				let server_listen = server.clone();
+				bind_and_handle!(listen_bind, {
+					let server = server_listen.clone();
+					let user = user.clone();
+					move |sock, addr| MiningServer::new_connection(server, sock, addr, mining_timeouts, user)
+				});
+			}
+			Some(server)
+		};
+
+		tokio::spawn(stop_listening.then(move |_| {
+			println!("Shutting down, asking clients to reconnect and draining shares upstream...");
+			if let Some(ref server) = stratum_server {
+				server.shutdown(&shutdown_reconnect_to);
+			}
+			if let Some(ref server) = mining_server {
+				server.shutdown(&shutdown_reconnect_to);
+			}
+			// Give clients a moment to get any in-flight shares to us before we flush upstream
+			let grace = cmp::min(Duration::from_secs(2), shutdown_timeout / 4);
+			timer::Delay::new(Instant::now() + grace).then(move |_| {
+				shutdown.start();
+				shutdown.flushed(shutdown_timeout - grace)
+			}).then(move |_| {
+				let _ = exit_tx.send(());
+				Ok(())
+			})
+		}));
 		Ok(())
 	}));
-	rt.shutdown_on_idle().wait().unwrap();
+	let _ = exit_rx.wait();
+	rt.shutdown_now().wait().unwrap();
 }
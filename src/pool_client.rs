@@ -1,17 +1,24 @@
+use auth_keys::PinnedAuthKeys;
 use connection_maintainer::*;
 use msg_framing::*;
+use share_queue::ShareQueue;
+use shutdown::Shutdown;
+use socks5;
+use transport_crypto::{ENCRYPTION_FLAG, TransportCrypto};
 use utils;
 
-use futures::sync::mpsc;
+use futures::future;
+use futures::sync::{mpsc,oneshot};
 
 use bitcoin::network;
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::util::hash::Sha256dHash;
 
 use bytes;
 use bytes::BufMut;
 
-use futures::{Stream, Sink};
+use futures::{Future, Stream, Sink};
 
 use tokio;
 
@@ -19,14 +26,13 @@ use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
 use secp256k1;
-use secp256k1::key::PublicKey;
 use secp256k1::Secp256k1;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::collections::hash_map;
-use std::io;
+use std::{cmp, io, usize};
 use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct PoolProviderJob {
@@ -40,6 +46,179 @@ pub struct PoolProviderUserJob {
 	pub target: [u8; 32],
 }
 
+/// Limits on how eagerly we move between pools (in MultiPoolProvider or pool-proxy). Failing over
+/// away from a pool that disconnected (or, in MultiPoolProvider, rejected our user) always happens
+/// immediately, but switching back to a higher-priority pool which previously failed waits until
+/// we've spent min_backup_secs on the backup and the higher-priority pool has been connected for
+/// primary_stable_secs, and never happens more than max_switches_per_hour times (counting
+/// failovers). Once back, the pool must accept a share within primary_stable_secs or we fail over
+/// away from it again.
+#[derive(Clone)]
+pub struct PoolFailoverConfig {
+	pub min_backup_secs: u64,
+	pub primary_stable_secs: u64,
+	pub max_switches_per_hour: usize,
+}
+
+impl Default for PoolFailoverConfig {
+	fn default() -> Self {
+		Self {
+			min_backup_secs: 60*10,
+			primary_stable_secs: 60*5,
+			max_switches_per_hour: 6,
+		}
+	}
+}
+
+struct FailoverPool {
+	/// Whether the pool has given us everything we need to mine on it
+	has_work: bool,
+	is_connected: bool,
+	/// When the pool last (re)connected, if it's currently connected
+	connected_since: Option<Instant>,
+	/// Whether the pool has ever disconnected (or rejected our user), after which we want to see
+	/// it stay up for a while before switching back to it
+	has_failed: bool,
+	/// Set while we're back on this pool after it failed, until it accepts a share: when we moved
+	/// back and how many shares it had accepted by then
+	probation: Option<(Instant, u64)>,
+}
+
+/// Tracks which of a list of pools (in priority order) we're mining on and which we should move
+/// to as they connect and fail, within the limits of a PoolFailoverConfig. Doesn't do any moving
+/// itself: callers move their work/users when a method hands them a pool index and then tell us
+/// with switched_to.
+pub struct PoolFailover {
+	config: PoolFailoverConfig,
+	pools: Vec<FailoverPool>,
+	/// usize::MAX until we first switch to a pool
+	cur_pool: usize,
+	/// When we switched to cur_pool
+	cur_pool_since: Instant,
+	/// When we switched pools in the last hour
+	recent_switches: VecDeque<Instant>,
+}
+
+impl PoolFailover {
+	pub fn new(config: PoolFailoverConfig, pool_count: usize) -> Self {
+		Self {
+			config,
+			pools: (0..pool_count).map(|_| FailoverPool {
+				has_work: false,
+				is_connected: false,
+				connected_since: None,
+				has_failed: false,
+				probation: None,
+			}).collect(),
+			cur_pool: usize::MAX,
+			cur_pool_since: Instant::now(),
+			recent_switches: VecDeque::new(),
+		}
+	}
+
+	pub fn cur_pool(&self) -> Option<usize> {
+		if self.cur_pool == usize::MAX { None } else { Some(self.cur_pool) }
+	}
+
+	/// Whether pool idx is connected and has work, ie we could mine on it right now
+	pub fn is_usable(&self, idx: usize) -> bool {
+		self.pools[idx].is_connected && self.pools[idx].has_work
+	}
+
+	/// Records that we moved to pool idx, which had accepted shares_accepted shares so far.
+	pub fn switched_to(&mut self, idx: usize, shares_accepted: u64) {
+		let now = Instant::now();
+		if self.cur_pool != usize::MAX {
+			self.recent_switches.push_back(now);
+		}
+		self.cur_pool = idx;
+		self.cur_pool_since = now;
+		self.pools[idx].probation = if self.pools[idx].has_failed { Some((now, shares_accepted)) } else { None };
+	}
+
+	/// Called whenever pool idx sends us something (ie is connected), and whether we now have
+	/// everything we need to mine on it.
+	pub fn pool_updated(&mut self, idx: usize, has_work: bool) {
+		if self.pools[idx].connected_since.is_none() {
+			self.pools[idx].connected_since = Some(Instant::now());
+		}
+		self.pools[idx].is_connected = true;
+		self.pools[idx].has_work = has_work;
+	}
+
+	/// Called when pool idx disconnects or rejects our user. Returns true if it was connected, in
+	/// which case, if it's cur_pool, the caller should fail_over().
+	pub fn pool_failed(&mut self, idx: usize) -> bool {
+		if !self.pools[idx].is_connected { return false; }
+		self.pools[idx].is_connected = false;
+		self.pools[idx].connected_since = None;
+		self.pools[idx].has_failed = true;
+		true
+	}
+
+	/// The pool to move to when cur_pool fails: prefer pools which are connected, then follow the
+	/// order they were provided in.
+	pub fn fail_over(&self) -> Option<usize> {
+		let mut lowest_with_work = usize::MAX;
+		for idx in 0..self.pools.len() {
+			if idx != self.cur_pool && self.pools[idx].has_work {
+				if self.pools[idx].is_connected {
+					return Some(idx);
+				} else {
+					lowest_with_work = cmp::min(lowest_with_work, idx);
+				}
+			}
+		}
+		if lowest_with_work != usize::MAX { Some(lowest_with_work) } else { None }
+	}
+
+	/// The highest-priority pool above cur_pool which we're willing to go back to, if any. Pools
+	/// which never failed (eg which just took a bit longer to connect at startup) don't have
+	/// anything to prove, ones which did have to have been connected for primary_stable_secs, and
+	/// we must have been on cur_pool for min_backup_secs without switching too often.
+	pub fn fail_back(&mut self) -> Option<usize> {
+		let now = Instant::now();
+		while self.recent_switches.front().map(|switch| now - *switch > Duration::from_secs(60*60)).unwrap_or(false) {
+			self.recent_switches.pop_front();
+		}
+		let may_switch_back = now - self.cur_pool_since >= Duration::from_secs(self.config.min_backup_secs) &&
+			self.recent_switches.len() < self.config.max_switches_per_hour;
+		for idx in 0..cmp::min(self.cur_pool, self.pools.len()) {
+			if !self.is_usable(idx) { continue; }
+			let is_stable = self.pools[idx].connected_since.map(|since| now - since >= Duration::from_secs(self.config.primary_stable_secs)).unwrap_or(false);
+			if self.cur_pool == usize::MAX || !self.pools[idx].has_failed || (may_switch_back && is_stable) {
+				return Some(idx);
+			}
+		}
+		None
+	}
+
+	/// We can't see a pool accept our shares until we mine on it, so going back to a pool which
+	/// failed is provisional: if it hasn't accepted a share (going by shares_accepted, its current
+	/// count) within primary_stable_secs, we pick a pool to fail over to, and it has to prove itself
+	/// (by staying connected) from scratch.
+	pub fn check_probation(&mut self, shares_accepted: u64) -> Option<usize> {
+		if self.cur_pool == usize::MAX { return None; }
+		let (since, accepted_before) = match self.pools[self.cur_pool].probation {
+			Some(probation) => probation,
+			None => return None,
+		};
+		if shares_accepted > accepted_before {
+			self.pools[self.cur_pool].probation = None;
+		} else if Instant::now() - since >= Duration::from_secs(self.config.primary_stable_secs) {
+			println!("Pool {} hasn't accepted a share since we went back to it, failing over again", self.cur_pool);
+			self.pools[self.cur_pool].probation = None;
+			self.pools[self.cur_pool].connected_since = Some(Instant::now());
+			return self.fail_over();
+		}
+		None
+	}
+}
+
+/// How often we check whether it's time to switch back to a higher-priority pool (or, when
+/// splitting hashrate, whether the current time slice is up)
+pub const FAILBACK_CHECK_SECS: u64 = 10;
+
 pub enum PoolProviderAction {
 	ProviderDisconnected,
 	PoolUpdate { info: PoolProviderJob },
@@ -47,9 +226,36 @@ pub enum PoolProviderAction {
 	UserReject { user_id: Vec<u8> },
 }
 
+/// What we've found for and heard back from a pool, for checking how our hashrate is split
+#[derive(Clone, Default)]
+pub struct PoolShareStats {
+	/// Shares we found (not counting weak blocks, which the pool accepts or rejects separately)
+	pub shares_found: u64,
+	/// Total difficulty (lower bound) of the shares we found
+	pub work_found: f64,
+	/// Shares (again not counting weak blocks) the pool accepted/rejected
+	pub shares_accepted: u64,
+	pub shares_rejected: u64,
+}
+
+// We never split hashrate in pool-proxy
+#[allow(dead_code)]
+impl PoolShareStats {
+	/// work_found, scaled by the fraction of shares the pool has accepted so far
+	pub fn accepted_work(&self) -> f64 {
+		if self.shares_accepted + self.shares_rejected == 0 { return 0.0; }
+		self.work_found * self.shares_accepted as f64 / (self.shares_accepted + self.shares_rejected) as f64
+	}
+}
+
 struct PoolHandlerState {
 	stream: Option<mpsc::UnboundedSender<PoolMessage>>,
-	auth_key: Option<PublicKey>,
+	/// Our end of the connection while we wait for the pool's EncryptionStart. Nothing else may be
+	/// sent until then, so in the meantime stream is None and shares are queued as if we were
+	/// disconnected.
+	handshake_stream: Option<mpsc::UnboundedSender<PoolMessage>>,
+	transport: TransportCrypto,
+	auth_keys: PinnedAuthKeys,
 
 	users_to_reauth: Vec<PoolUserAuth>,
 
@@ -64,8 +270,27 @@ struct PoolHandlerState {
 
 	last_header_sent: [u8; 32],
 	last_weak_block: Option<Vec<Vec<u8>>>,
+	/// The prevblock of the most recent share/weak block we found, anything queued on another one
+	/// is stale by the time we get to send it
+	latest_prevblock: [u8; 32],
+
+	/// Shares/weak blocks we couldn't send while disconnected
+	share_queue: ShareQueue,
+	/// How many users we've re-sent UserAuth for since connecting and not heard back about yet.
+	/// share_queue is sent once this reaches 0.
+	awaiting_reauth: usize,
+
+	share_stats: PoolShareStats,
+	/// For each share (true) and weak block (false) we've sent on this connection, in order, which
+	/// the pool hasn't yet accepted or rejected, so that we know which its responses are for.
+	unanswered: VecDeque<bool>,
 
 	job_stream: mpsc::Sender<PoolProviderAction>,
+
+	/// Fired once we've flushed everything to the pool after shutdown starts
+	flush_complete: Option<oneshot::Sender<()>>,
+	/// Where a NewPoolServer asked us to move to, taken once the connection closes
+	redirect: Option<String>,
 }
 struct PoolHandlerStateRefs<'a> {
 	stream: &'a mut Option<mpsc::UnboundedSender<PoolMessage>>,
@@ -75,6 +300,10 @@ struct PoolHandlerStateRefs<'a> {
 	user_id_to_postfix: &'a mut HashMap<Vec<u8>, (u64, Vec<u8>, usize)>,
 	last_header_sent: &'a mut [u8; 32],
 	last_weak_block: &'a mut Option<Vec<Vec<u8>>>,
+	latest_prevblock: &'a mut [u8; 32],
+	share_queue: &'a mut ShareQueue,
+	share_stats: &'a mut PoolShareStats,
+	unanswered: &'a mut VecDeque<bool>,
 	job_stream: &'a mut mpsc::Sender<PoolProviderAction>,
 }
 impl PoolHandlerState {
@@ -87,14 +316,137 @@ impl PoolHandlerState {
 			user_id_to_postfix: &mut self.user_id_to_postfix,
 			last_header_sent: &mut self.last_header_sent,
 			last_weak_block: &mut self.last_weak_block,
+			latest_prevblock: &mut self.latest_prevblock,
+			share_queue: &mut self.share_queue,
+			share_stats: &mut self.share_stats,
+			unanswered: &mut self.unanswered,
 			job_stream: &mut self.job_stream,
 		}
 	}
+
+	/// Called once the version handshake (and any encryption handshake) is done, re-authing our
+	/// users (and then sending share_queue)
+	fn handshake_complete(&mut self, handler: &Arc<PoolHandler>) -> Result<(), io::Error> {
+		for user_auth in self.users_to_reauth.iter() {
+			match self.stream.as_ref().unwrap().start_send(PoolMessage::UserAuth { info: user_auth.clone() }) {
+				Ok(_) => {},
+				Err(_) => {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+			}
+		}
+		// Hold off on sending queued shares until the pool knows who they're for again
+		self.awaiting_reauth = self.users_to_reauth.len();
+		if self.awaiting_reauth == 0 {
+			self.send_queued_shares(handler);
+		}
+		Ok(())
+	}
+
+	/// Called for each AcceptUserAuth/RejectUserAuth, sending share_queue once all our users have
+	/// been re-authed after a reconnect
+	fn user_reauthed(&mut self, handler: &Arc<PoolHandler>) {
+		if self.awaiting_reauth == 0 { return; }
+		self.awaiting_reauth -= 1;
+		if self.awaiting_reauth == 0 {
+			self.send_queued_shares(handler);
+		}
+	}
+
+	fn send_queued_shares(&mut self, handler: &Arc<PoolHandler>) {
+		if self.stream.is_none() { return; }
+		let msgs = self.share_queue.drain(&self.latest_prevblock);
+		self.send_queued(msgs);
+		self.send_spilled_shares(handler);
+	}
+
+	/// Reads the next chunk of spilled shares back (off the reactor, and without holding our lock),
+	/// sends it and carries on with the next until the spill file is empty. If we've reconnected
+	/// but not re-authed yet by the time a chunk arrives, it's left for send_queued_shares.
+	fn send_spilled_shares(&mut self, handler: &Arc<PoolHandler>) {
+		if let Some(read) = self.share_queue.read_spilled() {
+			let handler = handler.clone();
+			tokio::spawn(read.and_then(move |chunk| {
+				let mut us = handler.state.write().unwrap();
+				if us.stream.is_some() && us.awaiting_reauth == 0 {
+					let msgs = us.share_queue.take_spilled(chunk);
+					us.send_queued(msgs);
+					us.send_spilled_shares(&handler);
+				} else {
+					us.share_queue.put_back_spilled(chunk);
+				}
+				Ok(())
+			}));
+		}
+	}
+
+	fn send_queued(&mut self, msgs: Vec<PoolMessage>) {
+		if let Some(ref stream) = self.stream {
+			for msg in msgs {
+				// Queued weak blocks are sent in full, so the pool will diff the next one we send
+				// against whichever of them it got last, not whatever we sent live since connecting
+				let weak_block_txn = match msg {
+					PoolMessage::WeakBlock { ref sketch } => Some(weak_block_txn(&sketch.txn)),
+					_ => None,
+				};
+				match stream.unbounded_send(msg) {
+					Ok(_) => {
+						self.unanswered.push_back(weak_block_txn.is_none());
+						if let Some(txn) = weak_block_txn {
+							self.last_weak_block = txn;
+						}
+					},
+					Err(e) => self.share_queue.requeue(e.into_inner()),
+				}
+			}
+		}
+	}
+}
+
+/// The post-coinbase transactions of a weak block which was sent in full (ie only NewTx actions)
+fn weak_block_txn(actions: &Vec<WeakBlockAction>) -> Option<Vec<Vec<u8>>> {
+	let mut txn = Vec::with_capacity(actions.len());
+	for action in actions.iter().skip(1) {
+		match action {
+			&WeakBlockAction::NewTx { ref tx } => txn.push(tx.clone()),
+			_ => return None,
+		}
+	}
+	Some(txn)
+}
+
+/// The WeakBlockActions for a weak block, as a diff against the last one we sent if we have it
+fn weak_block_actions(coinbase_tx: &Transaction, post_coinbase_txn: &Vec<Vec<u8>>, last_weak_block: Option<Vec<Vec<u8>>>) -> Vec<WeakBlockAction> {
+	let mut actions = Vec::with_capacity(post_coinbase_txn.len() + 1);
+	actions.push(WeakBlockAction::NewTx { tx: network::serialize::serialize(coinbase_tx).unwrap() });
+
+	match last_weak_block {
+		Some(mut last_weak_block) => {
+			let mut old_txids_posn = HashMap::with_capacity(last_weak_block.len());
+			for (idx, tx) in last_weak_block.drain(..).enumerate() {
+				old_txids_posn.insert(tx, idx + 1); // offset by coinbase tx
+			}
+			for tx in post_coinbase_txn {
+				match old_txids_posn.get(tx) {
+					None => actions.push(WeakBlockAction::NewTx { tx: tx.clone() }),
+					Some(&idx) => actions.push(WeakBlockAction::TakeTx { n: idx as u16 }),
+				}
+			}
+		},
+		None => {
+			for tx in post_coinbase_txn {
+				actions.push(WeakBlockAction::NewTx { tx: tx.clone() });
+			}
+		}
+	}
+	actions
 }
 
 pub struct PoolHandler {
 	state: RwLock<PoolHandlerState>,
+	require_encryption: bool,
 	secp_ctx: Secp256k1,
+	shutdown: Arc<Shutdown>,
 }
 
 pub enum PoolAuthAction {
@@ -102,14 +454,32 @@ pub enum PoolAuthAction {
 	DropUser(Vec<u8>),
 }
 
+/// Updates for the users our servers asked a pool to auth (via PoolAuthAction)
+#[derive(Clone)]
+pub enum UserUpdate {
+	// We never construct this in mining-proxy
+	#[allow(dead_code)]
+	WorkUpdate {
+		user_id: Vec<u8>,
+		user_info: PoolProviderUserJob,
+	},
+	// We never construct this in mining-proxy
+	#[allow(dead_code)]
+	DropUser {
+		user_id: Vec<u8>
+	},
+}
+
 impl PoolHandler {
-	pub fn new(expected_auth_key: Option<PublicKey>, user_auth_requests: mpsc::Receiver<PoolAuthAction>) -> (Arc<PoolHandler>, mpsc::Receiver<PoolProviderAction>) {
+	pub fn new(auth_keys: PinnedAuthKeys, require_encryption: bool, share_queue: ShareQueue, user_auth_requests: mpsc::Receiver<PoolAuthAction>, shutdown: &Arc<Shutdown>) -> (Arc<PoolHandler>, mpsc::Receiver<PoolProviderAction>) {
 		let (work_sender, work_receiver) = mpsc::channel(25);
 
 		let us = Arc::new(PoolHandler {
 			state: RwLock::new(PoolHandlerState {
 				stream: None,
-				auth_key: expected_auth_key,
+				handshake_stream: None,
+				transport: TransportCrypto::new(),
+				auth_keys,
 
 				users_to_reauth: vec![],
 
@@ -120,13 +490,38 @@ impl PoolHandler {
 				cur_payout_info: None,
 
 				last_header_sent: [0; 32],
+				latest_prevblock: [0; 32],
 				last_weak_block: None,
 
+				share_queue,
+				awaiting_reauth: 0,
+
+				share_stats: PoolShareStats::default(),
+				unanswered: VecDeque::new(),
+
 				job_stream: work_sender,
+
+				flush_complete: Some(shutdown.register_flush()),
+				redirect: None,
 			}),
+			require_encryption,
 			secp_ctx: Secp256k1::new(),
+			shutdown: shutdown.clone(),
 		});
 
+		let us_shutdown = us.clone();
+		tokio::spawn(shutdown.wait().then(move |_| {
+			let mut us = us_shutdown.state.write().unwrap();
+			// Dropping our end of the stream lets the connection flush whatever shares are still
+			// queued and then close, at which point send_side_closed fires flush_complete.
+			if us.stream.take().is_none() && us.handshake_stream.take().is_none() {
+				if let Some(flush_complete) = us.flush_complete.take() {
+					let _ = flush_complete.send(());
+				}
+			}
+			future::result(Ok(()))
+		}));
+
 		let us_auth = us.clone();
 		// TODO: Ensure that user_auth_requests message sending never interferes with share
 		// submission somehow by blocking it
@@ -167,9 +562,20 @@ impl PoolHandler {
 		(us, work_receiver)
 	}
 
+	pub fn share_stats(&self) -> PoolShareStats {
+		self.state.read().unwrap().share_stats.clone()
+	}
+
+	/// Forgets the last weak block we sent so that the next one is sent in full, as if the pool
+	/// had sent us a WeakBlockStateReset.
+	pub fn reset_weak_block_state(&self) {
+		self.state.write().unwrap().last_weak_block = None;
+	}
+
 	pub fn send_nonce(&self, work: &(WinningNonce, Sha256dHash), template: &Arc<BlockTemplate>, post_coinbase_txn: &Vec<Vec<u8>>, prev_header: &BlockHeader, extra_block_data: &Vec<u8>) {
 		let mut us_lock = self.state.write().unwrap();
 		let us = us_lock.borrow_mut();
+		*us.latest_prevblock = template.header_prevblock;
 
 		if let Some(coinbase_postfix_match_len) = *us.coinbase_postfix_len {
 			let coinbase_postfix = if work.0.coinbase_tx.input.len() == 1 {
@@ -180,92 +586,80 @@ impl PoolHandler {
 
 			if let Some(ref difficulty) = us.coinbase_postfix_to_difficulty.get(&coinbase_postfix) {
 				if utils::does_hash_meet_target(&work.1[..], &difficulty.share_target[..]) {
+					us.share_stats.shares_found += 1;
+					us.share_stats.work_found += utils::target_to_diff_lb(&difficulty.share_target);
 					let previous_header = if *us.last_header_sent == template.header_prevblock { None } else {
 						*us.last_header_sent = template.header_prevblock.clone();
 						Some(prev_header.clone())
 					};
-					match us.stream {
+					let share = PoolShare {
+						header_version: work.0.header_version,
+						header_prevblock: template.header_prevblock.clone(),
+						header_time: work.0.header_time,
+						header_nbits: template.header_nbits,
+						header_nonce: work.0.header_nonce,
+						merkle_rhss: template.merkle_rhss.clone(),
+						coinbase_tx: work.0.coinbase_tx.clone(),
+						user_tag_1: work.0.user_tag.clone(),
+						user_tag_2: Vec::new(),
+						previous_header,
+					};
+					let unsent = match us.stream {
 						&mut Some(ref stream) => {
-							match stream.unbounded_send(PoolMessage::Share {
-								share: PoolShare {
-									header_version: work.0.header_version,
-									header_prevblock: template.header_prevblock.clone(),
-									header_time: work.0.header_time,
-									header_nbits: template.header_nbits,
-									header_nonce: work.0.header_nonce,
-									merkle_rhss: template.merkle_rhss.clone(),
-									coinbase_tx: work.0.coinbase_tx.clone(),
-									user_tag_1: work.0.user_tag.clone(),
-									user_tag_2: Vec::new(),
-									previous_header,
-								}
-							}) {
-								Ok(_) => { println!("Submitted share!"); },
-								//TODO: We should queue these for sending later
-								Err(_) => {
-									println!("Failed to submit nonce as pool connection lost");
-									return;
+							match stream.unbounded_send(PoolMessage::Share { share }) {
+								Ok(_) => {
+									println!("Submitted share!");
+									us.unanswered.push_back(true);
+									None
+								},
+								Err(e) => match e.into_inner() {
+									PoolMessage::Share { share } => Some(share),
+									_ => None,
 								},
 							}
 						},
-						&mut None => {
-							//TODO: We should queue these for sending later
-							println!("Failed to submit nonce as pool connection lost");
-							return;
-						}
+						&mut None => Some(share),
+					};
+					if let Some(mut share) = unsent {
+						println!("Failed to submit nonce as pool connection lost");
+						// The pool may never have gotten the previous_header we sent with an earlier share
+						share.previous_header = Some(prev_header.clone());
+						us.share_queue.push(&template.header_prevblock, PoolMessage::Share { share });
 					}
 				}
 				if utils::does_hash_meet_target(&work.1[..], &difficulty.weak_block_target[..]) {
-					match us.stream {
+					let sketch = |txn| {
+						PoolMessage::WeakBlock {
+							sketch: WeakBlock {
+								header_version: work.0.header_version,
+								header_prevblock: template.header_prevblock.clone(),
+								header_time: work.0.header_time,
+								header_nbits: template.header_nbits,
+								header_nonce: work.0.header_nonce,
+								merkle_rhss: template.merkle_rhss.clone(),
+								user_tag_1: work.0.user_tag.clone(),
+								user_tag_2: Vec::new(),
+								extra_block_data: extra_block_data.clone(),
+								txn,
+							},
+						}
+					};
+					let sent = match us.stream {
 						&mut Some(ref stream) => {
-							let mut actions = Vec::with_capacity(post_coinbase_txn.len() + 1);
-							actions.push(WeakBlockAction::NewTx { tx: network::serialize::serialize(&work.0.coinbase_tx).unwrap() });
-
-							match us.last_weak_block.take() {
-								Some(mut last_weak_block) => {
-									let mut old_txids_posn = HashMap::with_capacity(last_weak_block.len());
-									for (idx, tx) in last_weak_block.drain(..).enumerate() {
-										old_txids_posn.insert(tx, idx + 1); // offset by coinbase tx
-									}
-									for tx in post_coinbase_txn {
-										match old_txids_posn.get(tx) {
-											None => actions.push(WeakBlockAction::NewTx { tx: tx.clone() }),
-											Some(&idx) => actions.push(WeakBlockAction::TakeTx { n: idx as u16 }),
-										}
-									}
-								},
-								None => {
-									for tx in post_coinbase_txn {
-										actions.push(WeakBlockAction::NewTx { tx: tx.clone() });
-									}
-								}
-							}
+							let actions = weak_block_actions(&work.0.coinbase_tx, post_coinbase_txn, us.last_weak_block.take());
 							*us.last_weak_block = Some(post_coinbase_txn.clone());
-							match stream.unbounded_send(PoolMessage::WeakBlock {
-								sketch: WeakBlock {
-									header_version: work.0.header_version,
-									header_prevblock: template.header_prevblock.clone(),
-									header_time: work.0.header_time,
-									header_nbits: template.header_nbits,
-									header_nonce: work.0.header_nonce,
-									merkle_rhss: template.merkle_rhss.clone(),
-									user_tag_1: work.0.user_tag.clone(),
-									user_tag_2: Vec::new(),
-									extra_block_data: extra_block_data.clone(),
-									txn: actions,
-								},
-							}) {
-								Ok(_) => { println!("Submitted weak block!"); },
-								Err(_) => {
-									//TODO: We should queue these for sending later
-									println!("Failed to submit weak block as pool connection lost");
-								},
-							}
+							stream.unbounded_send(sketch(actions)).is_ok()
 						},
-						&mut None => {
-							//TODO: We should queue these for sending later
-							println!("Failed to submit weak block as pool connection lost");
-						}
+						&mut None => false,
+					};
+					if sent {
+						println!("Submitted weak block!");
+						us.unanswered.push_back(false);
+					} else {
+						println!("Failed to submit weak block as pool connection lost");
+						// The pool will have forgotten the last_weak_block we have now by the time this
+						// is sent, so send it in full (which send_queued_shares relies on)
+						us.share_queue.push(&template.header_prevblock, sketch(weak_block_actions(&work.0.coinbase_tx, post_coinbase_txn, None)));
 					}
 				}
 			} else {
@@ -288,7 +682,7 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 		match tx.start_send(PoolMessage::ProtocolSupport {
 			max_version: 1,
 			min_version: 1,
-			flags: 0,
+			flags: ENCRYPTION_FLAG,
 		}) {
 			Ok(_) => {
 				us.stream = Some(tx);
@@ -297,23 +691,65 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 		}
 
 		us.last_weak_block = None;
+		us.unanswered.clear();
 		us.coinbase_postfix_len = None;
 		us.cur_payout_info = None;
-		(PoolMsgFramer::new(), rx)
+		let framer = PoolMsgFramer::new();
+		us.transport = framer.transport();
+		(framer, rx)
 	}
 
 	fn connection_closed(&self) {
 		let mut us = self.state.write().unwrap();
 		us.stream = None;
+		us.handshake_stream = None;
 		let _ = us.job_stream.start_send(PoolProviderAction::ProviderDisconnected);
 	}
 
+	fn send_side_closed(&self) {
+		if self.shutdown.is_started() {
+			if let Some(flush_complete) = self.state.write().unwrap().flush_complete.take() {
+				println!("Flushed pending shares to pool");
+				let _ = flush_complete.send(());
+			}
+		}
+	}
+
+	fn should_reconnect(&self) -> bool {
+		!self.shutdown.is_started()
+	}
+
+	fn send_heartbeat(&self) {
+		if let Some(ref stream) = self.state.read().unwrap().stream {
+			let _ = stream.unbounded_send(PoolMessage::VendorMessage {
+				signature: None,
+				vendor: b"mining-proxy".to_vec(),
+				message: b"Heartbeat".to_vec(),
+			});
+		}
+	}
+
+	fn take_redirect(&self) -> Option<String> {
+		self.state.write().unwrap().redirect.take()
+	}
+
 	fn handle_message(&self, msg: PoolMessage) -> Result<(), io::Error> {
 		let mut us = self.state.write().unwrap();
-		if us.stream.is_none() { return Ok(()); }
+		if us.handshake_stream.is_some() {
+			match msg {
+				PoolMessage::EncryptionStart { .. } => {},
+				_ => {
+					println!("Pool sent something other than EncryptionStart while we waited for it");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				},
+			}
+		} else if us.stream.is_none() { return Ok(()); }
 
 		macro_rules! check_msg_sig {
 			($msg_type: expr, $msg: expr, $signature: expr) => {
+				check_msg_sig!($msg_type, $msg, $signature, verify)
+			};
+			($msg_type: expr, $msg: expr, $signature: expr, $verify: ident) => {
 				{
 					let mut msg_signed = bytes::BytesMut::with_capacity(1000);
 					msg_signed.put_u8($msg_type);
@@ -326,12 +762,8 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 						secp256k1::Message::from_slice(&h).unwrap()
 					};
 
-					match us.auth_key {
-						Some(pubkey) => match self.secp_ctx.verify(&hash, &$signature, &pubkey) {
-							Ok(()) => {},
-							Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))
-						},
-						None => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))
+					if !us.auth_keys.$verify(&self.secp_ctx, &hash, &$signature) {
+						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 					}
 				}
 			}
@@ -357,27 +789,33 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 				if selected_version != 1 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if flags != 0 {
+				if (flags & !ENCRYPTION_FLAG) != 0 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if us.auth_key.is_none() {
-					us.auth_key = Some(auth_key.clone());
-				} else {
-					if us.auth_key.unwrap() != *auth_key {
-						println!("Got unexpected auth key");
-						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
-					}
+				if !us.auth_keys.check_server_key(auth_key) {
+					println!("Got unexpected auth key");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				println!("Received ProtocolVersion, using version {}", selected_version);
-
-				for user_auth in us.users_to_reauth.iter() {
-					match us.stream.as_ref().unwrap().start_send(PoolMessage::UserAuth { info: user_auth.clone() }) {
+				if (flags & ENCRYPTION_FLAG) != 0 {
+					let (ephemeral_key, tag) = match us.transport.start_client(&self.secp_ctx, auth_key) {
+						Some(start) => start,
+						None => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)),
+					};
+					match us.stream.as_ref().unwrap().start_send(PoolMessage::EncryptionStart { ephemeral_key, tag }) {
 						Ok(_) => {},
-						Err(_) => {
-							return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
-						}
+						Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)),
 					}
+					// Nothing else can go out until the pool's EncryptionStart arrives
+					us.handshake_stream = us.stream.take();
+					println!("Received ProtocolVersion, using version {} over an encrypted connection", selected_version);
+					return Ok(());
+				} else if self.require_encryption {
+					println!("Pool would not encrypt our connection, disconnecting as encryption is required");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				} else {
+					println!("Received ProtocolVersion, using version {}", selected_version);
 				}
+				us.handshake_complete(self)?;
 			},
 			PoolMessage::PayoutInfo { signature, payout_info } => {
 				check_msg_sig!(13, payout_info, signature);
@@ -413,6 +851,7 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 			PoolMessage::AcceptUserAuth { signature, info } => {
 				check_msg_sig!(15, info, signature);
 				check_msg_timestamp!(info, "AcceptUserAuth");
+				us.user_reauthed(self);
 
 				if info.coinbase_postfix.len() > 42 {
 					println!("Pool sent accept_user_auth coinbase_postfix larger than 42 bytes");
@@ -465,6 +904,7 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 				}
 			},
 			PoolMessage::RejectUserAuth { user_id } => {
+				us.user_reauthed(self);
 				match us.job_stream.start_send(PoolProviderAction::UserReject { user_id }) {
 					Ok(_) => {},
 					Err(_) => {
@@ -534,28 +974,202 @@ impl ConnectionHandler<PoolMessage> for Arc<PoolHandler> {
 				us.last_weak_block = None;
 			},
 			PoolMessage::ShareAccepted { .. } => {
-				println!("Share ACCEPTED!");
+				if us.unanswered.pop_front().unwrap_or(true) {
+					println!("Share ACCEPTED!");
+					us.share_stats.shares_accepted += 1;
+				} else {
+					println!("Weak block ACCEPTED!");
+				}
 				return Ok(());
 			},
 			PoolMessage::ShareRejected { reason, .. } => {
+				let what = if us.unanswered.pop_front().unwrap_or(true) {
+					us.share_stats.shares_rejected += 1;
+					"Share"
+				} else { "Weak block" };
 				match reason {
-					ShareRejectedReason::StalePrevBlock => println!("Share REJECTED (stale prev block)!"),
-					ShareRejectedReason::BadHash => println!("Share REJECTED (bad hash)!"),
-					ShareRejectedReason::Duplicate => println!("Share REJECTED (duplicate)!"),
-					ShareRejectedReason::BadPayoutInfo => println!("Share REJECTED (bad payout info)!"),
-					ShareRejectedReason::BadWork => println!("Share REJECTED (bad work)!"),
-					ShareRejectedReason::Other(_) => println!("Share REJECTED (other)!"),
+					ShareRejectedReason::StalePrevBlock => println!("{} REJECTED (stale prev block)!", what),
+					ShareRejectedReason::BadHash => println!("{} REJECTED (bad hash)!", what),
+					ShareRejectedReason::Duplicate => println!("{} REJECTED (duplicate)!", what),
+					ShareRejectedReason::BadPayoutInfo => println!("{} REJECTED (bad payout info)!", what),
+					ShareRejectedReason::BadWork => println!("{} REJECTED (bad work)!", what),
+					ShareRejectedReason::Other(_) => println!("{} REJECTED (other)!", what),
 				}
 				return Ok(());
 			},
-			PoolMessage::NewPoolServer { .. } => {
-				unimplemented!();
+			PoolMessage::EncryptionStart { ephemeral_key, tag } => {
+				let stream = match us.handshake_stream.take() {
+					Some(stream) => stream,
+					None => {
+						println!("Received EncryptionStart?");
+						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+					},
+				};
+				if !us.transport.finish_client(&self.secp_ctx, &ephemeral_key, &tag) {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				us.stream = Some(stream);
+				us.handshake_complete(self)?;
+			},
+			PoolMessage::NewPoolServer { signature, new_host_port } => {
+				check_msg_sig!(11, NewServerHostPort(&new_host_port), signature);
+				// The new host still has to authenticate with the key(s) we've pinned for this one
+				if !socks5::is_host_port(&new_host_port) {
+					println!("Pool is going away and asked us to move to an invalid host:port, reconnecting");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				println!("Pool is going away and asked us to move to {}, reconnecting there", new_host_port);
+				us.redirect = Some(new_host_port);
+				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 			},
-			PoolMessage::VendorMessage { .. } => {
-				println!("Got vendor message");
-				return Ok(());
+			PoolMessage::VendorMessage { signature, vendor, message } => {
+				match AuthKeyRotation::from_vendor_message(&self.secp_ctx, &vendor, &message) {
+					Some(rotation) => {
+						let signature = match signature {
+							Some(signature) => signature,
+							None => {
+								println!("Got unsigned AuthKeyRotation");
+								return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+							}
+						};
+						check_msg_sig!(AUTH_KEY_ROTATION_SIG_TYPE, rotation, signature, verify_current);
+						us.auth_keys.pin_successor(&rotation);
+					},
+					None => println!("Got vendor message"),
+				}
 			},
 		}
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use pool_client::*;
+
+	fn config(min_backup_secs: u64, primary_stable_secs: u64, max_switches_per_hour: usize) -> PoolFailoverConfig {
+		PoolFailoverConfig { min_backup_secs, primary_stable_secs, max_switches_per_hour }
+	}
+
+	/// Three pools, on pool 1 after pool 0 failed and came back
+	fn failed_over(config: PoolFailoverConfig) -> PoolFailover {
+		let mut failover = PoolFailover::new(config, 3);
+		for idx in 0..3 {
+			failover.pool_updated(idx, true);
+		}
+		assert_eq!(failover.fail_back(), Some(0));
+		failover.switched_to(0, 0);
+		assert!(failover.pool_failed(0));
+		assert!(!failover.pool_failed(0));
+		assert_eq!(failover.fail_over(), Some(1));
+		failover.switched_to(1, 0);
+		failover.pool_updated(0, true);
+		failover
+	}
+
+	#[test]
+	fn test_failover_startup() {
+		let mut failover = PoolFailover::new(config(600, 600, 6), 3);
+		assert_eq!(failover.cur_pool(), None);
+		assert_eq!(failover.fail_back(), None);
+
+		// Connected but without work yet
+		failover.pool_updated(2, false);
+		assert!(!failover.is_usable(2));
+		assert_eq!(failover.fail_back(), None);
+
+		// Take whatever's there first...
+		failover.pool_updated(2, true);
+		assert!(failover.is_usable(2));
+		assert_eq!(failover.fail_back(), Some(2));
+		failover.switched_to(2, 0);
+		assert_eq!(failover.cur_pool(), Some(2));
+		assert_eq!(failover.fail_back(), None);
+
+		// ...but move up to better pools as soon as they show up, as they never failed
+		failover.pool_updated(1, true);
+		failover.pool_updated(0, true);
+		assert_eq!(failover.fail_back(), Some(0));
+		failover.switched_to(0, 0);
+		assert_eq!(failover.fail_back(), None);
+		assert_eq!(failover.check_probation(0), None);
+	}
+
+	#[test]
+	fn test_fail_over() {
+		let mut failover = PoolFailover::new(config(600, 600, 6), 3);
+		for idx in 0..3 {
+			failover.pool_updated(idx, true);
+		}
+		failover.switched_to(0, 0);
+
+		// Prefer connected pools...
+		assert!(failover.pool_failed(1));
+		assert!(failover.pool_failed(0));
+		assert_eq!(failover.fail_over(), Some(2));
+
+		// ...then go in order, even if nothing is connected
+		assert!(failover.pool_failed(2));
+		assert_eq!(failover.fail_over(), Some(1));
+
+		// Nothing with work, nothing to do
+		let mut failover = PoolFailover::new(config(600, 600, 6), 2);
+		failover.pool_updated(0, true);
+		failover.pool_updated(1, false);
+		failover.switched_to(0, 0);
+		assert!(failover.pool_failed(0));
+		assert_eq!(failover.fail_over(), None);
+	}
+
+	#[test]
+	fn test_fail_back() {
+		// Without any hysteresis we go straight back
+		assert_eq!(failed_over(config(0, 0, 6)).fail_back(), Some(0));
+
+		// Not if we haven't been on the backup long enough...
+		assert_eq!(failed_over(config(600, 0, 6)).fail_back(), None);
+		// ...or pool 0 hasn't been back up long enough...
+		assert_eq!(failed_over(config(0, 600, 6)).fail_back(), None);
+		// ...or we've already switched as many times as we're allowed this hour (picking our first
+		// pool at startup doesn't count)
+		assert_eq!(failed_over(config(0, 0, 1)).fail_back(), None);
+		assert_eq!(failed_over(config(0, 0, 2)).fail_back(), Some(0));
+
+		// Pools which fail again start over, and aren't considered while down
+		let mut failover = failed_over(config(0, 0, 6));
+		assert!(failover.pool_failed(0));
+		assert_eq!(failover.fail_back(), None);
+		failover.pool_updated(0, true);
+		assert_eq!(failover.fail_back(), Some(0));
+	}
+
+	#[test]
+	fn test_probation() {
+		// Going back to a pool which failed is fine once it accepts a share...
+		let mut failover = failed_over(config(0, 0, 6));
+		failover.switched_to(0, 5);
+		assert_eq!(failover.check_probation(6), None);
+		assert_eq!(failover.cur_pool(), Some(0));
+		// ...after which it's off probation
+		assert_eq!(failover.check_probation(6), None);
+
+		// But if it doesn't within primary_stable_secs we leave again
+		let mut failover = failed_over(config(0, 0, 6));
+		failover.switched_to(0, 5);
+		assert_eq!(failover.check_probation(5), Some(1));
+		failover.switched_to(1, 0);
+		assert_eq!(failover.check_probation(0), None);
+
+		// Until then we wait
+		let mut failover = failed_over(config(0, 600, 6));
+		failover.switched_to(0, 5);
+		assert_eq!(failover.check_probation(5), None);
+		assert_eq!(failover.cur_pool(), Some(0));
+
+		// Pools which never failed aren't on probation
+		let mut failover = PoolFailover::new(config(0, 0, 6), 2);
+		failover.pool_updated(0, true);
+		failover.switched_to(0, 0);
+		assert_eq!(failover.check_probation(0), None);
+	}
+}
@@ -0,0 +1,238 @@
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
+
+use tokio::{io, net, timer};
+
+use utils;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::time::Duration;
+
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+/// "PROXY " is included in the first 6 bytes we read, the rest of the line (including the \r\n)
+/// may be no more than 101 bytes.
+const V1_MAX_REMAINING_LEN: usize = 107 - 6;
+
+/// A set of IPs and IP/prefix_len subnets
+pub struct SubnetSet {
+	sources: Vec<(IpAddr, u8)>,
+}
+
+/// Set of source addresses (eg our load balancers) which we expect to send a PROXY protocol (v1
+/// or v2) header at the start of every connection. Connections from anywhere else are taken at
+/// face value.
+pub type TrustedProxies = SubnetSet;
+
+impl SubnetSet {
+	pub fn new() -> Self {
+		Self { sources: Vec::new() }
+	}
+
+	/// Adds an IP or IP/prefix_len subnet to the set, returning false if it didn't parse.
+	pub fn add(&mut self, source: &str) -> bool {
+		let mut split = source.splitn(2, '/');
+		let ip: IpAddr = match split.next().unwrap().parse() {
+			Ok(ip) => ip,
+			Err(_) => return false,
+		};
+		let max_len = if ip.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match split.next() {
+			Some(len) => match len.parse() {
+				Ok(len) if len <= max_len => len,
+				_ => return false,
+			},
+			None => max_len,
+		};
+		self.sources.push((ip, prefix_len));
+		true
+	}
+
+	pub fn contains(&self, addr: &IpAddr) -> bool {
+		let addr = unmap_ipv4(addr);
+		self.sources.iter().any(|&(ref source, prefix_len)| {
+			match (source, &addr) {
+				(&IpAddr::V4(ref source), &IpAddr::V4(ref addr)) =>
+					prefix_matches(&source.octets(), &addr.octets(), prefix_len),
+				(&IpAddr::V6(ref source), &IpAddr::V6(ref addr)) =>
+					prefix_matches(&source.octets(), &addr.octets(), prefix_len),
+				_ => false,
+			}
+		})
+	}
+}
+
+/// Listeners bound to [::] see IPv4 clients as ::ffff:a.b.c.d, map those back so they match
+/// IPv4 allowlist entries.
+fn unmap_ipv4(addr: &IpAddr) -> IpAddr {
+	match addr {
+		&IpAddr::V6(ref v6) => {
+			let segments = v6.segments();
+			if segments[..5] == [0; 5] && segments[5] == 0xffff {
+				IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8))
+			} else { addr.clone() }
+		},
+		_ => addr.clone(),
+	}
+}
+
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+	let full_bytes = (prefix_len / 8) as usize;
+	if a[..full_bytes] != b[..full_bytes] {
+		return false;
+	}
+	let rem_bits = prefix_len % 8;
+	if rem_bits == 0 {
+		return true;
+	}
+	let mask = 0xffu8 << (8 - rem_bits);
+	a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+macro_rules! bad_header {
+	() => {
+		io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)
+	}
+}
+
+/// Parses the remainder of a v1 header line (after "PROXY ", without the trailing \r\n).
+/// Returns None for UNKNOWN, in which case the socket's own peer address should be used.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+	let line = str::from_utf8(line).map_err(|_| bad_header!())?;
+	let mut fields = line.split(' ');
+	let is_v4 = match fields.next() {
+		Some("TCP4") => true,
+		Some("TCP6") => false,
+		Some("UNKNOWN") => return Ok(None),
+		_ => return Err(bad_header!()),
+	};
+	let src_ip: IpAddr = fields.next().ok_or(bad_header!())?.parse().map_err(|_| bad_header!())?;
+	let _dst_ip: IpAddr = fields.next().ok_or(bad_header!())?.parse().map_err(|_| bad_header!())?;
+	let src_port: u16 = fields.next().ok_or(bad_header!())?.parse().map_err(|_| bad_header!())?;
+	let _dst_port: u16 = fields.next().ok_or(bad_header!())?.parse().map_err(|_| bad_header!())?;
+	if fields.next().is_some() || src_ip.is_ipv4() != is_v4 {
+		return Err(bad_header!());
+	}
+	Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+/// Parses a v2 header given the 16 fixed bytes (signature included) and the address block which
+/// followed. Returns None for LOCAL connections (eg load balancer health checks) and address
+/// families we don't care about, in which case the socket's own peer address should be used.
+fn parse_v2(header: &[u8; 16], addrs: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+	if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+		return Err(bad_header!());
+	}
+	match header[12] & 0xf {
+		0 => return Ok(None),
+		1 => {},
+		_ => return Err(bad_header!()),
+	}
+	match header[13] >> 4 {
+		1 => {
+			if addrs.len() < 12 { return Err(bad_header!()); }
+			let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+			let port = ((addrs[8] as u16) << 8) | addrs[9] as u16;
+			Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+		},
+		2 => {
+			if addrs.len() < 36 { return Err(bad_header!()); }
+			let mut octets = [0; 16];
+			octets.copy_from_slice(&addrs[..16]);
+			let port = ((addrs[32] as u16) << 8) | addrs[33] as u16;
+			Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+		},
+		_ => Ok(None),
+	}
+}
+
+fn read_header(sock: net::TcpStream, peer: SocketAddr) -> impl Future<Item=(net::TcpStream, SocketAddr), Error=io::Error> {
+	io::read_exact(sock, [0u8; 6]).and_then(move |(sock, start)| {
+		if start == *b"PROXY " {
+			future::Either::A(future::loop_fn((sock, Vec::with_capacity(V1_MAX_REMAINING_LEN)), |(sock, mut line)| {
+				io::read_exact(sock, [0u8; 1]).and_then(move |(sock, byte)| {
+					line.push(byte[0]);
+					if line.ends_with(b"\r\n") {
+						Ok(Loop::Break((sock, line)))
+					} else if line.len() >= V1_MAX_REMAINING_LEN {
+						Err(bad_header!())
+					} else {
+						Ok(Loop::Continue((sock, line)))
+					}
+				})
+			}).and_then(move |(sock, line)| {
+				let addr = parse_v1(&line[..line.len() - 2])?;
+				Ok((sock, addr.unwrap_or(peer)))
+			}))
+		} else if start[..] == V2_SIGNATURE[..6] {
+			future::Either::B(future::Either::A(io::read_exact(sock, [0u8; 10]).and_then(move |(sock, rest)| {
+				let mut header = [0u8; 16];
+				header[..6].copy_from_slice(&start);
+				header[6..].copy_from_slice(&rest);
+				let len = ((header[14] as usize) << 8) | header[15] as usize;
+				io::read_exact(sock, vec![0; len]).and_then(move |(sock, addrs)| {
+					let addr = parse_v2(&header, &addrs)?;
+					Ok((sock, addr.unwrap_or(peer)))
+				})
+			})))
+		} else {
+			future::Either::B(future::Either::B(future::err(bad_header!())))
+		}
+	})
+}
+
+/// Resolves the real address of a freshly-accepted client. If the connection came from a trusted
+/// proxy we require a PROXY protocol header before anything else on the socket and consume it,
+/// otherwise we just use the socket's peer address.
+pub fn accept(sock: net::TcpStream, trusted: &TrustedProxies) -> impl Future<Item=(net::TcpStream, SocketAddr), Error=io::Error> {
+	let peer = match sock.peer_addr() {
+		Ok(peer) => peer,
+		Err(e) => return future::Either::A(future::err(e)),
+	};
+	if !trusted.contains(&peer.ip()) {
+		return future::Either::A(future::ok((sock, peer)));
+	}
+	future::Either::B(timer::Timeout::new(read_header(sock, peer), Duration::from_secs(10)).map_err(|e| {
+		e.into_inner().unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, utils::HandleError))
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use proxy_protocol::*;
+
+	#[test]
+	fn test_trusted_subnets() {
+		let mut trusted = TrustedProxies::new();
+		assert!(trusted.add("10.1.0.0/16"));
+		assert!(trusted.add("fd00::1"));
+		assert!(!trusted.add("10.0.0.0/33"));
+		assert!(!trusted.add("lb.example.com"));
+
+		assert!(trusted.contains(&"10.1.200.3".parse().unwrap()));
+		assert!(trusted.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+		assert!(!trusted.contains(&"10.2.0.1".parse().unwrap()));
+		assert!(trusted.contains(&"fd00::1".parse().unwrap()));
+		assert!(!trusted.contains(&"fd00::2".parse().unwrap()));
+	}
+
+	#[test]
+	fn test_parse_headers() {
+		assert_eq!(parse_v1(b"TCP4 192.0.2.1 192.0.2.2 3333 4444").unwrap(), Some("192.0.2.1:3333".parse().unwrap()));
+		assert_eq!(parse_v1(b"TCP6 2001:db8::1 2001:db8::2 3333 4444").unwrap(), Some("[2001:db8::1]:3333".parse().unwrap()));
+		assert_eq!(parse_v1(b"UNKNOWN").unwrap(), None);
+		assert!(parse_v1(b"TCP4 2001:db8::1 2001:db8::2 3333 4444").is_err());
+		assert!(parse_v1(b"TCP4 192.0.2.1 192.0.2.2 3333").is_err());
+
+		let mut header = [0; 16];
+		header[..12].copy_from_slice(&V2_SIGNATURE);
+		header[12] = 0x21;
+		header[13] = 0x11;
+		let addrs = [192, 0, 2, 1, 192, 0, 2, 2, 0x0d, 0x05, 0x11, 0x5c];
+		assert_eq!(parse_v2(&header, &addrs).unwrap(), Some("192.0.2.1:3333".parse().unwrap()));
+		assert!(parse_v2(&header, &addrs[..8]).is_err());
+		header[12] = 0x20;
+		assert_eq!(parse_v2(&header, &[]).unwrap(), None);
+	}
+}
@@ -0,0 +1,287 @@
+// A job provider which replays templates from a JSON scenario file on a schedule instead of
+// connecting to a (patched) bitcoind, so that proxies can be run end to end without one.
+//
+// The scenario looks like:
+// {
+//   "interval_secs": 30,  // default delay between templates
+//   "repeat": true,       // start over after the last template
+//   "templates": [ { ...fields... }, ... ]
+// }
+// Each template takes any fields it doesn't set from the one before it, so after the first only
+// changes need to be given. A template with a new header_prevblock (or previous_header) is a
+// simulated new block. Fields:
+//   target, header_prevblock - big-endian hex, as bitcoind prints them
+//   previous_header - hex serialized header, instead of header_prevblock
+//   header_version, header_time (default now), header_nbits, coinbase_value_remaining,
+//   coinbase_version, coinbase_input_sequence, coinbase_locktime, delay_secs - integers
+//   coinbase_prefix - hex, should start with the BIP 34 height push
+//   appended_coinbase_outputs - [{"value": 0, "script_pubkey": hex}]
+//   transactions - [hex], which merkle_rhss are computed from
+// See contrib/replay_scenario.json for an example.
+
+use block_assembly;
+use msg_framing::{BlockTemplate,TransactionData};
+use work_client::JobProviderHandler;
+use utils;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction,TxOut};
+use bitcoin::network::serialize;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use futures::future;
+use futures::future::Loop;
+use futures::Future;
+
+use serde_json;
+
+use tokio;
+use tokio::timer::Delay;
+
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct ReplayTemplate {
+	template: BlockTemplate,
+	data: TransactionData,
+	/// If None, the current time when we replay it
+	header_time: Option<u32>,
+	delay: Duration,
+}
+
+pub struct Scenario {
+	path: String,
+	templates: Vec<ReplayTemplate>,
+	repeat: bool,
+}
+
+fn get_u64(entry: &serde_json::Map<String, serde_json::Value>, key: &str, default: Option<u64>) -> Result<u64, String> {
+	match entry.get(key) {
+		Some(v) => v.as_u64().ok_or(format!("{} must be a non-negative integer", key)),
+		None => default.ok_or(format!("missing {}", key)),
+	}
+}
+
+fn get_hex(entry: &serde_json::Map<String, serde_json::Value>, key: &str) -> Result<Option<Vec<u8>>, String> {
+	match entry.get(key) {
+		Some(v) => match v.as_str().and_then(utils::hex_to_vec) {
+			Some(bytes) => Ok(Some(bytes)),
+			None => Err(format!("{} must be a hex string", key)),
+		},
+		None => Ok(None),
+	}
+}
+
+fn get_u256_be(entry: &serde_json::Map<String, serde_json::Value>, key: &str) -> Result<Option<[u8; 32]>, String> {
+	match entry.get(key) {
+		Some(v) => match v.as_str().and_then(utils::hex_to_u256_rev) {
+			Some(bytes) => Ok(Some(bytes)),
+			None => Err(format!("{} must be a 64-char hex string", key)),
+		},
+		None => Ok(None),
+	}
+}
+
+fn parse_template(entry: &serde_json::Map<String, serde_json::Value>, default_delay: u64) -> Result<ReplayTemplate, String> {
+	let previous_header: BlockHeader = match get_hex(entry, "previous_header")? {
+		Some(bytes) => serialize::deserialize(&bytes).map_err(|_| "previous_header must be an 80-byte serialized header".to_string())?,
+		None => BlockHeader {
+			version: 0,
+			prev_blockhash: Default::default(),
+			merkle_root: Default::default(),
+			time: 0,
+			bits: 0,
+			nonce: 0,
+		},
+	};
+	let header_prevblock = match get_u256_be(entry, "header_prevblock")? {
+		Some(prevblock) if !entry.contains_key("previous_header") => prevblock,
+		_ if entry.contains_key("previous_header") => {
+			let mut prevblock = [0; 32];
+			prevblock.copy_from_slice(&previous_header.bitcoin_hash()[..]);
+			prevblock
+		},
+		_ => return Err("missing header_prevblock or previous_header".to_string()),
+	};
+
+	let mut transactions = Vec::new();
+	let mut txids = vec![[0; 32]];
+	if let Some(txn) = entry.get("transactions") {
+		for tx in txn.as_array().ok_or("transactions must be an array of hex strings".to_string())? {
+			let tx_bytes = tx.as_str().and_then(utils::hex_to_vec).ok_or("transactions must be an array of hex strings".to_string())?;
+			let tx_deser: Transaction = serialize::deserialize(&tx_bytes).map_err(|_| "transactions must be serialized transactions".to_string())?;
+			let mut txid = [0; 32];
+			txid.copy_from_slice(&tx_deser.txid()[..]);
+			txids.push(txid);
+			transactions.push(tx_bytes);
+		}
+	}
+
+	let mut appended_coinbase_outputs = Vec::new();
+	if let Some(outputs) = entry.get("appended_coinbase_outputs") {
+		for output in outputs.as_array().ok_or("appended_coinbase_outputs must be an array".to_string())? {
+			let output = output.as_object().ok_or("appended_coinbase_outputs entries must be objects".to_string())?;
+			let value = get_u64(output, "value", Some(0))?;
+			if value != 0 {
+				return Err("appended_coinbase_outputs can't claim value in non-final work".to_string());
+			}
+			appended_coinbase_outputs.push(TxOut {
+				value,
+				script_pubkey: Script::from(get_hex(output, "script_pubkey")?.ok_or("missing script_pubkey".to_string())?),
+			});
+		}
+	}
+
+	let coinbase_prefix = get_hex(entry, "coinbase_prefix")?.ok_or("missing coinbase_prefix".to_string())?;
+	if coinbase_prefix.len() > 42 {
+		return Err("coinbase_prefix can be at most 42 bytes".to_string());
+	}
+
+	Ok(ReplayTemplate {
+		template: BlockTemplate {
+			template_timestamp: 0,
+			target: get_u256_be(entry, "target")?.ok_or("missing target".to_string())?,
+
+			header_version: get_u64(entry, "header_version", Some(0x20000000))? as u32,
+			header_prevblock,
+			header_time: 0,
+			header_nbits: get_u64(entry, "header_nbits", None)? as u32,
+
+			merkle_rhss: block_assembly::coinbase_merkle_branch(txids),
+			coinbase_value_remaining: get_u64(entry, "coinbase_value_remaining", None)?,
+
+			coinbase_version: get_u64(entry, "coinbase_version", Some(1))? as u32,
+			coinbase_prefix,
+			coinbase_postfix: Vec::new(),
+			coinbase_input_sequence: get_u64(entry, "coinbase_input_sequence", Some(0xffffffff))? as u32,
+			appended_coinbase_outputs,
+			coinbase_locktime: get_u64(entry, "coinbase_locktime", Some(0))? as u32,
+		},
+		data: TransactionData {
+			previous_header,
+			template_timestamp: 0,
+			extra_block_data: Vec::new(),
+			transactions,
+		},
+		header_time: match entry.get("header_time") {
+			Some(_) => Some(get_u64(entry, "header_time", None)? as u32),
+			None => None,
+		},
+		delay: Duration::from_secs(get_u64(entry, "delay_secs", Some(default_delay))?),
+	})
+}
+
+impl Scenario {
+	pub fn load(path: &str) -> Result<Self, String> {
+		let contents = fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+		Self::parse(path, &contents)
+	}
+
+	fn parse(path: &str, contents: &[u8]) -> Result<Self, String> {
+		let v: serde_json::Value = serde_json::from_slice(&contents).map_err(|e| format!("failed to parse {}: {}", path, e))?;
+		let obj = v.as_object().ok_or("scenario must be a JSON object".to_string())?;
+		let interval = get_u64(obj, "interval_secs", Some(30))?;
+		let repeat = obj.get("repeat").and_then(|v| v.as_bool()).unwrap_or(false);
+
+		let mut templates = Vec::new();
+		let mut fields = serde_json::Map::new();
+		for (idx, entry) in obj.get("templates").and_then(|v| v.as_array()).ok_or("missing templates array".to_string())?.iter().enumerate() {
+			let entry = entry.as_object().ok_or(format!("template {} must be an object", idx))?;
+			// A new prevblock or previous_header replaces whichever of the two the last one used
+			if entry.contains_key("header_prevblock") || entry.contains_key("previous_header") {
+				fields.remove("header_prevblock");
+				fields.remove("previous_header");
+			}
+			for (key, value) in entry.iter() {
+				fields.insert(key.clone(), value.clone());
+			}
+			templates.push(parse_template(&fields, interval).map_err(|e| format!("template {}: {}", idx, e))?);
+		}
+		if templates.is_empty() {
+			return Err("no templates".to_string());
+		}
+		Ok(Self { path: path.to_string(), templates, repeat })
+	}
+}
+
+/// Replays scenario's templates through handler, as if they came from a connected job provider.
+pub fn start(scenario: Arc<Scenario>, handler: Arc<JobProviderHandler>) {
+	handler.start_replay();
+	tokio::spawn(future::loop_fn(0, move |mut idx| {
+		if idx >= scenario.templates.len() {
+			if !scenario.repeat {
+				println!("Finished replaying job provider scenario {}", scenario.path);
+				return future::Either::A(future::ok(Loop::Break(())));
+			}
+			idx = 0;
+		}
+		let entry = &scenario.templates[idx];
+
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		let timestamp = time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000;
+		let mut template = entry.template.clone();
+		template.template_timestamp = timestamp;
+		template.header_time = entry.header_time.unwrap_or(time.as_secs() as u32);
+		let mut data = entry.data.clone();
+		data.template_timestamp = timestamp;
+
+		println!("Replaying template {} (on {}) from {}", idx, Sha256dHash::from(&template.header_prevblock[..]).be_hex_string(), scenario.path);
+		handler.replay_job(template, data);
+
+		future::Either::B(Delay::new(Instant::now() + entry.delay).then(move |_| {
+			Ok(Loop::Continue(idx + 1))
+		}))
+	}));
+}
+
+#[cfg(test)]
+mod tests {
+	use replay_provider::*;
+
+	#[test]
+	fn test_example_scenario() {
+		let scenario = Scenario::parse("example", include_bytes!("../contrib/replay_scenario.json")).unwrap();
+		assert!(scenario.repeat);
+		assert_eq!(scenario.templates.len(), 3);
+
+		let first = &scenario.templates[0];
+		assert_eq!(first.template.header_prevblock[0], 1);
+		assert_eq!(first.template.coinbase_value_remaining, 1250000000);
+		assert!(first.template.merkle_rhss.is_empty());
+		assert_eq!(first.header_time, None);
+		assert_eq!(first.delay, Duration::from_secs(10));
+
+		// Fields carry over from the previous template
+		let second = &scenario.templates[1];
+		assert_eq!(second.template.header_prevblock, first.template.header_prevblock);
+		assert_eq!(second.template.coinbase_prefix, first.template.coinbase_prefix);
+		assert_eq!(second.data.transactions.len(), 1);
+		assert_eq!(second.template.merkle_rhss.len(), 1);
+		let tx: Transaction = serialize::deserialize(&second.data.transactions[0]).unwrap();
+		assert_eq!(&second.template.merkle_rhss[0][..], &tx.txid()[..]);
+
+		let third = &scenario.templates[2];
+		assert_eq!(third.template.header_prevblock[0], 2);
+		assert!(third.data.transactions.is_empty());
+		assert!(third.template.merkle_rhss.is_empty());
+		assert_eq!(third.delay, Duration::from_secs(30));
+	}
+
+	#[test]
+	fn test_bad_scenarios() {
+		let base = r#""target": "00000000ffff0000000000000000000000000000000000000000000000000000", "header_nbits": 1, "coinbase_value_remaining": 1, "coinbase_prefix": "03a06806""#;
+		let parse = |templates: &str| Scenario::parse("test", format!(r#"{{"templates": [{}]}}"#, templates).as_bytes());
+
+		assert!(parse(&format!(r#"{{{}, "header_prevblock": "{}"}}"#, base, "00".repeat(32))).is_ok());
+		assert!(parse("").is_err());
+		assert!(parse(&format!("{{{}}}", base)).is_err());
+		assert!(parse(&format!(r#"{{{}, "header_prevblock": "00"}}"#, base)).is_err());
+		assert!(parse(&format!(r#"{{{}, "header_prevblock": "{}", "transactions": ["00"]}}"#, base, "00".repeat(32))).is_err());
+		assert!(parse(&format!(r#"{{{}, "header_prevblock": "{}", "appended_coinbase_outputs": [{{"value": 1, "script_pubkey": "51"}}]}}"#, base, "00".repeat(32))).is_err());
+		assert!(parse(&format!(r#"{{{}, "header_prevblock": "{}", "coinbase_prefix": "{}"}}"#, base, "00".repeat(32), "00".repeat(43))).is_err());
+		assert!(Scenario::parse("test", b"[]").is_err());
+	}
+}
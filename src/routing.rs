@@ -0,0 +1,203 @@
+use proxy_protocol::SubnetSet;
+
+use std::net::IpAddr;
+
+/// The rules for sending clients to one group's pipeline (ie its own pool list, pool user and
+/// payout script). A group matches a client if every kind of rule it has matches (any one rule
+/// of each kind will do).
+pub struct RouteGroup {
+	pub name: String,
+	worker_prefixes: Vec<String>,
+	listen_ports: Vec<u16>,
+	sources: Option<SubnetSet>,
+}
+
+// We never route in pool-proxy
+#[allow(dead_code)]
+impl RouteGroup {
+	pub fn new(name: String) -> Self {
+		Self {
+			name,
+			worker_prefixes: Vec::new(),
+			listen_ports: Vec::new(),
+			sources: None,
+		}
+	}
+
+	pub fn add_worker_prefix(&mut self, prefix: &str) {
+		self.worker_prefixes.push(prefix.to_string());
+	}
+
+	pub fn add_listen_port(&mut self, port: u16) {
+		self.listen_ports.push(port);
+	}
+
+	/// Adds an IP or IP/prefix_len subnet, returning false if it didn't parse.
+	pub fn add_source(&mut self, source: &str) -> bool {
+		if self.sources.is_none() {
+			self.sources = Some(SubnetSet::new());
+		}
+		self.sources.as_mut().unwrap().add(source)
+	}
+
+	pub fn has_rules(&self) -> bool {
+		!self.worker_prefixes.is_empty() || !self.listen_ports.is_empty() || self.sources.is_some()
+	}
+
+	/// worker is None for clients which don't give us a worker name (ie native clients), which
+	/// never match groups with worker prefix rules.
+	fn matches(&self, listen_port: u16, source: &IpAddr, worker: Option<&str>) -> bool {
+		if !self.worker_prefixes.is_empty() {
+			match worker {
+				Some(worker) => if !self.worker_prefixes.iter().any(|prefix| worker.starts_with(&prefix[..])) { return false; },
+				None => return false,
+			}
+		}
+		if !self.listen_ports.is_empty() && !self.listen_ports.contains(&listen_port) {
+			return false;
+		}
+		if let Some(ref sources) = self.sources {
+			if !sources.contains(source) { return false; }
+		}
+		true
+	}
+}
+
+/// Picks a group for each new client. Group 0 is the default group (ie everything given before
+/// the first --group), which takes any client no other group matches, the rest are tried in
+/// order.
+pub struct Router {
+	groups: Vec<RouteGroup>,
+}
+
+// We never route in pool-proxy
+#[allow(dead_code)]
+impl Router {
+	pub fn new(groups: Vec<RouteGroup>) -> Self {
+		Self { groups }
+	}
+
+	/// If set, stratum clients have to be held until they send mining.authorize before we know
+	/// where they go.
+	pub fn needs_worker_name(&self) -> bool {
+		self.groups.iter().any(|group| !group.worker_prefixes.is_empty())
+	}
+
+	pub fn route(&self, listen_port: u16, source: &IpAddr, worker: Option<&str>) -> usize {
+		for (idx, group) in self.groups.iter().enumerate().skip(1) {
+			if group.matches(listen_port, source, worker) {
+				return idx;
+			}
+		}
+		0
+	}
+
+	pub fn group_name(&self, idx: usize) -> &str {
+		&self.groups[idx].name
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use routing::*;
+
+	fn router() -> Router {
+		let default = RouteGroup::new("default".to_string());
+
+		let mut rigs = RouteGroup::new("rigs".to_string());
+		rigs.add_worker_prefix("rig");
+		rigs.add_worker_prefix("farm.");
+
+		let mut lan = RouteGroup::new("lan".to_string());
+		assert!(lan.add_source("10.0.0.0/8"));
+		assert!(lan.add_source("fd00::/8"));
+
+		let mut lan_3334 = RouteGroup::new("lan-3334".to_string());
+		lan_3334.add_listen_port(3334);
+		assert!(lan_3334.add_source("10.0.0.0/8"));
+
+		let mut port_3335 = RouteGroup::new("3335".to_string());
+		port_3335.add_listen_port(3335);
+
+		Router::new(vec![default, rigs, lan, lan_3334, port_3335])
+	}
+
+	#[test]
+	fn test_add_bad_source() {
+		let mut group = RouteGroup::new("bad".to_string());
+		assert!(!group.has_rules());
+		assert!(!group.add_source("10.0.0.0/33"));
+		assert!(!group.add_source("example.com"));
+		assert!(group.add_source("::1"));
+		assert!(group.has_rules());
+	}
+
+	#[test]
+	fn test_needs_worker_name() {
+		assert!(router().needs_worker_name());
+
+		let mut port = RouteGroup::new("port".to_string());
+		port.add_listen_port(3334);
+		assert!(!Router::new(vec![RouteGroup::new("default".to_string()), port]).needs_worker_name());
+	}
+
+	#[test]
+	fn test_route() {
+		let router = router();
+		let wan = "8.8.8.8".parse().unwrap();
+		let lan = "10.1.2.3".parse().unwrap();
+		let lan6 = "fd12::1".parse().unwrap();
+
+		// Nothing matches
+		assert_eq!(router.route(3333, &wan, Some("someone")), 0);
+		assert_eq!(router.route(3333, &wan, None), 0);
+		assert_eq!(router.group_name(0), "default");
+
+		// Worker prefixes
+		assert_eq!(router.route(3333, &wan, Some("rig1")), 1);
+		assert_eq!(router.route(3333, &wan, Some("farm.a")), 1);
+		assert_eq!(router.route(3333, &wan, Some("farm")), 0);
+		assert_eq!(router.route(3333, &wan, Some("myrig")), 0);
+
+		// Subnets, for both families
+		assert_eq!(router.route(3333, &lan, None), 2);
+		assert_eq!(router.route(3333, &lan6, None), 2);
+		assert_eq!(router.route(3333, &"11.1.2.3".parse().unwrap(), None), 0);
+
+		// Ports
+		assert_eq!(router.route(3335, &wan, None), 4);
+		assert_eq!(router.route(3334, &wan, None), 0);
+
+		// Earlier groups win, so a rig on the LAN goes to rigs and anything on the LAN never makes
+		// it to lan-3334, despite it being the more specific match
+		assert_eq!(router.route(3333, &lan, Some("rig1")), 1);
+		assert_eq!(router.route(3334, &lan, None), 2);
+		assert_eq!(router.route(3335, &lan, None), 2);
+
+		// Group 0's rules (if it had any) don't matter, it's only ever the fallback
+		let mut default = RouteGroup::new("default".to_string());
+		default.add_listen_port(3333);
+		let mut port = RouteGroup::new("3333".to_string());
+		port.add_listen_port(3333);
+		let router = Router::new(vec![default, port]);
+		assert_eq!(router.route(3333, &wan, None), 1);
+		assert_eq!(router.route(3334, &wan, None), 0);
+	}
+
+	#[test]
+	fn test_group_needs_every_kind_of_rule() {
+		let mut group = RouteGroup::new("lan-rigs".to_string());
+		group.add_worker_prefix("rig");
+		group.add_listen_port(3334);
+		assert!(group.add_source("10.0.0.0/8"));
+		let router = Router::new(vec![RouteGroup::new("default".to_string()), group]);
+		let lan = "10.1.2.3".parse().unwrap();
+
+		assert_eq!(router.route(3334, &lan, Some("rig1")), 1);
+		assert_eq!(router.route(3333, &lan, Some("rig1")), 0);
+		assert_eq!(router.route(3334, &"8.8.8.8".parse().unwrap(), Some("rig1")), 0);
+		assert_eq!(router.route(3334, &lan, Some("asic")), 0);
+		// Native clients never give us a worker name
+		assert_eq!(router.route(3334, &lan, None), 0);
+	}
+}
@@ -7,6 +7,7 @@ extern crate bytes;
 extern crate crypto;
 extern crate futures;
 extern crate hyper;
+extern crate rand;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_codec;
@@ -16,6 +17,9 @@ extern crate serde_json;
 mod msg_framing;
 use msg_framing::*;
 
+mod transport_crypto;
+use transport_crypto::ENCRYPTION_FLAG;
+
 mod utils;
 
 mod rpc_client;
@@ -25,7 +29,13 @@ mod generational_hash_sets;
 use generational_hash_sets::*;
 
 mod timeout_stream;
-use timeout_stream::TimeoutStream;
+use timeout_stream::{IdleTimeouts, ProgressIo, TimeoutSink, TimeoutStream};
+
+mod auth_keys;
+use auth_keys::ServerAuthKeys;
+
+mod proxy_protocol;
+use proxy_protocol::TrustedProxies;
 
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::blockdata::block::BlockHeader;
@@ -202,18 +212,29 @@ impl AllowedBlocksInfo {
 }
 
 fn main() {
-	println!("USAGE: sample-pool --listen_bind=IP:port --auth_key=base58privkey --payout_address=addr [--server_id=up_to_36_byte_string_for_coinbase] --bitcoind_rpc_path=user:pass@host:port");
+	println!("USAGE: sample-pool --listen_bind=IP:port --auth_key=base58privkey [--next_auth_key=base58privkey --key_rotation_time=unix_secs] --payout_address=addr [--server_id=up_to_36_byte_string_for_coinbase] --bitcoind_rpc_path=user:pass@host:port (--proxy_protocol_from=IP[/len])* [--read_timeout=secs] [--write_timeout=secs]");
 	println!("--listen_bind - the address to bind to");
 	println!("--auth_key - the auth key to use to authenticate to clients");
+	println!("--next_auth_key - the auth key to switch to at --key_rotation_time. Until then clients");
+	println!("                  are told to accept either key");
 	println!("--payout_address - the Bitcoin address on which to receive payment");
 	println!("--bitcoind_rpc_path - the bitcoind RPC server for checking weak block validity");
 	println!("                      and header submission");
+	println!("--proxy_protocol_from - connections from this IP or IP/prefix_len subnet (eg your TCP");
+	println!("                         load balancer) must start with a PROXY protocol v1/v2 header");
+	println!("                         giving the real client address (may be given multiple times)");
+	println!("--read_timeout - disconnect clients which send nothing for this many seconds (default 600)");
+	println!("--write_timeout - disconnect clients which stop reading for this many seconds (default 120)");
 
 	let mut listen_bind = None;
 	let mut auth_key = None;
+	let mut next_auth_key = None;
+	let mut key_rotation_time = None;
 	let mut payout_addr = None;
 	let mut server_id = None;
 	let mut rpc_path = None;
+	let mut trusted_proxies = TrustedProxies::new();
+	let mut timeouts = IdleTimeouts::default();
 
 	for arg in env::args().skip(1) {
 		if arg.starts_with("--listen_bind") {
@@ -246,6 +267,36 @@ fn main() {
 					return;
 				}
 			});
+		} else if arg.starts_with("--next_auth_key") {
+			if next_auth_key.is_some() {
+				println!("Cannot specify multiple next auth keys");
+				return;
+			}
+			next_auth_key = Some(match privkey::Privkey::from_str(arg.split_at(16).1) {
+				Ok(privkey) => {
+					if !privkey.compressed {
+						println!("Private key must represent a compressed key!");
+						return;
+					}
+					privkey.key
+				},
+				Err(_) =>{
+					println!("Failed to parse next_auth_key into a private key");
+					return;
+				}
+			});
+		} else if arg.starts_with("--key_rotation_time") {
+			if key_rotation_time.is_some() {
+				println!("Cannot specify multiple key rotation times");
+				return;
+			}
+			key_rotation_time = Some(match arg.split_at(20).1.parse::<u64>() {
+				Ok(secs) => secs * 1000,
+				Err(_) => {
+					println!("Failed to parse key_rotation_time into an integer");
+					return;
+				}
+			});
 		} else if arg.starts_with("--payout_address") {
 			if payout_addr.is_some() {
 				println!("Cannot specify multiple payout addresses");
@@ -275,6 +326,27 @@ fn main() {
 				return;
 			}
 			rpc_path = Some(arg.split_at(20).1.to_string());
+		} else if arg.starts_with("--proxy_protocol_from") {
+			if !trusted_proxies.add(arg.split_at(22).1) {
+				println!("Failed to parse proxy_protocol_from into an IP or IP/prefix_len subnet");
+				return;
+			}
+		} else if arg.starts_with("--read_timeout") {
+			timeouts.read = match arg.split_at(15).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse read_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--write_timeout") {
+			timeouts.write = match arg.split_at(16).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse write_timeout into a number of seconds");
+					return;
+				}
+			};
 		} else {
 			println!("Unkown arg: {}", arg);
 			return;
@@ -285,6 +357,11 @@ fn main() {
 		println!("Need to specify all but server_id parameters");
 		return;
 	}
+	if next_auth_key.is_some() != key_rotation_time.is_some() {
+		println!("Need both next_auth_key and key_rotation_time to rotate keys");
+		return;
+	}
+	let auth_keys = Arc::new(ServerAuthKeys::new(auth_key.unwrap(), next_auth_key.map(|next| (next, key_rotation_time.unwrap()))));
 
 	let users: Arc<Mutex<Vec<Weak<PerUserClientRef>>>> = Arc::new(Mutex::new(Vec::new()));
 	let block_info = Arc::new(RwLock::new(AllowedBlocksInfo {
@@ -422,12 +499,32 @@ fn main() {
 					future::result(Ok(()))
 				}));
 
+				let (conn_tx, conn_rx) = mpsc::unbounded();
 				tokio::spawn(listener.incoming().for_each(move |sock| {
+					let conn_tx = conn_tx.clone();
+					tokio::spawn(proxy_protocol::accept(sock, &trusted_proxies).then(move |res| {
+						match res {
+							Ok(conn) => { let _ = conn_tx.unbounded_send(conn); },
+							Err(e) => println!("Dropping connection which failed to send a valid PROXY protocol header: {}", e),
+						}
+						future::result(Ok(()))
+					}));
+					future::result(Ok(()))
+				}).then(|_| {
+					future::result(Ok(()))
+				}));
+
+				tokio::spawn(conn_rx.for_each(move |(sock, addr)| {
 					sock.set_nodelay(true).unwrap();
+					println!("Got new connection from {}", addr);
+					let auth_keys = auth_keys.clone();
 
-					let (tx, rx) = tokio_codec::Framed::new(sock, PoolMsgFramer::new()).split();
+					let framer = PoolMsgFramer::new();
+					let transport = framer.transport();
+					let (sock, write_progress) = ProgressIo::new(sock);
+					let (tx, rx) = tokio_codec::Framed::new(sock, framer).split();
 					let (mut send_sink, send_stream) = mpsc::channel(5);
-					tokio::spawn(tx.send_all(send_stream.map_err(|_| -> io::Error {
+					tokio::spawn(TimeoutSink::new(tx, timeouts.write, write_progress).send_all(send_stream.map_err(|_| -> io::Error {
 						panic!("mpsc streams cant generate errors!");
 					})).then(|_| {
 						future::result(Ok(()))
@@ -436,6 +533,9 @@ fn main() {
 					let secp_ctx = Secp256k1::new();
 					macro_rules! sign_message {
 						($msg: expr, $msg_type: expr) => {
+							sign_message!($msg, $msg_type, auth_keys.signing_key())
+						};
+						($msg: expr, $msg_type: expr, $key: expr) => {
 							{
 								let mut msg_signed = bytes::BytesMut::with_capacity(1000);
 								msg_signed.put_u8($msg_type);
@@ -448,7 +548,7 @@ fn main() {
 									secp256k1::Message::from_slice(&h).unwrap()
 								};
 
-								secp_ctx.sign(&hash, &auth_key.unwrap()).unwrap()
+								secp_ctx.sign(&hash, $key).unwrap()
 							}
 						}
 					}
@@ -464,12 +564,14 @@ fn main() {
 					let mut client_ids = HashMap::new();
 
 					let mut client_version = None;
+					// The key we gave in ProtocolVersion, while we wait for the client's EncryptionStart
+					let mut pending_encryption = None;
 					let mut last_weak_block = None;
 
 					let block_info_clone = block_info.clone();
 					let rpc_client_clone = rpc_client.clone();
 
-					tokio::spawn(TimeoutStream::new(rx, Duration::from_secs(60*10)).for_each(move |msg| {
+					tokio::spawn(TimeoutStream::new(rx, timeouts.read).for_each(move |msg| {
 						macro_rules! send_response {
 							($msg: expr) => {
 								match send_sink.start_send($msg) {
@@ -603,6 +705,31 @@ fn main() {
 							}
 						}
 
+						// Sends everything which follows ProtocolVersion, once any encryption has started
+						macro_rules! finish_handshake {
+							() => {
+								if let Some((rotation, current_key)) = auth_keys.pending_rotation(&secp_ctx) {
+									send_response!(PoolMessage::VendorMessage {
+										signature: Some(sign_message!(rotation, AUTH_KEY_ROTATION_SIG_TYPE, current_key)),
+										vendor: b"mining-proxy".to_vec(),
+										message: rotation.to_vendor_message(),
+									});
+								}
+
+								let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+								let timestamp = time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000;
+								let payout_info = PoolPayoutInfo {
+									timestamp,
+									remaining_payout: payout_addr_clone.clone(),
+									appended_outputs: vec![],
+								};
+								send_response!(PoolMessage::PayoutInfo {
+									signature: sign_message!(payout_info, 13),
+									payout_info,
+								});
+							}
+						}
+
 						match msg {
 							PoolMessage::ProtocolSupport { max_version, min_version, flags } => {
 								if client_version.is_some() {
@@ -612,27 +739,38 @@ fn main() {
 								if min_version > 1 || max_version < 1 {
 									return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 								}
-								if flags != 0 {
+								if (flags & !ENCRYPTION_FLAG) != 0 {
 									println!("Client requested unknown flags {}", flags);
 								}
 								client_version = Some(1);
+								let encrypt = (flags & ENCRYPTION_FLAG) != 0;
+								let signing_key = *auth_keys.signing_key();
 								send_response!(PoolMessage::ProtocolVersion {
 									selected_version: 1,
-									flags: 0,
-									auth_key: PublicKey::from_secret_key(&secp_ctx, &auth_key.unwrap()).unwrap(),
+									flags: if encrypt { ENCRYPTION_FLAG } else { 0 },
+									auth_key: PublicKey::from_secret_key(&secp_ctx, &signing_key).unwrap(),
 								});
-
-								let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-								let timestamp = time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000;
-								let payout_info = PoolPayoutInfo {
-									timestamp,
-									remaining_payout: payout_addr_clone.clone(),
-									appended_outputs: vec![],
+								if encrypt {
+									// Everything else waits for the client's EncryptionStart so that it goes out encrypted
+									pending_encryption = Some(signing_key);
+								} else {
+									finish_handshake!();
+								}
+							},
+							PoolMessage::EncryptionStart { ephemeral_key, tag } => {
+								let signing_key = match pending_encryption.take() {
+									Some(key) => key,
+									None => {
+										println!("Client sent EncryptionStart without negotiating encryption");
+										return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
+									}
 								};
-								send_response!(PoolMessage::PayoutInfo {
-									signature: sign_message!(payout_info, 13),
-									payout_info,
-								});
+								let (ephemeral_key, tag) = match transport.start_server(&secp_ctx, &signing_key, &ephemeral_key, &tag) {
+									Some(reply) => reply,
+									None => return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))),
+								};
+								send_response!(PoolMessage::EncryptionStart { ephemeral_key, tag });
+								finish_handshake!();
 							},
 							PoolMessage::ProtocolVersion { .. } => {
 								println!("Got ProtocolVersion?");
@@ -897,6 +1035,7 @@ fn main() {
 								println!("Got NewPoolServer?");
 								return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)));
 							},
+							PoolMessage::VendorMessage { ref vendor, ref message, .. } if &vendor[..] == b"mining-proxy" && &message[..] == b"Heartbeat" => {},
 							PoolMessage::VendorMessage { .. } => {
 								println!("Got vendor message");
 								return future::result(Ok(()));
@@ -0,0 +1,456 @@
+use msg_framing::{PoolMessage,PoolMsgFramer,ENCODE_SCRATCH_LEN};
+
+use bytes;
+
+use futures_cpupool::{CpuFuture,CpuPool};
+
+use tokio_io::codec::{Decoder,Encoder};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// How many bytes of (encoded) shares/weak blocks we hold in memory while the pool is unreachable.
+/// Weak blocks carry their full transaction list when queued, so these can be large.
+const MAX_QUEUED_BYTES_IN_MEMORY: usize = 32 * 1024 * 1024;
+/// How many more bytes we append to the spill file (if we have one) once memory is full
+const MAX_SPILLED_BYTES: usize = 1024 * 1024 * 1024;
+/// How many bytes of spilled shares/weak blocks we read back into memory at once (plus however
+/// much of one message goes past it)
+const MAX_SPILL_READ_BYTES: usize = 8 * 1024 * 1024;
+
+/// A run of whole encoded messages read back from the spill file, for ShareQueue::take_spilled
+pub struct SpillChunk {
+	generation: u64,
+	/// How many messages were in the file when we started reading
+	spilled: usize,
+	contents: Vec<u8>,
+	end_of_file: bool,
+}
+
+/// Reads whole messages from offset on until we have at least MAX_SPILL_READ_BYTES or hit the end
+/// of the file (ignoring a trailing partial message, ie a failed write). Returns whether we hit the
+/// end.
+fn read_spill_chunk(path: &str, offset: u64) -> Result<(Vec<u8>, bool), io::Error> {
+	let mut file = io::BufReader::new(fs::File::open(path)?);
+	file.seek(SeekFrom::Start(offset))?;
+	let mut contents = Vec::new();
+	while contents.len() < MAX_SPILL_READ_BYTES {
+		let start = contents.len();
+		let mut header = [0; 4];
+		match file.read_exact(&mut header) {
+			Ok(_) => {},
+			Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok((contents, true)),
+			Err(e) => return Err(e),
+		}
+		let len = ((((header[3] as usize) << 8) | (header[2] as usize)) << 8) | header[1] as usize;
+		contents.extend_from_slice(&header);
+		contents.resize(start + 4 + len, 0);
+		match file.read_exact(&mut contents[start + 4..]) {
+			Ok(_) => {},
+			Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+				contents.truncate(start);
+				return Ok((contents, true));
+			},
+			Err(e) => return Err(e),
+		}
+	}
+	Ok((contents, false))
+}
+
+/// Shares and weak blocks which we couldn't send because the pool connection was down, to be sent
+/// once we've reconnected and re-authed our users. Only work on the most recent prevblock is kept
+/// as anything else would just be rejected as stale. Once MAX_QUEUED_BYTES_IN_MEMORY bytes are
+/// queued the rest go to a spill file (if configured) so that a long outage doesn't eat our memory,
+/// which is read back MAX_SPILL_READ_BYTES at a time.
+pub struct ShareQueue {
+	prevblock: [u8; 32],
+	/// Encoded messages, so that we know how many bytes we're holding
+	queue: VecDeque<Vec<u8>>,
+	queued_bytes: usize,
+	spill_path: Option<String>,
+	/// All spill file I/O happens here, off the reactor. With a single thread appends, reads and
+	/// removals happen in the order we queue them.
+	spill_pool: CpuPool,
+	/// Bumped each time we remove the spill file, so that a read which was in flight is ignored
+	spill_generation: u64,
+	spill_read_offset: u64,
+	spill_read_pending: bool,
+	/// Messages (and their bytes) in the spill file which we have yet to read back
+	spilled: usize,
+	spilled_bytes: usize,
+	dropped_stale: u64,
+	dropped_full: u64,
+}
+
+impl ShareQueue {
+	/// If spill_dir is set, overflow goes to a file in it named after the pool's host_port.
+	pub fn new(spill_dir: &Option<String>, host_port: &str) -> Self {
+		let spill_path = spill_dir.as_ref().map(|dir| {
+			let name: String = host_port.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect();
+			dir.clone() + "/shares-" + &name
+		});
+		let mut res = Self {
+			prevblock: [0; 32],
+			queue: VecDeque::new(),
+			queued_bytes: 0,
+			spill_path,
+			spill_pool: CpuPool::new(1),
+			spill_generation: 0,
+			spill_read_offset: 0,
+			spill_read_pending: false,
+			spilled: 0,
+			spilled_bytes: 0,
+			dropped_stale: 0,
+			dropped_full: 0,
+		};
+		// Anything left over from a previous run is long stale
+		res.remove_spill_file();
+		res
+	}
+
+	pub fn len(&self) -> usize {
+		self.queue.len() + self.spilled
+	}
+
+	fn remove_spill_file(&mut self) {
+		if let Some(ref path) = self.spill_path {
+			let path = path.clone();
+			self.spill_pool.spawn_fn(move || -> Result<(), ()> {
+				let _ = fs::remove_file(path);
+				Ok(())
+			}).forget();
+		}
+		self.spill_generation += 1;
+		self.spill_read_offset = 0;
+		self.spill_read_pending = false;
+		self.spilled = 0;
+		self.spilled_bytes = 0;
+	}
+
+	fn clear(&mut self) {
+		self.queue.clear();
+		self.queued_bytes = 0;
+		if self.spilled != 0 || self.spill_read_offset != 0 {
+			self.remove_spill_file();
+		}
+	}
+
+	fn spill(&mut self, encoded: &[u8]) -> bool {
+		let path = match self.spill_path {
+			Some(ref path) if self.spilled_bytes + encoded.len() <= MAX_SPILLED_BYTES => path.clone(),
+			_ => return false,
+		};
+		self.spilled += 1;
+		self.spilled_bytes += encoded.len();
+		let encoded = encoded.to_vec();
+		self.spill_pool.spawn_fn(move || -> Result<(), ()> {
+			if let Err(e) = fs::OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| file.write_all(&encoded)) {
+				println!("Failed to spill queued share to {}: {}", path, e);
+			}
+			Ok(())
+		}).forget();
+		true
+	}
+
+	/// Queues a Share or WeakBlock on prevblock, dropping anything queued on an older prevblock.
+	pub fn push(&mut self, prevblock: &[u8; 32], msg: PoolMessage) {
+		if self.prevblock != *prevblock {
+			self.dropped_stale += self.len() as u64;
+			self.clear();
+			self.prevblock = *prevblock;
+		}
+		// Like Framed, leave the encoder the slack it expects
+		let mut encoded = bytes::BytesMut::with_capacity(ENCODE_SCRATCH_LEN);
+		if PoolMsgFramer::new().encode(msg, &mut encoded).is_err() {
+			self.dropped_full += 1;
+			return;
+		}
+		// Once we've started spilling keep going so that things are sent in order
+		if self.spilled == 0 && self.queued_bytes + encoded.len() <= MAX_QUEUED_BYTES_IN_MEMORY {
+			self.queued_bytes += encoded.len();
+			self.queue.push_back(encoded.to_vec());
+		} else if !self.spill(&encoded[..]) {
+			self.dropped_full += 1;
+		}
+		println!("Queued share/weak block for when the pool comes back ({} queued)", self.len());
+	}
+
+	/// Puts back a message we got from drain or take_spilled but failed to send
+	pub fn requeue(&mut self, msg: PoolMessage) {
+		let prevblock = self.prevblock;
+		self.push(&prevblock, msg);
+	}
+
+	fn decode(&mut self, contents: Vec<u8>, expected: usize) -> Vec<PoolMessage> {
+		let mut res = Vec::with_capacity(expected);
+		let mut framer = PoolMsgFramer::new();
+		let mut buf = bytes::BytesMut::from(contents);
+		while let Ok(Some(msg)) = framer.decode(&mut buf) {
+			res.push(msg);
+		}
+		self.dropped_full += expected.saturating_sub(res.len()) as u64;
+		res
+	}
+
+	fn print_dropped(&mut self, sending: usize) {
+		if sending != 0 || self.dropped_stale != 0 || self.dropped_full != 0 {
+			println!("Sending {} queued shares/weak blocks to pool ({} more to read back from disk), dropped {} as stale and {} for lack of space while it was away", sending, self.spilled, self.dropped_stale, self.dropped_full);
+		}
+		self.dropped_stale = 0;
+		self.dropped_full = 0;
+	}
+
+	/// Takes everything queued in memory (in the order it was queued) and prints how much we had
+	/// to drop since the last time. If the latest work we've seen is no longer on the prevblock of
+	/// what's queued, it's all dropped as stale instead. Anything spilled to disk comes after, via
+	/// read_spilled and take_spilled.
+	pub fn drain(&mut self, tip: &[u8; 32]) -> Vec<PoolMessage> {
+		if self.prevblock != *tip {
+			self.dropped_stale += self.len() as u64;
+			self.clear();
+			self.prevblock = *tip;
+		}
+		let expected = self.queue.len();
+		let mut contents = Vec::with_capacity(self.queued_bytes);
+		for encoded in self.queue.drain(..) {
+			contents.extend_from_slice(&encoded[..]);
+		}
+		self.queued_bytes = 0;
+		let res = self.decode(contents, expected);
+		self.print_dropped(res.len());
+		res
+	}
+
+	/// Starts reading the next chunk of the spill file back, if there's anything in it and we
+	/// aren't already. Pass the result to take_spilled (or put_back_spilled if it can't be sent
+	/// yet).
+	pub fn read_spilled(&mut self) -> Option<CpuFuture<SpillChunk, ()>> {
+		if self.spilled == 0 || self.spill_read_pending { return None; }
+		let path = self.spill_path.clone()?;
+		self.spill_read_pending = true;
+		let generation = self.spill_generation;
+		let spilled = self.spilled;
+		let offset = self.spill_read_offset;
+		Some(self.spill_pool.spawn_fn(move || -> Result<SpillChunk, ()> {
+			match read_spill_chunk(&path, offset) {
+				Ok((contents, end_of_file)) => Ok(SpillChunk { generation, spilled, contents, end_of_file }),
+				Err(e) => {
+					println!("Failed to read back spilled shares from {}: {}", path, e);
+					Ok(SpillChunk { generation, spilled, contents: Vec::new(), end_of_file: true })
+				},
+			}
+		}))
+	}
+
+	/// Takes the messages in a chunk from read_spilled, unless the queue was cleared since.
+	pub fn take_spilled(&mut self, chunk: SpillChunk) -> Vec<PoolMessage> {
+		if chunk.generation != self.spill_generation { return Vec::new(); }
+		self.spill_read_pending = false;
+		self.spill_read_offset += chunk.contents.len() as u64;
+		self.spilled_bytes = self.spilled_bytes.saturating_sub(chunk.contents.len());
+		// Anything which was spilled before we hit the end of the file but which we didn't get failed
+		// to write (or read back). Anything spilled since comes after it.
+		let expected = if chunk.end_of_file { chunk.spilled } else { 0 };
+		let res = self.decode(chunk.contents, expected);
+		self.spilled = self.spilled.saturating_sub(cmp::max(res.len(), expected));
+		if self.spilled == 0 {
+			self.remove_spill_file();
+		}
+		self.print_dropped(res.len());
+		res
+	}
+
+	/// Gives back a chunk from read_spilled which we couldn't send, to be read again later.
+	pub fn put_back_spilled(&mut self, chunk: SpillChunk) {
+		if chunk.generation == self.spill_generation {
+			self.spill_read_pending = false;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use share_queue::*;
+	use msg_framing::{WeakBlock, WeakBlockAction};
+
+	use futures::Future;
+
+	use std::env;
+
+	fn weak_block(prevblock: [u8; 32], tag: u8, tx_len: usize) -> PoolMessage {
+		PoolMessage::WeakBlock {
+			sketch: WeakBlock {
+				header_version: 0x20000000,
+				header_prevblock: prevblock,
+				header_time: 0,
+				header_nbits: 0,
+				header_nonce: 0,
+				merkle_rhss: Vec::new(),
+				user_tag_1: vec![tag],
+				user_tag_2: Vec::new(),
+				extra_block_data: Vec::new(),
+				txn: vec![WeakBlockAction::NewTx { tx: vec![tag; tx_len] }],
+			},
+		}
+	}
+
+	/// Waits for the spill file I/O queued so far
+	fn flush(queue: &ShareQueue) {
+		queue.spill_pool.spawn_fn(|| -> Result<(), ()> { Ok(()) }).wait().unwrap();
+	}
+
+	/// Drains what's in memory and then reads back everything spilled, returning how many messages
+	/// each read of the spill file gave us
+	fn drain_all(queue: &mut ShareQueue, tip: &[u8; 32]) -> (Vec<PoolMessage>, Vec<usize>) {
+		let mut msgs = queue.drain(tip);
+		let mut chunks = Vec::new();
+		while let Some(read) = queue.read_spilled() {
+			// Only one read at a time
+			assert!(queue.read_spilled().is_none());
+			let chunk = queue.take_spilled(read.wait().unwrap());
+			chunks.push(chunk.len());
+			msgs.extend(chunk);
+		}
+		(msgs, chunks)
+	}
+
+	fn tags(msgs: &Vec<PoolMessage>) -> Vec<u8> {
+		msgs.iter().map(|msg| match msg {
+			&PoolMessage::WeakBlock { ref sketch } => sketch.user_tag_1[0],
+			_ => panic!(),
+		}).collect()
+	}
+
+	#[test]
+	fn test_queue_drops_stale() {
+		let mut queue = ShareQueue::new(&None, "pool.example.com:8080");
+		queue.push(&[1; 32], weak_block([1; 32], 0, 100));
+		queue.push(&[1; 32], weak_block([1; 32], 1, 100));
+		assert_eq!(queue.len(), 2);
+
+		// A new prevblock drops what we had
+		queue.push(&[2; 32], weak_block([2; 32], 2, 100));
+		queue.push(&[2; 32], weak_block([2; 32], 3, 100));
+		assert_eq!(queue.dropped_stale, 2);
+		assert_eq!(tags(&queue.drain(&[2; 32])), vec![2, 3]);
+		assert_eq!(queue.len(), 0);
+		assert_eq!(queue.queued_bytes, 0);
+
+		// As does finding work on a new prevblock before we get to send
+		queue.push(&[2; 32], weak_block([2; 32], 4, 100));
+		assert!(queue.drain(&[3; 32]).is_empty());
+		assert_eq!(queue.len(), 0);
+	}
+
+	#[test]
+	fn test_queue_bounded_by_bytes() {
+		// Without a spill dir, big weak blocks stop fitting long before MAX_QUEUED_BYTES_IN_MEMORY
+		// entries would
+		let mut queue = ShareQueue::new(&None, "pool.example.com:8080");
+		let count = MAX_QUEUED_BYTES_IN_MEMORY / (1024 * 1024);
+		for i in 0..count {
+			queue.push(&[1; 32], weak_block([1; 32], i as u8, 1024 * 1024));
+		}
+		assert_eq!(queue.len(), count - 1);
+		assert_eq!(queue.dropped_full, 1);
+		assert!(queue.queued_bytes <= MAX_QUEUED_BYTES_IN_MEMORY);
+
+		// Small things still fit
+		queue.push(&[1; 32], weak_block([1; 32], 0xff, 100));
+		assert_eq!(queue.len(), count);
+		let msgs = queue.drain(&[1; 32]);
+		assert_eq!(msgs.len(), count);
+		assert_eq!(tags(&msgs)[count - 1], 0xff);
+	}
+
+	#[test]
+	fn test_queue_spill_and_requeue() {
+		let dir = env::temp_dir().join(format!("share-queue-test-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let dir_str = dir.to_str().unwrap().to_string();
+		let mut queue = ShareQueue::new(&Some(dir_str), "pool.example.com:8080");
+
+		let count = MAX_QUEUED_BYTES_IN_MEMORY / (1024 * 1024);
+		for i in 0..count + 2 {
+			queue.push(&[1; 32], weak_block([1; 32], i as u8, 1024 * 1024));
+		}
+		assert_eq!(queue.len(), count + 2);
+		assert_eq!(queue.spilled, 3);
+		assert_eq!(queue.dropped_full, 0);
+		// Once we've started spilling small things go to the spill file too, keeping things in order
+		queue.push(&[1; 32], weak_block([1; 32], 0xff, 100));
+		assert_eq!(queue.spilled, 4);
+
+		let spill_path = queue.spill_path.clone().unwrap();
+		flush(&queue);
+		assert!(fs::metadata(&spill_path).is_ok());
+		// drain only takes what's in memory, the rest is read back separately
+		assert_eq!(queue.drain(&[1; 32]).len(), count - 1);
+		assert_eq!(queue.len(), 4);
+		let (mut msgs, chunks) = drain_all(&mut queue, &[1; 32]);
+		assert_eq!(chunks, vec![4]);
+		flush(&queue);
+		assert!(fs::metadata(&spill_path).is_err());
+		let mut expected: Vec<u8> = (count as u8 - 1..count as u8 + 2).collect();
+		expected.push(0xff);
+		assert_eq!(tags(&msgs), expected);
+		assert_eq!(queue.len(), 0);
+
+		// Anything we fail to send goes back on the queue for next time
+		let unsent = msgs.split_off(2);
+		for msg in unsent {
+			queue.requeue(msg);
+		}
+		assert_eq!(tags(&drain_all(&mut queue, &[1; 32]).0), vec![count as u8 + 1, 0xff]);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_spill_read_back_in_chunks() {
+		let dir = env::temp_dir().join(format!("share-queue-chunks-test-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let dir_str = dir.to_str().unwrap().to_string();
+		let mut queue = ShareQueue::new(&Some(dir_str), "pool.example.com:8080");
+
+		// Fill memory, then spill a bit more than two reads' worth
+		let in_memory = MAX_QUEUED_BYTES_IN_MEMORY / (1024 * 1024) - 1;
+		let per_read = MAX_SPILL_READ_BYTES / (1024 * 1024);
+		for i in 0..in_memory + per_read * 2 + 1 {
+			queue.push(&[1; 32], weak_block([1; 32], i as u8, 1024 * 1024));
+		}
+		assert_eq!(queue.spilled, per_read * 2 + 1);
+		let (msgs, chunks) = drain_all(&mut queue, &[1; 32]);
+		assert_eq!(chunks, vec![per_read, per_read, 1]);
+		assert_eq!(tags(&msgs), (0..(in_memory + per_read * 2 + 1) as u8).collect::<Vec<u8>>());
+
+		// Things spilled while a read is in flight come after it
+		for i in 0..in_memory + 2 {
+			queue.push(&[1; 32], weak_block([1; 32], i as u8, 1024 * 1024));
+		}
+		assert_eq!(queue.drain(&[1; 32]).len(), in_memory);
+		let read = queue.read_spilled().unwrap();
+		queue.push(&[1; 32], weak_block([1; 32], 0xff, 100));
+		assert_eq!(tags(&queue.take_spilled(read.wait().unwrap())), vec![in_memory as u8, in_memory as u8 + 1]);
+		assert_eq!(queue.len(), 1);
+		// A chunk we couldn't send is read again
+		let read = queue.read_spilled().unwrap();
+		queue.put_back_spilled(read.wait().unwrap());
+		assert_eq!(tags(&drain_all(&mut queue, &[1; 32]).0), vec![0xff]);
+
+		// A read which was in flight when everything went stale is ignored
+		for i in 0..in_memory + 2 {
+			queue.push(&[1; 32], weak_block([1; 32], i as u8, 1024 * 1024));
+		}
+		queue.drain(&[1; 32]);
+		let read = queue.read_spilled().unwrap();
+		queue.push(&[2; 32], weak_block([2; 32], 0xfe, 100));
+		assert!(queue.take_spilled(read.wait().unwrap()).is_empty());
+		assert_eq!(tags(&drain_all(&mut queue, &[2; 32]).0), vec![0xfe]);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
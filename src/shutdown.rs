@@ -0,0 +1,90 @@
+use futures::future;
+use futures::sync::oneshot;
+use futures::{Future,Stream};
+
+use tokio::timer;
+
+use tokio_signal;
+use tokio_signal::unix::{Signal, SIGTERM};
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Tracks a graceful shutdown. Anything holding queued messages which should make it upstream
+/// before we exit (eg shares for the pool or full-block nonces for a job provider) registers a
+/// flush and watches wait(), firing its flush sender once everything it had queued is on the wire.
+pub struct Shutdown {
+	started: AtomicBool,
+	waiters: Mutex<Vec<oneshot::Sender<()>>>,
+	flushes: Mutex<Vec<oneshot::Receiver<()>>>,
+}
+
+impl Shutdown {
+	pub fn new() -> Arc<Self> {
+		Arc::new(Self {
+			started: AtomicBool::new(false),
+			waiters: Mutex::new(Vec::new()),
+			flushes: Mutex::new(Vec::new()),
+		})
+	}
+
+	pub fn is_started(&self) -> bool {
+		self.started.load(Ordering::Acquire)
+	}
+
+	/// Completes once start() has been called (immediately if it already has been).
+	pub fn wait(&self) -> impl Future<Item=(), Error=()> {
+		let (tx, rx) = oneshot::channel();
+		{
+			let mut waiters = self.waiters.lock().unwrap();
+			if self.is_started() {
+				let _ = tx.send(());
+			} else {
+				waiters.push(tx);
+			}
+		}
+		rx.then(|_| { future::result(Ok(())) })
+	}
+
+	/// Registers something that has to be flushed before we can exit. The returned sender should
+	/// be fired (or dropped) once it has been.
+	pub fn register_flush(&self) -> oneshot::Sender<()> {
+		let (tx, rx) = oneshot::channel();
+		self.flushes.lock().unwrap().push(rx);
+		tx
+	}
+
+	pub fn start(&self) {
+		let mut waiters = self.waiters.lock().unwrap();
+		self.started.store(true, Ordering::Release);
+		for waiter in waiters.drain(..) {
+			let _ = waiter.send(());
+		}
+	}
+
+	/// Completes once every registered flush has completed, or after timeout, whichever is first.
+	pub fn flushed(&self, timeout: Duration) -> impl Future<Item=(), Error=()> {
+		let flushes: Vec<_> = self.flushes.lock().unwrap().drain(..).map(|flush| {
+			flush.then(|_| { future::result::<(), ()>(Ok(())) })
+		}).collect();
+		let flush_count = flushes.len();
+		future::join_all(flushes).map(move |_| {
+			println!("Flushed all {} upstream connections", flush_count);
+		}).select(timer::Delay::new(Instant::now() + timeout).then(|_| {
+			println!("Timed out waiting for upstream connections to flush, exiting anyway");
+			future::result(Ok(()))
+		})).then(|_| {
+			future::result(Ok(()))
+		})
+	}
+}
+
+/// Completes the first time we receive a SIGINT or SIGTERM. Must be called from within a runtime.
+pub fn signal_received() -> impl Future<Item=(), Error=()> {
+	let sigint = tokio_signal::ctrl_c().flatten_stream();
+	let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+	sigint.select(sigterm).into_future().then(|_| {
+		future::result(Ok(()))
+	})
+}
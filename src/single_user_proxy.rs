@@ -1,9 +1,18 @@
+extern crate base64;
 extern crate bitcoin;
 extern crate bytes;
 extern crate futures;
+extern crate futures_cpupool;
+extern crate hyper;
+#[cfg(feature = "stratum-tls")]
+extern crate native_tls;
+extern crate rand;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_codec;
+extern crate tokio_signal;
+#[cfg(feature = "stratum-tls")]
+extern crate tokio_tls;
 extern crate crypto;
 extern crate secp256k1;
 
@@ -11,10 +20,16 @@ extern crate secp256k1;
 extern crate serde_json;
 
 mod msg_framing;
+mod transport_crypto;
 
 mod stratum_server;
 use stratum_server::*;
 
+#[cfg(feature = "stratum-tls")]
+mod stratum_tls;
+#[cfg(feature = "stratum-tls")]
+use stratum_tls::StratumTls;
+
 mod mining_server;
 use mining_server::*;
 
@@ -25,95 +40,336 @@ use work_getter::*;
 
 mod work_info;
 
+mod block_assembly;
+use block_assembly::BlockSubmitter;
+
+mod rpc_client;
+use rpc_client::RPCClient;
+
+mod replay_provider;
+
 mod connection_maintainer;
+use connection_maintainer::UpstreamConfig;
+
+mod socks5;
+use socks5::Socks5Proxy;
+
+mod auth_keys;
+use auth_keys::{KnownHosts, ServerAuthKeys};
 
 mod pool_client;
+use pool_client::PoolFailoverConfig;
+
+mod share_queue;
 mod work_client;
+use work_client::JobProviderInfo;
 
 mod timeout_stream;
+use timeout_stream::IdleTimeouts;
+
+mod shutdown;
+use shutdown::*;
+
+mod proxy_protocol;
+use proxy_protocol::TrustedProxies;
+
+mod routing;
+use routing::{RouteGroup, Router};
 
 use bitcoin::util::address::Address;
 use bitcoin::util::privkey;
 
+use secp256k1::key::PublicKey;
+
 use futures::future;
-use futures::sync::mpsc;
+use futures::sync::{mpsc,oneshot};
 use futures::{Future,Stream,Sink};
 
-use tokio::net;
+use tokio::{net, timer};
 
-use std::{env};
-use std::net::ToSocketAddrs;
+use std::{cmp, env, fs};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The pools, pool user and payout address for one routing group (the first of which holds
+/// everything given before the first --group)
+struct GroupConfig {
+	route: RouteGroup,
+	pool_server_hosts: Vec<(String, Option<PublicKey>, Option<Socks5Proxy>)>,
+	user_id: Option<Vec<u8>>,
+	user_auth: Option<Vec<u8>>,
+	payout_addr: Option<Address>,
+	pool_weights: Option<Vec<u64>>,
+}
+
+impl GroupConfig {
+	fn new(name: String) -> Self {
+		Self {
+			route: RouteGroup::new(name),
+			pool_server_hosts: Vec::new(),
+			user_id: None,
+			user_auth: None,
+			payout_addr: None,
+			pool_weights: None,
+		}
+	}
+}
 
 fn main() {
-	println!("USAGE: mining-proxy (--job_provider=host:port)* (--pool_server=host:port)* --stratum_listen_bind=IP:port --mining_listen_bind=IP:port --mining_auth_key=base58privkey --payout_address=addr");
+	println!("USAGE: mining-proxy (--job_provider=host:port[@pubkey]|file://path)* (--pool_server=host:port[@pubkey])* [--socks5_proxy=[user:pass@]host:port|none] [--known_hosts=path] [--share_queue_dir=path] [--pool_weights=N,N,...] [--pool_failback_min_secs=secs] [--pool_failback_stable_secs=secs] [--pool_max_switches_per_hour=N] [--reconnect_min_secs=secs] [--reconnect_max_secs=secs] [--upstream_heartbeat_secs=secs] [--require_encryption] (--submitblock_rpc=user:pass@host:port)* (--stratum_listen_bind=IP:port)* [(--stratum_tls_listen_bind=IP:port)* --stratum_tls_cert=path --stratum_tls_key=path] (--mining_listen_bind=IP:port)* --mining_auth_key=base58privkey [--mining_next_auth_key=base58privkey --mining_key_rotation_time=unix_secs] --payout_address=addr [--shutdown_reconnect_to=host:port] [--shutdown_timeout=secs] (--proxy_protocol_from=IP[/len])* [--stratum_read_timeout=secs] [--stratum_write_timeout=secs] [--mining_read_timeout=secs] [--mining_write_timeout=secs] (--group=name (--match_worker_prefix=prefix|--match_listen_port=port|--match_source=IP[/len])* (--pool_server=host:port[@pubkey])* [--pool_user_id=id] [--pool_user_auth=auth] [--pool_weights=N,N,...] --payout_address=addr)*");
 	println!("A stratum/work protocol proxy for a number of ASICs mining on a single user");
 	println!("account on a pool or for a solo miner.");
 	println!("--job_provider - bitcoind(s) running as mining server(s) to get work from");
+	println!("               or file://path to replay templates from a JSON scenario file (for testing)");
 	println!("--pool_server - pool server(s) to get payout address from/submit shares to");
 	println!("--pool_user_id - user id (eg username) on pool");
 	println!("--pool_user_auth - user auth (eg password) on pool");
-	println!("--stratum_listen_bind - the address to bind to to announce stratum jobs on");
-	println!("--mining_listen_bind - the address to bind to to announce jobs on natively");
+	println!("@pubkey - pin the hex-encoded auth key the job provider/pool must authenticate with");
+	println!("--socks5_proxy - connect to the job_providers/pool_servers given after this through a");
+	println!("                 SOCKS5 proxy (eg Tor, which also lets us reach .onion hosts), until");
+	println!("                 the next --socks5_proxy (none to connect directly again)");
+	println!("--known_hosts - file in which to remember the auth keys of job providers/pools which");
+	println!("                weren't pinned with @pubkey the first time they connect, refusing any");
+	println!("                other key after that (otherwise unpinned keys are trusted each run)");
+	println!("--share_queue_dir - directory in which to spill shares we couldn't send while the pool");
+	println!("                    was unreachable once too many are queued to keep in memory");
+	println!("--pool_weights - split our hashrate across all the pool_servers (in order) by these");
+	println!("               weights (eg 70,30) instead of using them in priority order. We mine");
+	println!("               on one pool at a time in slices of at least 30 seconds");
+	println!("--pool_failback_min_secs - time to stay on a backup pool before going back to a");
+	println!("                           higher-priority one which failed (default 600)");
+	println!("--pool_failback_stable_secs - time a higher-priority pool which failed must stay");
+	println!("                              connected before we go back to it, and within which it");
+	println!("                              must then accept a share (default 300)");
+	println!("--pool_max_switches_per_hour - stop going back to higher-priority pools after this");
+	println!("                               many pool switches in an hour (default 6)");
+	println!("--reconnect_min_secs - time to wait before retrying a job provider/pool we failed to");
+	println!("                       connect to, doubling on each failure (default 1)");
+	println!("--reconnect_max_secs - most time to wait between connection attempts (default 60)");
+	println!("--upstream_heartbeat_secs - how often to send job providers/pools a heartbeat so that");
+	println!("                            quiet connections stay up, or 0 to not (default 60)");
+	println!("--require_encryption - disconnect from job providers/pools which won't encrypt our");
+	println!("                       connection, instead of carrying on in the clear");
+	println!("--submitblock_rpc - bitcoind RPC endpoint(s) to also submitblock any full blocks we find");
+	println!("                    to, in addition to the job provider they were found on. The first is");
+	println!("                    also asked to validate job providers' templates, including their fees.");
+	println!("                    Full blocks are never sent to other job providers, so without this a");
+	println!("                    block found just as its job provider disconnects for good is lost");
+	println!("--stratum_listen_bind - the address(es) to bind to to announce stratum jobs on");
+	println!("--stratum_tls_listen_bind - the address(es) to bind to to announce the same stratum jobs");
+	println!("                            on over TLS (ie stratum+ssl), if built with the stratum-tls");
+	println!("                            feature");
+	println!("--stratum_tls_cert - PEM certificate chain (leaf first) for stratum_tls_listen_bind");
+	println!("--stratum_tls_key - PEM (PKCS#8) private key for stratum_tls_cert. Both are re-read on");
+	println!("                    SIGHUP, so renewed certificates don't need a restart");
+	println!("--mining_listen_bind - the address(es) to bind to to announce jobs on natively");
+	println!("                       (other mining-proxies may use this as their --job_provider)");
 	println!("--mining_auth_key - the auth key to use to authenticate to native clients");
+	println!("--mining_next_auth_key - the auth key to switch to at --mining_key_rotation_time. Until");
+	println!("                         then native clients are told to accept either key");
 	println!("--payout_address - the Bitcoin address on which to receive payment");
+	println!("--shutdown_reconnect_to - on SIGINT/SIGTERM, point clients here (eg a peer proxy)");
+	println!("                          instead of asking them to reconnect to us");
+	println!("--shutdown_timeout - seconds to drain shares upstream before exiting (default 10)");
+	println!("--proxy_protocol_from - connections from this IP or IP/prefix_len subnet (eg your TCP");
+	println!("                         load balancer) must start with a PROXY protocol v1/v2 header");
+	println!("                         giving the real client address (may be given multiple times)");
+	println!("--stratum_read_timeout - disconnect stratum clients which send nothing for this many seconds (default 600)");
+	println!("--stratum_write_timeout - disconnect stratum clients which stop reading for this many seconds (default 120)");
+	println!("--mining_read_timeout - disconnect native clients which send nothing for this many seconds (default 600)");
+	println!("--mining_write_timeout - disconnect native clients which stop reading for this many seconds (default 120)");
+	println!("--group - start a routing group: clients it matches get their own --pool_server(s),");
+	println!("          --pool_user_id, --pool_user_auth, --pool_weights and --payout_address,");
+	println!("          given after it. Clients no group matches use the ones given before the");
+	println!("          first --group. Each group connects to the job providers separately");
+	println!("--match_worker_prefix - route stratum clients whose worker name starts with this");
+	println!("                        (native clients never match groups with this set)");
+	println!("--match_listen_port - route clients which connected to this listen_bind port");
+	println!("--match_source - route clients from this IP or IP/prefix_len subnet");
+	println!("A group matches if any of each kind of --match_* given for it matches, the first");
+	println!("group which matches is used.");
 	println!("We always try to keep exactly one connection open per argument, no matter how");
 	println!("many hosts a DNS name may resolve to. We try each hostname until one works.");
 	println!("Job providers are not prioritized (the latest job is always used), pools are");
-	println!("prioritized in the order they appear on the command line.");
+	println!("prioritized in the order they appear on the command line (subject to the");
+	println!("--pool_failback_* limits once a pool has failed) unless --pool_weights is given.");
 	println!("--payout_address is used whenever no pools are available but does not affect");
 	println!("pool payout information (only --pool_user_id does so).");
 
 	let mut job_provider_hosts = Vec::new();
-	let mut pool_server_hosts = Vec::new();
-	let mut user_id = None;
-	let mut user_auth = None;
-	let mut stratum_listen_bind = None;
-	let mut mining_listen_bind = None;
+	let mut known_hosts = None;
+	let mut socks5_proxy = None;
+	let mut share_queue_dir = None;
+	let mut pool_failover = PoolFailoverConfig::default();
+	let mut upstream = UpstreamConfig::default();
+	let mut groups = vec![GroupConfig::new("default".to_string())];
+	let mut submitblock_rpcs = Vec::new();
+	let mut stratum_listen_binds = Vec::new();
+	let mut stratum_tls_listen_binds: Vec<SocketAddr> = Vec::new();
+	let mut stratum_tls_cert = None;
+	let mut stratum_tls_key = None;
+	let mut mining_listen_binds = Vec::new();
+	let mut trusted_proxies = TrustedProxies::new();
+	let mut stratum_timeouts = IdleTimeouts::default();
+	let mut mining_timeouts = IdleTimeouts::default();
 	let mut mining_auth_key = None;
-	let mut payout_addr = None;
+	let mut mining_next_auth_key = None;
+	let mut mining_key_rotation_time = None;
+	let mut shutdown_reconnect_to = None;
+	let mut shutdown_timeout = None;
 
 	for arg in env::args().skip(1) {
-		if arg.starts_with("--job_provider") {
-			match arg.split_at(15).1.to_socket_addrs() {
-				Err(_) => {
-					println!("Bad address resolution: {}", arg);
+		if arg.starts_with("--job_provider=file://") {
+			let host_port = arg.split_at(15).1.to_string();
+			let scenario = match replay_provider::Scenario::load(&host_port["file://".len()..]) {
+				Ok(scenario) => Arc::new(scenario),
+				Err(e) => {
+					println!("Failed to load job provider scenario: {}", e);
+					return;
+				}
+			};
+			job_provider_hosts.push(JobProviderInfo { host_port, auth_key: None, proxy: None, scenario: Some(scenario) });
+		} else if arg.starts_with("--job_provider") {
+			let (host_port, auth_key) = match auth_keys::parse_host_port_key(arg.split_at(15).1) {
+				Some(host_port_key) => host_port_key,
+				None => {
+					println!("Failed to parse job_provider auth key into a hex public key");
 					return;
+				}
+			};
+			if !socks5::check_upstream(&arg, &host_port, &socks5_proxy) {
+				return;
+			}
+			job_provider_hosts.push(JobProviderInfo { host_port, auth_key, proxy: socks5_proxy.clone(), scenario: None });
+		} else if arg.starts_with("--socks5_proxy") {
+			socks5_proxy = match arg.split_at(15).1 {
+				"none" => None,
+				proxy => match Socks5Proxy::parse(proxy) {
+					Some(proxy) => Some(proxy),
+					None => {
+						println!("Failed to parse socks5_proxy into [user:pass@]host:port");
+						return;
+					}
 				},
-				Ok(_) => job_provider_hosts.push(arg.split_at(15).1.to_string())
+			};
+		} else if arg.starts_with("--submitblock_rpc") {
+			let path_parts: Vec<&str> = arg.split_at(18).1.split('@').collect();
+			if path_parts.len() != 2 {
+				println!("Bad RPC URL provided");
+				return;
 			}
-		} else if arg.starts_with("--pool_server") {
-			match arg.split_at(14).1.to_socket_addrs() {
-				Err(_) => {
-					println!("Bad address resolution: {}", arg);
+			submitblock_rpcs.push(RPCClient::new(path_parts[0], path_parts[1]));
+		} else if arg.starts_with("--known_hosts") {
+			if known_hosts.is_some() {
+				println!("Cannot specify multiple known_hosts files");
+				return;
+			}
+			known_hosts = Some(match KnownHosts::load(arg.split_at(14).1.to_string()) {
+				Ok(known_hosts) => known_hosts,
+				Err(e) => {
+					println!("Failed to read known_hosts file: {}", e);
 					return;
-				},
-				Ok(_) => pool_server_hosts.push(arg.split_at(14).1.to_string())
+				}
+			});
+		} else if arg.starts_with("--share_queue_dir") {
+			if share_queue_dir.is_some() {
+				println!("Cannot specify multiple share_queue_dirs");
+				return;
 			}
-		} else if arg.starts_with("--stratum_listen_bind") {
-			if stratum_listen_bind.is_some() {
-				println!("Cannot specify multiple listen binds");
+			let dir = arg.split_at(18).1.to_string();
+			if !fs::metadata(&dir).map(|metadata| metadata.is_dir()).unwrap_or(false) {
+				println!("share_queue_dir {} is not a directory", dir);
+				return;
+			}
+			share_queue_dir = Some(dir);
+		} else if arg.starts_with("--pool_server") {
+			let (host_port, auth_key) = match auth_keys::parse_host_port_key(arg.split_at(14).1) {
+				Some(host_port_key) => host_port_key,
+				None => {
+					println!("Failed to parse pool_server auth key into a hex public key");
+					return;
+				}
+			};
+			if !socks5::check_upstream(&arg, &host_port, &socks5_proxy) {
 				return;
 			}
-			stratum_listen_bind = Some(match arg.split_at(22).1.parse() {
+			groups.last_mut().unwrap().pool_server_hosts.push((host_port, auth_key, socks5_proxy.clone()));
+		} else if arg.starts_with("--stratum_listen_bind") {
+			stratum_listen_binds.push(match arg.split_at(22).1.parse() {
 				Ok(sockaddr) => sockaddr,
 				Err(_) =>{
 					println!("Failed to parse stratum_listen_bind into a socket address");
 					return;
 				}
 			});
-		} else if arg.starts_with("--mining_listen_bind") {
-			if mining_listen_bind.is_some() {
-				println!("Cannot specify multiple listen binds");
+		} else if arg.starts_with("--stratum_tls_listen_bind") {
+			stratum_tls_listen_binds.push(match arg.split_at(26).1.parse() {
+				Ok(sockaddr) => sockaddr,
+				Err(_) =>{
+					println!("Failed to parse stratum_tls_listen_bind into a socket address");
+					return;
+				}
+			});
+		} else if arg.starts_with("--stratum_tls_cert") {
+			if stratum_tls_cert.is_some() {
+				println!("Cannot specify multiple stratum_tls_certs");
+				return;
+			}
+			stratum_tls_cert = Some(arg.split_at(19).1.to_string());
+		} else if arg.starts_with("--stratum_tls_key") {
+			if stratum_tls_key.is_some() {
+				println!("Cannot specify multiple stratum_tls_keys");
 				return;
 			}
-			mining_listen_bind = Some(match arg.split_at(21).1.parse() {
+			stratum_tls_key = Some(arg.split_at(18).1.to_string());
+		} else if arg.starts_with("--mining_listen_bind") {
+			mining_listen_binds.push(match arg.split_at(21).1.parse() {
 				Ok(sockaddr) => sockaddr,
 				Err(_) =>{
 					println!("Failed to parse mining_listen_bind into a socket address");
 					return;
 				}
 			});
+		} else if arg.starts_with("--group") {
+			let name = arg.split_at(8).1.to_string();
+			if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+				println!("Group names must be made up of letters, numbers, '-' and '_'");
+				return;
+			}
+			if groups.iter().any(|group| group.route.name == name) {
+				println!("Cannot specify multiple groups named {}", name);
+				return;
+			}
+			groups.push(GroupConfig::new(name));
+		} else if arg.starts_with("--match_worker_prefix") {
+			if groups.len() == 1 {
+				println!("match_worker_prefix must come after a --group");
+				return;
+			}
+			groups.last_mut().unwrap().route.add_worker_prefix(arg.split_at(22).1);
+		} else if arg.starts_with("--match_listen_port") {
+			if groups.len() == 1 {
+				println!("match_listen_port must come after a --group");
+				return;
+			}
+			match arg.split_at(20).1.parse() {
+				Ok(port) => groups.last_mut().unwrap().route.add_listen_port(port),
+				Err(_) => {
+					println!("Failed to parse match_listen_port into a port number");
+					return;
+				}
+			}
+		} else if arg.starts_with("--match_source") {
+			if groups.len() == 1 {
+				println!("match_source must come after a --group");
+				return;
+			}
+			if !groups.last_mut().unwrap().route.add_source(arg.split_at(15).1) {
+				println!("Failed to parse match_source into an IP or IP/prefix_len subnet");
+				return;
+			}
 		} else if arg.starts_with("--mining_auth_key") {
 			if mining_auth_key.is_some() {
 				println!("Cannot specify multiple auth keys");
@@ -132,13 +388,44 @@ fn main() {
 					return;
 				}
 			});
+		} else if arg.starts_with("--mining_next_auth_key") {
+			if mining_next_auth_key.is_some() {
+				println!("Cannot specify multiple next auth keys");
+				return;
+			}
+			mining_next_auth_key = Some(match privkey::Privkey::from_str(arg.split_at(23).1) {
+				Ok(privkey) => {
+					if !privkey.compressed {
+						println!("Private key must represent a compressed key!");
+						return;
+					}
+					privkey.key
+				},
+				Err(_) =>{
+					println!("Failed to parse mining_next_auth_key into a private key");
+					return;
+				}
+			});
+		} else if arg.starts_with("--mining_key_rotation_time") {
+			if mining_key_rotation_time.is_some() {
+				println!("Cannot specify multiple key rotation times");
+				return;
+			}
+			mining_key_rotation_time = Some(match arg.split_at(27).1.parse::<u64>() {
+				Ok(secs) => secs * 1000,
+				Err(_) => {
+					println!("Failed to parse mining_key_rotation_time into an integer");
+					return;
+				}
+			});
 		} else if arg.starts_with("--payout_address") {
+			let payout_addr = &mut groups.last_mut().unwrap().payout_addr;
 			if payout_addr.is_some() {
 				println!("Cannot specify multiple payout addresses");
 				return;
 			}
 			//TODO: check network magic byte? We're allowed to mine on any net, though...
-			payout_addr = Some(match Address::from_str(arg.split_at(17).1) {
+			*payout_addr = Some(match Address::from_str(arg.split_at(17).1) {
 				Ok(addr) => addr,
 				Err(_) => {
 					println!("Failed to parse payout_address into a Bitcoin address");
@@ -146,17 +433,148 @@ fn main() {
 				}
 			});
 		} else if arg.starts_with("--pool_user_id") {
+			let user_id = &mut groups.last_mut().unwrap().user_id;
 			if user_id.is_some() {
 				println!("Cannot specify multiple pool_user_ids");
 				return;
 			}
-			user_id = Some(arg.split_at(15).1.as_bytes().to_vec());
+			*user_id = Some(arg.split_at(15).1.as_bytes().to_vec());
 		} else if arg.starts_with("--pool_user_auth") {
+			let user_auth = &mut groups.last_mut().unwrap().user_auth;
 			if user_auth.is_some() {
 				println!("Cannot specify multiple pool_user_auths");
 				return;
 			}
-			user_auth = Some(arg.split_at(17).1.as_bytes().to_vec());
+			*user_auth = Some(arg.split_at(17).1.as_bytes().to_vec());
+		} else if arg.starts_with("--shutdown_reconnect_to") {
+			if shutdown_reconnect_to.is_some() {
+				println!("Cannot specify multiple shutdown_reconnect_tos");
+				return;
+			}
+			shutdown_reconnect_to = Some(match socks5::split_host_port(arg.split_at(24).1) {
+				Some((host, port)) => (host.to_string(), port),
+				None => {
+					println!("Failed to parse shutdown_reconnect_to into a host:port");
+					return;
+				}
+			});
+		} else if arg.starts_with("--shutdown_timeout") {
+			if shutdown_timeout.is_some() {
+				println!("Cannot specify multiple shutdown_timeouts");
+				return;
+			}
+			shutdown_timeout = Some(match arg.split_at(19).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse shutdown_timeout into a number of seconds");
+					return;
+				}
+			});
+		} else if arg.starts_with("--proxy_protocol_from") {
+			if !trusted_proxies.add(arg.split_at(22).1) {
+				println!("Failed to parse proxy_protocol_from into an IP or IP/prefix_len subnet");
+				return;
+			}
+		} else if arg.starts_with("--pool_weights") {
+			let pool_weights = &mut groups.last_mut().unwrap().pool_weights;
+			if pool_weights.is_some() {
+				println!("Cannot specify multiple pool_weights");
+				return;
+			}
+			let mut weights = Vec::new();
+			for weight in arg.split_at(15).1.split(',') {
+				match weight.parse::<u64>() {
+					Ok(weight) if weight > 0 => weights.push(weight),
+					_ => {
+						println!("Failed to parse pool_weights into a comma-separated list of positive numbers");
+						return;
+					}
+				}
+			}
+			*pool_weights = Some(weights);
+		} else if arg.starts_with("--pool_failback_min_secs") {
+			pool_failover.min_backup_secs = match arg.split_at(25).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse pool_failback_min_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--pool_failback_stable_secs") {
+			pool_failover.primary_stable_secs = match arg.split_at(28).1.parse() {
+				Ok(secs) => secs,
+				Err(_) => {
+					println!("Failed to parse pool_failback_stable_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--pool_max_switches_per_hour") {
+			pool_failover.max_switches_per_hour = match arg.split_at(29).1.parse() {
+				Ok(switches) => switches,
+				Err(_) => {
+					println!("Failed to parse pool_max_switches_per_hour into a number");
+					return;
+				}
+			};
+		} else if arg.starts_with("--reconnect_min_secs") {
+			upstream.reconnect.min = match arg.split_at(21).1.parse() {
+				Ok(secs) if secs > 0 => Duration::from_secs(secs),
+				_ => {
+					println!("Failed to parse reconnect_min_secs into a positive number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--reconnect_max_secs") {
+			upstream.reconnect.max = match arg.split_at(21).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse reconnect_max_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--upstream_heartbeat_secs") {
+			upstream.heartbeat = match arg.split_at(26).1.parse() {
+				Ok(0) => None,
+				Ok(secs) => Some(Duration::from_secs(secs)),
+				Err(_) => {
+					println!("Failed to parse upstream_heartbeat_secs into a number of seconds");
+					return;
+				}
+			};
+		} else if arg == "--require_encryption" {
+			upstream.require_encryption = true;
+		} else if arg.starts_with("--stratum_read_timeout") {
+			stratum_timeouts.read = match arg.split_at(23).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse stratum_read_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--stratum_write_timeout") {
+			stratum_timeouts.write = match arg.split_at(24).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse stratum_write_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--mining_read_timeout") {
+			mining_timeouts.read = match arg.split_at(22).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse mining_read_timeout into a number of seconds");
+					return;
+				}
+			};
+		} else if arg.starts_with("--mining_write_timeout") {
+			mining_timeouts.write = match arg.split_at(23).1.parse() {
+				Ok(secs) => Duration::from_secs(secs),
+				Err(_) => {
+					println!("Failed to parse mining_write_timeout into a number of seconds");
+					return;
+				}
+			};
 		} else {
 			println!("Unkown arg: {}", arg);
 			return;
@@ -167,83 +585,214 @@ fn main() {
 		println!("Need at least some job providers");
 		return;
 	}
-	if stratum_listen_bind.is_none() && mining_listen_bind.is_none() {
+	if stratum_listen_binds.is_empty() && stratum_tls_listen_binds.is_empty() && mining_listen_binds.is_empty() {
 		println!("Need some listen bind");
 		return;
 	}
-	if payout_addr.is_none() {
-		println!("Need some payout address for fallback/solo mining");
-		return;
+	for group in groups.iter() {
+		if group.payout_addr.is_none() {
+			println!("Need some payout address for fallback/solo mining in group {}", group.route.name);
+			return;
+		}
+		if !group.route.has_rules() && group.route.name != "default" {
+			println!("Need some --match_* rule for group {}", group.route.name);
+			return;
+		}
+		if let Some(ref weights) = group.pool_weights {
+			if weights.len() != group.pool_server_hosts.len() {
+				println!("Need exactly one pool_weight per pool_server in group {}", group.route.name);
+				return;
+			}
+		}
 	}
-	if mining_listen_bind.is_some() && mining_auth_key.is_none() {
+	if !mining_listen_binds.is_empty() && mining_auth_key.is_none() {
 		println!("Need some mining_auth_key for mining_listen_bind");
 		return;
 	}
-
-	if user_id.is_none() {
-		user_id = Some(Vec::new());
+	#[cfg(feature = "stratum-tls")]
+	let stratum_tls = if stratum_tls_listen_binds.is_empty() { None } else {
+		match (stratum_tls_cert, stratum_tls_key) {
+			(Some(cert), Some(key)) => match StratumTls::load(cert, key) {
+				Some(tls) => Some(tls),
+				None => return,
+			},
+			_ => {
+				println!("Need some stratum_tls_cert and stratum_tls_key for stratum_tls_listen_bind");
+				return;
+			}
+		}
+	};
+	#[cfg(not(feature = "stratum-tls"))]
+	let stratum_tls: Option<()> = if stratum_tls_listen_binds.is_empty() { None } else {
+		println!("Can't serve stratum_tls_listen_bind without building with the stratum-tls feature");
+		return;
+	};
+	if upstream.reconnect.min > upstream.reconnect.max {
+		println!("reconnect_min_secs must not be greater than reconnect_max_secs");
+		return;
 	}
-	if user_auth.is_none() {
-		user_auth = Some(Vec::new());
+	if mining_next_auth_key.is_some() != mining_key_rotation_time.is_some() {
+		println!("Need both mining_next_auth_key and mining_key_rotation_time to rotate keys");
+		return;
 	}
+	let mining_auth_keys = mining_auth_key.map(|key| ServerAuthKeys::new(key, mining_next_auth_key.map(|next| (next, mining_key_rotation_time.unwrap()))));
 
-	let mut pools = Vec::with_capacity(pool_server_hosts.len());
-	for pool in pool_server_hosts.drain(..) {
-		pools.push(PoolInfo {
-			host_port: pool,
-			user_id: user_id.as_ref().unwrap().clone(),
-			user_auth: user_auth.as_ref().unwrap().clone(),
-		});
+	// Each group gets its own pipeline, ie pool connections (each with their own share queue) and
+	// job provider connections
+	let mut pipelines = Vec::with_capacity(groups.len());
+	let mut route_groups = Vec::with_capacity(groups.len());
+	for (idx, group) in groups.drain(..).enumerate() {
+		let user_id = group.user_id.unwrap_or(Vec::new());
+		let user_auth = group.user_auth.unwrap_or(Vec::new());
+		let mut pools = Vec::with_capacity(group.pool_server_hosts.len());
+		for (host_port, auth_key, proxy) in group.pool_server_hosts {
+			pools.push(PoolInfo {
+				host_port,
+				auth_key,
+				proxy,
+				user_id: user_id.clone(),
+				user_auth: user_auth.clone(),
+				split_weight: group.pool_weights.as_ref().map(|weights: &Vec<u64>| weights[pools.len()]),
+			});
+		}
+
+		// Groups may well share pools, so keep their spilled shares apart
+		let group_share_queue_dir = if idx == 0 { share_queue_dir.clone() } else {
+			let name = &group.route.name;
+			share_queue_dir.as_ref().map(|dir: &String| dir.clone() + "/" + name)
+		};
+		if let Some(ref dir) = group_share_queue_dir {
+			if let Err(e) = fs::create_dir_all(dir) {
+				println!("Failed to create share_queue_dir {}: {}", dir, e);
+				return;
+			}
+		}
+
+		pipelines.push((pools, group.payout_addr.unwrap().script_pubkey(), group_share_queue_dir));
+		route_groups.push(group.route);
 	}
+	let router = Arc::new(Router::new(route_groups));
+
+	let shutdown_timeout = shutdown_timeout.unwrap_or(Duration::from_secs(10));
+
+	let shutdown = Shutdown::new();
+	let (exit_tx, exit_rx) = oneshot::channel();
+
+	let trusted_proxies = Arc::new(trusted_proxies);
+	// Plaintext and TLS stratum clients share one StratumServer, and so one set of jobs
+	let serve_stratum = !stratum_listen_binds.is_empty() || stratum_tls.is_some();
 
 	let mut rt = tokio::runtime::Runtime::new().unwrap();
 	rt.spawn(future::lazy(move || -> Result<(), ()> {
-		let job_rx = WorkGetter::create(job_provider_hosts, pools, payout_addr.clone().unwrap().script_pubkey());
+		let submitter = BlockSubmitter::new(submitblock_rpcs);
+		let mut stratum_job_rxs = Vec::with_capacity(pipelines.len());
+		let mut mining_servers = Vec::with_capacity(pipelines.len());
+		for (pools, payout_script, share_queue_dir) in pipelines.drain(..) {
+			let job_rx = WorkGetter::create(job_provider_hosts.clone(), pools, payout_script, known_hosts.clone(), upstream.clone(), share_queue_dir, pool_failover.clone(), submitter.clone(), shutdown.clone());
+			if serve_stratum && mining_listen_binds.is_empty() {
+				stratum_job_rxs.push(job_rx);
+			} else if !serve_stratum && !mining_listen_binds.is_empty() {
+				mining_servers.push(MiningServer::new(job_rx, mining_auth_keys.clone().unwrap(), None));
+			} else {
+				let (mut stratum_tx, stratum_rx) = mpsc::unbounded();
+				let (mut mining_tx, mining_rx) = mpsc::unbounded();
+				tokio::spawn(job_rx.for_each(move |job| {
+					mining_tx.start_send(job.clone()).unwrap();
+					stratum_tx.start_send(job).unwrap();
+					Ok(())
+				}).then(|_| {
+					Ok(())
+				}));
+				stratum_job_rxs.push(stratum_rx);
+				mining_servers.push(MiningServer::new(mining_rx, mining_auth_keys.clone().unwrap(), None));
+			}
+		}
+		let stratum_server = if stratum_job_rxs.is_empty() { None } else {
+			Some(StratumServer::new(stratum_job_rxs, None, Some(router.clone())))
+		};
+		let stop_listening = signal_received().shared();
 
 		macro_rules! bind_and_handle {
-			($listen_bind_option: expr, $server: expr, $server_type: tt) => {
-				match $listen_bind_option {
-					Some(listen_bind) => {
-						let server = $server;
-						match net::TcpListener::bind(&listen_bind) {
-							Ok(listener) => {
-								tokio::spawn(listener.incoming().for_each(move |sock| {
-									$server_type::new_connection(server.clone(), sock);
-									Ok(())
-								}).then(|_| {
-									Ok(())
+			($listen_binds: expr, $new_connection: expr, $timeouts: expr) => {
+				for listen_bind in $listen_binds.iter() {
+					let new_connection = $new_connection;
+					let trusted_proxies = trusted_proxies.clone();
+					let timeouts = $timeouts;
+					match net::TcpListener::bind(listen_bind) {
+						Ok(listener) => {
+							tokio::spawn(listener.incoming().for_each(move |sock| {
+								let new_connection = new_connection.clone();
+								tokio::spawn(proxy_protocol::accept(sock, &trusted_proxies).then(move |res| {
+									match res {
+										Ok((sock, addr)) => new_connection(sock, addr, timeouts),
+										Err(e) => println!("Dropping connection which failed to send a valid PROXY protocol header: {}", e),
+									}
+									future::result(Ok(()))
 								}));
-							},
-							Err(_) => {
-								println!("Failed to bind to listen bind addr");
-								return Ok(());
-							}
-						};
-					},
-					None => {},
+								Ok(())
+							}).select2(stop_listening.clone()).then(|_| {
+								Ok(())
+							}));
+						},
+						Err(_) => {
+							println!("Failed to bind to listen bind addr");
+							return Ok(());
+						}
+					};
 				}
 			}
 		}
 
-		if stratum_listen_bind.is_some() && mining_listen_bind.is_none() {
-			bind_and_handle!(stratum_listen_bind, StratumServer::new(job_rx, None), StratumServer);
-		} else if stratum_listen_bind.is_none() && mining_listen_bind.is_some() {
-			bind_and_handle!(mining_listen_bind, MiningServer::new(job_rx, mining_auth_key.unwrap()), MiningServer);
-		} else {
-			let (mut stratum_tx, stratum_rx) = mpsc::unbounded();
-			let (mut mining_tx, mining_rx) = mpsc::unbounded();
-			tokio::spawn(job_rx.for_each(move |job| {
-				mining_tx.start_send(job.clone()).unwrap();
-				stratum_tx.start_send(job).unwrap();
-				Ok(())
-			}).then(|_| {
-				Ok(())
-			}));
-			bind_and_handle!(stratum_listen_bind, StratumServer::new(stratum_rx, None), StratumServer);
-			bind_and_handle!(mining_listen_bind, MiningServer::new(mining_rx, mining_auth_key.unwrap()), MiningServer);
+		if let Some(ref server) = stratum_server {
+			bind_and_handle!(stratum_listen_binds, {
+				let server = server.clone();
+				Arc::new(move |sock, addr, timeouts| StratumServer::new_connection(server.clone(), sock, addr, timeouts))
+			}, stratum_timeouts);
+			#[cfg(feature = "stratum-tls")]
+			if let Some(ref tls) = stratum_tls {
+				bind_and_handle!(stratum_tls_listen_binds, {
+					let server = server.clone();
+					let tls = tls.clone();
+					Arc::new(move |sock, addr, timeouts| StratumServer::new_tls_connection(server.clone(), sock, addr, timeouts, &tls))
+				}, stratum_timeouts);
+				tokio::spawn(StratumTls::reload_on_sighup(tls.clone()));
+			}
 		}
+		if !mining_servers.is_empty() {
+			// Native clients don't have worker names, so we can route them as soon as they connect
+			let mining_servers = Arc::new(mining_servers.clone());
+			bind_and_handle!(mining_listen_binds, {
+				let mining_servers = mining_servers.clone();
+				let router = router.clone();
+				Arc::new(move |sock: net::TcpStream, addr: SocketAddr, timeouts| {
+					let listen_port = sock.local_addr().map(|addr| addr.port()).unwrap_or(0);
+					let group = router.route(listen_port, &addr.ip(), None);
+					MiningServer::new_connection(mining_servers[group].clone(), sock, addr, timeouts, None)
+				})
+			}, mining_timeouts);
+		}
+
+		tokio::spawn(stop_listening.then(move |_| {
+			println!("Shutting down, asking clients to reconnect and draining shares upstream...");
+			if let Some(ref server) = stratum_server {
+				server.shutdown(&shutdown_reconnect_to);
+			}
+			for server in mining_servers.iter() {
+				server.shutdown(&shutdown_reconnect_to);
+			}
+			// Give clients a moment to get any in-flight shares to us before we flush upstream
+			let grace = cmp::min(Duration::from_secs(2), shutdown_timeout / 4);
+			timer::Delay::new(Instant::now() + grace).then(move |_| {
+				shutdown.start();
+				shutdown.flushed(shutdown_timeout - grace)
+			}).then(move |_| {
+				let _ = exit_tx.send(());
+				Ok(())
+			})
+		}));
 
 		Ok(())
 	}));
-	rt.shutdown_on_idle().wait().unwrap();
+	let _ = exit_rx.wait();
+	rt.shutdown_now().wait().unwrap();
 }
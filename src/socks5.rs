@@ -0,0 +1,229 @@
+use futures::future;
+use futures::Future;
+
+use tokio::net::TcpStream;
+use tokio::timer;
+
+use tokio_io::io::{read_exact, write_all};
+
+use utils;
+
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A SOCKS5 proxy (eg Tor or an egress gateway) to make upstream connections through
+#[derive(Clone)]
+pub struct Socks5Proxy {
+	pub host_port: String,
+	/// Username and password, if the proxy wants them (Tor uses them to isolate circuits)
+	pub auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+	/// Parses [user:pass@]host:port
+	pub fn parse(arg: &str) -> Option<Self> {
+		let mut parts = arg.rsplitn(2, '@');
+		let host_port = parts.next().unwrap().to_string();
+		if split_host_port(&host_port).is_none() { return None; }
+		let auth = match parts.next() {
+			Some(user_pass) => {
+				let mut user_pass = user_pass.splitn(2, ':');
+				let user = user_pass.next().unwrap().to_string();
+				let pass = match user_pass.next() { Some(pass) => pass.to_string(), None => return None };
+				if user.is_empty() || user.len() > 255 || pass.len() > 255 { return None; }
+				Some((user, pass))
+			},
+			None => None,
+		};
+		Some(Self { host_port, auth })
+	}
+}
+
+/// Splits host:port (or [v6 addr]:port) without resolving anything
+pub fn split_host_port(host_port: &str) -> Option<(&str, u16)> {
+	let mut parts = host_port.rsplitn(2, ':');
+	let port = match parts.next().unwrap().parse() { Ok(port) => port, Err(_) => return None };
+	let host = match parts.next() { Some(host) => host, None => return None };
+	let host = if host.starts_with('[') && host.ends_with(']') { &host[1..host.len() - 1] } else { host };
+	if host.is_empty() || host.len() > 255 { return None; }
+	Some((host, port))
+}
+
+/// Checks that host_port looks like a host:port we could connect to, without resolving it
+pub fn is_host_port(host_port: &str) -> bool {
+	split_host_port(host_port).is_some()
+}
+
+/// Checks an upstream host:port from the command line, printing why if it's no good. We only
+/// resolve it if we're going to be connecting to it ourselves, otherwise the proxy does.
+pub fn check_upstream(arg: &str, host_port: &str, proxy: &Option<Socks5Proxy>) -> bool {
+	match split_host_port(host_port) {
+		Some((host, _)) if proxy.is_none() && host.ends_with(".onion") => {
+			println!("Need a --socks5_proxy (eg Tor) to reach .onion hosts: {}", arg);
+			false
+		},
+		Some(_) if proxy.is_some() => true,
+		_ => match host_port.to_socket_addrs() {
+			Ok(_) => true,
+			Err(_) => {
+				println!("Bad address resolution: {}", arg);
+				false
+			}
+		},
+	}
+}
+
+fn proxy_error(proxy: &Socks5Proxy, host_port: &str, msg: &str) -> io::Error {
+	println!("SOCKS5 proxy {} failed to connect us to {}: {}", proxy.host_port, host_port, msg);
+	io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)
+}
+
+/// Checks the fixed part of the proxy's reply to our CONNECT, returning how many more bytes of
+/// bound address and port we have to skip past before the stream is ours.
+fn connect_reply_remaining(reply: &[u8; 5]) -> Result<usize, String> {
+	if reply[0] != 5 || reply[1] != 0 {
+		return Err(format!("CONNECT rejected with reply code {}", reply[1]));
+	}
+	// We've read the first byte of the bound address, skip the rest and the port
+	match reply[3] {
+		1 => Ok(4 - 1 + 2),
+		4 => Ok(16 - 1 + 2),
+		3 => Ok(reply[4] as usize + 2),
+		_ => Err("bad bound address type".to_string()),
+	}
+}
+
+/// Asks the proxy we're connected to on stream to connect us on to host_port. Hostnames are
+/// passed through for the proxy to resolve, which is what makes .onion hosts work (and keeps
+/// our DNS lookups away from the local network). Gives up if the proxy stalls for more than
+/// 10 seconds, so that a wedged proxy doesn't stop us from reconnecting.
+pub fn handshake(stream: TcpStream, proxy: &Socks5Proxy, host_port: &str) -> impl Future<Item = TcpStream, Error = io::Error> {
+	let (proxy_host_port, target) = (proxy.host_port.clone(), host_port.to_string());
+	timer::Timeout::new(negotiate(stream, proxy, host_port), Duration::from_secs(10)).map_err(move |e| {
+		e.into_inner().unwrap_or_else(|| {
+			println!("SOCKS5 proxy {} timed out connecting us to {}", proxy_host_port, target);
+			io::Error::new(io::ErrorKind::TimedOut, utils::HandleError)
+		})
+	})
+}
+
+fn negotiate(stream: TcpStream, proxy: &Socks5Proxy, host_port: &str) -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+	let (host, port) = match split_host_port(host_port) {
+		Some(host_port) => host_port,
+		None => return Box::new(future::err(proxy_error(proxy, host_port, "bad host:port"))),
+	};
+	let mut request = vec![5, 1, 0];
+	match host.parse() {
+		Ok(IpAddr::V4(addr)) => {
+			request.push(1);
+			request.extend_from_slice(&addr.octets());
+		},
+		Ok(IpAddr::V6(addr)) => {
+			request.push(4);
+			request.extend_from_slice(&addr.octets());
+		},
+		Err(_) => {
+			request.push(3);
+			request.push(host.len() as u8);
+			request.extend_from_slice(host.as_bytes());
+		},
+	}
+	request.push((port >> 8) as u8);
+	request.push(port as u8);
+
+	let method = if proxy.auth.is_some() { 2 } else { 0 };
+	let target = Arc::new((proxy.clone(), host_port.to_string()));
+	let (target_auth, target_reply, target_err) = (target.clone(), target.clone(), target.clone());
+	Box::new(write_all(stream, [5, 1, method]).and_then(|(stream, _)| {
+		read_exact(stream, [0; 2])
+	}).and_then(move |(stream, reply)| -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+		if reply[0] != 5 || reply[1] != method {
+			return Box::new(future::err(proxy_error(&target.0, &target.1, "no acceptable auth method")));
+		}
+		match target.0.auth {
+			// Username/password auth is RFC 1929
+			Some((ref user, ref pass)) => {
+				let mut auth = vec![1, user.len() as u8];
+				auth.extend_from_slice(user.as_bytes());
+				auth.push(pass.len() as u8);
+				auth.extend_from_slice(pass.as_bytes());
+				Box::new(write_all(stream, auth).and_then(|(stream, _)| {
+					read_exact(stream, [0; 2])
+				}).and_then(move |(stream, reply)| {
+					if reply[1] != 0 {
+						return Err(proxy_error(&target_auth.0, &target_auth.1, "bad username/password"));
+					}
+					Ok(stream)
+				}))
+			},
+			None => Box::new(future::ok(stream)),
+		}
+	}).and_then(move |stream| {
+		write_all(stream, request)
+	}).and_then(|(stream, _)| {
+		read_exact(stream, [0; 5])
+	}).and_then(move |(stream, reply)| {
+		match connect_reply_remaining(&reply) {
+			Ok(remaining) => Ok((stream, remaining)),
+			Err(msg) => Err(proxy_error(&target_reply.0, &target_reply.1, &msg)),
+		}
+	}).and_then(|(stream, remaining)| {
+		read_exact(stream, vec![0; remaining])
+	}).map(|(stream, _)| stream).map_err(move |e| {
+		if e.kind() != io::ErrorKind::InvalidData {
+			println!("Lost connection to SOCKS5 proxy {} while connecting to {}: {}", target_err.0.host_port, target_err.1, e);
+		}
+		e
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use socks5::*;
+
+	#[test]
+	fn test_parse_proxy() {
+		let proxy = Socks5Proxy::parse("127.0.0.1:9050").unwrap();
+		assert_eq!(proxy.host_port, "127.0.0.1:9050");
+		assert!(proxy.auth.is_none());
+
+		let proxy = Socks5Proxy::parse("user:p@ss:w0rd@proxy.example.com:1080").unwrap();
+		assert_eq!(proxy.host_port, "proxy.example.com:1080");
+		assert_eq!(proxy.auth, Some(("user".to_string(), "p@ss:w0rd".to_string())));
+
+		assert!(Socks5Proxy::parse("127.0.0.1").is_none());
+		assert!(Socks5Proxy::parse("user@127.0.0.1:9050").is_none());
+		assert!(Socks5Proxy::parse(":pass@127.0.0.1:9050").is_none());
+		assert!(Socks5Proxy::parse(&format!("{}:pass@127.0.0.1:9050", "u".repeat(256))).is_none());
+	}
+
+	#[test]
+	fn test_split_host_port() {
+		assert_eq!(split_host_port("pool.example.com:3333"), Some(("pool.example.com", 3333)));
+		assert_eq!(split_host_port("10.0.0.1:8333"), Some(("10.0.0.1", 8333)));
+		assert_eq!(split_host_port("[2001:db8::1]:8333"), Some(("2001:db8::1", 8333)));
+		assert_eq!(split_host_port("abcdefghijklmnop.onion:80"), Some(("abcdefghijklmnop.onion", 80)));
+
+		assert_eq!(split_host_port("pool.example.com"), None);
+		assert_eq!(split_host_port(":3333"), None);
+		assert_eq!(split_host_port("[]:3333"), None);
+		assert_eq!(split_host_port("pool.example.com:65536"), None);
+		assert_eq!(split_host_port("pool.example.com:port"), None);
+	}
+
+	#[test]
+	fn test_connect_reply() {
+		assert_eq!(connect_reply_remaining(&[5, 0, 0, 1, 10]), Ok(5));
+		assert_eq!(connect_reply_remaining(&[5, 0, 0, 4, 0x20]), Ok(17));
+		assert_eq!(connect_reply_remaining(&[5, 0, 0, 3, 11]), Ok(13));
+
+		assert!(connect_reply_remaining(&[5, 0, 0, 2, 0]).is_err());
+		assert!(connect_reply_remaining(&[4, 0, 0, 1, 0]).is_err());
+		// Host unreachable, connection refused and so on all fail the connection
+		for code in 1..9 {
+			assert!(connect_reply_remaining(&[5, code, 0, 1, 0]).is_err());
+		}
+	}
+}
@@ -1,6 +1,7 @@
 use msg_framing::{BlockTemplate,WinningNonce,PoolUserAuth};
-use work_info::WorkInfo;
-use pool_client::{PoolAuthAction, PoolProviderUserJob};
+use work_info::{JobSet, WorkInfo};
+use pool_client::{PoolAuthAction, PoolProviderUserJob, UserUpdate};
+use routing::Router;
 use utils;
 
 use bitcoin::blockdata::transaction::{TxIn,Transaction};
@@ -21,16 +22,22 @@ use tokio::{net, timer};
 
 use tokio_codec;
 
-use timeout_stream::TimeoutStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "stratum-tls")]
+use stratum_tls::StratumTls;
+
+use timeout_stream::{IdleTimeouts, ProgressIo, TimeoutSink, TimeoutStream};
 
 use serde_json;
 
 use std::{char, cmp, fmt, io, mem};
-use std::collections::{BTreeMap, HashMap, hash_map};
+use std::collections::{HashMap, hash_map};
+use std::net::SocketAddr;
 use std::error::Error;
 use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 struct BadMessageError;
@@ -174,7 +181,7 @@ fn job_to_json_string_prefix(template: &BlockTemplate, update_id: u32, user_coin
 	prefix
 }
 
-fn job_to_json_string_postfix(template: &BlockTemplate, prev_changed: bool) -> String {
+fn job_to_json_string_postfix(template: &BlockTemplate, clean_jobs: bool) -> String {
 	let mut postfix = String::with_capacity(57 + template.coinbase_postfix.len()*2 + 64*template.appended_coinbase_outputs.len() + 66*template.merkle_rhss.len());
 
 	utils::push_bytes_hex(&template.coinbase_postfix[..], &mut postfix);
@@ -206,7 +213,7 @@ fn job_to_json_string_postfix(template: &BlockTemplate, prev_changed: bool) -> S
 	postfix.push_str("\",\""); // 3 chars
 	postfix.push_str(&be32_to_hex(template.header_time)); // 8 chars
 	postfix.push_str("\","); // 2 chars
-	if prev_changed {
+	if clean_jobs {
 		postfix.push_str("true],\"id\":null,\"method\":\"mining.notify\"}");
 	} else {
 		postfix.push_str("false],\"id\":null,\"method\":\"mining.notify\"}");
@@ -235,6 +242,14 @@ struct StratumClient {
 	stream: Mutex<mpsc::Sender<String>>,
 	needs_close: AtomicBool,
 	client_id: u64,
+	/// The client's real address (ie from the PROXY protocol header if we're behind a balancer)
+	addr: SocketAddr,
+	/// The port of the listener the client connected to, for routing
+	listen_port: u16,
+	/// The routing group (ie index into jobs) we're sending this client work from
+	group: AtomicUsize,
+	/// group has been picked (we may have to wait for mining.authorize to get a worker name)
+	routed: AtomicBool,
 	last_send: Mutex<Instant>,
 	/// mining.subscribe has been received
 	subscribed: AtomicBool,
@@ -275,95 +290,94 @@ struct StratumUser {
 
 pub struct StratumServer {
 	clients: Mutex<(Vec<Arc<StratumClient>>, u64)>,
-	jobs: RwLock<BTreeMap<u32, WorkInfo>>,
+	/// Jobs for each routing group
+	jobs: Vec<RwLock<JobSet<u32, WorkInfo>>>,
+	router: Option<Arc<Router>>,
 	users: Mutex<HashMap<Vec<u8>, StratumUser>>,
 	/// Locked concurrently (after) users
 	user_auth_requests: Option<Mutex<mpsc::Sender<PoolAuthAction>>>,
 	user_coinbase_postfix_len: AtomicUsize,
 	job_update_id: AtomicUsize,
-}
-
-pub enum UserUpdate {
-	// We never construct this in single_user_pool
-	#[allow(dead_code)]
-	WorkUpdate {
-		user_id: Vec<u8>,
-		user_info: PoolProviderUserJob,
-	},
-	// We never construct this in single_user_pool
-	#[allow(dead_code)]
-	DropUser {
-		user_id: Vec<u8>
-	},
+	/// Set once we've started shutting down, after which we stop sending new work
+	shutting_down: AtomicBool,
 }
 
 impl StratumServer {
-	/// If user_providers is set, job_providers' difficulty is ignored
-	pub fn new(job_providers: mpsc::UnboundedReceiver<WorkInfo>, user_providers: Option<(mpsc::UnboundedReceiver<UserUpdate>, mpsc::Sender<PoolAuthAction>)>) -> Arc<Self> {
+	/// If user_providers is set, job_providers' difficulty is ignored (and there must be only one
+	/// job provider). Otherwise there is one job provider per routing group in router (or just
+	/// one if we have no router).
+	pub fn new(job_providers: Vec<mpsc::UnboundedReceiver<WorkInfo>>, user_providers: Option<(mpsc::UnboundedReceiver<UserUpdate>, mpsc::Sender<PoolAuthAction>)>, router: Option<Arc<Router>>) -> Arc<Self> {
 		let (user_job_stream, user_auth_requests) = if let Some((user_job_stream, auth_sink)) = user_providers {
 			(Some(user_job_stream), Some(Mutex::new(auth_sink))) } else { (None, None) };
 
 		let us = Arc::new(Self {
 			clients: Mutex::new((Vec::new(), 0)),
-			jobs: RwLock::new(BTreeMap::new()),
+			jobs: job_providers.iter().map(|_| RwLock::new(JobSet::new())).collect(),
+			router,
 			users: Mutex::new(HashMap::new()),
 			user_auth_requests,
 			user_coinbase_postfix_len: AtomicUsize::new(0),
 			job_update_id: AtomicUsize::new(0),
+			shutting_down: AtomicBool::new(false),
 		});
 
-		let us_cp = us.clone();
-		let mut last_prevblock = [0; 32];
-		let mut last_diff = [0; 32];
 		let need_work_diff = user_job_stream.is_none();
-		tokio::spawn(job_providers.for_each(move |job| {
-			{
-				let new_job = job.clone();
-				let mut jobs = us_cp.jobs.write().unwrap();
-				jobs.insert(template_timestamp_to_job_id(job.template.template_timestamp), new_job);
-			}
+		for (group, job_provider) in job_providers.into_iter().enumerate() {
+			let us_cp = us.clone();
+			let mut last_prevblock = [0; 32];
+			let mut last_diff = [0; 32];
+			tokio::spawn(job_provider.for_each(move |job| {
+				{
+					let new_job = job.clone();
+					let mut jobs = us_cp.jobs[group].write().unwrap();
+					jobs.insert(template_timestamp_to_job_id(job.template.template_timestamp), job.template.header_prevblock, new_job);
+				}
+				if us_cp.shutting_down.load(Ordering::Acquire) {
+					return future::result(Ok(()));
+				}
 
-			let prev_changed = last_prevblock != job.template.header_prevblock;
-			if prev_changed {
-				last_prevblock = job.template.header_prevblock;
-			}
-			let diff_changed = need_work_diff && last_diff != job.template.target;
-			let diff_str = if diff_changed {
-				last_diff = job.template.target;
-				job_to_difficulty_string(&job.template)
-			} else { String::new() };
-			let user_coinbase_postfix_len = if need_work_diff { 0 } else { us_cp.user_coinbase_postfix_len.load(Ordering::Acquire) };
-			let job_update_id = (us_cp.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
-			let job_json_prefix = job_to_json_string_prefix(&job.template, job_update_id, user_coinbase_postfix_len);
-			let job_json_postfix = job_to_json_string_postfix(&job.template, prev_changed);
-
-			if need_work_diff {
-				let job_json = job_json_prefix + &job_json_postfix;
-				let clients = us_cp.clients.lock().unwrap().0.clone();
-				for client in clients {
-					if !client.mining.load(Ordering::Acquire) { continue; }
-					if diff_changed {
-						client.attempt_send(diff_str.clone());
-					}
-					client.attempt_send(job_json.clone());
-					*client.last_send.lock().unwrap() = Instant::now();
+				let prev_changed = last_prevblock != job.template.header_prevblock;
+				if prev_changed {
+					last_prevblock = job.template.header_prevblock;
 				}
-			} else {
-				let users: Vec<StratumUser> = us_cp.users.lock().unwrap().values().map(|user| (*user).clone()).collect();
-				for user in users {
-					if let &Some(ref job) = &user.cur_job {
-						let job_json = job_json_prefix.clone() + &utils::bytes_to_hex(&job.coinbase_postfix) + &job_json_postfix;
-						for client in user.clients.iter() {
-							if !client.mining.load(Ordering::Acquire) { continue; }
-							client.attempt_send(job_json.clone());
-							*client.last_send.lock().unwrap() = Instant::now();
+				let diff_changed = need_work_diff && last_diff != job.template.target;
+				let diff_str = if diff_changed {
+					last_diff = job.template.target;
+					job_to_difficulty_string(&job.template)
+				} else { String::new() };
+				let user_coinbase_postfix_len = if need_work_diff { 0 } else { us_cp.user_coinbase_postfix_len.load(Ordering::Acquire) };
+				let job_update_id = (us_cp.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
+				let job_json_prefix = job_to_json_string_prefix(&job.template, job_update_id, user_coinbase_postfix_len);
+				let job_json_postfix = job_to_json_string_postfix(&job.template, prev_changed || job.clean_jobs);
+
+				if need_work_diff {
+					let job_json = job_json_prefix + &job_json_postfix;
+					let clients = us_cp.clients.lock().unwrap().0.clone();
+					for client in clients {
+						if !client.mining.load(Ordering::Acquire) || client.group.load(Ordering::Acquire) != group { continue; }
+						if diff_changed {
+							client.attempt_send(diff_str.clone());
+						}
+						client.attempt_send(job_json.clone());
+						*client.last_send.lock().unwrap() = Instant::now();
+					}
+				} else {
+					let users: Vec<StratumUser> = us_cp.users.lock().unwrap().values().map(|user| (*user).clone()).collect();
+					for user in users {
+						if let &Some(ref job) = &user.cur_job {
+							let job_json = job_json_prefix.clone() + &utils::bytes_to_hex(&job.coinbase_postfix) + &job_json_postfix;
+							for client in user.clients.iter() {
+								if !client.mining.load(Ordering::Acquire) { continue; }
+								client.attempt_send(job_json.clone());
+								*client.last_send.lock().unwrap() = Instant::now();
+							}
 						}
 					}
 				}
-			}
 
-			future::result(Ok(()))
-		}));
+				future::result(Ok(()))
+			}));
+		}
 
 		let us_cp = us.clone();
 		if let Some(users) = user_job_stream {
@@ -394,9 +408,10 @@ impl StratumServer {
 
 						if need_diff_update || need_postfix_update {
 							let last_job = {
-								let jobs = us_cp.jobs.read().unwrap();
-								if let Some((_, v)) = jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
-									v.clone()
+								// We only ever have one group when using upstream user auth
+								let jobs = us_cp.jobs[0].read().unwrap();
+								if let Some(job) = jobs.latest() {
+									job.clone()
 								} else { return Ok(()); }
 							};
 							let now = Instant::now();
@@ -433,77 +448,60 @@ impl StratumServer {
 
 		let us_timer = us.clone(); // Wait, you wanted a deconstructor? LOL
 		tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(10), Duration::from_secs(1)).for_each(move |_| {
-			let last_job = {
-				let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-				let timestamp = (time.as_secs() - 30) * 1000 + time.subsec_nanos() as u64 / 1_000_000;
-
-				let jobs = us_timer.jobs.read().unwrap();
-				let last_job = match jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
-					Some((_, v)) => Some(v.clone()),
-					None => None,
+			for (group, jobs_lock) in us_timer.jobs.iter().enumerate() {
+				let last_job = {
+					let jobs = jobs_lock.read().unwrap();
+					let last_job = jobs.latest().cloned();
+
+					// We have to keep a job until the one which replaced it is 30 seconds old
+					if { // Avoid write lock unless we need it
+						let res = jobs.has_expired(Duration::from_secs(30));
+						mem::drop(jobs);
+						res
+					} {
+						jobs_lock.write().unwrap().expire(Duration::from_secs(30));
+					}
+
+					last_job
 				};
 
-				if { // Avoid write lock unless we need it
-					let res = {
-						if jobs.len() > 1 {
-							let mut iter = jobs.iter();
-							iter.next(); // We have to keep a job until the next one is 30 seconds old
-							if let Some((_, v)) = iter.next() {
-								v.template.template_timestamp < timestamp
-							} else { false }
-						} else { false }
-					};
-					mem::drop(jobs);
-					res
-				} {
-					let mut jobs = us_timer.jobs.write().unwrap();
-					while jobs.len() > 1 {
-						// There should be a much easier way to implement this...
-						let (first_id, second_timestamp) = {
-							let mut iter = jobs.iter();
-							(*iter.next().unwrap().0, iter.next().unwrap().1.template.template_timestamp)
-						};
-						if second_timestamp < timestamp {
-							jobs.remove(&first_id);
-						} else { break; }
-					}
+				if us_timer.shutting_down.load(Ordering::Acquire) {
+					continue;
 				}
 
-				last_job
-			};
-
-			match last_job {
-				Some(job) => {
-					let now = Instant::now();
-					let send_target = now - Duration::from_secs(29);
-					let job_update_id = (us_timer.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
-					let job_json_prefix = job_to_json_string_prefix(&job.template, job_update_id, us_timer.user_coinbase_postfix_len.load(Ordering::Acquire));
-					let job_json_postfix = job_to_json_string_postfix(&job.template, false);
-
-					if need_work_diff {
-						let job_json = job_json_prefix + &job_json_postfix;
-						let clients = us_timer.clients.lock().unwrap().0.clone();
-						for client in clients {
-							if client.mining.load(Ordering::Acquire) && *client.last_send.lock().unwrap() < send_target {
-								client.attempt_send(job_json.clone());
-								*client.last_send.lock().unwrap() = Instant::now();
+				match last_job {
+					Some(job) => {
+						let now = Instant::now();
+						let send_target = now - Duration::from_secs(29);
+						let job_update_id = (us_timer.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
+						let job_json_prefix = job_to_json_string_prefix(&job.template, job_update_id, us_timer.user_coinbase_postfix_len.load(Ordering::Acquire));
+						let job_json_postfix = job_to_json_string_postfix(&job.template, false);
+
+						if need_work_diff {
+							let job_json = job_json_prefix + &job_json_postfix;
+							let clients = us_timer.clients.lock().unwrap().0.clone();
+							for client in clients {
+								if client.mining.load(Ordering::Acquire) && client.group.load(Ordering::Acquire) == group && *client.last_send.lock().unwrap() < send_target {
+									client.attempt_send(job_json.clone());
+									*client.last_send.lock().unwrap() = Instant::now();
+								}
 							}
-						}
-					} else {
-						let users: Vec<StratumUser> = us_timer.users.lock().unwrap().values().map(|user| (*user).clone()).collect();
-						for user in users {
-							if let &Some(ref job) = &user.cur_job {
-								let job_json = job_json_prefix.clone() + &utils::bytes_to_hex(&job.coinbase_postfix) + &job_json_postfix;
-								for client in user.clients.iter() {
-									if client.mining.load(Ordering::Acquire) && *client.last_send.lock().unwrap() < send_target {
-										client.attempt_send(job_json.clone());
-										*client.last_send.lock().unwrap() = Instant::now();
+						} else {
+							let users: Vec<StratumUser> = us_timer.users.lock().unwrap().values().map(|user| (*user).clone()).collect();
+							for user in users {
+								if let &Some(ref job) = &user.cur_job {
+									let job_json = job_json_prefix.clone() + &utils::bytes_to_hex(&job.coinbase_postfix) + &job_json_postfix;
+									for client in user.clients.iter() {
+										if client.mining.load(Ordering::Acquire) && *client.last_send.lock().unwrap() < send_target {
+											client.attempt_send(job_json.clone());
+											*client.last_send.lock().unwrap() = Instant::now();
+										}
 									}
 								}
 							}
 						}
-					}
-				}, None => {}
+					}, None => {}
+				}
 			}
 
 			future::result(Ok(()))
@@ -513,15 +511,73 @@ impl StratumServer {
 		us
 	}
 
-	pub fn new_connection(us: Arc<Self>, stream: net::TcpStream) {
+	/// Stops sending new work and sends every client a client.reconnect, either to reconnect_to
+	/// or, if it isn't set, back to wherever they connected to (ie us, once we've restarted).
+	/// Existing connections stay up so that in-flight shares still make it upstream.
+	pub fn shutdown(&self, reconnect_to: &Option<(String, u16)>) {
+		self.shutting_down.store(true, Ordering::Release);
+
+		let reconnect_msg = match reconnect_to {
+			&Some((ref host, port)) => json!({
+				"params": [host, port, 0],
+				"id": serde_json::Value::Null,
+				"method": "client.reconnect",
+			}),
+			&None => json!({
+				"params": [],
+				"id": serde_json::Value::Null,
+				"method": "client.reconnect",
+			}),
+		}.to_string();
+
+		let clients = self.clients.lock().unwrap().0.clone();
+		println!("Sending client.reconnect to {} clients", clients.len());
+		for client in clients {
+			client.attempt_send(reconnect_msg.clone());
+		}
+	}
+
+	pub fn new_connection(us: Arc<Self>, stream: net::TcpStream, addr: SocketAddr, timeouts: IdleTimeouts) {
 		stream.set_nodelay(true).unwrap();
 		stream.set_send_buffer_size(3072).unwrap(); // At least two packets, but we do our own buffer management, mostly
+		let listen_port = stream.local_addr().map(|addr| addr.port()).unwrap_or(0);
+		Self::handle_connection(us, stream, addr, listen_port, timeouts);
+	}
+
+	/// Handles a stratum+ssl connection, which gets the same jobs as plaintext ones once the TLS
+	/// handshake completes.
+	#[cfg(feature = "stratum-tls")]
+	pub fn new_tls_connection(us: Arc<Self>, stream: net::TcpStream, addr: SocketAddr, timeouts: IdleTimeouts, tls: &StratumTls) {
+		stream.set_nodelay(true).unwrap();
+		// TLS records add some overhead, but we still want a job to fit in a couple of packets
+		stream.set_send_buffer_size(4096).unwrap();
+		let listen_port = stream.local_addr().map(|addr| addr.port()).unwrap_or(0);
+		tokio::spawn(timer::Timeout::new(tls.accept(stream), Duration::from_secs(10)).then(move |res| {
+			match res {
+				Ok(stream) => Self::handle_connection(us, stream, addr, listen_port, timeouts),
+				Err(e) => match e.into_inner() {
+					Some(e) => println!("TLS handshake with {} failed: {}", addr, e),
+					None => println!("TLS handshake with {} timed out", addr),
+				},
+			}
+			future::result(Ok(()))
+		}));
+	}
+
+	fn handle_connection<S: AsyncRead + AsyncWrite + Send + 'static>(us: Arc<Self>, stream: S, addr: SocketAddr, listen_port: u16, timeouts: IdleTimeouts) {
+		// If we route on worker names we can't pick a group until mining.authorize
+		let group = match us.router {
+			Some(ref router) if router.needs_worker_name() => None,
+			Some(ref router) => Some(router.route(listen_port, &addr.ip(), None)),
+			None => Some(0),
+		};
 
+		let (stream, write_progress) = ProgressIo::new(stream);
 		let (tx, rx) = tokio_codec::Framed::new(stream, tokio_codec::LinesCodec::new()).split();
 
 		let client = {
 			let (send_sink, send_stream) = mpsc::channel(5);
-			tokio::spawn(tx.send_all(send_stream.map_err(|_| -> io::Error {
+			tokio::spawn(TimeoutSink::new(tx, timeouts.write, write_progress).send_all(send_stream.map_err(|_| -> io::Error {
 				unreachable!();
 			})).then(|_| {
 				future::result(Ok(()))
@@ -532,6 +588,10 @@ impl StratumServer {
 				stream: Mutex::new(send_sink),
 				needs_close: AtomicBool::new(false),
 				client_id: client_list.1,
+				addr,
+				listen_port,
+				group: AtomicUsize::new(group.unwrap_or(0)),
+				routed: AtomicBool::new(group.is_some()),
 				last_send: Mutex::new(Instant::now()),
 				subscribed: AtomicBool::new(false),
 				user_id: Mutex::new(None),
@@ -539,7 +599,10 @@ impl StratumServer {
 				cur_coinbase_postfix: Mutex::new(Vec::new()),
 				nicehash_quirks: AtomicBool::new(false),
 			});
-			println!("Got new client connection (id {})", client_list.1);
+			match (&us.router, group) {
+				(&Some(ref router), Some(group)) => println!("Got new client connection (id {}) from {} for group {}", client_list.1, client.addr, router.group_name(group)),
+				_ => println!("Got new client connection (id {}) from {}", client_list.1, client.addr),
+			}
 			client_list.1 += 1;
 
 			let client_ref = client.clone();
@@ -550,7 +613,7 @@ impl StratumServer {
 		let client_close = client.clone();
 		let us_close = us.clone();
 
-		tokio::spawn(TimeoutStream::new(rx, Duration::from_secs(60*10)).for_each(move |line| -> future::FutureResult<(), io::Error> {
+		tokio::spawn(TimeoutStream::new(rx, timeouts.read).for_each(move |line| -> future::FutureResult<(), io::Error> {
 			if client.needs_close.load(Ordering::Acquire) {
 				return future::result(Err(io::Error::new(io::ErrorKind::InvalidData, BadMessageError)));
 			}
@@ -583,6 +646,19 @@ impl StratumServer {
 				}
 			}
 
+			macro_rules! send_latest_job {
+				() => {
+					let jobs = us.jobs[client.group.load(Ordering::Acquire)].read().unwrap();
+					if let Some(job) = jobs.latest() {
+						let diff_string = job_to_difficulty_string(&job.template);
+						send_message!(diff_string);
+						let job_update_id = (us.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
+						send_message!(job_to_json_string_prefix(&job.template, job_update_id, 0) + &job_to_json_string_postfix(&job.template, true));
+						*client.last_send.lock().unwrap() = Instant::now();
+					}
+				}
+			}
+
 			macro_rules! send_response {
 				($err: expr, $res: tt) => {
 					let msg_str = json!({
@@ -615,16 +691,13 @@ impl StratumServer {
 					client.subscribed.store(true, Ordering::Release);
 
 					if us.user_auth_requests.is_none() {
-						let jobs = us.jobs.read().unwrap();
-						if let Some(job) = jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
-							let diff_string = job_to_difficulty_string(&job.1.template);
-							send_message!(diff_string);
-							let job_update_id = (us.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
-							send_message!(job_to_json_string_prefix(&job.1.template, job_update_id, 0) + &job_to_json_string_postfix(&job.1.template, true));
-							*client.last_send.lock().unwrap() = Instant::now();
+						// If we aren't routed yet we'll send work once mining.authorize tells
+						// us the worker name
+						if client.routed.load(Ordering::Acquire) {
+							send_latest_job!();
+							client.mining.store(true, Ordering::Release);
 						}
-					}
-					if us.user_auth_requests.is_none() || client.user_id.lock().unwrap().is_some() {
+					} else if client.user_id.lock().unwrap().is_some() {
 						// If user_id is_some we'll send this client work within a second on the
 						// timer event.
 						client.mining.store(true, Ordering::Release);
@@ -663,7 +736,7 @@ impl StratumServer {
 						Ok(nonce) => nonce,
 					};
 
-					let jobs = us.jobs.read().unwrap();
+					let jobs = us.jobs[client.group.load(Ordering::Acquire)].read().unwrap();
 					match jobs.get(&job_id) {
 						Some(job) => {
 							let version = if params.len() >= 6 {
@@ -871,11 +944,11 @@ impl StratumServer {
 
 							if let Some(user_coinbase_postfix) = job_user_coinbase_postfix {
 								if should_notify {
-									let jobs = us.jobs.read().unwrap();
-									if let Some(job) = jobs.iter().last() { //TODO: This is ineffecient, map should have a last()
+									let jobs = us.jobs[0].read().unwrap();
+									if let Some(job) = jobs.latest() {
 										let job_update_id = (us.job_update_id.fetch_add(1, Ordering::AcqRel) & 0xffffffff) as u32;
-										let job_prefix = job_to_json_string_prefix(&job.1.template, job_update_id, user_coinbase_postfix.len());
-										let job_postfix = job_to_json_string_postfix(&job.1.template, true);
+										let job_prefix = job_to_json_string_prefix(&job.template, job_update_id, user_coinbase_postfix.len());
+										let job_postfix = job_to_json_string_postfix(&job.template, true);
 										let job_string = job_prefix + &utils::bytes_to_hex(&user_coinbase_postfix) + &job_postfix;
 										send_message!(job_string);
 										*client.last_send.lock().unwrap() = Instant::now();
@@ -885,7 +958,28 @@ impl StratumServer {
 							}
 						},
 						&None => {
-							send_response!(serde_json::Value::Null, true);
+							if !client.routed.load(Ordering::Acquire) {
+								let router = us.router.as_ref().unwrap();
+								let worker = match msg["params"].as_array().and_then(|params| params.get(0)).and_then(|worker| worker.as_str()) {
+									Some(worker) => worker,
+									None => {
+										// We can't route them without a worker name, but they can try again
+										send_response!(serde_json::Value::Null, false);
+										return future::result(Ok(()));
+									},
+								};
+								send_response!(serde_json::Value::Null, true);
+								let group = router.route(client.listen_port, &client.addr.ip(), Some(worker));
+								println!("Routing client {} (worker {}) to group {}", client.client_id, worker, router.group_name(group));
+								client.group.store(group, Ordering::Release);
+								client.routed.store(true, Ordering::Release);
+								if client.subscribed.load(Ordering::Acquire) {
+									send_latest_job!();
+									client.mining.store(true, Ordering::Release);
+								}
+							} else {
+								send_response!(serde_json::Value::Null, true);
+							}
 						},
 					}
 				},
@@ -945,7 +1039,7 @@ impl StratumServer {
 				clients.0.retain(|client| {
 					!Arc::ptr_eq(&client_close, client)
 				});
-				println!("Client {} disconnected, now have {} clients!", client_close.client_id, clients.0.len());
+				println!("Client {} ({}) disconnected, now have {} clients!", client_close.client_id, client_close.addr, clients.0.len());
 			}
 			if let Some(user_id) = client_close.user_id.lock().unwrap().take() {
 				let mut users = us_close.users.lock().unwrap();
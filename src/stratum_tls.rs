@@ -0,0 +1,186 @@
+use futures::future;
+use futures::{Future,Stream};
+
+use native_tls;
+
+use tokio::net::TcpStream;
+
+use tokio_signal::unix::{Signal, SIGHUP};
+
+use tokio_tls::{Accept, TlsAcceptor};
+
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+/// The certificate and key our stratum+ssl listeners present, reloaded from disk on SIGHUP so
+/// that certificates can be renewed without dropping every connected client.
+pub struct StratumTls {
+	cert_path: String,
+	key_path: String,
+	acceptor: RwLock<TlsAcceptor>,
+}
+
+/// Loads a PEM certificate chain (leaf first) and PEM PKCS#8 key, printing why if we can't
+fn load_acceptor(cert_path: &str, key_path: &str) -> Option<TlsAcceptor> {
+	let cert = match fs::read(cert_path) {
+		Ok(cert) => cert,
+		Err(e) => {
+			println!("Failed to read TLS certificate from {}: {}", cert_path, e);
+			return None;
+		}
+	};
+	let key = match fs::read(key_path) {
+		Ok(key) => key,
+		Err(e) => {
+			println!("Failed to read TLS key from {}: {}", key_path, e);
+			return None;
+		}
+	};
+	let identity = match native_tls::Identity::from_pkcs8(&cert, &key) {
+		Ok(identity) => identity,
+		Err(e) => {
+			println!("Failed to parse TLS certificate {} with key {}: {}", cert_path, key_path, e);
+			return None;
+		}
+	};
+	match native_tls::TlsAcceptor::new(identity) {
+		Ok(acceptor) => Some(TlsAcceptor::from(acceptor)),
+		Err(e) => {
+			println!("Failed to set up TLS with certificate {}: {}", cert_path, e);
+			None
+		}
+	}
+}
+
+impl StratumTls {
+	pub fn load(cert_path: String, key_path: String) -> Option<Arc<Self>> {
+		let acceptor = load_acceptor(&cert_path, &key_path)?;
+		Some(Arc::new(Self {
+			cert_path,
+			key_path,
+			acceptor: RwLock::new(acceptor),
+		}))
+	}
+
+	/// Re-reads the certificate and key, keeping the old ones if the new ones are no good (eg
+	/// we caught them half-written). Existing connections keep whatever they negotiated.
+	pub fn reload(&self) {
+		match load_acceptor(&self.cert_path, &self.key_path) {
+			Some(acceptor) => {
+				*self.acceptor.write().unwrap() = acceptor;
+				println!("Reloaded TLS certificate from {}", self.cert_path);
+			},
+			None => println!("Keeping the TLS certificate we already had"),
+		}
+	}
+
+	/// Reloads every time we get a SIGHUP
+	pub fn reload_on_sighup(us: Arc<Self>) -> impl Future<Item=(), Error=()> {
+		Signal::new(SIGHUP).flatten_stream().for_each(move |_| {
+			us.reload();
+			Ok(())
+		}).then(|_| {
+			future::result(Ok(()))
+		})
+	}
+
+	pub fn accept(&self, stream: TcpStream) -> Accept<TcpStream> {
+		self.acceptor.read().unwrap().accept(stream)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use stratum_tls::*;
+
+	use tokio::net::TcpListener;
+	use tokio::runtime::current_thread::Runtime;
+
+	use tokio_signal::unix::Signal;
+
+	use std::{env, net, process, thread};
+	use std::path::PathBuf;
+	use std::time::Duration;
+
+	const CERT_A: &[u8] = include_bytes!("../contrib/test_tls/cert_a.pem");
+	const KEY_A: &[u8] = include_bytes!("../contrib/test_tls/key_a.pem");
+	const CERT_B: &[u8] = include_bytes!("../contrib/test_tls/cert_b.pem");
+	const KEY_B: &[u8] = include_bytes!("../contrib/test_tls/key_b.pem");
+
+	fn der(pem: &[u8]) -> Vec<u8> {
+		native_tls::Certificate::from_pem(pem).unwrap().to_der().unwrap()
+	}
+
+	fn write_files(dir: &PathBuf, cert: &[u8], key: &[u8]) -> (String, String) {
+		fs::write(dir.join("cert.pem"), cert).unwrap();
+		fs::write(dir.join("key.pem"), key).unwrap();
+		(dir.join("cert.pem").to_str().unwrap().to_string(), dir.join("key.pem").to_str().unwrap().to_string())
+	}
+
+	/// Does a TLS handshake with tls, returning the (DER) certificate it presented
+	fn served_cert(rt: &mut Runtime, tls: &StratumTls) -> Vec<u8> {
+		let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = thread::spawn(move || {
+			let connector = native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build().unwrap();
+			let stream = connector.connect("localhost", net::TcpStream::connect(addr).unwrap()).unwrap();
+			stream.peer_certificate().unwrap().unwrap().to_der().unwrap()
+		});
+		let stream = match rt.block_on(listener.incoming().into_future()) {
+			Ok((Some(stream), _)) => stream,
+			_ => panic!(),
+		};
+		// accept starts the handshake right away, so it needs to be called from within a task
+		rt.block_on(future::lazy(|| tls.accept(stream))).unwrap();
+		client.join().unwrap()
+	}
+
+	#[test]
+	fn test_load_reload() {
+		let dir = env::temp_dir().join(format!("stratum-tls-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let mut rt = Runtime::new().unwrap();
+
+		let missing = dir.join("missing.pem").to_str().unwrap().to_string();
+		let (cert, key) = write_files(&dir, CERT_A, KEY_A);
+		assert!(StratumTls::load(missing.clone(), key.clone()).is_none());
+		assert!(StratumTls::load(cert.clone(), missing).is_none());
+		let tls = StratumTls::load(cert.clone(), key.clone()).unwrap();
+		assert_eq!(served_cert(&mut rt, &tls), der(CERT_A));
+
+		// We keep what we had if we catch a renewal half-written
+		fs::write(&cert, &CERT_B[..CERT_B.len() / 2]).unwrap();
+		tls.reload();
+		assert_eq!(served_cert(&mut rt, &tls), der(CERT_A));
+
+		write_files(&dir, CERT_B, KEY_B);
+		tls.reload();
+		assert_eq!(served_cert(&mut rt, &tls), der(CERT_B));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_reload_on_sighup() {
+		let dir = env::temp_dir().join(format!("stratum-tls-sighup-test-{}", process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let mut rt = Runtime::new().unwrap();
+
+		// Make sure SIGHUP is handled before we send any, as by default it kills us
+		let _sighup = rt.block_on(Signal::new(SIGHUP)).unwrap();
+		let (cert, key) = write_files(&dir, CERT_A, KEY_A);
+		let tls = StratumTls::load(cert, key).unwrap();
+		rt.spawn(StratumTls::reload_on_sighup(tls.clone()));
+		assert_eq!(served_cert(&mut rt, &tls), der(CERT_A));
+
+		write_files(&dir, CERT_B, KEY_B);
+		for _ in 0..50 {
+			assert!(process::Command::new("kill").arg("-HUP").arg(process::id().to_string()).status().unwrap().success());
+			thread::sleep(Duration::from_millis(100));
+			if served_cert(&mut rt, &tls) == der(CERT_B) { break; }
+		}
+		assert_eq!(served_cert(&mut rt, &tls), der(CERT_B));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
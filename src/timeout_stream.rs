@@ -1,8 +1,16 @@
 use futures::stream::Stream;
-use futures::{Async, Future};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
 
 use tokio::timer::Delay;
 
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use utils;
+
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 pub struct TimeoutStream<S> where S : Stream {
@@ -42,3 +50,125 @@ impl<S> Stream for TimeoutStream<S> where S : Stream {
 		}
 	}
 }
+
+/// How many bytes have been written to a ProgressIo
+#[derive(Clone)]
+pub struct WriteProgress(Arc<AtomicUsize>);
+
+/// Wraps a socket, counting what's written to it so that a TimeoutSink on top of a Framed (which
+/// hides its write buffer) can tell a slow client that's still reading from a stuck one.
+pub struct ProgressIo<S> {
+	io: S,
+	written: WriteProgress,
+}
+
+impl<S> ProgressIo<S> {
+	pub fn new(io: S) -> (Self, WriteProgress) {
+		let written = WriteProgress(Arc::new(AtomicUsize::new(0)));
+		(Self { io, written: written.clone() }, written)
+	}
+}
+
+impl<S: Read> Read for ProgressIo<S> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.io.read(buf)
+	}
+}
+impl<S: Write> Write for ProgressIo<S> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.io.write(buf)?;
+		self.written.0.fetch_add(written, Ordering::Relaxed);
+		Ok(written)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		self.io.flush()
+	}
+}
+impl<S: AsyncRead> AsyncRead for ProgressIo<S> {}
+impl<S: AsyncWrite> AsyncWrite for ProgressIo<S> {
+	fn shutdown(&mut self) -> Poll<(), io::Error> {
+		self.io.shutdown()
+	}
+}
+
+/// Wraps a Sink, failing with TimedOut once a write has been stuck (ie the other end stopped
+/// reading and the socket buffers filled up) for longer than timeout. Any progress writing to the
+/// underlying ProgressIo restarts the clock.
+pub struct TimeoutSink<S> where S : Sink<SinkError = io::Error> {
+	sink: S,
+	stuck_deadline: Option<Delay>,
+	timeout: Duration,
+	progress: WriteProgress,
+	written_at_deadline: usize,
+}
+
+impl<S> TimeoutSink<S> where S : Sink<SinkError = io::Error> {
+	pub fn new(sink: S, timeout: Duration, progress: WriteProgress) -> Self {
+		Self {
+			sink,
+			stuck_deadline: None,
+			timeout,
+			progress,
+			written_at_deadline: 0,
+		}
+	}
+
+	fn check_stuck(&mut self) -> Result<(), io::Error> {
+		let written = self.progress.0.load(Ordering::Relaxed);
+		if self.stuck_deadline.is_none() || written != self.written_at_deadline {
+			self.stuck_deadline = Some(Delay::new(Instant::now() + self.timeout));
+			self.written_at_deadline = written;
+		}
+		match self.stuck_deadline.as_mut().unwrap().poll() {
+			Ok(Async::NotReady) => Ok(()),
+			_ => Err(io::Error::new(io::ErrorKind::TimedOut, utils::HandleError)),
+		}
+	}
+}
+
+impl<S> Sink for TimeoutSink<S> where S : Sink<SinkError = io::Error> {
+	type SinkItem = S::SinkItem;
+	type SinkError = io::Error;
+	fn start_send(&mut self, item: S::SinkItem) -> StartSend<S::SinkItem, io::Error> {
+		match self.sink.start_send(item)? {
+			AsyncSink::Ready => Ok(AsyncSink::Ready),
+			AsyncSink::NotReady(item) => {
+				self.check_stuck()?;
+				Ok(AsyncSink::NotReady(item))
+			},
+		}
+	}
+	fn poll_complete(&mut self) -> Poll<(), io::Error> {
+		match self.sink.poll_complete()? {
+			Async::Ready(()) => {
+				self.stuck_deadline = None;
+				Ok(Async::Ready(()))
+			},
+			Async::NotReady => {
+				self.check_stuck()?;
+				Ok(Async::NotReady)
+			},
+		}
+	}
+	fn close(&mut self) -> Poll<(), io::Error> {
+		self.sink.close()
+	}
+}
+
+/// Idle timeouts for connections accepted on one listener
+#[derive(Clone, Copy)]
+pub struct IdleTimeouts {
+	/// Disconnect if we haven't received anything from the client in this long
+	pub read: Duration,
+	/// Disconnect if a write to the client has been stuck for this long
+	pub write: Duration,
+}
+
+impl Default for IdleTimeouts {
+	fn default() -> Self {
+		Self {
+			read: Duration::from_secs(60*10),
+			write: Duration::from_secs(60*2),
+		}
+	}
+}
@@ -0,0 +1,436 @@
+use bytes;
+use bytes::BufMut;
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::digest::Digest;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+
+use rand::OsRng;
+
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+
+use utils;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Set in ProtocolSupport.flags by clients which can encrypt the rest of the connection, and in
+/// ProtocolVersion.flags by servers which agree to. The two then run a Noise NK handshake, with the
+/// auth_key the server gave in ProtocolVersion as the server's static key: the client sends
+/// EncryptionStart with a fresh key, the server replies with an EncryptionStart carrying its own
+/// fresh key, and everything after that (in both directions) is encrypted. Only the holder of the
+/// auth key can read or write the connection, and as both sides' fresh keys go into the
+/// connection's keys, a leaked auth key doesn't decrypt recorded connections, nor can a recorded
+/// connection be replayed.
+pub const ENCRYPTION_FLAG: u16 = 0b100;
+
+/// The Noise protocol name, which (hashed, as it's longer than 32 bytes) starts the handshake hash.
+/// DH results are the SHA256 of the compressed shared point, as libsecp256k1's ECDH gives us.
+const PROTOCOL_NAME: &[u8] = b"Noise_NK_secp256k1_ChaChaPoly_SHA256";
+
+/// Encrypted frames are the length of the encrypted message (which is also authenticated), the
+/// encrypted message and a tag.
+const FRAME_LEN_BYTES: usize = 4;
+pub const TAG_LEN: usize = 16;
+/// Messages have a 3-byte length, plus the type and length themselves
+const MAX_MESSAGE_LEN: usize = 0xffffff + 4;
+
+struct CipherState {
+	key: [u8; 32],
+	nonce: u64,
+}
+
+impl CipherState {
+	fn new(key: &[u8]) -> Self {
+		let mut our_key = [0; 32];
+		our_key.copy_from_slice(key);
+		Self { key: our_key, nonce: 0 }
+	}
+
+	fn next_cipher(&mut self, frame_len: &[u8]) -> ChaCha20Poly1305 {
+		let nonce = utils::le64_to_array(self.nonce);
+		self.nonce += 1;
+		ChaCha20Poly1305::new(&self.key, &nonce, frame_len)
+	}
+}
+
+/// Noise's HKDF, giving two keys from the chaining key and some input key material
+fn hkdf2(chaining_key: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+	let mut prk = [0; 32];
+	hkdf_extract(Sha256::new(), chaining_key, ikm, &mut prk);
+	let mut keys = [0; 64];
+	hkdf_expand(Sha256::new(), &prk, &[], &mut keys);
+	let (mut first, mut second) = ([0; 32], [0; 32]);
+	first.copy_from_slice(&keys[..32]);
+	second.copy_from_slice(&keys[32..]);
+	(first, second)
+}
+
+/// Noise's SymmetricState: the chaining key, the hash of the handshake so far, and the key for
+/// handshake payloads once we've done a DH. Our handshake payloads are empty, so they're just a
+/// tag authenticating the handshake hash.
+struct SymmetricState {
+	chaining_key: [u8; 32],
+	hash: [u8; 32],
+	key: Option<[u8; 32]>,
+}
+
+impl SymmetricState {
+	/// Starts an NK handshake, in which the client already knows the server's static key
+	fn new(server_key: &PublicKey) -> Self {
+		let mut hash = [0; 32];
+		let mut sha = Sha256::new();
+		sha.input(PROTOCOL_NAME);
+		sha.result(&mut hash);
+		let mut state = Self { chaining_key: hash, hash, key: None };
+		state.mix_hash(&[]); // Empty prologue
+		state.mix_hash(&server_key.serialize());
+		state
+	}
+
+	fn mix_hash(&mut self, data: &[u8]) {
+		let mut sha = Sha256::new();
+		sha.input(&self.hash);
+		sha.input(data);
+		sha.result(&mut self.hash);
+	}
+
+	fn mix_key(&mut self, secp_ctx: &Secp256k1, their_key: &PublicKey, our_key: &SecretKey) {
+		let shared_secret = SharedSecret::new(secp_ctx, their_key, our_key);
+		let (chaining_key, key) = hkdf2(&self.chaining_key, &shared_secret[..]);
+		self.chaining_key = chaining_key;
+		self.key = Some(key);
+	}
+
+	/// Encrypts our (empty) handshake payload, returning its tag
+	fn encrypt_and_hash(&mut self) -> [u8; TAG_LEN] {
+		let mut tag = [0; TAG_LEN];
+		CipherState::new(&self.key.unwrap()).next_cipher(&self.hash).encrypt(&[], &mut [], &mut tag);
+		self.mix_hash(&tag);
+		tag
+	}
+
+	/// Checks the tag of the other side's (empty) handshake payload
+	fn decrypt_and_hash(&mut self, tag: &[u8; TAG_LEN]) -> bool {
+		if !CipherState::new(&self.key.unwrap()).next_cipher(&self.hash).decrypt(&[], &mut [], tag) {
+			return false;
+		}
+		self.mix_hash(tag);
+		true
+	}
+
+	/// Returns the (client-to-server, server-to-client) ciphers
+	fn split(&self) -> (CipherState, CipherState) {
+		let (client_to_server, server_to_client) = hkdf2(&self.chaining_key, &[]);
+		(CipherState::new(&client_to_server), CipherState::new(&server_to_client))
+	}
+}
+
+fn generate_keypair(secp_ctx: &Secp256k1) -> Option<(SecretKey, PublicKey)> {
+	let mut rng = match OsRng::new() {
+		Ok(rng) => rng,
+		Err(e) => {
+			println!("Failed to get randomness for an encrypted connection: {}", e);
+			return None;
+		}
+	};
+	secp_ctx.generate_keypair(&mut rng).ok()
+}
+
+struct TransportState {
+	/// A client's fresh key and handshake state while it waits for the server's EncryptionStart
+	client_handshake: Option<(SecretKey, SymmetricState)>,
+	/// The cipher a server has derived but only starts sending with once its EncryptionStart is
+	/// written
+	pending_send: Option<CipherState>,
+	send: Option<CipherState>,
+	recv: Option<CipherState>,
+}
+
+/// Shared between a connection's framer and whatever handles its messages, letting the handler
+/// turn on encryption part way through the connection (ie once the version handshake is done).
+#[derive(Clone)]
+pub struct TransportCrypto {
+	state: Arc<Mutex<TransportState>>,
+}
+
+impl TransportCrypto {
+	pub fn new() -> Self {
+		Self {
+			state: Arc::new(Mutex::new(TransportState {
+				client_handshake: None,
+				pending_send: None,
+				send: None,
+				recv: None,
+			})),
+		}
+	}
+
+	/// Picks our key for the connection, returning it (and our handshake payload's tag) to be sent
+	/// in EncryptionStart. Nothing else may be sent until the server's EncryptionStart arrives, to
+	/// be passed to finish_client.
+	// We never connect out in sample-pool
+	#[allow(dead_code)]
+	pub fn start_client(&self, secp_ctx: &Secp256k1, server_key: &PublicKey) -> Option<(PublicKey, [u8; TAG_LEN])> {
+		let (our_key, our_pubkey) = generate_keypair(secp_ctx)?;
+		let mut handshake = SymmetricState::new(server_key);
+		handshake.mix_hash(&our_pubkey.serialize());
+		handshake.mix_key(secp_ctx, server_key, &our_key);
+		let tag = handshake.encrypt_and_hash();
+		self.state.lock().unwrap().client_handshake = Some((our_key, handshake));
+		Some((our_pubkey, tag))
+	}
+
+	/// Called with the key and tag from the server's EncryptionStart, from which point everything
+	/// we send and receive is encrypted. Returns false if the server failed to authenticate (or we
+	/// weren't waiting for it).
+	// We never connect out in sample-pool
+	#[allow(dead_code)]
+	pub fn finish_client(&self, secp_ctx: &Secp256k1, server_ephemeral_key: &PublicKey, tag: &[u8; TAG_LEN]) -> bool {
+		let mut state = self.state.lock().unwrap();
+		let (our_key, mut handshake) = match state.client_handshake.take() {
+			Some(handshake) => handshake,
+			None => return false,
+		};
+		handshake.mix_hash(&server_ephemeral_key.serialize());
+		handshake.mix_key(secp_ctx, server_ephemeral_key, &our_key);
+		if !handshake.decrypt_and_hash(tag) {
+			println!("Server's EncryptionStart failed to authenticate");
+			return false;
+		}
+		let (client_to_server, server_to_client) = handshake.split();
+		state.send = Some(client_to_server);
+		state.recv = Some(server_to_client);
+		true
+	}
+
+	/// Called with the key and tag from the client's EncryptionStart (and the secret for the
+	/// auth_key we sent in ProtocolVersion). Returns our key and tag to reply with in our own
+	/// EncryptionStart, or None if the client failed to authenticate or we'd already started.
+	/// Everything we receive from here on is encrypted, as is everything we send after our reply.
+	pub fn start_server(&self, secp_ctx: &Secp256k1, our_static_key: &SecretKey, client_key: &PublicKey, tag: &[u8; TAG_LEN]) -> Option<(PublicKey, [u8; TAG_LEN])> {
+		let mut state = self.state.lock().unwrap();
+		if state.recv.is_some() { return None; }
+		let mut handshake = SymmetricState::new(&PublicKey::from_secret_key(secp_ctx, our_static_key).unwrap());
+		handshake.mix_hash(&client_key.serialize());
+		handshake.mix_key(secp_ctx, client_key, our_static_key);
+		if !handshake.decrypt_and_hash(tag) {
+			println!("Client's EncryptionStart failed to authenticate");
+			return None;
+		}
+		let (our_key, our_pubkey) = generate_keypair(secp_ctx)?;
+		handshake.mix_hash(&our_pubkey.serialize());
+		handshake.mix_key(secp_ctx, client_key, &our_key);
+		let our_tag = handshake.encrypt_and_hash();
+		let (client_to_server, server_to_client) = handshake.split();
+		state.recv = Some(client_to_server);
+		state.pending_send = Some(server_to_client);
+		Some((our_pubkey, our_tag))
+	}
+
+	/// Called by the framer once it's written an (unencrypted) EncryptionStart. A server starts
+	/// encrypting what it sends after that, a client waits for finish_client.
+	pub fn sent_encryption_start(&self) -> Result<(), io::Error> {
+		let mut state = self.state.lock().unwrap();
+		if let Some(server_to_client) = state.pending_send.take() {
+			state.send = Some(server_to_client);
+			Ok(())
+		} else if state.client_handshake.is_some() {
+			Ok(())
+		} else {
+			Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))
+		}
+	}
+
+	pub fn is_sending_encrypted(&self) -> bool {
+		self.state.lock().unwrap().send.is_some()
+	}
+
+	pub fn is_receiving_encrypted(&self) -> bool {
+		self.state.lock().unwrap().recv.is_some()
+	}
+
+	/// Encrypts an encoded message into res
+	pub fn seal(&self, msg: &[u8], res: &mut bytes::BytesMut) {
+		let mut state = self.state.lock().unwrap();
+		let cipher_state = state.send.as_mut().unwrap();
+		let frame_len = utils::le32_to_array(msg.len() as u32);
+		let mut encrypted = vec![0; msg.len()];
+		let mut tag = [0; TAG_LEN];
+		cipher_state.next_cipher(&frame_len).encrypt(msg, &mut encrypted, &mut tag);
+		res.reserve(FRAME_LEN_BYTES + msg.len() + TAG_LEN);
+		res.put_slice(&frame_len);
+		res.put_slice(&encrypted);
+		res.put_slice(&tag);
+	}
+
+	/// Takes the next encrypted frame off of bytes and returns the encoded message in it, if we
+	/// have a full one.
+	pub fn open(&self, bytes: &mut bytes::BytesMut) -> Result<Option<bytes::BytesMut>, io::Error> {
+		if bytes.len() < FRAME_LEN_BYTES { return Ok(None); }
+		let msg_len = utils::slice_to_le32(&bytes[..FRAME_LEN_BYTES]) as usize;
+		if msg_len > MAX_MESSAGE_LEN {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+		}
+		if bytes.len() < FRAME_LEN_BYTES + msg_len + TAG_LEN { return Ok(None); }
+		let frame = bytes.split_to(FRAME_LEN_BYTES + msg_len + TAG_LEN);
+
+		let mut state = self.state.lock().unwrap();
+		let cipher_state = state.recv.as_mut().unwrap();
+		let mut msg = vec![0; msg_len];
+		if !cipher_state.next_cipher(&frame[..FRAME_LEN_BYTES]).decrypt(&frame[FRAME_LEN_BYTES..FRAME_LEN_BYTES + msg_len], &mut msg, &frame[FRAME_LEN_BYTES + msg_len..]) {
+			println!("Got an encrypted message which failed to authenticate");
+			return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+		}
+		Ok(Some(bytes::BytesMut::from(msg)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use transport_crypto::*;
+
+	fn server_key(secp_ctx: &Secp256k1) -> SecretKey {
+		SecretKey::from_slice(secp_ctx, &[42; 32]).unwrap()
+	}
+
+	fn connected_pair(secp_ctx: &Secp256k1) -> (TransportCrypto, TransportCrypto) {
+		let server_key = server_key(secp_ctx);
+		let (client, server) = (TransportCrypto::new(), TransportCrypto::new());
+		let (client_ephemeral, client_tag) = client.start_client(secp_ctx, &PublicKey::from_secret_key(secp_ctx, &server_key).unwrap()).unwrap();
+		client.sent_encryption_start().unwrap();
+		assert!(!client.is_sending_encrypted());
+
+		let (server_ephemeral, server_tag) = server.start_server(secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		assert!(server.start_server(secp_ctx, &server_key, &client_ephemeral, &client_tag).is_none());
+		assert!(server.is_receiving_encrypted() && !server.is_sending_encrypted());
+		server.sent_encryption_start().unwrap();
+		assert!(server.is_sending_encrypted());
+
+		assert!(client.finish_client(secp_ctx, &server_ephemeral, &server_tag));
+		assert!(!client.finish_client(secp_ctx, &server_ephemeral, &server_tag));
+		assert!(client.is_sending_encrypted() && client.is_receiving_encrypted());
+		(client, server)
+	}
+
+	#[test]
+	fn test_seal_open() {
+		let secp_ctx = Secp256k1::new();
+		let (client, server) = connected_pair(&secp_ctx);
+
+		let mut buf = bytes::BytesMut::new();
+		client.seal(b"to the server", &mut buf);
+		client.seal(b"", &mut buf);
+		assert_eq!(buf.len(), (4 + 13 + 16) + (4 + 16));
+		assert_eq!(&server.open(&mut buf).unwrap().unwrap()[..], b"to the server");
+		assert!(server.open(&mut buf).unwrap().unwrap().is_empty());
+		assert!(buf.is_empty());
+
+		// Nothing is taken (or nonce used up) until we have a whole frame
+		client.seal(b"in pieces", &mut buf);
+		let rest = buf.split_off(10);
+		assert!(server.open(&mut buf).unwrap().is_none());
+		assert_eq!(buf.len(), 10);
+		buf.unsplit(rest);
+		assert_eq!(&server.open(&mut buf).unwrap().unwrap()[..], b"in pieces");
+
+		// Each direction has its own key, so we can't read what we sent
+		server.seal(b"to the client", &mut buf);
+		let mut echoed = buf.clone();
+		assert_eq!(&client.open(&mut buf).unwrap().unwrap()[..], b"to the client");
+		assert!(server.open(&mut echoed).is_err());
+
+		// Can't send EncryptionStart without having picked our key first
+		assert!(TransportCrypto::new().sent_encryption_start().is_err());
+	}
+
+	#[test]
+	fn test_tampering() {
+		let secp_ctx = Secp256k1::new();
+		// The length, the message and the tag are all authenticated
+		for tampered_byte in [0, 4, 4 + 5, 4 + 11 + 3].iter() {
+			let (client, server) = connected_pair(&secp_ctx);
+			let mut buf = bytes::BytesMut::new();
+			client.seal(b"don't touch", &mut buf);
+			buf[*tampered_byte] ^= 1;
+			assert!(server.open(&mut buf).is_err());
+		}
+
+		let (_, server) = connected_pair(&secp_ctx);
+		let mut buf = bytes::BytesMut::from(vec![0xff; 4]);
+		assert!(server.open(&mut buf).is_err());
+	}
+
+	#[test]
+	fn test_nonces() {
+		let secp_ctx = Secp256k1::new();
+		let (client, server) = connected_pair(&secp_ctx);
+		let mut first = bytes::BytesMut::new();
+		let mut second = bytes::BytesMut::new();
+		client.seal(b"same message", &mut first);
+		client.seal(b"same message", &mut second);
+		assert_ne!(first, second);
+		let mut replayed = first.clone();
+		assert_eq!(&server.open(&mut first).unwrap().unwrap()[..], b"same message");
+		assert!(server.open(&mut replayed).is_err());
+
+		// Frames can't be dropped or reordered either
+		let (client, server) = connected_pair(&secp_ctx);
+		let mut first = bytes::BytesMut::new();
+		let mut second = bytes::BytesMut::new();
+		client.seal(b"first", &mut first);
+		client.seal(b"second", &mut second);
+		assert!(server.open(&mut second).is_err());
+	}
+
+	#[test]
+	fn test_handshake_authentication() {
+		let secp_ctx = Secp256k1::new();
+		let server_key = server_key(&secp_ctx);
+		let server_pubkey = PublicKey::from_secret_key(&secp_ctx, &server_key).unwrap();
+
+		// Only the holder of the server key can complete the handshake
+		let client = TransportCrypto::new();
+		let (client_ephemeral, client_tag) = client.start_client(&secp_ctx, &server_pubkey).unwrap();
+		let other_key = SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap();
+		assert!(TransportCrypto::new().start_server(&secp_ctx, &other_key, &client_ephemeral, &client_tag).is_none());
+		let mut bad_tag = client_tag;
+		bad_tag[0] ^= 1;
+		assert!(TransportCrypto::new().start_server(&secp_ctx, &server_key, &client_ephemeral, &bad_tag).is_none());
+
+		// Nor can the reply be swapped for another one
+		let (server_ephemeral, server_tag) = TransportCrypto::new().start_server(&secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		let (other_ephemeral, _) = TransportCrypto::new().start_server(&secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		assert!(!client.finish_client(&secp_ctx, &other_ephemeral, &server_tag));
+		let client = TransportCrypto::new();
+		let (client_ephemeral, client_tag) = client.start_client(&secp_ctx, &server_pubkey).unwrap();
+		let (_, server_tag) = TransportCrypto::new().start_server(&secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		assert!(!client.finish_client(&secp_ctx, &server_ephemeral, &server_tag));
+	}
+
+	#[test]
+	fn test_replay() {
+		let secp_ctx = Secp256k1::new();
+		let server_key = server_key(&secp_ctx);
+		let client = TransportCrypto::new();
+		let (client_ephemeral, client_tag) = client.start_client(&secp_ctx, &PublicKey::from_secret_key(&secp_ctx, &server_key).unwrap()).unwrap();
+		let server = TransportCrypto::new();
+		let (server_ephemeral, server_tag) = server.start_server(&secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		server.sent_encryption_start().unwrap();
+		assert!(client.finish_client(&secp_ctx, &server_ephemeral, &server_tag));
+		let mut recorded = bytes::BytesMut::new();
+		client.seal(b"recorded", &mut recorded);
+		assert_eq!(&server.open(&mut recorded.clone()).unwrap().unwrap()[..], b"recorded");
+
+		// Replaying the client's side of the connection to the server gets a new server key, and so
+		// different keys for the rest of the connection
+		let replayed_to = TransportCrypto::new();
+		let (replayed_ephemeral, _) = replayed_to.start_server(&secp_ctx, &server_key, &client_ephemeral, &client_tag).unwrap();
+		assert_ne!(replayed_ephemeral, server_ephemeral);
+		assert!(replayed_to.open(&mut recorded).is_err());
+	}
+}
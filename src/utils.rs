@@ -164,6 +164,27 @@ pub fn bytes_to_hex(bytes: &[u8]) -> String {
 	ret
 }
 
+#[allow(dead_code)]
+pub fn hex_to_vec(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 { return None; }
+
+	let mut out = Vec::with_capacity(hex.len() / 2);
+	let mut b = 0;
+	for (idx, c) in hex.chars().enumerate() {
+		b <<= 4;
+		b |= match c.to_digit(16) {
+			Some(d) => d as u8,
+			None => return None,
+		};
+		if (idx & 1) == 1 {
+			out.push(b);
+			b = 0;
+		}
+	}
+
+	Some(out)
+}
+
 #[derive(Debug)]
 pub struct HandleError;
 impl std::fmt::Display for HandleError {
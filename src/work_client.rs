@@ -1,10 +1,21 @@
+use auth_keys::{KnownHosts, PinnedAuthKeys};
+use block_assembly;
+use block_assembly::BlockSubmitter;
 use connection_maintainer::*;
 use msg_framing::*;
+use replay_provider;
+use shutdown::Shutdown;
+use socks5;
+use socks5::Socks5Proxy;
+use transport_crypto::{ENCRYPTION_FLAG, TransportCrypto};
 use utils;
 
 use futures::sync::{mpsc,oneshot};
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize;
+use bitcoin::network::serialize::BitcoinHash;
 
 use bytes;
 use bytes::BufMut;
@@ -13,6 +24,7 @@ use futures::future;
 use futures::{Future,Stream,Sink};
 
 use tokio;
+use tokio::timer;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
@@ -21,10 +33,10 @@ use secp256k1;
 use secp256k1::key::PublicKey;
 use secp256k1::Secp256k1;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 
 /// A future, essentially
 pub struct EventualTxData {
@@ -74,69 +86,253 @@ impl EventualTxData {
 	}
 }
 
+/// How long (by our clock) after a newer template arrives we keep the TransactionData for an old
+/// one around. Our servers drop superseded jobs after 30 seconds, so nobody should need it after
+/// that.
+const TX_DATA_CACHE_EXPIRY_MS: u64 = 30 * 1000;
+/// Full blocks are rare enough that more than this many waiting on a reconnect means something
+/// has gone very wrong
+const MAX_PENDING_NONCES: usize = 16;
+
 #[derive(Clone)]
 pub struct WorkProviderJob {
 	pub template: BlockTemplate,
 	pub coinbase_prefix_postfix: Option<CoinbasePrefixPostfix>,
 	pub tx_data: Arc<EventualTxData>,
 	pub provider: Arc<JobProviderHandler>,
+	pub submitter: Arc<BlockSubmitter>,
 }
 
 enum WorkProviderAction {
 	ProviderDisconnected,
 	JobUpdate { job: WorkProviderJob },
+	/// The provider sent a template which didn't match its TransactionData
+	BadTemplateData,
+}
+
+/// How long we ignore a job provider's templates after one didn't match its transaction data
+const BAD_TEMPLATE_DATA_BACKOFF_SECS: u64 = 600;
+
+/// Checks that a template's merkle branch and witness commitment commit to the transactions the
+/// provider sent for it. We can't recompute fees without the outputs they spend, so
+/// coinbase_value_remaining is only checked against the subsidy when there are no transactions,
+/// the rest is left to BlockSubmitter::check_template.
+fn check_template_tx_data(template: &BlockTemplate, data: &TransactionData) -> Result<(), &'static str> {
+	// The coinbase's (w)txid doesn't matter for its merkle branch and is defined as 0 in the
+	// witness merkle tree
+	let mut txids = vec![[0; 32]];
+	let mut wtxids = vec![[0; 32]];
+	let mut has_witness = false;
+	for tx in data.transactions.iter() {
+		let tx_deser: Transaction = match serialize::deserialize(tx) {
+			Ok(tx) => tx,
+			Err(_) => return Err("unparseable transaction"),
+		};
+		if tx_deser.input.is_empty() || (tx_deser.input[0].prev_hash == Default::default() && tx_deser.input[0].prev_index == 0xffffffff) {
+			return Err("coinbase-like transaction");
+		}
+		has_witness |= tx_deser.input.iter().any(|input| !input.witness.is_empty());
+		let mut txid = [0; 32];
+		txid.copy_from_slice(&tx_deser.txid()[..]);
+		txids.push(txid);
+		let mut wtxid = [0; 32];
+		wtxid.copy_from_slice(&tx_deser.bitcoin_hash()[..]);
+		wtxids.push(wtxid);
+	}
+
+	if block_assembly::coinbase_merkle_branch(txids) != template.merkle_rhss {
+		return Err("merkle branch mismatch");
+	}
+
+	match block_assembly::witness_commitment_output(template) {
+		Some(output) => {
+			while wtxids.len() > 1 {
+				wtxids = block_assembly::merkle_level_up(&wtxids);
+			}
+			// bitcoind always uses an all-0 witness reserved value
+			let mut commitment_preimage = [0; 64];
+			commitment_preimage[..32].copy_from_slice(&wtxids[0]);
+			if output.script_pubkey[6..38] != block_assembly::sha256d(&commitment_preimage) {
+				return Err("witness commitment mismatch");
+			}
+		},
+		None => if has_witness {
+			return Err("witness transactions without a witness commitment");
+		},
+	}
+
+	if data.transactions.is_empty() {
+		let subsidy = match template_height(template) / 210_000 {
+			halvings if halvings < 64 => (50 * 100_000_000) >> halvings,
+			_ => 0,
+		};
+		let claimed = template.appended_coinbase_outputs.iter().fold(template.coinbase_value_remaining, |sum, output| sum.saturating_add(output.value));
+		if claimed > subsidy {
+			return Err("coinbase value above subsidy with no fee-paying transactions");
+		}
+	}
+	Ok(())
 }
 
 struct JobProviderState {
 	stream: Option<mpsc::UnboundedSender<WorkMessage>>,
-	auth_key: Option<PublicKey>,
+	/// Our end of the connection while we wait for the provider's EncryptionStart. Nothing else may
+	/// be sent until then, so in the meantime stream is None and full-block nonces are queued as if
+	/// we were disconnected.
+	handshake_stream: Option<mpsc::UnboundedSender<WorkMessage>>,
+	transport: TransportCrypto,
+	auth_keys: PinnedAuthKeys,
 
 	cur_template: Option<BlockTemplate>,
 	cur_prefix_postfix: Option<CoinbasePrefixPostfix>,
 
-	pending_tx_data_requests: HashMap<u64, oneshot::Sender<TransactionData>>,
+	/// Along with the template each was for, so we can check the data against it
+	pending_tx_data_requests: HashMap<u64, (oneshot::Sender<TransactionData>, BlockTemplate)>,
+	/// Full-block nonces we found while disconnected, sent once we reconnect (the provider may
+	/// still have the template around)
+	pending_nonces: Vec<WinningNonce>,
+	/// TransactionData for recent templates by template_timestamp, shared by every job built from
+	/// the same template so that CoinbasePrefixPostfix updates don't require a new request, along
+	/// with our local time when a newer template replaced it (if one has)
+	tx_data_cache: BTreeMap<u64, (Arc<EventualTxData>, Option<u64>)>,
 	job_stream: mpsc::Sender<WorkProviderAction>,
+
+	/// Fired once we've flushed any full-block nonces after shutdown starts
+	flush_complete: Option<oneshot::Sender<()>>,
+	/// Where a NewWorkServer asked us to move to, taken once the connection closes
+	redirect: Option<String>,
+	/// Set if we replay templates from a file instead of connecting to anything
+	replayed: bool,
+}
+
+impl JobProviderState {
+	/// Called once the version handshake (and any encryption handshake) is done. Anything we
+	/// couldn't get to (or hear back from) the provider before we were last disconnected may still
+	/// be of use to it, or to us.
+	fn handshake_complete(&mut self) {
+		let stream = self.stream.as_ref().unwrap().clone();
+		for nonces in self.pending_nonces.drain(..) {
+			println!("Submitting job-matching (ie full-block) nonce found while we were disconnected");
+			let _ = stream.unbounded_send(WorkMessage::WinningNonce { nonces });
+		}
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		let timestamp = time.as_secs() * 1000 + time.subsec_nanos() as u64 / 1_000_000;
+		self.pending_tx_data_requests.retain(|template_timestamp, _| *template_timestamp >= timestamp.saturating_sub(TX_DATA_CACHE_EXPIRY_MS));
+		for template_timestamp in self.pending_tx_data_requests.keys() {
+			let _ = stream.unbounded_send(WorkMessage::TransactionDataRequest { template_timestamp: *template_timestamp });
+		}
+	}
 }
 
 pub struct JobProviderHandler {
 	state: Mutex<JobProviderState>,
+	require_encryption: bool,
+	submitter: Arc<BlockSubmitter>,
 	secp_ctx: Secp256k1,
+	shutdown: Arc<Shutdown>,
 }
 
 impl JobProviderHandler {
-	fn new(expected_auth_key: Option<PublicKey>) -> (Arc<JobProviderHandler>, mpsc::Receiver<WorkProviderAction>) {
+	fn new(auth_keys: PinnedAuthKeys, require_encryption: bool, submitter: &Arc<BlockSubmitter>, shutdown: &Arc<Shutdown>) -> (Arc<JobProviderHandler>, mpsc::Receiver<WorkProviderAction>) {
 		let (work_sender, work_receiver) = mpsc::channel(10);
 
-		(Arc::new(JobProviderHandler {
+		let us = Arc::new(JobProviderHandler {
 			state: Mutex::new(JobProviderState {
 				stream: None,
-				auth_key: expected_auth_key,
+				handshake_stream: None,
+				transport: TransportCrypto::new(),
+				auth_keys,
 
 				cur_template: None,
 				cur_prefix_postfix: None,
 
 				pending_tx_data_requests: HashMap::new(),
+				pending_nonces: Vec::new(),
+				tx_data_cache: BTreeMap::new(),
 				job_stream: work_sender,
+
+				flush_complete: Some(shutdown.register_flush()),
+				redirect: None,
+				replayed: false,
 			}),
+			require_encryption,
+			submitter: submitter.clone(),
 			secp_ctx: Secp256k1::new(),
-		}), work_receiver)
+			shutdown: shutdown.clone(),
+		});
+
+		let us_shutdown = us.clone();
+		tokio::spawn(shutdown.wait().then(move |_| {
+			let mut us = us_shutdown.state.lock().unwrap();
+			// Dropping our end of the stream lets the connection flush any winning nonces still
+			// queued and then close, at which point send_side_closed fires flush_complete.
+			if us.stream.take().is_none() && us.handshake_stream.take().is_none() {
+				if let Some(flush_complete) = us.flush_complete.take() {
+					let _ = flush_complete.send(());
+				}
+			}
+			future::result(Ok(()))
+		}));
+
+		(us, work_receiver)
+	}
+
+	/// Marks this provider as replayed from a file, so that there's never anything to connect to or
+	/// flush at shutdown, and full-block nonces found on its templates have nowhere to go.
+	pub fn start_replay(&self) {
+		let mut us = self.state.lock().unwrap();
+		us.replayed = true;
+		if let Some(flush_complete) = us.flush_complete.take() {
+			let _ = flush_complete.send(());
+		}
+	}
+
+	/// Hands a template (and its TransactionData) on as if this provider had sent them, for
+	/// providers replayed from a file instead of connected to.
+	pub fn replay_job(self: &Arc<Self>, template: BlockTemplate, data: TransactionData) {
+		let (txn, txn_tx) = EventualTxData::new();
+		let _ = txn_tx.send(data);
+		let mut us = self.state.lock().unwrap();
+		match us.job_stream.start_send(WorkProviderAction::JobUpdate {
+			job: WorkProviderJob {
+				template: template.clone(),
+				coinbase_prefix_postfix: None,
+				tx_data: txn,
+				provider: self.clone(),
+				submitter: self.submitter.clone(),
+			}
+		}) {
+			Ok(_) => {},
+			Err(_) => println!("Replayed job provider sending jobs too quickly"),
+		}
+		us.cur_template = Some(template);
 	}
 
 	pub fn send_nonce(&self, work: WinningNonce) {
-		let state = self.state.lock().unwrap();
-		match &state.stream {
+		let mut state = self.state.lock().unwrap();
+		if state.replayed {
+			println!("Dropping job-matching (ie full-block) nonce for a replayed template, there's no job provider to submit it to");
+			return;
+		}
+		let work = match &state.stream {
 			&Some(ref stream) => {
 				match stream.unbounded_send(WorkMessage::WinningNonce {
 					nonces: work
 				}) {
-					Ok(_) => { println!("Submitted job-matching (ie full-block) nonce!"); },
-					Err(_) => { println!("Failed to submit job-matching (ie full-block) nonce as job provider disconnected"); }
+					Ok(_) => { println!("Submitted job-matching (ie full-block) nonce!"); return; },
+					Err(e) => match e.into_inner() {
+						WorkMessage::WinningNonce { nonces } => nonces,
+						_ => unreachable!(),
+					},
 				}
 			},
-			&None => {
-				println!("Failed to submit job-matching (ie full-block) nonce!");
-			}
+			&None => work,
+		};
+		println!("Job provider is disconnected, will submit job-matching (ie full-block) nonce once it reconnects");
+		if state.pending_nonces.len() >= MAX_PENDING_NONCES {
+			state.pending_nonces.remove(0);
 		}
+		state.pending_nonces.push(work);
 	}
 }
 
@@ -149,28 +345,71 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 		match tx.start_send(WorkMessage::ProtocolSupport {
 			max_version: 1,
 			min_version: 1,
-			flags: 0,
+			flags: ENCRYPTION_FLAG,
 		}) {
 			Ok(_) => {
 				self.state.lock().unwrap().stream = Some(tx);
 			},
 			Err(_) => { println!("Job Provider disconnected before we could send version handshake"); },
 		}
-		(WorkMsgFramer::new(), rx)
+		let framer = WorkMsgFramer::new();
+		self.state.lock().unwrap().transport = framer.transport();
+		(framer, rx)
 	}
 
 	fn connection_closed(&self) {
 		let mut us = self.state.lock().unwrap();
 		let _ = us.job_stream.start_send(WorkProviderAction::ProviderDisconnected);
 		us.stream = None;
+		us.handshake_stream = None;
+		// Requests still in flight died with the connection, but we keep their EventualTxDatas (which
+		// jobs, and any full blocks found on them, are waiting on) and re-request them on reconnect
+	}
+
+	fn send_side_closed(&self) {
+		if self.shutdown.is_started() {
+			if let Some(flush_complete) = self.state.lock().unwrap().flush_complete.take() {
+				println!("Flushed pending nonces to job provider");
+				let _ = flush_complete.send(());
+			}
+		}
+	}
+
+	fn should_reconnect(&self) -> bool {
+		!self.shutdown.is_started()
+	}
+
+	fn send_heartbeat(&self) {
+		if let Some(ref stream) = self.state.lock().unwrap().stream {
+			let _ = stream.unbounded_send(WorkMessage::VendorMessage {
+				signature: None,
+				vendor: b"mining-proxy".to_vec(),
+				message: b"Heartbeat".to_vec(),
+			});
+		}
+	}
+
+	fn take_redirect(&self) -> Option<String> {
+		self.state.lock().unwrap().redirect.take()
 	}
 
 	fn handle_message(&self, msg: WorkMessage) -> Result<(), io::Error> {
 		let mut us = self.state.lock().unwrap();
-		if us.stream.is_none() { return Ok(()); }
+		if us.handshake_stream.is_some() {
+			match msg {
+				WorkMessage::EncryptionStart { .. } => {},
+				_ => {
+					println!("Job provider sent something other than EncryptionStart while we waited for it");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				},
+			}
+		} else if us.stream.is_none() { return Ok(()); }
 
 		macro_rules! check_msg_sig {
 			($msg_type: expr, $msg: expr, $signature: expr) => {
+				check_msg_sig!($msg_type, $msg, $signature, verify)
+			};
+			($msg_type: expr, $msg: expr, $signature: expr, $verify: ident) => {
 				{
 					let mut msg_signed = bytes::BytesMut::with_capacity(1000);
 					msg_signed.put_u8($msg_type);
@@ -183,12 +422,8 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 						secp256k1::Message::from_slice(&h).unwrap()
 					};
 
-					match us.auth_key {
-						Some(pubkey) => match self.secp_ctx.verify(&hash, &$signature, &pubkey) {
-							Ok(()) => {},
-							Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))
-						},
-						None => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError))
+					if !us.auth_keys.$verify(&self.secp_ctx, &hash, &$signature) {
+						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 					}
 				}
 			}
@@ -203,18 +438,47 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 				if selected_version != 1 {
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if flags != 0 {
+				if (flags & !ENCRYPTION_FLAG) != 0 {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				if !us.auth_keys.check_server_key(auth_key) {
+					println!("Got unexpected auth key");
 					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				if us.auth_key.is_none() {
-					us.auth_key = Some(auth_key.clone());
+				if (flags & ENCRYPTION_FLAG) != 0 {
+					let (ephemeral_key, tag) = match us.transport.start_client(&self.secp_ctx, auth_key) {
+						Some(start) => start,
+						None => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)),
+					};
+					match us.stream.as_ref().unwrap().start_send(WorkMessage::EncryptionStart { ephemeral_key, tag }) {
+						Ok(_) => {},
+						Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError)),
+					}
+					// Nothing else can go out until the provider's EncryptionStart arrives
+					us.handshake_stream = us.stream.take();
+					println!("Received ProtocolVersion, using version {} over an encrypted connection", selected_version);
+					return Ok(());
+				} else if self.require_encryption {
+					println!("Job provider would not encrypt our connection, disconnecting as encryption is required");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				} else {
-					if us.auth_key.unwrap() != *auth_key {
-						println!("Got unexpected auth key");
+					println!("Received ProtocolVersion, using version {}", selected_version);
+				}
+				us.handshake_complete();
+			},
+			WorkMessage::EncryptionStart { ephemeral_key, tag } => {
+				let stream = match us.handshake_stream.take() {
+					Some(stream) => stream,
+					None => {
+						println!("Received EncryptionStart?");
 						return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
-					}
+					},
+				};
+				if !us.transport.finish_client(&self.secp_ctx, &ephemeral_key, &tag) {
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 				}
-				println!("Received ProtocolVersion, using version {}", selected_version);
+				us.stream = Some(stream);
+				us.handshake_complete();
 			},
 			WorkMessage::AdditionalCoinbaseLength { .. } => {
 				println!("Received AdditionalCoinbaseLength?");
@@ -258,6 +522,11 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 						Ok(_) => {},
 						Err(_) => return Ok(()), // Disconnected
 					}
+					for &mut (_, ref mut replaced_time) in us.tx_data_cache.values_mut() {
+						if replaced_time.is_none() { *replaced_time = Some(timestamp); }
+					}
+					us.tx_data_cache.retain(|_, &mut (_, replaced_time)| replaced_time.unwrap() + TX_DATA_CACHE_EXPIRY_MS >= timestamp);
+					us.tx_data_cache.insert(template.template_timestamp, (txn.clone(), None));
 					let cur_postfix_prefix = us.cur_prefix_postfix.clone();
 					match us.job_stream.start_send(WorkProviderAction::JobUpdate {
 						job: WorkProviderJob {
@@ -265,6 +534,7 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 							coinbase_prefix_postfix: cur_postfix_prefix.clone(),
 							tx_data: txn,
 							provider: self.clone(),
+							submitter: self.submitter.clone(),
 						}
 					}) {
 						Ok(_) => {},
@@ -273,7 +543,7 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 							return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 						}
 					}
-					us.pending_tx_data_requests.insert(template.template_timestamp, txn_tx);
+					us.pending_tx_data_requests.insert(template.template_timestamp, (txn_tx, template.clone()));
 					us.cur_template = Some(template);
 				}
 			},
@@ -289,7 +559,24 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 				check_msg_sig!(7, data, signature);
 
 				match us.pending_tx_data_requests.remove(&data.template_timestamp) {
-					Some(chan) => {
+					Some((chan, template)) => {
+						if let Err(reason) = check_template_tx_data(&template, &data) {
+							println!("ALERT: Job provider sent a template which doesn't match its transactions ({})!", reason);
+							println!("ALERT: Ignoring its templates for the next {} seconds, please check the node behind it", BAD_TEMPLATE_DATA_BACKOFF_SECS);
+							let _ = us.job_stream.start_send(WorkProviderAction::BadTemplateData);
+							return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+						}
+						if let Some(check) = self.submitter.check_template(&template, &data.transactions) {
+							let mut job_stream = us.job_stream.clone();
+							tokio::spawn(check.then(move |res| {
+								if let Ok(Some(reason)) = res {
+									println!("ALERT: bitcoind rejected a job provider's template ({})!", reason);
+									println!("ALERT: Ignoring its templates for the next {} seconds, please check the node behind it", BAD_TEMPLATE_DATA_BACKOFF_SECS);
+									let _ = job_stream.start_send(WorkProviderAction::BadTemplateData);
+								}
+								future::result(Ok(()))
+							}));
+						}
 						match chan.send(data) {
 							Ok(()) => {},
 							Err(_) => {
@@ -334,15 +621,20 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 						let cur_prefix_postfix = us.cur_prefix_postfix.clone();
 						let template = us.cur_template.as_ref().unwrap().clone();
 
-						let (txn, txn_tx) = EventualTxData::new();
-						//TODO: This is pretty lazy...we should cache these instead of requesting
-						//new ones from the server...hopefully they dont update the coinbase prefix
-						//postfix very often...
-						match us.stream.as_ref().unwrap().unbounded_send(WorkMessage::TransactionDataRequest { template_timestamp: template.template_timestamp }) {
-							Ok(_) => {},
-							Err(_) => return Ok(()), // Disconnected
-						}
-						us.pending_tx_data_requests.insert(template.template_timestamp, txn_tx);
+						let cached_txn = us.tx_data_cache.get(&template.template_timestamp).map(|&(ref txn, _)| txn.clone());
+						let txn = match cached_txn {
+							Some(txn) => txn,
+							None => {
+								let (txn, txn_tx) = EventualTxData::new();
+								match us.stream.as_ref().unwrap().unbounded_send(WorkMessage::TransactionDataRequest { template_timestamp: template.template_timestamp }) {
+									Ok(_) => {},
+									Err(_) => return Ok(()), // Disconnected
+								}
+								us.pending_tx_data_requests.insert(template.template_timestamp, (txn_tx, template.clone()));
+								us.tx_data_cache.insert(template.template_timestamp, (txn.clone(), None));
+								txn
+							}
+						};
 
 						match us.job_stream.start_send(WorkProviderAction::JobUpdate {
 							job: WorkProviderJob {
@@ -350,6 +642,7 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 								coinbase_prefix_postfix: cur_prefix_postfix,
 								tx_data: txn,
 								provider: self.clone(),
+								submitter: self.submitter.clone(),
 							}
 						}) {
 							Ok(_) => {},
@@ -369,44 +662,314 @@ impl ConnectionHandler<WorkMessage> for Arc<JobProviderHandler> {
 				println!("Received WinningNonceHeader?");
 				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 			},
-			WorkMessage::NewWorkServer { .. } => {
-				unimplemented!();
+			WorkMessage::NewWorkServer { signature, new_host_port } => {
+				check_msg_sig!(11, NewServerHostPort(&new_host_port), signature);
+				// The new host still has to authenticate with the key(s) we've pinned for this one
+				if !socks5::is_host_port(&new_host_port) {
+					println!("Job provider is going away and asked us to move to an invalid host:port, reconnecting");
+					return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+				}
+				println!("Job provider is going away and asked us to move to {}, reconnecting there", new_host_port);
+				us.redirect = Some(new_host_port);
+				return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
 			},
-			WorkMessage::VendorMessage { .. } => {
-				println!("Got vendor message");
-				return Ok(());
+			WorkMessage::VendorMessage { signature, vendor, message } => {
+				match AuthKeyRotation::from_vendor_message(&self.secp_ctx, &vendor, &message) {
+					Some(rotation) => {
+						let signature = match signature {
+							Some(signature) => signature,
+							None => {
+								println!("Got unsigned AuthKeyRotation");
+								return Err(io::Error::new(io::ErrorKind::InvalidData, utils::HandleError));
+							}
+						};
+						check_msg_sig!(AUTH_KEY_ROTATION_SIG_TYPE, rotation, signature, verify_current);
+						us.auth_keys.pin_successor(&rotation);
+					},
+					None => println!("Got vendor message"),
+				}
 			},
 		}
 		Ok(())
 	}
 }
 
+#[derive(Clone)]
+pub struct JobProviderInfo {
+	pub host_port: String,
+	/// If set, we refuse to work with a job provider which doesn't authenticate with this key
+	pub auth_key: Option<PublicKey>,
+	/// If set, we connect through this proxy instead of directly
+	pub proxy: Option<Socks5Proxy>,
+	/// If set, we replay templates from this (loaded from the file:// host_port) instead
+	pub scenario: Option<Arc<replay_provider::Scenario>>,
+}
+
+/// How many of a provider's most recent new-tip lags we judge it on
+const TIP_LAG_SAMPLES: usize = 8;
+/// A provider which (over at least TIP_LAG_MIN_SAMPLES new tips) on average delivers templates on
+/// a new tip this much later than the fastest provider is demoted below every provider which isn't
+const TIP_LAG_DEMOTE_MS: u64 = 2_000;
+const TIP_LAG_MIN_SAMPLES: usize = 3;
+/// How many recent tips we remember the time we first saw a template building on
+const TIP_HISTORY: usize = 16;
+/// A provider which disconnects FLAP_DISCONNECTS times within FLAP_WINDOW_SECS is ignored for
+/// FLAP_BACKOFF_SECS
+const FLAP_DISCONNECTS: usize = 3;
+const FLAP_WINDOW_SECS: u64 = 10 * 60;
+const FLAP_BACKOFF_SECS: u64 = 5 * 60;
+const HEALTH_REPORT_SECS: u64 = 5 * 60;
+
+fn duration_ms(duration: Duration) -> u64 {
+	duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// How well a job provider has been keeping up, which we use to demote providers that are
+/// consistently slow to move to a new tip and to ignore providers whose connection keeps flapping.
+struct ProviderHealth {
+	host_port: String,
+	/// How long after the fastest provider we got a template on each of the last TIP_LAG_SAMPLES
+	/// new tips from this provider
+	tip_lags_ms: VecDeque<u64>,
+	last_template: Option<Instant>,
+	/// Exponential moving average of the time between templates
+	avg_template_interval_ms: Option<u64>,
+	recent_disconnects: VecDeque<Instant>,
+	total_disconnects: u64,
+}
+
+impl ProviderHealth {
+	fn new(host_port: String) -> Self {
+		Self {
+			host_port,
+			tip_lags_ms: VecDeque::with_capacity(TIP_LAG_SAMPLES),
+			last_template: None,
+			avg_template_interval_ms: None,
+			recent_disconnects: VecDeque::with_capacity(FLAP_DISCONNECTS),
+			total_disconnects: 0,
+		}
+	}
+
+	fn avg_tip_lag_ms(&self) -> Option<u64> {
+		if self.tip_lags_ms.len() < TIP_LAG_MIN_SAMPLES { return None; }
+		Some(self.tip_lags_ms.iter().sum::<u64>() / self.tip_lags_ms.len() as u64)
+	}
+
+	fn is_lagging(&self) -> bool {
+		self.avg_tip_lag_ms().map(|lag| lag > TIP_LAG_DEMOTE_MS).unwrap_or(false)
+	}
+
+	fn template_received(&mut self, now: Instant) {
+		if let Some(last) = self.last_template {
+			let interval = duration_ms(now - last);
+			self.avg_template_interval_ms = Some(match self.avg_template_interval_ms {
+				Some(avg) => (avg * 7 + interval) / 8,
+				None => interval,
+			});
+		}
+		self.last_template = Some(now);
+	}
+
+	fn tip_received(&mut self, lag_ms: u64) {
+		let was_lagging = self.is_lagging();
+		if self.tip_lags_ms.len() >= TIP_LAG_SAMPLES {
+			self.tip_lags_ms.pop_front();
+		}
+		self.tip_lags_ms.push_back(lag_ms);
+		if self.is_lagging() != was_lagging {
+			if was_lagging {
+				println!("Job provider {} caught up with the others on new tips, no longer demoting it", self.host_port);
+			} else {
+				println!("Job provider {} is lagging {} ms behind the fastest provider on new tips, demoting it", self.host_port, self.avg_tip_lag_ms().unwrap());
+			}
+		}
+	}
+
+	/// Returns true if the provider has now disconnected too often and should be ignored for a bit
+	fn disconnected(&mut self, now: Instant) -> bool {
+		self.total_disconnects += 1;
+		while self.recent_disconnects.front().map(|first| now - *first > Duration::from_secs(FLAP_WINDOW_SECS)).unwrap_or(false) {
+			self.recent_disconnects.pop_front();
+		}
+		self.recent_disconnects.push_back(now);
+		if self.recent_disconnects.len() >= FLAP_DISCONNECTS {
+			self.recent_disconnects.clear();
+			true
+		} else { false }
+	}
+}
+
 struct WorkProviderHolder {
 	is_connected: bool,
 	last_job: Option<WorkProviderJob>,
+	/// Set when the provider sent us a template which didn't match its transaction data or keeps
+	/// disconnecting
+	ignore_until: Option<Instant>,
+	health: ProviderHealth,
+}
+
+impl WorkProviderHolder {
+	fn ignore_for(&mut self, now: Instant, secs: u64) {
+		let until = now + Duration::from_secs(secs);
+		if self.ignore_until.map(|cur| cur < until).unwrap_or(true) {
+			self.ignore_until = Some(until);
+		}
+	}
+
+	fn health_summary(&self, now: Instant) -> String {
+		let status = match self.ignore_until {
+			Some(until) if until > now => format!("ignored for another {} s", (until - now).as_secs()),
+			_ if !self.is_connected => "disconnected".to_string(),
+			_ if self.health.is_lagging() => "connected, demoted for lagging".to_string(),
+			_ => "connected".to_string(),
+		};
+		let lag = match self.health.avg_tip_lag_ms() {
+			Some(lag) => format!("{} ms", lag),
+			None => "unknown".to_string(),
+		};
+		let interval = match self.health.avg_template_interval_ms {
+			Some(interval) => format!("{} ms", interval),
+			None => "unknown".to_string(),
+		};
+		format!("{}, average new-tip lag {}, average time between templates {}, {} disconnects", status, lag, interval, self.health.total_disconnects)
+	}
 }
 
 pub struct MultiJobProvider {
-	best_job: u64,
+	/// Provider index, template_timestamp and header_prevblock of the job we last handed out
+	best_job: Option<(usize, u64, [u8; 32])>,
 	jobs: Vec<WorkProviderHolder>,
+	/// When we first got a template building on each recent tip, from any provider
+	tip_first_seen: VecDeque<([u8; 32], Instant)>,
 	job_tx: mpsc::UnboundedSender<WorkProviderJob>,
 }
 
+/// The height of the block a template builds, from the BIP 34 height push at the start of its
+/// coinbase scriptSig. Templates without one sort below everything else.
+///
+/// Job providers don't tell us the total work of the chain they're on, so we use this in its
+/// place: outside of a difficulty adjustment a longer chain has more work, and competing tips at
+/// the same height fall back to which one more providers agree on.
+fn template_height(template: &BlockTemplate) -> u64 {
+	match template.coinbase_prefix.get(0) {
+		Some(&len) if len >= 1 && len <= 8 && template.coinbase_prefix.len() > len as usize => {
+			let mut height = 0;
+			for (i, byte) in template.coinbase_prefix[1..1 + len as usize].iter().enumerate() {
+				height |= (*byte as u64) << 8*i;
+			}
+			height
+		},
+		Some(&op) if op >= 0x51 && op <= 0x60 => (op - 0x50) as u64,
+		_ => 0,
+	}
+}
+
+/// How we rank a provider's job against the others', highest first: templates building on the
+/// highest tip, then providers which aren't lagging behind the others on new tips, then the tip
+/// most providers agree on, and only then the freshest template and the one paying the most fees.
+/// This way a provider stuck on a stale tip can't win just by having a later clock, nor a slow
+/// provider take over right after a block just because its template came last. Lag is only
+/// sampled when a provider moves to a new tip (so one stuck on an old tip never looks lagging),
+/// hence it never outranks height.
+fn job_rank(template: &BlockTemplate, tip_support: usize, lagging: bool) -> (u64, bool, usize, u64, u64) {
+	(template_height(template), !lagging, tip_support, template.template_timestamp, template.coinbase_value_remaining)
+}
+
 impl MultiJobProvider {
-	pub fn create(mut job_provider_hosts: Vec<String>) -> mpsc::UnboundedReceiver<WorkProviderJob> {
+	/// Picks the provider whose job we should be mining on, by job_rank. Providers whose templates
+	/// recently didn't match their transaction data are skipped, and otherwise only connected
+	/// providers are considered (or, if there are none, those whose last job we have full
+	/// transaction data for, which the pool could relay for us).
+	fn best_job_idx(&self) -> Option<usize> {
+		let now = Instant::now();
+		let usable = |holder: &&WorkProviderHolder| holder.ignore_until.map(|until| until <= now).unwrap_or(true);
+		let any_connected = self.jobs.iter().filter(usable).any(|holder| holder.is_connected && holder.last_job.is_some());
+		let candidates: Vec<(usize, &WorkProviderJob)> = self.jobs.iter().enumerate().filter(|&(_, ref holder)| usable(holder)).filter_map(|(idx, holder)| {
+			match holder.last_job {
+				Some(ref job) if holder.is_connected || (!any_connected && job.tx_data.has_result()) => Some((idx, job)),
+				_ => None,
+			}
+		}).collect();
+
+		candidates.iter().max_by_key(|&&(idx, job)| {
+			let tip_support = candidates.iter().filter(|&&(_, other)| other.template.header_prevblock == job.template.header_prevblock).count();
+			job_rank(&job.template, tip_support, self.jobs[idx].health.is_lagging())
+		}).map(|&(idx, _)| idx)
+	}
+
+	/// How long after we first got a template on prevblock (from any provider) now is
+	fn tip_lag_ms(&mut self, prevblock: &[u8; 32], now: Instant) -> u64 {
+		if let Some(&(_, first_seen)) = self.tip_first_seen.iter().find(|&&(ref tip, _)| tip == prevblock) {
+			return duration_ms(now - first_seen);
+		}
+		if self.tip_first_seen.len() >= TIP_HISTORY {
+			self.tip_first_seen.pop_front();
+		}
+		self.tip_first_seen.push_back((*prevblock, now));
+		0
+	}
+
+	fn job_received(&mut self, idx: usize, job: WorkProviderJob) {
+		let now = Instant::now();
+		let new_tip = self.jobs[idx].last_job.as_ref().map(|last| last.template.header_prevblock != job.template.header_prevblock).unwrap_or(true);
+		if new_tip {
+			let lag = self.tip_lag_ms(&job.template.header_prevblock, now);
+			// Only count lag if the provider was connected (with a job on the previous tip) when the
+			// new tip showed up, otherwise a reconnect would look like a slow provider
+			if self.jobs[idx].is_connected && self.jobs[idx].last_job.is_some() {
+				self.jobs[idx].health.tip_received(lag);
+			}
+		}
+		let holder = &mut self.jobs[idx];
+		holder.health.template_received(now);
+		holder.is_connected = true;
+		holder.last_job = Some(job);
+	}
+
+	fn provider_disconnected(&mut self, idx: usize) {
+		let now = Instant::now();
+		let holder = &mut self.jobs[idx];
+		holder.is_connected = false;
+		if holder.health.disconnected(now) {
+			println!("Job provider {} disconnected {} times in {} minutes, ignoring it for {} minutes", holder.health.host_port, FLAP_DISCONNECTS, FLAP_WINDOW_SECS / 60, FLAP_BACKOFF_SECS / 60);
+			holder.ignore_for(now, FLAP_BACKOFF_SECS);
+		}
+	}
+
+	fn update_best_job(&mut self) {
+		let idx = match self.best_job_idx() {
+			Some(idx) => idx,
+			None => return,
+		};
+		let job = self.jobs[idx].last_job.as_ref().unwrap().clone();
+		let job_id = (idx, job.template.template_timestamp, job.template.header_prevblock);
+		if self.best_job == Some(job_id) { return; }
+		if let Some((best_idx, _, best_prevblock)) = self.best_job {
+			if best_idx != idx || best_prevblock != job.template.header_prevblock {
+				println!("Switching to job provider {}'s tip at height {} ({})", self.jobs[idx].health.host_port, template_height(&job.template), self.jobs[idx].health_summary(Instant::now()));
+			}
+		}
+		self.best_job = Some(job_id);
+		self.job_tx.start_send(job).unwrap();
+	}
+
+	pub fn create(mut job_provider_hosts: Vec<JobProviderInfo>, known_hosts: Option<Arc<KnownHosts>>, upstream: UpstreamConfig, submitter: Arc<BlockSubmitter>, shutdown: Arc<Shutdown>) -> mpsc::UnboundedReceiver<WorkProviderJob> {
 		let (job_tx, job_rx) = mpsc::unbounded();
 		let cur_work_rc = Arc::new(Mutex::new(MultiJobProvider {
-			best_job: 0,
+			best_job: None,
 			jobs: Vec::with_capacity(job_provider_hosts.len()),
+			tip_first_seen: VecDeque::with_capacity(TIP_HISTORY),
 			job_tx: job_tx,
 		}));
 
 		tokio::spawn(future::lazy(move || -> Result<(), ()> {
 			for (idx, host) in job_provider_hosts.drain(..).enumerate() {
-				let (mut handler, mut job_rx) = JobProviderHandler::new(None);
+				let (mut handler, mut job_rx) = JobProviderHandler::new(PinnedAuthKeys::new(&host.host_port, host.auth_key, &known_hosts), upstream.require_encryption, &submitter, &shutdown);
 				cur_work_rc.lock().unwrap().jobs.push(WorkProviderHolder {
 					is_connected: false,
 					last_job: None,
+					ignore_until: None,
+					health: ProviderHealth::new(host.host_port.clone()),
 				});
 
 				let work_rc = cur_work_rc.clone();
@@ -414,55 +977,280 @@ impl MultiJobProvider {
 					let mut cur_work = work_rc.lock().unwrap();
 					match job {
 						WorkProviderAction::JobUpdate { job } => {
-							cur_work.jobs[idx].is_connected = true;
-							if cur_work.best_job < job.template.template_timestamp {
-								cur_work.best_job = job.template.template_timestamp;
-								cur_work.job_tx.start_send(job.clone()).unwrap();
-							}
-							cur_work.jobs[idx].last_job = Some(job);
+							cur_work.job_received(idx, job);
 						},
 						WorkProviderAction::ProviderDisconnected => {
-							if cur_work.jobs[idx].is_connected {
-								cur_work.jobs[idx].is_connected = false;
-								if cur_work.jobs[idx].last_job.is_some() && cur_work.jobs[idx].last_job.as_ref().unwrap().template.template_timestamp == cur_work.best_job {
-									cur_work.best_job = 0;
-
-									// Prefer jobs which are from connected providers, then jobs for which we can construct
-									// a complete weak block (which the pool could relay for us)...
-									let mut highest_timestamp = (0, 0);
-									let mut highest_connected_timestamp = (0, 0);
-									for (idx, job) in cur_work.jobs.iter().enumerate() {
-										if job.last_job.is_some() {
-											let job_template = job.last_job.as_ref().unwrap();
-											if job_template.tx_data.has_result() && job_template.template.template_timestamp > highest_timestamp.0 {
-												highest_timestamp = (job_template.template.template_timestamp, idx);
-											}
-											if job.is_connected && job_template.template.template_timestamp > highest_connected_timestamp.0 {
-												highest_connected_timestamp = (job_template.template.template_timestamp, idx);
-											}
-										}
-									}
-									if highest_connected_timestamp.0 != 0 {
-										let new_job = cur_work.jobs[highest_connected_timestamp.1].last_job.as_ref().unwrap().clone();
-										cur_work.job_tx.start_send(new_job).unwrap();
-									} else if highest_timestamp.0 != 0 {
-										let new_job = cur_work.jobs[highest_timestamp.1].last_job.as_ref().unwrap().clone();
-										cur_work.job_tx.start_send(new_job).unwrap();
-									}
-								}
-							}
+							cur_work.provider_disconnected(idx);
+						},
+						WorkProviderAction::BadTemplateData => {
+							cur_work.jobs[idx].last_job = None;
+							cur_work.jobs[idx].ignore_for(Instant::now(), BAD_TEMPLATE_DATA_BACKOFF_SECS);
 						},
 					}
+					cur_work.update_best_job();
 					Ok(())
 				}).then(|_| {
 					Ok(())
 				}));
-				ConnectionMaintainer::new(host, handler).make_connection();
+				match host.scenario {
+					Some(scenario) => replay_provider::start(scenario, handler),
+					None => ConnectionMaintainer::new(host.host_port, host.proxy, upstream.clone(), handler).make_connection(),
+				}
 			}
 
+			tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(HEALTH_REPORT_SECS), Duration::from_secs(HEALTH_REPORT_SECS)).for_each(move |_| {
+				let cur_work = cur_work_rc.lock().unwrap();
+				let now = Instant::now();
+				for (idx, holder) in cur_work.jobs.iter().enumerate() {
+					println!("Job provider {} ({}): {}", idx, holder.health.host_port, holder.health_summary(now));
+				}
+				future::result(Ok(()))
+			}).then(|_| {
+				future::result(Ok(()))
+			}));
+
 			Ok(())
 		}));
 
 		job_rx
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use work_client::*;
+
+	use bitcoin::blockdata::block::Block;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{TxIn, TxOut};
+	use bitcoin::util::hash::MerkleRoot;
+
+	fn template_with_prefix(coinbase_prefix: Vec<u8>) -> BlockTemplate {
+		BlockTemplate {
+			template_timestamp: 0,
+			target: [0; 32],
+			header_version: 0,
+			header_prevblock: [0; 32],
+			header_time: 0,
+			header_nbits: 0,
+			merkle_rhss: Vec::new(),
+			coinbase_value_remaining: 0,
+			coinbase_version: 0,
+			coinbase_prefix,
+			coinbase_postfix: Vec::new(),
+			coinbase_input_sequence: 0,
+			appended_coinbase_outputs: Vec::new(),
+			coinbase_locktime: 0,
+		}
+	}
+
+	fn spend_tx(prev_index: u32) -> Vec<u8> {
+		serialize::serialize(&Transaction {
+			version: 1,
+			input: vec![TxIn {
+				prev_hash: Default::default(),
+				prev_index,
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: vec![],
+			}],
+			output: vec![TxOut { value: 1000, script_pubkey: Script::from(vec![0x51]) }],
+			lock_time: 0,
+		}).unwrap()
+	}
+
+	fn tx_data(transactions: Vec<Vec<u8>>) -> TransactionData {
+		TransactionData {
+			previous_header: BlockHeader {
+				version: 0,
+				prev_blockhash: Default::default(),
+				merkle_root: Default::default(),
+				time: 0,
+				bits: 0,
+				nonce: 0,
+			},
+			template_timestamp: 0,
+			extra_block_data: Vec::new(),
+			transactions,
+		}
+	}
+
+	/// The txids of txn, after a placeholder for the coinbase
+	fn txids(txn: &[Vec<u8>]) -> Vec<[u8; 32]> {
+		let mut txids = vec![[0; 32]];
+		for tx in txn.iter() {
+			let tx_deser: Transaction = serialize::deserialize(tx).unwrap();
+			let mut txid = [0; 32];
+			txid.copy_from_slice(&tx_deser.txid()[..]);
+			txids.push(txid);
+		}
+		txids
+	}
+
+	#[test]
+	fn test_check_template_tx_data() {
+		// Height 420000, so a 12.5 BTC subsidy
+		let mut template = template_with_prefix(vec![3, 0xa0, 0x68, 0x06]);
+		template.coinbase_value_remaining = 1_250_000_000;
+		assert!(check_template_tx_data(&template, &tx_data(Vec::new())).is_ok());
+		// Claiming fees with no transactions to pay them
+		template.coinbase_value_remaining = 1_250_000_001;
+		assert!(check_template_tx_data(&template, &tx_data(Vec::new())).is_err());
+		template.coinbase_value_remaining = 1_250_000_000;
+		template.appended_coinbase_outputs.push(TxOut { value: 1, script_pubkey: Script::new() });
+		assert!(check_template_tx_data(&template, &tx_data(Vec::new())).is_err());
+		template.appended_coinbase_outputs.clear();
+
+		let txn = vec![spend_tx(0), spend_tx(1), spend_tx(2)];
+		template.merkle_rhss = block_assembly::coinbase_merkle_branch(txids(&txn));
+		assert!(check_template_tx_data(&template, &tx_data(txn.clone())).is_ok());
+
+		// Missing, reordered and extra transactions are all caught
+		assert!(check_template_tx_data(&template, &tx_data(txn[..2].to_vec())).is_err());
+		assert!(check_template_tx_data(&template, &tx_data(vec![txn[1].clone(), txn[0].clone(), txn[2].clone()])).is_err());
+		let mut extra = txn.clone();
+		extra.push(spend_tx(3));
+		assert!(check_template_tx_data(&template, &tx_data(extra)).is_err());
+		assert!(check_template_tx_data(&template, &tx_data(vec![txn[0].clone(), txn[1].clone(), vec![1, 2, 3]])).is_err());
+
+		// A transaction which looks like a coinbase is never valid
+		let mut coinbase_like = txn.clone();
+		coinbase_like[2] = spend_tx(0xffffffff);
+		template.merkle_rhss = block_assembly::coinbase_merkle_branch(txids(&coinbase_like));
+		assert!(check_template_tx_data(&template, &tx_data(coinbase_like)).is_err());
+	}
+
+	#[test]
+	fn test_proposal_block() {
+		let mut template = template_with_prefix(vec![3, 0xa0, 0x68, 0x06]);
+		template.coinbase_value_remaining = 1_250_000_000;
+		let txn = vec![spend_tx(0), spend_tx(1)];
+		template.merkle_rhss = block_assembly::coinbase_merkle_branch(txids(&txn));
+
+		let block: Block = serialize::deserialize(&block_assembly::proposal_block(&template, &txn)).unwrap();
+		assert_eq!(block.txdata.len(), 3);
+		assert_eq!(block.txdata[0].output[0].value, 1_250_000_000);
+		assert!(block.txdata[0].input[0].script_sig[..].starts_with(&template.coinbase_prefix));
+		// None of these have witnesses, so their txids are what MerkleRoot hashes
+		assert_eq!(block.header.merkle_root, block.txdata.merkle_root());
+	}
+
+	#[test]
+	fn test_job_rank() {
+		let mut low = template_with_prefix(vec![3, 0xa0, 0x68, 0x06]);
+		low.template_timestamp = 2;
+		let mut high = template_with_prefix(vec![3, 0xa1, 0x68, 0x06]);
+		high.template_timestamp = 1;
+
+		// A higher tip wins over a later clock, even from a provider which has been lagging behind
+		assert!(job_rank(&high, 1, false) > job_rank(&low, 1, false));
+		assert!(job_rank(&high, 1, true) > job_rank(&low, 1, false));
+		// On the same tip, providers which keep up win
+		assert!(job_rank(&low, 1, true) < job_rank(&low, 1, false));
+		let mut low_later = low.clone();
+		low_later.template_timestamp = 3;
+		assert!(job_rank(&low_later, 1, true) < job_rank(&low, 1, false));
+
+		// Then the tip more providers agree on
+		assert!(job_rank(&low, 2, false) > job_rank(&low, 1, false));
+		assert!(job_rank(&high, 1, false) > job_rank(&low, 3, false));
+
+		// Then the freshest template, and then fees
+		let mut fresh = low.clone();
+		fresh.template_timestamp = 3;
+		assert!(job_rank(&fresh, 1, false) > job_rank(&low, 1, false));
+		let mut rich = low.clone();
+		rich.coinbase_value_remaining = 1;
+		assert!(job_rank(&rich, 1, false) > job_rank(&low, 1, false));
+		assert!(job_rank(&fresh, 1, false) > job_rank(&rich, 1, false));
+	}
+
+	fn provider_holder(template: BlockTemplate, tip_lag_ms: u64, shutdown: &Arc<Shutdown>) -> WorkProviderHolder {
+		let (provider, _) = JobProviderHandler::new(PinnedAuthKeys::new("provider.example.com:8080", None, &None), false, &BlockSubmitter::new(Vec::new()), shutdown);
+		let mut health = ProviderHealth::new("provider.example.com:8080".to_string());
+		for _ in 0..TIP_LAG_SAMPLES {
+			health.tip_received(tip_lag_ms);
+		}
+		WorkProviderHolder {
+			is_connected: true,
+			last_job: Some(WorkProviderJob {
+				template,
+				coinbase_prefix_postfix: None,
+				tx_data: EventualTxData::new().0,
+				provider,
+				submitter: BlockSubmitter::new(Vec::new()),
+			}),
+			ignore_until: None,
+			health,
+		}
+	}
+
+	#[test]
+	fn test_best_job_lagging_alone_on_higher_tip() {
+		// JobProviderHandler::new spawns its shutdown handling, so needs to be called from a task
+		tokio::runtime::Runtime::new().unwrap().block_on(future::lazy(|| -> Result<(), ()> {
+			let shutdown = Shutdown::new();
+			let mut stale = template_with_prefix(vec![3, 0xa0, 0x68, 0x06]);
+			stale.template_timestamp = 2;
+			let mut tip = template_with_prefix(vec![3, 0xa1, 0x68, 0x06]);
+			tip.header_prevblock = [1; 32];
+			tip.template_timestamp = 1;
+
+			// The only provider on the real tip was slow to get there in the past, while the other
+			// is stuck on the old tip (and so never got a chance to look slow). We still want the tip.
+			let mut multi = MultiJobProvider {
+				best_job: None,
+				jobs: vec![provider_holder(stale.clone(), 0, &shutdown), provider_holder(tip, TIP_LAG_DEMOTE_MS * 2, &shutdown)],
+				tip_first_seen: VecDeque::new(),
+				job_tx: mpsc::unbounded().0,
+			};
+			assert!(multi.jobs[1].health.is_lagging());
+			assert_eq!(multi.best_job_idx(), Some(1));
+
+			// Once a provider which keeps up reaches the same tip, it wins
+			let mut caught_up = stale;
+			caught_up.header_prevblock = [1; 32];
+			caught_up.coinbase_prefix = vec![3, 0xa1, 0x68, 0x06];
+			multi.jobs[0] = provider_holder(caught_up, 0, &shutdown);
+			assert_eq!(multi.best_job_idx(), Some(0));
+			Ok(())
+		})).unwrap();
+	}
+
+	#[test]
+	fn test_provider_lagging() {
+		let mut health = ProviderHealth::new("test".to_string());
+		assert!(!health.is_lagging());
+		// Not enough samples to judge yet
+		for _ in 0..TIP_LAG_MIN_SAMPLES - 1 {
+			health.tip_received(TIP_LAG_DEMOTE_MS * 2);
+		}
+		assert!(!health.is_lagging());
+		health.tip_received(TIP_LAG_DEMOTE_MS * 2);
+		assert!(health.is_lagging());
+
+		// Catching up over the sample window clears it
+		for _ in 0..TIP_LAG_SAMPLES {
+			health.tip_received(0);
+		}
+		assert!(!health.is_lagging());
+		assert_eq!(health.avg_tip_lag_ms(), Some(0));
+
+		// It's the average which counts, not the odd slow tip
+		health.tip_received(TIP_LAG_DEMOTE_MS * 4);
+		assert!(!health.is_lagging());
+	}
+
+	#[test]
+	fn test_template_height() {
+		// 3-byte push of 550000
+		assert_eq!(template_height(&template_with_prefix(vec![3, 0x70, 0x64, 0x08, 0xff])), 550000);
+		// OP_1 through OP_16 for the first few blocks
+		assert_eq!(template_height(&template_with_prefix(vec![0x51])), 1);
+		assert_eq!(template_height(&template_with_prefix(vec![0x60, 0])), 16);
+		// Truncated pushes and missing heights sort last
+		assert_eq!(template_height(&template_with_prefix(vec![3, 0x70, 0x64])), 0);
+		assert_eq!(template_height(&template_with_prefix(vec![9, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10])), 0);
+		assert_eq!(template_height(&template_with_prefix(Vec::new())), 0);
+	}
+}
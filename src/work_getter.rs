@@ -1,8 +1,13 @@
 // This module is useful for anything that needs to speak pool+work protocols to get remote work
 // (eg for a mining client, or a proxy). Simpler clients may wish to only speak work protocol.
 
+use auth_keys::{KnownHosts, PinnedAuthKeys};
+use block_assembly::BlockSubmitter;
 use connection_maintainer::*;
 use pool_client::*;
+use share_queue::ShareQueue;
+use shutdown::Shutdown;
+use socks5::Socks5Proxy;
 use work_client::*;
 use work_info::*;
 
@@ -12,43 +17,231 @@ use futures::sync::mpsc;
 
 use bitcoin::blockdata::script::Script;
 
+use secp256k1::key::PublicKey;
+
 use futures::future;
 use futures::{Future,Stream,Sink};
 
 use tokio;
+use tokio::timer;
 
-use std;
-use std::cmp;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 struct PoolProviderHolder {
-	is_connected: bool,
+	handler: Arc<PoolHandler>,
+	host_port: String,
+	/// Our share of the hashrate if we're splitting it across pools
+	split_weight: Option<u64>,
+	/// Seconds we've spent mining on this pool, decaying with SPLIT_HISTORY_SECS
+	time_mined: f64,
 	last_job: Option<PoolProviderJob>,
 	last_user_job: Option<PoolProviderUserJob>,
 }
 
+/// When splitting hashrate we mine on one pool at a time for at least this long...
+const SPLIT_SLICE_SECS: u64 = 30;
+/// ...and then move to whichever pool is furthest behind its share of our mining time over roughly
+/// this long, so that a pool which was down for a while gets to catch up a bit, but not forever
+const SPLIT_HISTORY_SECS: f64 = 60.0 * 60.0;
+/// How often we print how our hashrate has actually been split
+const SPLIT_REPORT_SECS: u64 = 5 * 60;
+
+/// Given each pool's (time_mined, split_weight, whether we can mine on it), the index of the one we
+/// can mine on which is furthest behind its weighted share of the total time mined, preferring
+/// earlier pools on a tie.
+fn most_owed(pools: &[(f64, u64, bool)]) -> Option<usize> {
+	let total_time: f64 = pools.iter().map(|&(time_mined, _, _)| time_mined).sum();
+	let total_weight: u64 = pools.iter().map(|&(_, weight, _)| weight).sum();
+	pools.iter().enumerate().filter(|&(_, &(_, _, usable))| usable).map(|(idx, &(time_mined, weight, _))| {
+		(idx, total_time * weight as f64 / total_weight as f64 - time_mined)
+	}).fold(None, |best: Option<(usize, f64)>, (idx, owed)| {
+		match best {
+			Some((_, best_owed)) if best_owed >= owed => best,
+			_ => Some((idx, owed)),
+		}
+	}).map(|(idx, _)| idx)
+}
+
 pub struct MultiPoolProvider {
-	cur_pool: usize,
+	failover: PoolFailover,
+	/// Set if pools have split_weights and we're time-slicing our hashrate across them instead of
+	/// using them in priority order
+	split: bool,
+	/// When the current time slice started
+	slice_since: Instant,
+	/// When we last added to time_mined
+	time_accounted: Instant,
+	last_split_report: Instant,
 	pools: Vec<PoolProviderHolder>,
 	job_tx: mpsc::UnboundedSender<PoolProviderUserWork>,
 }
 
 pub struct PoolInfo {
 	pub host_port: String,
+	/// If set, we refuse to work with a pool which doesn't authenticate with this key
+	pub auth_key: Option<PublicKey>,
+	/// If set, we connect through this proxy instead of directly
+	pub proxy: Option<Socks5Proxy>,
 	pub user_id: Vec<u8>,
 	pub user_auth: Vec<u8>,
+	/// If set (for every pool), we split our hashrate across pools by these weights instead of
+	/// failing over between them in order
+	pub split_weight: Option<u64>,
 }
 
 pub struct PoolProviderUserWork {
 	pub payout_info: PoolProviderJob,
 	pub user_payout_info: PoolProviderUserJob,
+	/// Set if we just moved to this pool, so clients should drop work for the old one
+	pub clean_jobs: bool,
 }
 
 impl MultiPoolProvider {
-	pub fn create(mut pool_hosts: Vec<PoolInfo>) -> mpsc::UnboundedReceiver<PoolProviderUserWork> {
+	fn has_work(&self, idx: usize) -> bool {
+		self.pools[idx].last_job.is_some() && self.pools[idx].last_user_job.is_some()
+	}
+
+	fn send_cur_work(&mut self, clean_jobs: bool) {
+		let msg = {
+			let pool = &self.pools[self.failover.cur_pool().unwrap()];
+			PoolProviderUserWork {
+				payout_info: pool.last_job.as_ref().unwrap().clone(),
+				user_payout_info: pool.last_user_job.as_ref().unwrap().clone(),
+				clean_jobs,
+			}
+		};
+		self.job_tx.start_send(msg).unwrap();
+	}
+
+	/// Moves to pool idx, which must have work. The new work goes out to clients with clean_jobs
+	/// set, so that stratum clients drop the old pool's jobs immediately, and we start the pool's
+	/// weak block state over as it may have been a while since we sent it one.
+	fn switch_to(&mut self, idx: usize) {
+		self.account_time();
+		if let Some(cur_pool) = self.failover.cur_pool() {
+			println!("Switching from pool {} ({}) to pool {} ({})", cur_pool, self.pools[cur_pool].host_port, idx, self.pools[idx].host_port);
+		}
+		self.failover.switched_to(idx, self.pools[idx].handler.share_stats().shares_accepted);
+		self.slice_since = Instant::now();
+		self.pools[idx].last_job.as_ref().unwrap().provider.reset_weak_block_state();
+		self.send_cur_work(true);
+	}
+
+	fn account_time(&mut self) {
+		let now = Instant::now();
+		let elapsed = now - self.time_accounted;
+		let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+		let decay = (-elapsed_secs / SPLIT_HISTORY_SECS).exp();
+		for pool in self.pools.iter_mut() {
+			pool.time_mined *= decay;
+		}
+		if let Some(cur_pool) = self.failover.cur_pool() {
+			self.pools[cur_pool].time_mined += elapsed_secs;
+		}
+		self.time_accounted = now;
+	}
+
+	/// When splitting hashrate, the connected pool with work which is furthest behind its weighted
+	/// share of our mining time.
+	fn most_owed_pool(&self) -> Option<usize> {
+		let pools: Vec<(f64, u64, bool)> = (0..self.pools.len()).map(|idx| {
+			(self.pools[idx].time_mined, self.pools[idx].split_weight.unwrap(), self.failover.is_usable(idx))
+		}).collect();
+		most_owed(&pools)
+	}
+
+	/// Moves to the most owed pool once the current time slice is up (or cur_pool went away).
+	fn rebalance(&mut self) {
+		let now = Instant::now();
+		let cur_pool = self.failover.cur_pool();
+		if cur_pool.map(|idx| self.failover.is_usable(idx)).unwrap_or(false) &&
+				now - self.slice_since < Duration::from_secs(SPLIT_SLICE_SECS) {
+			return;
+		}
+		self.account_time();
+		match self.most_owed_pool() {
+			Some(idx) if Some(idx) != cur_pool => self.switch_to(idx),
+			Some(_) => self.slice_since = now,
+			None => {},
+		}
+	}
+
+	fn print_split_report(&mut self) {
+		self.account_time();
+		let total_time: f64 = self.pools.iter().map(|pool| pool.time_mined).sum();
+		let total_weight: u64 = self.pools.iter().map(|pool| pool.split_weight.unwrap()).sum();
+		let stats: Vec<PoolShareStats> = self.pools.iter().map(|pool| pool.handler.share_stats()).collect();
+		let total_work: f64 = stats.iter().map(|stats| stats.accepted_work()).sum();
+		for (idx, pool) in self.pools.iter().enumerate() {
+			println!("Pool {} ({}): target {:.1}% of hashrate, got {:.1}% of recent mining time and {:.1}% of all accepted work ({} shares accepted, {} rejected)",
+				idx, pool.host_port,
+				pool.split_weight.unwrap() as f64 * 100.0 / total_weight as f64,
+				if total_time > 0.0 { pool.time_mined * 100.0 / total_time } else { 0.0 },
+				if total_work > 0.0 { stats[idx].accepted_work() * 100.0 / total_work } else { 0.0 },
+				stats[idx].shares_accepted, stats[idx].shares_rejected);
+		}
+	}
+
+	fn timer_tick(&mut self) {
+		if self.split {
+			self.rebalance();
+			if Instant::now() - self.last_split_report >= Duration::from_secs(SPLIT_REPORT_SECS) {
+				self.last_split_report = Instant::now();
+				self.print_split_report();
+			}
+		} else {
+			if let Some(cur_pool) = self.failover.cur_pool() {
+				let shares_accepted = self.pools[cur_pool].handler.share_stats().shares_accepted;
+				if let Some(idx) = self.failover.check_probation(shares_accepted) {
+					self.switch_to(idx);
+				}
+			}
+			if let Some(idx) = self.failover.fail_back() {
+				self.switch_to(idx);
+			}
+		}
+	}
+
+	/// Called when cur_pool disconnects or rejects our user
+	fn fail_over(&mut self) {
+		let next = if self.split { self.most_owed_pool().or_else(|| self.failover.fail_over()) } else { self.failover.fail_over() };
+		if let Some(idx) = next {
+			self.switch_to(idx);
+		}
+	}
+
+	fn pool_updated(&mut self, idx: usize) {
+		let has_work = self.has_work(idx);
+		self.failover.pool_updated(idx, has_work);
+		let cur_pool = self.failover.cur_pool();
+		if Some(idx) == cur_pool {
+			if has_work {
+				self.send_cur_work(false);
+			}
+		} else if self.split {
+			if cur_pool.is_none() {
+				self.rebalance();
+			}
+		} else if let Some(idx) = self.failover.fail_back() {
+			self.switch_to(idx);
+		}
+	}
+
+	fn pool_failed(&mut self, idx: usize) {
+		if self.failover.pool_failed(idx) && self.failover.cur_pool() == Some(idx) {
+			self.fail_over();
+		}
+	}
+
+	pub fn create(mut pool_hosts: Vec<PoolInfo>, known_hosts: Option<Arc<KnownHosts>>, upstream: UpstreamConfig, share_queue_dir: Option<String>, config: PoolFailoverConfig, shutdown: Arc<Shutdown>) -> mpsc::UnboundedReceiver<PoolProviderUserWork> {
 		let (job_tx, job_rx) = mpsc::unbounded();
 		let cur_work_rc = Arc::new(Mutex::new(MultiPoolProvider {
-			cur_pool: std::usize::MAX,
+			failover: PoolFailover::new(config, pool_hosts.len()),
+			split: pool_hosts.iter().all(|pool| pool.split_weight.is_some()) && !pool_hosts.is_empty(),
+			slice_since: Instant::now(),
+			time_accounted: Instant::now(),
+			last_split_report: Instant::now(),
 			pools: Vec::with_capacity(pool_hosts.len()),
 			job_tx: job_tx,
 		}));
@@ -56,7 +249,7 @@ impl MultiPoolProvider {
 		tokio::spawn(future::lazy(move || -> Result<(), ()> {
 			for (idx, pool) in pool_hosts.drain(..).enumerate() {
 				let (mut auth_write, auth_read) = mpsc::channel(5);
-				let (mut handler, mut pool_rx) = PoolHandler::new(None, auth_read);
+				let (mut handler, mut pool_rx) = PoolHandler::new(PinnedAuthKeys::new(&pool.host_port, pool.auth_key, &known_hosts), upstream.require_encryption, ShareQueue::new(&share_queue_dir, &pool.host_port), auth_read, &shutdown);
 				auth_write.start_send(PoolAuthAction::AuthUser(PoolUserAuth {
 					suggested_target: [0xff; 32],
 					minimum_target: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0], // Diff 1
@@ -64,7 +257,10 @@ impl MultiPoolProvider {
 					user_auth: pool.user_auth,
 				})).unwrap();
 				cur_work_rc.lock().unwrap().pools.push(PoolProviderHolder {
-					is_connected: false,
+					handler: handler.clone(),
+					host_port: pool.host_port.clone(),
+					split_weight: pool.split_weight,
+					time_mined: 0.0,
 					last_job: None,
 					last_user_job: None,
 				});
@@ -72,73 +268,32 @@ impl MultiPoolProvider {
 				let work_rc = cur_work_rc.clone();
 				tokio::spawn(pool_rx.for_each(move |job| {
 					let mut cur_work = work_rc.lock().unwrap();
-					macro_rules! provider_disconnect {
-						() => {
-							if cur_work.pools[idx].is_connected {
-								cur_work.pools[idx].is_connected = false;
-								if cur_work.cur_pool == idx {
-									// Prefer pools which are connected, then follow the order they
-									// were provided in...
-									let mut lowest_with_work = std::usize::MAX;
-									for (iter_idx, pool) in cur_work.pools.iter().enumerate() {
-										if pool.last_job.is_some() && pool.last_user_job.is_some() {
-											if pool.is_connected {
-												lowest_with_work = iter_idx;
-												break;
-											} else {
-												lowest_with_work = cmp::min(lowest_with_work, iter_idx);
-											}
-										}
-									}
-									if lowest_with_work != std::usize::MAX {
-										let msg = {
-											let new_pool = &cur_work.pools[lowest_with_work];
-											PoolProviderUserWork {
-												payout_info: new_pool.last_job.as_ref().unwrap().clone(),
-												user_payout_info: new_pool.last_user_job.as_ref().unwrap().clone(),
-											}
-										};
-										cur_work.job_tx.start_send(msg).unwrap();
-									}
-								}
-							}
-						}
-					}
 					match job {
 						PoolProviderAction::UserUpdate { update, .. } => {
-							cur_work.pools[idx].is_connected = true;
-							if cur_work.cur_pool >= idx && cur_work.pools[idx].last_job.is_some() {
-								cur_work.cur_pool = idx;
-								let payout_info = cur_work.pools[idx].last_job.as_ref().unwrap().clone();
-								cur_work.job_tx.start_send(PoolProviderUserWork {
-									payout_info,
-									user_payout_info: update.clone(),
-								}).unwrap();
-							}
 							cur_work.pools[idx].last_user_job = Some(update);
+							cur_work.pool_updated(idx);
 						},
 						PoolProviderAction::PoolUpdate { info } => {
-							cur_work.pools[idx].is_connected = true;
-							if cur_work.cur_pool >= idx && cur_work.pools[idx].last_user_job.is_some() {
-								cur_work.cur_pool = idx;
-								let user_payout_info = cur_work.pools[idx].last_user_job.as_ref().unwrap().clone();
-								cur_work.job_tx.start_send(PoolProviderUserWork {
-									payout_info: info.clone(),
-									user_payout_info,
-								}).unwrap();
-							}
 							cur_work.pools[idx].last_job = Some(info);
+							cur_work.pool_updated(idx);
 						},
-						PoolProviderAction::UserReject { .. } => provider_disconnect!(),
-						PoolProviderAction::ProviderDisconnected => provider_disconnect!(),
+						PoolProviderAction::UserReject { .. } => cur_work.pool_failed(idx),
+						PoolProviderAction::ProviderDisconnected => cur_work.pool_failed(idx),
 					}
 					Ok(())
 				}).then(|_| {
 					Ok(())
 				}));
-				ConnectionMaintainer::new(pool.host_port, handler).make_connection();
+				ConnectionMaintainer::new(pool.host_port, pool.proxy, upstream.clone(), handler).make_connection();
 			}
 
+			tokio::spawn(timer::Interval::new(Instant::now() + Duration::from_secs(FAILBACK_CHECK_SECS), Duration::from_secs(FAILBACK_CHECK_SECS)).for_each(move |_| {
+				cur_work_rc.lock().unwrap().timer_tick();
+				future::result(Ok(()))
+			}).then(|_| {
+				future::result(Ok(()))
+			}));
+
 			Ok(())
 		}));
 
@@ -153,7 +308,7 @@ pub struct WorkGetter {
 }
 
 impl WorkGetter {
-	pub fn create(job_provider_hosts: Vec<String>, pool_server: Vec<PoolInfo>, solo_payout_script: Script) -> mpsc::UnboundedReceiver<WorkInfo> {
+	pub fn create(job_provider_hosts: Vec<JobProviderInfo>, pool_server: Vec<PoolInfo>, solo_payout_script: Script, known_hosts: Option<Arc<KnownHosts>>, upstream: UpstreamConfig, share_queue_dir: Option<String>, pool_failover: PoolFailoverConfig, submitter: Arc<BlockSubmitter>, shutdown: Arc<Shutdown>) -> mpsc::UnboundedReceiver<WorkInfo> {
 		let (mut job_tx, job_rx) = mpsc::unbounded();
 		let cur_work_rc = Arc::new(Mutex::new(WorkGetter {
 			payout_script: Some(solo_payout_script),
@@ -163,7 +318,7 @@ impl WorkGetter {
 
 		let job_work_rc = cur_work_rc.clone();
 		let mut job_work_tx = job_tx.clone();
-		tokio::spawn(MultiJobProvider::create(job_provider_hosts).for_each(move |work_update| {
+		tokio::spawn(MultiJobProvider::create(job_provider_hosts, known_hosts.clone(), upstream.clone(), submitter, shutdown.clone()).for_each(move |work_update| {
 			let mut cur_work = job_work_rc.lock().unwrap();
 			cur_work.cur_work = Some(work_update);
 			let cur_pool = if let &Some(ref pool) = &cur_work.cur_pool { Some(&pool.payout_info) } else { None };
@@ -173,10 +328,11 @@ impl WorkGetter {
 			}
 			Ok(())
 		}));
-		tokio::spawn(MultiPoolProvider::create(pool_server).for_each(move |pool_update| {
+		tokio::spawn(MultiPoolProvider::create(pool_server, known_hosts, upstream, share_queue_dir, pool_failover, shutdown).for_each(move |pool_update| {
 			let mut cur_work = cur_work_rc.lock().unwrap();
 			if let Some(ref work) = cur_work.cur_work {
-				if let Some(work) = merge_job_pool(&cur_work.payout_script, work, Some(&pool_update.payout_info), Some(&pool_update.user_payout_info)) {
+				if let Some(mut work) = merge_job_pool(&cur_work.payout_script, work, Some(&pool_update.payout_info), Some(&pool_update.user_payout_info)) {
+					work.clean_jobs = pool_update.clean_jobs;
 					job_tx.start_send(work).unwrap();
 				}
 			}
@@ -187,3 +343,30 @@ impl WorkGetter {
 		job_rx
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use work_getter::*;
+
+	#[test]
+	fn test_most_owed() {
+		// Nothing mined yet goes to the first usable pool
+		assert_eq!(most_owed(&[(0.0, 1, true), (0.0, 1, true)]), Some(0));
+		assert_eq!(most_owed(&[(0.0, 1, false), (0.0, 1, true)]), Some(1));
+		assert_eq!(most_owed(&[(0.0, 1, false), (0.0, 1, false)]), None);
+
+		// Equal weights go to whichever has had less time
+		assert_eq!(most_owed(&[(60.0, 1, true), (30.0, 1, true)]), Some(1));
+		assert_eq!(most_owed(&[(30.0, 1, true), (60.0, 1, true)]), Some(0));
+
+		// 3:1 weights, pool 0 is owed 3/4 of the time
+		assert_eq!(most_owed(&[(60.0, 3, true), (30.0, 1, true)]), Some(0));
+		assert_eq!(most_owed(&[(90.0, 3, true), (30.0, 1, true)]), Some(0));
+		assert_eq!(most_owed(&[(120.0, 3, true), (30.0, 1, true)]), Some(1));
+
+		// Pools we can't mine on don't get picked, however far behind they are, but still count
+		// towards the total time
+		assert_eq!(most_owed(&[(0.0, 1, false), (60.0, 1, true), (30.0, 1, true)]), Some(2));
+		assert_eq!(most_owed(&[(300.0, 1, false), (60.0, 1, true), (30.0, 1, true)]), Some(2));
+	}
+}
@@ -1,6 +1,7 @@
 use msg_framing::*;
 use pool_client::*;
 use work_client::*;
+use block_assembly::BlockSubmitter;
 
 use utils;
 
@@ -15,12 +16,85 @@ use futures::{Future,Stream};
 
 use tokio;
 
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct WorkInfo {
 	pub template: Arc<BlockTemplate>,
 	pub solutions: mpsc::UnboundedSender<Arc<(WinningNonce, Sha256dHash)>>,
+	/// Set if clients should drop their old jobs for this one even though it builds on the same
+	/// block (eg because we just moved to another pool)
+	pub clean_jobs: bool,
+	// The remaining fields are only used by MiningServer, which pool-proxy doesn't include
+
+	/// The transactions (and previous header) the template commits to, once the job provider
+	/// gets them to us.
+	#[allow(dead_code)]
+	pub tx_data: Arc<EventualTxData>,
+	/// The job provider's template before we added any payout info, for clients which add their
+	/// own (ie other proxies using us as their job provider).
+	#[allow(dead_code)]
+	pub nonfinal_template: Arc<BlockTemplate>,
+	/// Solutions to nonfinal_template pay someone else, so only go to the job provider, and only
+	/// if they're full blocks.
+	#[allow(dead_code)]
+	pub work_provider: Arc<JobProviderHandler>,
+	/// Where solutions to nonfinal_template go, along with work_provider
+	#[allow(dead_code)]
+	pub submitter: Arc<BlockSubmitter>,
+}
+
+/// The jobs a server's clients may still be submitting solutions for, by whatever id the server
+/// gave them. Job providers moving to a better tip (or us moving to a provider on one) may hand us
+/// jobs with older timestamps than ones we already have, so which job is the latest is tracked
+/// separately from the ids' order.
+pub struct JobSet<K, V> {
+	/// Each job, the block it builds on and when we replaced it with a newer job (if we have)
+	jobs: BTreeMap<K, (V, [u8; 32], Option<Instant>)>,
+	latest: Option<K>,
+}
+
+impl<K: Ord + Copy, V> JobSet<K, V> {
+	pub fn new() -> Self {
+		Self {
+			jobs: BTreeMap::new(),
+			latest: None,
+		}
+	}
+
+	/// Adds the job we should now hand out. Jobs building on any other block can no longer find
+	/// anything of use, so are dropped right away, the rest are kept until expire()d.
+	pub fn insert(&mut self, key: K, prevblock: [u8; 32], job: V) {
+		self.jobs.retain(|_, &mut (_, ref old_prevblock, _)| *old_prevblock == prevblock);
+		if let Some(latest) = self.latest {
+			if let Some(&mut (_, _, ref mut replaced_at)) = self.jobs.get_mut(&latest) {
+				*replaced_at = Some(Instant::now());
+			}
+		}
+		self.jobs.insert(key, (job, prevblock, None));
+		self.latest = Some(key);
+	}
+
+	pub fn get(&self, key: &K) -> Option<&V> {
+		self.jobs.get(key).map(|&(ref job, _, _)| job)
+	}
+
+	/// The job we most recently inserted
+	pub fn latest(&self) -> Option<&V> {
+		self.latest.and_then(|latest| self.get(&latest))
+	}
+
+	/// Whether expire(keep) would drop anything
+	pub fn has_expired(&self, keep: Duration) -> bool {
+		self.jobs.values().any(|&(_, _, replaced_at)| replaced_at.map(|at| at.elapsed() >= keep).unwrap_or(false))
+	}
+
+	/// Drops jobs which were replaced by a newer job at least keep ago
+	pub fn expire(&mut self, keep: Duration) {
+		self.jobs.retain(|_, &mut (_, _, replaced_at)| replaced_at.map(|at| at.elapsed() < keep).unwrap_or(true));
+	}
 }
 
 /// Merges some work and some pool payout information to build a job to mine on.
@@ -42,6 +116,8 @@ pub fn merge_job_pool(our_payout_script: &Option<Script>, work: &WorkProviderJob
 		&None => {}
 	}
 
+	let nonfinal_template = Arc::new(template.clone());
+
 	if template.coinbase_value_remaining <= 0 {
 		println!("Work provider returning 0-value work! Can't mine!");
 		return None;
@@ -106,12 +182,13 @@ pub fn merge_job_pool(our_payout_script: &Option<Script>, work: &WorkProviderJob
 	let tx_data_ref = work.tx_data.clone();
 	let template_ref = template_rc.clone();
 	let work_provider = work.provider.clone();
+	let submitter = work.submitter.clone();
 	let pool_provider = if let Some(ref pool_info) = pool {
 		Some(pool_info.provider.clone()) } else { None };
 
 	tokio::spawn(solution_rx.for_each(move |nonces: Arc<(WinningNonce, Sha256dHash)>| {
 		if utils::does_hash_meet_target(&nonces.1[..], &work_target[..]) {
-			work_provider.send_nonce(nonces.0.clone());
+			submitter.submit(&template_ref, &nonces.0, &tx_data_ref, &work_provider);
 		}
 		match pool_provider {
 			Some(ref provider) => {
@@ -131,6 +208,53 @@ pub fn merge_job_pool(our_payout_script: &Option<Script>, work: &WorkProviderJob
 
 	Some(WorkInfo {
 		template: template_rc,
-		solutions: solution_tx
+		solutions: solution_tx,
+		clean_jobs: false,
+		tx_data: work.tx_data.clone(),
+		nonfinal_template,
+		work_provider: work.provider.clone(),
+		submitter: work.submitter.clone(),
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use work_info::*;
+
+	#[test]
+	fn test_job_set_keeps_tip() {
+		let mut jobs = JobSet::new();
+		jobs.insert(20, [1; 32], "a");
+		jobs.insert(30, [1; 32], "b");
+		// An older template on the same tip replaces neither...
+		jobs.insert(10, [1; 32], "c");
+		assert_eq!(jobs.get(&20), Some(&"a"));
+		assert_eq!(jobs.get(&30), Some(&"b"));
+		// ...but is what we now hand out
+		assert_eq!(jobs.latest(), Some(&"c"));
+
+		// Anything not on a new tip is dropped immediately
+		jobs.insert(5, [2; 32], "d");
+		assert_eq!(jobs.get(&10), None);
+		assert_eq!(jobs.get(&20), None);
+		assert_eq!(jobs.get(&30), None);
+		assert_eq!(jobs.latest(), Some(&"d"));
+	}
+
+	#[test]
+	fn test_job_set_expiry() {
+		let mut jobs = JobSet::new();
+		jobs.insert(1, [1; 32], "a");
+		assert!(!jobs.has_expired(Duration::from_secs(0)));
+		jobs.insert(2, [1; 32], "b");
+		assert!(!jobs.has_expired(Duration::from_secs(30)));
+		jobs.expire(Duration::from_secs(30));
+		assert_eq!(jobs.get(&1), Some(&"a"));
+
+		assert!(jobs.has_expired(Duration::from_secs(0)));
+		jobs.expire(Duration::from_secs(0));
+		assert_eq!(jobs.get(&1), None);
+		// The latest job never expires
+		assert_eq!(jobs.latest(), Some(&"b"));
+	}
+}